@@ -0,0 +1,425 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Waker},
+};
+
+use crate::{database::Dataset, error::ReplyError};
+
+/// Runs the command of a blocked client on a locked dataset. Returns true if the client was
+/// served, with its result stored for it.
+type Serve = Box<dyn FnMut(&mut Dataset<'_>) -> bool + Send>;
+
+/// A client blocked on one or more keys.
+struct Waiter {
+    /// Keys the command accesses, which must be locked to serve it.
+    locked: Vec<Box<[u8]>>,
+    state: Mutex<WaiterState>,
+}
+
+struct WaiterState {
+    serve: Serve,
+    /// Set while the client waits to be served, and may be served by a writer.
+    parked: bool,
+    served: bool,
+    /// Keys signaled since the client last retried its command.
+    signaled: Vec<Box<[u8]>>,
+    /// Keys the client is retrying its command for.
+    retrying: Vec<Box<[u8]>>,
+    waker: Option<Waker>,
+}
+
+impl WaiterState {
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Waiter {
+    /// Signals the key unless it already is. Returns false if the waiter was already signaled
+    /// or served.
+    fn wake(&self, key: &[u8]) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.served || state.signaled.iter().any(|signaled| **signaled == *key) {
+            return false;
+        }
+        state.signaled.push(Box::from(key));
+        state.wake();
+        true
+    }
+
+    /// Serves the client if it is parked and its keys are locked. Returns None if it can't be
+    /// served, else whether it was.
+    fn serve(&self, lock: &mut Dataset<'_>) -> Option<bool> {
+        let mut state = self.state.lock().unwrap();
+        if state.served {
+            return Some(false);
+        }
+        if !state.parked || !lock.holds(&self.locked) {
+            return None;
+        }
+        if !(state.serve)(lock) {
+            return Some(false);
+        }
+        state.served = true;
+        state.parked = false;
+        state.wake();
+        Some(true)
+    }
+}
+
+/// Dataset index and key.
+type BlockedKey = (usize, Box<[u8]>);
+
+/// Clients blocked on keys, e.g. by BLPOP, queued per (dataset, key) in the order they blocked.
+///
+/// A write that makes a key ready serves the waiting clients with `serve`, longest waiting
+/// first, while it still holds the lock, so no later command can take what they were served.
+/// A client that is not parked, e.g. because it is retrying its command, is only woken to
+/// retry. When it leaves the queue, because it was served, timed out or disconnected, the
+/// signal passes to the next client in case the key has more to serve.
+pub(crate) struct BlockingKeys {
+    waiters: Mutex<HashMap<BlockedKey, VecDeque<Arc<Waiter>>>>,
+    /// Number of registered waiters, to skip looking up keys when none are blocked.
+    blocked: AtomicUsize,
+}
+
+impl BlockingKeys {
+    pub(crate) fn new() -> Self {
+        Self {
+            waiters: Mutex::new(HashMap::new()),
+            blocked: AtomicUsize::new(0),
+        }
+    }
+
+    /// Queues a waiter on all `keys`. The waiter is removed when the registration is dropped.
+    /// `serve` runs the command with the shards of `keys` and `also_locked` locked.
+    pub(crate) fn register<'a, K: AsRef<[u8]>, T: Send + 'static>(
+        &'a self,
+        dataset: usize,
+        keys: &[K],
+        also_locked: &[&[u8]],
+        mut serve: impl FnMut(&mut Dataset<'_>) -> Result<Option<T>, ReplyError> + Send + 'static,
+    ) -> Registration<'a, T> {
+        let result = Arc::new(Mutex::new(None));
+        let served = result.clone();
+        let serve: Serve = Box::new(move |lock| match serve(lock).transpose() {
+            Some(result) => {
+                *served.lock().unwrap() = Some(result);
+                true
+            }
+            None => false,
+        });
+
+        let mut keys: Vec<Box<[u8]>> = keys
+            .iter()
+            .map(|key| key.as_ref().to_vec().into_boxed_slice())
+            .collect();
+        keys.sort();
+        keys.dedup();
+        let locked = keys
+            .iter()
+            .cloned()
+            .chain(also_locked.iter().map(|&key| Box::from(key)))
+            .collect();
+        let waiter = Arc::new(Waiter {
+            locked,
+            state: Mutex::new(WaiterState {
+                serve,
+                parked: false,
+                served: false,
+                signaled: Vec::new(),
+                retrying: Vec::new(),
+                waker: None,
+            }),
+        });
+
+        let mut waiters = self.waiters.lock().unwrap();
+        for key in &keys {
+            waiters
+                .entry((dataset, key.clone()))
+                .or_default()
+                .push_back(waiter.clone());
+        }
+        self.blocked.fetch_add(1, Ordering::Relaxed);
+
+        Registration {
+            keys: self,
+            dataset,
+            registered: keys,
+            waiter,
+            result,
+        }
+    }
+
+    /// Whether a client is blocked on any of the keys, so that writing them may serve it.
+    pub(crate) fn is_blocked(&self, dataset: usize, keys: &[impl AsRef<[u8]>]) -> bool {
+        if self.blocked.load(Ordering::Relaxed) == 0 {
+            return false;
+        }
+        let waiters = self.waiters.lock().unwrap();
+        keys.iter()
+            .any(|key| waiters.contains_key(&(dataset, Box::from(key.as_ref()))))
+    }
+
+    /// Serves the clients blocked on the key from the locked dataset, longest waiting first.
+    pub(crate) fn serve(&self, dataset: usize, key: &[u8], lock: &mut Dataset<'_>) {
+        let waiters = self.waiters.lock().unwrap();
+        if let Some(queue) = waiters.get(&(dataset, Box::from(key))) {
+            serve_queue(queue, key, lock);
+        }
+    }
+
+    /// Serves the clients blocked on any key of the dataset, e.g. after SWAPDB.
+    pub(crate) fn serve_dataset(&self, dataset: usize, lock: &mut Dataset<'_>) {
+        let waiters = self.waiters.lock().unwrap();
+        for ((waiter_dataset, key), queue) in waiters.iter() {
+            if *waiter_dataset == dataset {
+                serve_queue(queue, key, lock);
+            }
+        }
+    }
+}
+
+/// Serves the queue in order until a client can't be served under the lock, which is then
+/// woken to retry instead. The clients behind it wait for their turn.
+fn serve_queue(queue: &VecDeque<Arc<Waiter>>, key: &[u8], lock: &mut Dataset<'_>) {
+    for waiter in queue {
+        if waiter.serve(lock).is_none() && waiter.wake(key) {
+            return;
+        }
+    }
+}
+
+/// Wakes the longest waiting client that has not been signaled or served yet.
+fn wake_first(queue: &VecDeque<Arc<Waiter>>, key: &[u8]) {
+    for waiter in queue {
+        if waiter.wake(key) {
+            return;
+        }
+    }
+}
+
+/// A client queued in `BlockingKeys`, served a `T`.
+pub(crate) struct Registration<'a, T> {
+    keys: &'a BlockingKeys,
+    dataset: usize,
+    registered: Vec<Box<[u8]>>,
+    waiter: Arc<Waiter>,
+    result: Arc<Mutex<Option<Result<T, ReplyError>>>>,
+}
+
+impl<T> Registration<'_, T> {
+    /// Keys to lock for `attempt`.
+    pub(crate) fn locked(&self) -> &[Box<[u8]>] {
+        &self.waiter.locked
+    }
+
+    /// Runs the command on the dataset, with the `locked` keys locked, unless a writer has
+    /// already served the client.
+    pub(crate) fn attempt(&self, lock: &mut Dataset<'_>) -> Option<Result<T, ReplyError>> {
+        {
+            let mut state = self.waiter.state.lock().unwrap();
+            state.parked = false;
+            if !state.served {
+                state.served = (state.serve)(lock);
+            }
+        }
+        self.result.lock().unwrap().take()
+    }
+
+    /// Lets writers serve the client until `unpark`, after an attempt that served nothing.
+    /// The keys the attempt was signaled for have nothing left to pass to the next client.
+    pub(crate) fn park(&self) {
+        let mut state = self.waiter.state.lock().unwrap();
+        state.retrying.clear();
+        state.parked = true;
+    }
+
+    /// Stops writers from serving the client. Returns what it was served while parked.
+    pub(crate) fn unpark(&self) -> Option<Result<T, ReplyError>> {
+        self.waiter.state.lock().unwrap().parked = false;
+        self.result.lock().unwrap().take()
+    }
+
+    /// Completes when the client has been served, or one of its keys has been signaled since
+    /// the last call.
+    pub(crate) fn notified(&self) -> Notified<'_> {
+        Notified {
+            waiter: &self.waiter,
+        }
+    }
+}
+
+impl<T> Drop for Registration<'_, T> {
+    fn drop(&mut self) {
+        let mut waiters = self.keys.waiters.lock().unwrap();
+        for key in &self.registered {
+            let map_key = (self.dataset, key.clone());
+            if let Some(queue) = waiters.get_mut(&map_key) {
+                queue.retain(|waiter| !Arc::ptr_eq(waiter, &self.waiter));
+                if queue.is_empty() {
+                    waiters.remove(&map_key);
+                }
+            }
+        }
+        self.keys.blocked.fetch_sub(1, Ordering::Relaxed);
+
+        // Pass the signals this client did not use up to the next clients in line.
+        let passed: Vec<Box<[u8]>> = {
+            let mut state = self.waiter.state.lock().unwrap();
+            let WaiterState {
+                signaled, retrying, ..
+            } = &mut *state;
+            signaled.drain(..).chain(retrying.drain(..)).collect()
+        };
+        for key in passed {
+            if let Some(queue) = waiters.get(&(self.dataset, key.clone())) {
+                wake_first(queue, &key);
+            }
+        }
+    }
+}
+
+pub(crate) struct Notified<'a> {
+    waiter: &'a Waiter,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.waiter.state.lock().unwrap();
+        if state.served {
+            return Poll::Ready(());
+        }
+        if state.signaled.is_empty() {
+            state.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let WaiterState {
+            signaled, retrying, ..
+        } = &mut *state;
+        retrying.append(signaled);
+        Poll::Ready(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::pin::pin;
+
+    use crate::database::Value;
+
+    /// Pops from the list under `key`, like BLPOP does when served.
+    fn pop(
+        key: &'static [u8],
+    ) -> impl FnMut(&mut Dataset<'_>) -> Result<Option<Vec<u8>>, ReplyError> {
+        move |lock| {
+            let Some(Value::List(list)) = lock.get_mut(key) else {
+                return Ok(None);
+            };
+            let element = list.pop_front();
+            if list.is_empty() {
+                lock.remove(key);
+            }
+            Ok(element)
+        }
+    }
+
+    fn push(lock: &mut Dataset<'_>, key: &[u8], elements: &[&str]) {
+        let list = elements.iter().map(|e| e.as_bytes().to_vec()).collect();
+        lock.set(Box::from(key), Value::List(list));
+    }
+
+    /// Registers a client blocked on `key`, which has tried its command and is waiting.
+    fn block<'a>(keys: &'a BlockingKeys, lock: &mut Dataset<'_>) -> Registration<'a, Vec<u8>> {
+        let registration = keys.register(0, &[b"key"], &[], pop(b"key"));
+        assert!(registration.attempt(lock).is_none());
+        registration.park();
+        registration
+    }
+
+    fn served(registration: &Registration<'_, Vec<u8>>) -> Option<String> {
+        let element = registration.unpark()?.unwrap();
+        Some(String::from_utf8(element).unwrap())
+    }
+
+    #[test]
+    fn longest_waiting_client_is_served_first() {
+        let keys = BlockingKeys::new();
+        let mut dataset = Dataset::new();
+        let first = block(&keys, &mut dataset);
+        let second = block(&keys, &mut dataset);
+
+        push(&mut dataset, b"key", &["a"]);
+        keys.serve(0, b"key", &mut dataset);
+        assert_eq!(served(&first).as_deref(), Some("a"));
+        assert_eq!(served(&second), None);
+    }
+
+    #[test]
+    fn push_of_several_elements_serves_several_clients() {
+        let keys = BlockingKeys::new();
+        let mut dataset = Dataset::new();
+        let first = block(&keys, &mut dataset);
+        let second = block(&keys, &mut dataset);
+
+        push(&mut dataset, b"key", &["a", "b"]);
+        keys.serve(0, b"key", &mut dataset);
+        assert_eq!(served(&first).as_deref(), Some("a"));
+        assert_eq!(served(&second).as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn served_before_next_command_of_writer() {
+        let keys = BlockingKeys::new();
+        let mut dataset = Dataset::new();
+        let blocked = block(&keys, &mut dataset);
+        let mut notified = pin!(blocked.notified());
+        let mut cx = Context::from_waker(Waker::noop());
+        assert!(notified.as_mut().poll(&mut cx).is_pending());
+
+        // Like RPUSH followed by a pipelined LPOP of the same client.
+        push(&mut dataset, b"key", &["x"]);
+        keys.serve(0, b"key", &mut dataset);
+        assert_eq!(pop(b"key")(&mut dataset).unwrap(), None);
+        assert!(notified.as_mut().poll(&mut cx).is_ready());
+        assert_eq!(served(&blocked).as_deref(), Some("x"));
+    }
+
+    #[test]
+    fn signal_passes_on_when_client_leaves() {
+        let keys = BlockingKeys::new();
+        let mut dataset = Dataset::new();
+        // Not parked yet, so woken to retry rather than served.
+        let first = keys.register(0, &[b"key"], &[], pop(b"key"));
+        let second = block(&keys, &mut dataset);
+        let mut notified = pin!(second.notified());
+        let mut cx = Context::from_waker(Waker::noop());
+        assert!(notified.as_mut().poll(&mut cx).is_pending());
+
+        push(&mut dataset, b"key", &["a"]);
+        keys.serve(0, b"key", &mut dataset);
+        assert_eq!(served(&second), None);
+        assert!(notified.as_mut().poll(&mut cx).is_pending());
+
+        // Like the first client timing out before it retried.
+        drop(first);
+        assert!(notified.as_mut().poll(&mut cx).is_ready());
+        assert_eq!(
+            second.attempt(&mut dataset).unwrap().unwrap(),
+            b"a".to_vec()
+        );
+    }
+}
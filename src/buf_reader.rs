@@ -1,7 +1,13 @@
 use std::io;
 
 use bytes::{BytesMut, Buf};
-use monoio::{io::AsyncReadRent, fs::File};
+use monoio::{
+    buf::IoBufMut,
+    fs::File,
+    io::{AsyncReadRent, AsyncWriteRent, AsyncWriteRentExt},
+};
+
+use crate::protocol::Protocol;
 
 pub(crate) trait BufReader {
     async fn try_fill_buf(&mut self) -> io::Result<usize>;
@@ -15,35 +21,69 @@ pub(crate) trait BufReaderExt {
     async fn read_u16(&mut self) -> io::Result<u16>;
     async fn read_u32(&mut self) -> io::Result<u32>;
     async fn read_u64(&mut self) -> io::Result<u64>;
+    async fn read_u32_be(&mut self) -> io::Result<u32>;
+    async fn read_u64_be(&mut self) -> io::Result<u64>;
     async fn read_bytes(&mut self, len: usize) -> io::Result<BytesMut>;
-    async fn read_until(&mut self, delimeter: &[u8]) -> io::Result<BytesMut>;
 }
 
 /// A buffered reader that reads bytes from an underlying reader.
 /// Uses a BytesMut buffer to store read bytes.
+/// Writes are buffered too, and sent before waiting for more input.
 pub(crate) struct TcpBufReader<R> {
     pub inner: R,
-    buffer: BytesMut
+    /// Protocol of the replies written to the stream.
+    pub protocol: Protocol,
+    /// While set, output is only sent by an explicit `flush`.
+    pub hold_output: bool,
+    buffer: BytesMut,
+    output: BytesMut,
 }
 
-impl<R: AsyncReadRent> TcpBufReader<R> {
+impl<R: AsyncReadRent + AsyncWriteRent> TcpBufReader<R> {
     pub fn new(inner: R) -> Self {
         Self {
             inner,
-            buffer: BytesMut::new()
+            protocol: Protocol::default(),
+            hold_output: false,
+            buffer: BytesMut::new(),
+            output: BytesMut::new(),
+        }
+    }
+
+    pub fn output_mut(&mut self) -> &mut BytesMut {
+        &mut self.output
+    }
+
+    /// Sends the buffered output.
+    pub async fn flush(&mut self) -> io::Result<()> {
+        if self.output.is_empty() {
+            return Ok(());
         }
+
+        let output = std::mem::take(&mut self.output);
+        let (result, mut output) = self.inner.write_all(output).await;
+        // Keeping the allocation avoids growing a new buffer for the next replies.
+        output.clear();
+        self.output = output;
+        result.map(|_| ())
     }
 }
 
-impl<R: AsyncReadRent> BufReader for TcpBufReader<R> {
+impl<R: AsyncReadRent + AsyncWriteRent> BufReader for TcpBufReader<R> {
     async fn try_fill_buf(&mut self) -> io::Result<usize> {
-        if self.buffer.capacity() < 1024 {
+        // The replies to all the commands read so far go out together in a single write.
+        self.flush().await?;
+
+        if self.buffer.capacity() - self.buffer.len() < 1024 {
             self.buffer.reserve(1024 * 1024);
         }
 
-        let (n, buffer) = self.inner.read(self.buffer.split_off(0)).await;
-        self.buffer = buffer;
-        Ok(n?)
+        // Read into the spare capacity, after the bytes that have not been consumed yet.
+        let buffer = self.buffer.split_off(0);
+        let (len, capacity) = (buffer.len(), buffer.capacity());
+        let (n, buffer) = self.inner.read(buffer.slice_mut(len..capacity)).await;
+        self.buffer = buffer.into_inner();
+        n
     }
 
     fn buffer(&self) -> &BytesMut {
@@ -69,16 +109,30 @@ impl FileBufReader {
             buffer: BytesMut::new()
         }
     }
+
+    /// Offset in the file of the next byte to be consumed.
+    pub fn position(&self) -> u64 {
+        self.pointer - self.buffer.len() as u64
+    }
+
+    pub fn file(&self) -> &File {
+        &self.inner
+    }
 }
 
 impl BufReader for FileBufReader {
     async fn try_fill_buf(&mut self) -> io::Result<usize> {
-        if self.buffer.capacity() < 1024 {
+        if self.buffer.capacity() - self.buffer.len() < 1024 {
             self.buffer.reserve(1024 * 1024);
         }
 
-        let (n, buffer) = self.inner.read_at(self.buffer.split_off(0), self.pointer).await;
-        self.buffer = buffer;
+        let buffer = self.buffer.split_off(0);
+        let (len, capacity) = (buffer.len(), buffer.capacity());
+        let (n, buffer) = self
+            .inner
+            .read_at(buffer.slice_mut(len..capacity), self.pointer)
+            .await;
+        self.buffer = buffer.into_inner();
         let n = n?;
 
         self.pointer += n as u64;
@@ -134,27 +188,25 @@ impl<R: BufReader> BufReaderExt for R {
         Ok(self.buffer_mut().get_u64_le())
     }
 
-    async fn read_bytes(&mut self, len: usize) -> io::Result<BytesMut> {
-        while self.buffer().len() < len {
+    async fn read_u32_be(&mut self) -> io::Result<u32> {
+        while self.buffer().len() < 4 {
             self.fill_buf().await?;
         }
+        Ok(self.buffer_mut().get_u32())
+    }
 
-        Ok(self.buffer_mut().split_to(len))
+    async fn read_u64_be(&mut self) -> io::Result<u64> {
+        while self.buffer().len() < 8 {
+            self.fill_buf().await?;
+        }
+        Ok(self.buffer_mut().get_u64())
     }
 
-    async fn read_until(&mut self, delimeter: &[u8]) -> io::Result<BytesMut> {
-        let mut line = BytesMut::new();
-        loop {
-            match self.buffer().windows(delimeter.len()).position(|bytes| bytes == delimeter) {
-                Some(pos) => {
-                    line.unsplit(self.buffer_mut().split_to(pos + delimeter.len()));
-                    return Ok(line);
-                }
-                None => {
-                    line.unsplit(self.buffer_mut().split());
-                    self.fill_buf().await?;
-                }
-            }
+    async fn read_bytes(&mut self, len: usize) -> io::Result<BytesMut> {
+        while self.buffer().len() < len {
+            self.fill_buf().await?;
         }
+
+        Ok(self.buffer_mut().split_to(len))
     }
 }
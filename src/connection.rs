@@ -2,15 +2,21 @@ use std::{
     collections::{HashMap, VecDeque},
     future::Future,
     pin::Pin,
-    time::{Duration, SystemTime},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use monoio::time::Instant;
+
 use bytes::BytesMut;
 use monoio::io::{AsyncReadRent, AsyncWriteRent};
 
 use crate::{
-    database::{Database, Value},
-    protocol::{RedisReadExt, RedisWrite}, buf_reader::TcpBufReader,
+    database::{Database, Dataset, DatasetContents, WatchedKeys},
+    error::{parse_int, CmdError, CmdResult, ReplyError},
+    protocol::{ProtocolError, ProtocolLimits, RedisRead, RedisWrite},
+    buf_reader::{BufReader, TcpBufReader},
+    rdb::save_rdb,
 };
 
 struct ParsedArgs {
@@ -18,7 +24,7 @@ struct ParsedArgs {
     named_args: HashMap<&'static str, Vec<BytesMut>>,
 }
 
-type CmdResultFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<()>> + 'a>>;
+type CmdResultFuture<'a> = Pin<Box<dyn Future<Output = CmdResult> + 'a>>;
 type CmdHandler<'db, Stream> =
     for<'a> fn(&'a mut Connection<'db, Stream>, ParsedArgs) -> CmdResultFuture<'a>;
 
@@ -74,14 +80,37 @@ macro_rules! cmd {
     }
 }
 
+mod client;
+mod databases;
+mod hash;
+mod keys;
+mod list;
+mod set;
+mod sorted_set;
+mod stream;
+mod string;
+mod transaction;
+
 fn create_command_specs<'db, Stream: AsyncReadRent + AsyncWriteRent>() -> CmdSpecs<'db, Stream> {
     let mut specs: CmdSpecs<'db, Stream> = HashMap::new();
 
     cmd!(specs, "ping", handle_ping);
     cmd!(specs, "echo", handle_echo, leading(1));
-    cmd!(specs, "get", handle_get, leading(1));
-    cmd!(specs, "set", handle_set, leading(2), named("px", 1));
-    cmd!(specs, "keys", handle_keys, leading(1));
+    cmd!(specs, "info", handle_info);
+    cmd!(specs, "save", handle_save);
+    cmd!(specs, "bgsave", handle_bgsave);
+    cmd!(specs, "lastsave", handle_lastsave);
+
+    client::register(&mut specs);
+    databases::register(&mut specs);
+    hash::register(&mut specs);
+    keys::register(&mut specs);
+    list::register(&mut specs);
+    set::register(&mut specs);
+    sorted_set::register(&mut specs);
+    stream::register(&mut specs);
+    string::register(&mut specs);
+    transaction::register(&mut specs);
 
     {
         // Subcommand: config
@@ -90,15 +119,18 @@ fn create_command_specs<'db, Stream: AsyncReadRent + AsyncWriteRent>() -> CmdSpe
         specs.insert("config", CmdListItem::SubSpecs(sub_specs));
     }
 
-    return specs;
+    specs
 }
 
 fn parse_args(
+    name: &str,
     leading_argc: usize,
     named_arg_argc: &HashMap<&'static str, usize>,
     mut unparsed_args: VecDeque<BytesMut>,
-) -> anyhow::Result<ParsedArgs> {
-    anyhow::ensure!(unparsed_args.len() >= leading_argc, "Not enough arguments");
+) -> Result<ParsedArgs, ReplyError> {
+    if unparsed_args.len() < leading_argc {
+        return Err(ReplyError::wrong_arity(name));
+    }
 
     let mut args = unparsed_args
         .drain(..leading_argc)
@@ -112,7 +144,7 @@ fn parse_args(
         let named_arg = named_arg_argc.get_key_value(lowercase.as_str());
         if let Some((key, argc)) = named_arg {
             if *argc > unparsed_args.len() {
-                anyhow::bail!("Missing value for named argument: {}", lowercase);
+                return Err(ReplyError::syntax());
             }
             named_args.insert(key, unparsed_args.drain(..*argc).collect());
         } else {
@@ -123,9 +155,24 @@ fn parse_args(
     Ok(ParsedArgs { args, named_args })
 }
 
+/// Source of the client IDs, unique during the lifetime of the server.
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
 pub(crate) struct Connection<'db, Stream: AsyncReadRent + AsyncWriteRent> {
     specs: CmdSpecs<'db, Stream>,
     db: &'db Database,
+    id: u64,
+    /// Name set with CLIENT SETNAME or HELLO SETNAME.
+    name: Option<BytesMut>,
+    /// Index of the dataset selected with SELECT.
+    db_index: usize,
+    limits: ProtocolLimits,
+    /// Commands queued after MULTI, until EXEC or DISCARD.
+    transaction: Option<transaction::Transaction<'db, Stream>>,
+    /// Set while EXEC runs the queued commands, which must not block.
+    executing: bool,
+    /// Keys watched with WATCH, until EXEC, DISCARD or UNWATCH.
+    watched: Option<WatchedKeys<'db>>,
     stream: TcpBufReader<Stream>,
 }
 
@@ -134,16 +181,23 @@ impl<'db, Stream: AsyncReadRent + AsyncWriteRent> Connection<'db, Stream> {
         Self {
             specs: create_command_specs(),
             db,
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            name: None,
+            db_index: 0,
+            limits: db.protocol_limits(),
+            transaction: None,
+            executing: false,
+            watched: None,
             stream: TcpBufReader::new(stream),
         }
     }
 
-    async fn handle_ping(&mut self, _: ParsedArgs) -> anyhow::Result<()> {
+    async fn handle_ping(&mut self, _: ParsedArgs) -> CmdResult {
         self.stream.write_simple_string("PONG").await?;
         Ok(())
     }
 
-    async fn handle_echo(&mut self, command: ParsedArgs) -> anyhow::Result<()> {
+    async fn handle_echo(&mut self, command: ParsedArgs) -> CmdResult {
         let mut args = command.args.into_iter();
         let echo = args.next().unwrap();
 
@@ -151,124 +205,436 @@ impl<'db, Stream: AsyncReadRent + AsyncWriteRent> Connection<'db, Stream> {
         Ok(())
     }
 
-    async fn handle_get(&mut self, command: ParsedArgs) -> anyhow::Result<()> {
+    async fn handle_config_get(&mut self, command: ParsedArgs) -> CmdResult {
         let mut args = command.args.into_iter();
         let key = args.next().unwrap();
 
-        let value;
-        {
-            let lock = self.db.read(0);
-            match lock.get(&key) {
-                Some(Value::String(data)) => {
-                    value = Some(data.clone());
-                }
-                // TODO: Handle more data types?
-                _ => {
-                    value = None;
+        let value = self.db.get_config(&key);
+        self.stream.write_map(1).await?;
+        self.stream.write_bulk_string(key).await?;
+        self.stream
+            .write_bulk_string_opt(value.map(|v| v.into_bytes()))
+            .await?;
+        Ok(())
+    }
+
+    async fn handle_info(&mut self, command: ParsedArgs) -> CmdResult {
+        let sections: Vec<String> = command
+            .args
+            .iter()
+            .map(|arg| String::from_utf8_lossy(arg).to_lowercase())
+            .collect();
+        let wants = |section: &str| {
+            sections.is_empty()
+                || sections
+                    .iter()
+                    .any(|s| s == section || s == "all" || s == "default" || s == "everything")
+        };
+
+        let mut info = String::new();
+        if wants("stats") {
+            let expired_keys: u64 = (0..self.db.dataset_count())
+                .map(|i| self.db.read(i).expired_keys())
+                .sum();
+            info.push_str("# Stats\r\n");
+            info.push_str(&format!("expired_keys:{}\r\n", expired_keys));
+            info.push_str("\r\n");
+        }
+        if wants("keyspace") {
+            info.push_str("# Keyspace\r\n");
+            for i in 0..self.db.dataset_count() {
+                let lock = self.db.read(i);
+                if lock.len() > 0 {
+                    info.push_str(&format!(
+                        "db{}:keys={},expires={}\r\n",
+                        i,
+                        lock.len(),
+                        lock.expires_len()
+                    ));
                 }
             }
         }
 
-        self.stream.write_bulk_string_opt(value).await?;
+        self.stream
+            .write_verbatim_string("txt", info.into_bytes())
+            .await?;
         Ok(())
     }
 
-    async fn handle_set(&mut self, command: ParsedArgs) -> anyhow::Result<()> {
-        let mut args = command.args.into_iter();
-        let key = args.next().unwrap();
-        let value = args.next().unwrap();
-
-        let expiry: Option<SystemTime>;
-        if let Some(px) = command.named_args.get("px") {
-            let px = std::str::from_utf8(&px[0])?.parse::<u64>()?;
-            expiry = Some(SystemTime::now() + Duration::from_millis(px));
-        } else {
-            expiry = None;
+    async fn handle_save(&mut self, _: ParsedArgs) -> CmdResult {
+        let state = self.db.save_state();
+        if !state.start_save() {
+            return Err(ReplyError::err("Background save already in progress").into());
         }
 
-        let key = key.to_vec().into_boxed_slice();
-        let value = value.to_vec();
-        {
-            let mut lock = self.db.write(0);
-            match expiry {
-                Some(expiry) => lock.set_expiry(key.clone(), expiry),
-                None => lock.unset_expiry(&key),
-            }
-            lock.set(key, Value::String(value));
+        let path = self.db.rdb_path();
+        let result = {
+            let locks = self.db.read_all();
+            let datasets: Vec<&Dataset> = locks.iter().collect();
+            save_rdb(&path, &datasets)
+        };
+        state.finish_save(result.is_ok());
+        if let Err(e) = result {
+            println!("Failed to save RDB to {}: {:?}", path.display(), e);
+            return Err(ReplyError::err("Failed to save the RDB file").into());
         }
+
         self.stream.write_simple_string("OK").await?;
         Ok(())
     }
 
-    async fn handle_config_get(&mut self, command: ParsedArgs) -> anyhow::Result<()> {
-        let mut args = command.args.into_iter();
-        let key = args.next().unwrap();
+    async fn handle_bgsave(&mut self, _: ParsedArgs) -> CmdResult {
+        let state = self.db.save_state().clone();
+        if !state.start_save() {
+            return Err(ReplyError::err("Background save already in progress").into());
+        }
 
-        let value = self.db.get_config(&key);
-        self.stream.write_array(2).await?;
-        self.stream.write_bulk_string(key).await?;
-        self.stream
-            .write_bulk_string_opt(value.map(|v| v.into_bytes()))
-            .await?;
+        // Only copying the datasets happens under the locks, the write is done by a separate
+        // thread so clients are served in the meanwhile.
+        let path = self.db.rdb_path();
+        let snapshot: Vec<DatasetContents> = (0..self.db.dataset_count())
+            .map(|i| self.db.read(i).snapshot())
+            .collect();
+        std::thread::spawn(move || {
+            let datasets: Vec<Dataset> = snapshot.iter().map(DatasetContents::as_dataset).collect();
+            let datasets: Vec<&Dataset> = datasets.iter().collect();
+            let result = save_rdb(&path, &datasets);
+            if let Err(ref e) = result {
+                println!("Background save to {} failed: {:?}", path.display(), e);
+            }
+            state.finish_save(result.is_ok());
+        });
+
+        self.stream.write_simple_string("Background saving started").await?;
         Ok(())
     }
 
-    async fn handle_keys(&mut self, command: ParsedArgs) -> anyhow::Result<()> {
-        let mut args = command.args.into_iter();
-        let pattern = args.next().unwrap();
+    async fn handle_lastsave(&mut self, _: ParsedArgs) -> CmdResult {
+        let last_save = self.db.save_state().last_save();
+        self.stream.write_integer(last_save as i64).await?;
+        Ok(())
+    }
 
-        if pattern.as_ref() != b"*" {
-            anyhow::bail!("Unsupported pattern: {:?}", pattern);
-        }
+    /// Runs `serve` until it returns a result, blocking on `keys` in between attempts
+    /// without holding any locks. While blocked, a write that makes one of the keys ready runs
+    /// `serve` for the client. `serve` gets the shards of `keys` and `also_locked` locked.
+    /// Returns None if the deadline passes first.
+    async fn block_on_keys<K: AsRef<[u8]>, T: Send + 'static>(
+        &mut self,
+        keys: &[K],
+        also_locked: &[&[u8]],
+        deadline: Option<Instant>,
+        serve: impl FnMut(&mut Dataset<'_>) -> Result<Option<T>, ReplyError> + Send + 'static,
+    ) -> Result<Option<T>, CmdError> {
+        let db = self.db;
+        // Registering before the first attempt makes sure no push in between is missed.
+        let registration = db
+            .blocking()
+            .register(self.db_index, keys, also_locked, serve);
 
-        let keys;
-        {
-            let lock = self.db.read(0);
-            keys = lock.all_keys().into_iter().map(|k| k.to_vec()).collect::<Vec<_>>();
-        }
-        self.stream.write_array(keys.len() as i64).await?;
-        for key in keys {
-            self.stream.write_bulk_string(key).await?;
+        loop {
+            let mut lock = db.write_keys(self.db_index, registration.locked());
+            if let Some(result) = registration.attempt(&mut lock) {
+                return Ok(Some(result?));
+            }
+            drop(lock);
+            // Inside EXEC, blocking commands time out right away.
+            if self.executing {
+                return Ok(None);
+            }
+            registration.park();
+
+            // Replies to the commands before this one are not held back while blocked.
+            self.stream.flush().await?;
+
+            // Reading from the socket detects a disconnected client. Pipelined commands
+            // already in the buffer are kept there until the command is unblocked.
+            let watch_socket = self.stream.buffer().is_empty();
+            let mut disconnected = false;
+            monoio::select! {
+                _ = registration.notified() => {}
+                _ = monoio::time::sleep_until(deadline.unwrap_or_else(Instant::now)),
+                    if deadline.is_some() => {
+                    return registration.unpark().transpose().map_err(CmdError::from);
+                }
+                read = self.stream.try_fill_buf(), if watch_socket => {
+                    disconnected = read? == 0;
+                }
+            }
+            if let Some(result) = registration.unpark() {
+                return Ok(Some(result?));
+            }
+            if disconnected {
+                return Err(anyhow::anyhow!("Client disconnected while blocked").into());
+            }
         }
-        Ok(())
     }
 
     pub(crate) async fn handle_connection(&mut self) -> anyhow::Result<()> {
-        let mut names: Vec<String> = Vec::new();
         loop {
-            let mut command: VecDeque<_> = self.stream.read_string_array().await?.into();
-            names.clear();
-
-            let mut map = &self.specs;
-            let found_spec: &CmdSpec<'db, Stream>;
-            loop {
-                anyhow::ensure!(
-                    !command.is_empty(),
-                    "Unexpected end of command: {:?}",
-                    names
-                );
-
-                let arg = command.pop_front().unwrap();
-                let lowercase = String::from_utf8_lossy(&arg).to_lowercase();
-                match map.get(lowercase.as_str()) {
-                    Some(CmdListItem::Spec(spec)) => {
-                        found_spec = spec;
-                        break;
+            let command = match self.stream.read_command(&self.limits).await {
+                Ok(Some(command)) => command,
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    if let Some(protocol_error) = ProtocolError::from_io(&e) {
+                        let reply = ReplyError::err(protocol_error.to_string());
+                        self.stream.write_error(reply.to_bytes()).await?;
+                        self.stream.flush().await?;
                     }
-                    Some(CmdListItem::SubSpecs(sub_cmds)) => {
-                        names.push(lowercase);
-                        map = sub_cmds;
-                    }
-                    None => {
-                        anyhow::bail!("Unknown command: {:?} -> {}", names, lowercase);
-                    }
-                };
+                    return Err(e.into());
+                }
+            };
+            match self.dispatch(command).await {
+                Ok(()) => {}
+                Err(CmdError::Reply(e)) => self.stream.write_error(e.to_bytes()).await?,
+                Err(CmdError::Fatal(e)) => return Err(e),
             }
+        }
+    }
 
-            let parsed_args =
-                parse_args(found_spec.leading_argc, &found_spec.named_arg_argc, command)?;
-            let handler = found_spec.handler;
-            handler(self, parsed_args).await?;
+    async fn dispatch(&mut self, command: Vec<BytesMut>) -> CmdResult {
+        let parsed = self.parse_command(command);
+        if self.transaction.is_some() {
+            return self.queue_command(parsed).await;
         }
+
+        let (_, handler, parsed_args) = parsed?;
+        handler(self, parsed_args).await
+    }
+
+    /// Looks up the handler of the command and parses its arguments. Returns the full name
+    /// of the command too, like `client|setname`.
+    fn parse_command(
+        &self,
+        command: Vec<BytesMut>,
+    ) -> Result<(String, CmdHandler<'db, Stream>, ParsedArgs), ReplyError> {
+        let mut command: VecDeque<_> = command.into();
+        let mut names: Vec<String> = Vec::new();
+
+        let mut map = &self.specs;
+        let found_spec: &CmdSpec<'db, Stream>;
+        loop {
+            let Some(arg) = command.pop_front() else {
+                return Err(match names.first() {
+                    Some(name) => ReplyError::wrong_arity(name),
+                    None => ReplyError::err("empty command"),
+                });
+            };
+
+            let lowercase = String::from_utf8_lossy(&arg).to_lowercase();
+            match map.get(lowercase.as_str()) {
+                Some(CmdListItem::Spec(spec)) => {
+                    names.push(lowercase);
+                    found_spec = spec;
+                    break;
+                }
+                Some(CmdListItem::SubSpecs(sub_cmds)) => {
+                    names.push(lowercase);
+                    map = sub_cmds;
+                }
+                None => {
+                    return Err(unknown_command(&names, &arg, &command));
+                }
+            };
+        }
+
+        let name = names.join("|");
+        let parsed_args = parse_args(
+            &name,
+            found_spec.leading_argc,
+            &found_spec.named_arg_argc,
+            command,
+        )?;
+        Ok((name, found_spec.handler, parsed_args))
+    }
+}
+
+/// Parses the timeout of a blocking command, in seconds. Zero blocks forever.
+fn parse_timeout(arg: &[u8]) -> Result<Option<Instant>, ReplyError> {
+    let timeout = std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse::<f64>().ok())
+        .filter(|timeout| timeout.is_finite())
+        .ok_or_else(|| ReplyError::err("timeout is not a float or out of range"))?;
+    if timeout < 0.0 {
+        return Err(ReplyError::err("timeout is negative"));
+    }
+    if timeout == 0.0 {
+        return Ok(None);
+    }
+    let timeout = Duration::try_from_secs_f64(timeout)
+        .map_err(|_| ReplyError::err("timeout is out of range"))?;
+    deadline_after(timeout).map(Some)
+}
+
+/// The instant a blocking command that waits for `timeout` gives up. Like Redis, which keeps
+/// deadlines as Unix time in milliseconds in a long long, rejects timeouts ending after that.
+fn deadline_after(timeout: Duration) -> Result<Instant, ReplyError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    if timeout.as_millis() > i64::MAX as u128 - now {
+        return Err(ReplyError::err("timeout is out of range"));
+    }
+    Instant::now()
+        .checked_add(timeout)
+        .ok_or_else(|| ReplyError::err("timeout is out of range"))
+}
+
+/// Parses a SCAN cursor, which is an unsigned 64-bit integer.
+fn parse_cursor(arg: &[u8]) -> Result<u64, ReplyError> {
+    parse_int(arg).map_err(|_| ReplyError::err("invalid cursor"))
+}
+
+/// Parses the COUNT option of the SCAN family, 10 by default.
+fn parse_scan_count(command: &ParsedArgs) -> Result<usize, ReplyError> {
+    let Some(count) = command.named_args.get("count") else {
+        return Ok(10);
+    };
+    let count: i64 = parse_int(&count[0])?;
+    if count < 1 {
+        return Err(ReplyError::syntax());
+    }
+    Ok(count as usize)
+}
+
+/// Advances a SCAN family cursor with `step`, which visits one bucket of the table into
+/// `found` and returns the next cursor. Stops once `count` elements are found or the table
+/// is done, and returns the cursor to continue from.
+fn scan_batch<T>(
+    mut cursor: u64,
+    count: usize,
+    found: &mut Vec<T>,
+    mut step: impl FnMut(u64, &mut Vec<T>) -> u64,
+) -> u64 {
+    // Bound the work on sparse tables, like Redis does.
+    let mut iterations = count.saturating_mul(10);
+    loop {
+        cursor = step(cursor, found);
+        iterations -= 1;
+        if cursor == 0 || iterations == 0 || found.len() >= count {
+            return cursor;
+        }
+    }
+}
+
+/// Formats a float for INCRBYFLOAT results, never in exponent notation and without trailing
+/// zeros. The shortest representation that parses back to the same value is used, so the
+/// reply is exactly the value stored.
+fn format_float(value: f64) -> String {
+    let mut formatted = value.to_string();
+    if formatted.contains('.') {
+        formatted.truncate(formatted.trim_end_matches('0').trim_end_matches('.').len());
+    }
+    if formatted == "-0" {
+        formatted.remove(0);
+    }
+    formatted
+}
+
+fn unknown_command(names: &[String], arg: &[u8], rest: &VecDeque<BytesMut>) -> ReplyError {
+    let arg = String::from_utf8_lossy(arg);
+    if let Some(parent) = names.first() {
+        return ReplyError::err(format!(
+            "unknown subcommand '{}'. Try {} HELP.",
+            arg,
+            parent.to_uppercase()
+        ));
+    }
+
+    let mut message = format!("unknown command '{}', with args beginning with: ", arg);
+    for arg in rest {
+        message.push_str(&format!("'{}' ", String::from_utf8_lossy(arg)));
+    }
+    ReplyError::err(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use monoio::{
+        io::AsyncWriteRentExt,
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::protocol::Protocol;
+
+    /// Sends an inline request and checks that the reply is `expected`.
+    async fn exchange(client: &mut TcpStream, request: &str, expected: &str) {
+        let (result, _) = client
+            .write_all(format!("{request}\r\n").into_bytes())
+            .await;
+        result.unwrap();
+        let mut reply = Vec::new();
+        // Stops at the first difference, so that a shorter reply fails instead of waiting.
+        while reply.len() < expected.len() && expected.as_bytes().starts_with(&reply) {
+            let (result, read) = client.read(Vec::with_capacity(4096)).await;
+            assert!(result.unwrap() > 0, "Connection closed");
+            reply.extend_from_slice(&read);
+        }
+        assert_eq!(
+            String::from_utf8_lossy(&reply),
+            expected,
+            "Reply to {request}"
+        );
+    }
+
+    /// Serves one client, until it disconnects.
+    async fn serve_one(db: &Database, listener: TcpListener, protocol: Protocol) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut connection = Connection::new(db, stream);
+        connection.stream.protocol = protocol;
+        connection.handle_connection().await.unwrap();
+    }
+
+    #[monoio::test(driver = "legacy", timer_enabled = true)]
+    async fn incrbyfloat_result_round_trips() {
+        let db = Database::new(1);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server = serve_one(&db, listener, Protocol::Resp2);
+        let client = async {
+            let mut client = TcpStream::connect(address).await.unwrap();
+            // The exact sum of the doubles, not 0.3.
+            let sum = "$19\r\n0.30000000000000004\r\n";
+            exchange(&mut client, "SET k 0.1", "+OK\r\n").await;
+            exchange(&mut client, "INCRBYFLOAT k 0.2", sum).await;
+            exchange(&mut client, "GET k", sum).await;
+            let zero = "$1\r\n0\r\n";
+            exchange(&mut client, "INCRBYFLOAT k -0.30000000000000004", zero).await;
+            // Never in exponent notation.
+            let large = "$21\r\n100000000000000000000\r\n";
+            exchange(&mut client, "INCRBYFLOAT k 1e20", large).await;
+        };
+        monoio::join!(server, client);
+    }
+
+    #[monoio::test(driver = "legacy", timer_enabled = true)]
+    async fn scan_replies_are_flat_arrays_in_resp3() {
+        let db = Database::new(1);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server = serve_one(&db, listener, Protocol::Resp3);
+        let client = async {
+            let mut client = TcpStream::connect(address).await.unwrap();
+            exchange(&mut client, "SET k v", "+OK\r\n").await;
+            exchange(&mut client, "HSET h f v", ":1\r\n").await;
+            exchange(&mut client, "SADD s m", ":1\r\n").await;
+            exchange(&mut client, "ZADD z 1 m", ":1\r\n").await;
+
+            let scan = "*2\r\n$1\r\n0\r\n*1\r\n$1\r\nk\r\n";
+            exchange(&mut client, "SCAN 0 MATCH k COUNT 1000", scan).await;
+            let hscan = "*2\r\n$1\r\n0\r\n*2\r\n$1\r\nf\r\n$1\r\nv\r\n";
+            exchange(&mut client, "HSCAN h 0", hscan).await;
+            let sscan = "*2\r\n$1\r\n0\r\n*1\r\n$1\r\nm\r\n";
+            exchange(&mut client, "SSCAN s 0", sscan).await;
+            let zscan = "*2\r\n$1\r\n0\r\n*2\r\n$1\r\nm\r\n$1\r\n1\r\n";
+            exchange(&mut client, "ZSCAN z 0", zscan).await;
+        };
+        monoio::join!(server, client);
     }
 }
@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+
+use bytes::BytesMut;
+use monoio::io::{AsyncReadRent, AsyncWriteRent};
+
+use crate::{
+    error::{parse_int, CmdResult, ReplyError},
+    protocol::{Protocol, RedisWrite},
+};
+
+use super::{CmdListItem, CmdSpec, CmdSpecs, Connection, ParsedArgs};
+
+/// Redis version reported to clients, which check it to know the supported commands.
+const REDIS_VERSION: &str = "7.2.0";
+
+pub(super) fn register<'db, Stream: AsyncReadRent + AsyncWriteRent>(
+    specs: &mut CmdSpecs<'db, Stream>,
+) {
+    cmd!(specs, "hello", handle_hello);
+
+    let mut sub_specs: CmdSpecs<'db, Stream> = HashMap::new();
+    cmd!(sub_specs, "id", handle_client_id);
+    cmd!(sub_specs, "getname", handle_client_getname);
+    cmd!(sub_specs, "setname", handle_client_setname, leading(1));
+    specs.insert("client", CmdListItem::SubSpecs(sub_specs));
+
+    let mut sub_specs: CmdSpecs<'db, Stream> = HashMap::new();
+    cmd!(sub_specs, "protocol", handle_debug_protocol, leading(1));
+    specs.insert("debug", CmdListItem::SubSpecs(sub_specs));
+}
+
+/// Checks a name given with CLIENT SETNAME or HELLO SETNAME. An empty name removes it.
+fn parse_client_name(name: BytesMut) -> Result<Option<BytesMut>, ReplyError> {
+    if name.iter().any(|&c| !(b'!'..=b'~').contains(&c)) {
+        return Err(ReplyError::err(
+            "Client names cannot contain spaces, newlines or special characters.",
+        ));
+    }
+    Ok(Some(name).filter(|name| !name.is_empty()))
+}
+
+impl<'db, Stream: AsyncReadRent + AsyncWriteRent> Connection<'db, Stream> {
+    /// Checks the credentials of HELLO AUTH. The only user is `default`, which accepts any
+    /// password unless `requirepass` is configured.
+    fn authenticate(&self, user: &[u8], password: &[u8]) -> Result<(), ReplyError> {
+        let valid = user == b"default"
+            && match self.db.get_config(b"requirepass") {
+                Some(required) if !required.is_empty() => required.as_bytes() == password,
+                _ => true,
+            };
+        if !valid {
+            return Err(ReplyError::new(
+                "WRONGPASS",
+                "invalid username-password pair or user is disabled.",
+            ));
+        }
+        Ok(())
+    }
+
+    async fn handle_hello(&mut self, command: ParsedArgs) -> CmdResult {
+        let mut args = command.args.into_iter();
+        let mut protocol = self.stream.protocol;
+        let mut name = None;
+
+        if let Some(version) = args.next() {
+            protocol = match parse_int::<i64>(&version) {
+                Ok(2) => Protocol::Resp2,
+                Ok(3) => Protocol::Resp3,
+                Ok(_) => {
+                    return Err(ReplyError::new("NOPROTO", "unsupported protocol version").into())
+                }
+                Err(_) => {
+                    return Err(ReplyError::err(
+                        "Protocol version is not an integer or out of range",
+                    )
+                    .into())
+                }
+            };
+        }
+
+        while let Some(option) = args.next() {
+            let lowercase = String::from_utf8_lossy(&option).to_lowercase();
+            match (lowercase.as_str(), args.len()) {
+                ("auth", 2..) => {
+                    let (user, password) = (args.next().unwrap(), args.next().unwrap());
+                    self.authenticate(&user, &password)?;
+                }
+                ("setname", 1..) => name = Some(parse_client_name(args.next().unwrap())?),
+                _ => {
+                    return Err(ReplyError::err(format!(
+                        "Syntax error in HELLO option '{}'",
+                        String::from_utf8_lossy(&option)
+                    ))
+                    .into())
+                }
+            }
+        }
+
+        // Nothing changes unless all the options are valid.
+        if let Some(name) = name {
+            self.name = name;
+        }
+        self.stream.protocol = protocol;
+
+        self.stream.write_map(7).await?;
+        self.stream.write_bulk_string("server").await?;
+        self.stream.write_bulk_string("redis").await?;
+        self.stream.write_bulk_string("version").await?;
+        self.stream.write_bulk_string(REDIS_VERSION).await?;
+        self.stream.write_bulk_string("proto").await?;
+        self.stream.write_integer(protocol.version()).await?;
+        self.stream.write_bulk_string("id").await?;
+        self.stream.write_integer(self.id as i64).await?;
+        self.stream.write_bulk_string("mode").await?;
+        self.stream.write_bulk_string("standalone").await?;
+        self.stream.write_bulk_string("role").await?;
+        self.stream.write_bulk_string("master").await?;
+        self.stream.write_bulk_string("modules").await?;
+        self.stream.write_array(0).await?;
+        Ok(())
+    }
+
+    async fn handle_client_id(&mut self, command: ParsedArgs) -> CmdResult {
+        if !command.args.is_empty() {
+            return Err(ReplyError::wrong_arity("client|id").into());
+        }
+
+        self.stream.write_integer(self.id as i64).await?;
+        Ok(())
+    }
+
+    async fn handle_client_getname(&mut self, command: ParsedArgs) -> CmdResult {
+        if !command.args.is_empty() {
+            return Err(ReplyError::wrong_arity("client|getname").into());
+        }
+
+        let name = self.name.clone();
+        self.stream.write_bulk_string_opt(name).await?;
+        Ok(())
+    }
+
+    async fn handle_client_setname(&mut self, command: ParsedArgs) -> CmdResult {
+        let mut args = command.args.into_iter();
+        let name = args.next().unwrap();
+        if args.next().is_some() {
+            return Err(ReplyError::wrong_arity("client|setname").into());
+        }
+
+        self.name = parse_client_name(name)?;
+        self.stream.write_simple_string("OK").await?;
+        Ok(())
+    }
+
+    /// Replies with a sample of the given type, to test how clients parse each of them.
+    async fn handle_debug_protocol(&mut self, command: ParsedArgs) -> CmdResult {
+        let kind = String::from_utf8_lossy(&command.args[0]).to_lowercase();
+        let resp3 = self.stream.protocol == Protocol::Resp3;
+
+        match kind.as_str() {
+            "string" => self.stream.write_bulk_string("Hello World").await?,
+            "integer" => self.stream.write_integer(12345).await?,
+            "double" => self.stream.write_double(1.5).await?,
+            "bignum" => {
+                self.stream
+                    .write_big_number("1234567999999999999999999999999999999")
+                    .await?
+            }
+            "null" => self.stream.write_null().await?,
+            "array" | "set" => {
+                if kind == "array" {
+                    self.stream.write_array(3).await?;
+                } else {
+                    self.stream.write_set(3).await?;
+                }
+                for i in 0..3 {
+                    self.stream.write_integer(i).await?;
+                }
+            }
+            "map" => {
+                self.stream.write_map(3).await?;
+                for i in 0..3 {
+                    self.stream.write_integer(i).await?;
+                    self.stream.write_bool(i == 1).await?;
+                }
+            }
+            "attrib" => {
+                if resp3 {
+                    self.stream.write_attribute(1).await?;
+                    self.stream.write_bulk_string("key-popularity").await?;
+                    self.stream.write_array(2).await?;
+                    self.stream.write_bulk_string("key:123").await?;
+                    self.stream.write_integer(90).await?;
+                }
+                // Attributes only describe the reply that follows them.
+                self.stream
+                    .write_bulk_string("Some real reply following the attribute")
+                    .await?;
+            }
+            "push" => {
+                if !resp3 {
+                    return Err(ReplyError::err("RESP2 is not supported by this command").into());
+                }
+                self.stream.write_push(2).await?;
+                self.stream.write_bulk_string("server-cpu-usage").await?;
+                self.stream.write_integer(42).await?;
+                // Clients waiting for the reply of the command skip the push.
+                self.stream
+                    .write_bulk_string("Some real reply following the push reply")
+                    .await?;
+            }
+            "verbatim" => {
+                self.stream
+                    .write_verbatim_string("txt", "This is a verbatim\nstring")
+                    .await?
+            }
+            "true" => self.stream.write_bool(true).await?,
+            "false" => self.stream.write_bool(false).await?,
+            _ => {
+                return Err(ReplyError::err(
+                    "Wrong protocol type name. Please use one of the following: \
+                     string|integer|double|bignum|null|array|set|map|attrib|push|verbatim|true|false",
+                )
+                .into())
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+use monoio::io::{AsyncReadRent, AsyncWriteRent};
+
+use crate::{
+    database::DatasetContents,
+    error::{parse_int, CmdResult, ReplyError},
+    lazy_free,
+    protocol::RedisWrite,
+};
+
+use super::{CmdListItem, CmdSpec, CmdSpecs, Connection, ParsedArgs};
+
+pub(super) fn register<'db, Stream: AsyncReadRent + AsyncWriteRent>(
+    specs: &mut CmdSpecs<'db, Stream>,
+) {
+    cmd!(specs, "select", handle_select, leading(1));
+    cmd!(specs, "swapdb", handle_swapdb, leading(2));
+    cmd!(specs, "move", handle_move, leading(2));
+    cmd!(specs, "dbsize", handle_dbsize);
+    cmd!(specs, "flushdb", handle_flushdb, flag("async"), flag("sync"));
+    cmd!(specs, "flushall", handle_flushall, flag("async"), flag("sync"));
+}
+
+/// Drops the contents of flushed datasets, on the lazy-free thread with FLUSH... ASYNC.
+fn drop_flushed(contents: impl Send + 'static, command: &ParsedArgs) {
+    if command.named_args.contains_key("async") {
+        lazy_free::free_later(contents);
+    }
+}
+
+impl<'db, Stream: AsyncReadRent + AsyncWriteRent> Connection<'db, Stream> {
+    pub(super) fn parse_db_index(&self, arg: &[u8]) -> Result<usize, ReplyError> {
+        let index: i64 = parse_int(arg)?;
+        if index < 0 || index as usize >= self.db.dataset_count() {
+            return Err(ReplyError::err("DB index is out of range"));
+        }
+        Ok(index as usize)
+    }
+
+    async fn handle_select(&mut self, command: ParsedArgs) -> CmdResult {
+        self.db_index = self.parse_db_index(&command.args[0])?;
+        self.stream.write_simple_string("OK").await?;
+        Ok(())
+    }
+
+    async fn handle_swapdb(&mut self, command: ParsedArgs) -> CmdResult {
+        let first = self.parse_db_index(&command.args[0])?;
+        let second = self.parse_db_index(&command.args[1])?;
+
+        if first != second {
+            let (mut first_lock, mut second_lock) = self.db.write_two(first, second);
+            first_lock.swap_contents(&mut second_lock);
+            // Clients blocked on keys of either dataset may be served by what was swapped in.
+            self.db.blocking().serve_dataset(first, &mut first_lock);
+            self.db.blocking().serve_dataset(second, &mut second_lock);
+        }
+        self.stream.write_simple_string("OK").await?;
+        Ok(())
+    }
+
+    async fn handle_move(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = &command.args[0];
+        let target = self.parse_db_index(&command.args[1])?;
+        if target == self.db_index {
+            return Err(ReplyError::err("source and destination objects are the same").into());
+        }
+
+        let moved;
+        {
+            let (mut source, mut destination) = self.db.write_two(self.db_index, target);
+            source.expire_if_needed(key);
+            destination.expire_if_needed(key);
+
+            if source.get(key).is_none() || destination.get(key).is_some() {
+                moved = false;
+            } else {
+                let expiry = source.get_expiry(key);
+                let value = source.remove(key).unwrap();
+                let key = key.to_vec().into_boxed_slice();
+                destination.set(key.clone(), value);
+                if let Some(expiry) = expiry {
+                    destination.set_expiry(key, expiry);
+                }
+                moved = true;
+            }
+            if moved {
+                self.db.signal_ready(&mut destination, target, key);
+            }
+        }
+
+        self.stream.write_integer(moved as i64).await?;
+        Ok(())
+    }
+
+    async fn handle_dbsize(&mut self, _: ParsedArgs) -> CmdResult {
+        let size = self.db.read(self.db_index).len();
+        self.stream.write_integer(size as i64).await?;
+        Ok(())
+    }
+
+    async fn handle_flushdb(&mut self, command: ParsedArgs) -> CmdResult {
+        if !command.args.is_empty() {
+            return Err(ReplyError::syntax().into());
+        }
+
+        let contents = self.db.write(self.db_index).take();
+        drop_flushed(contents, &command);
+        self.stream.write_simple_string("OK").await?;
+        Ok(())
+    }
+
+    async fn handle_flushall(&mut self, command: ParsedArgs) -> CmdResult {
+        if !command.args.is_empty() {
+            return Err(ReplyError::syntax().into());
+        }
+
+        // Every dataset is locked before any is cleared, so no client sees some flushed and
+        // others not.
+        let contents: Vec<DatasetContents> = self
+            .db
+            .write_all()
+            .iter_mut()
+            .map(|lock| lock.take())
+            .collect();
+        drop_flushed(contents, &command);
+        self.stream.write_simple_string("OK").await?;
+        Ok(())
+    }
+}
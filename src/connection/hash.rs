@@ -0,0 +1,480 @@
+use std::collections::HashSet;
+
+use monoio::io::{AsyncReadRent, AsyncWriteRent};
+
+use rand::{rngs::ThreadRng, seq::index};
+
+use crate::{
+    database::Value,
+    dict::Dict,
+    error::{parse_float, parse_int, CmdResult, ReplyError},
+    glob,
+    protocol::{Protocol, RedisWrite},
+};
+
+use super::{
+    format_float, parse_cursor, parse_scan_count, scan_batch, CmdListItem, CmdSpec, CmdSpecs,
+    Connection, ParsedArgs,
+};
+
+pub(super) fn register<'db, Stream: AsyncReadRent + AsyncWriteRent>(
+    specs: &mut CmdSpecs<'db, Stream>,
+) {
+    cmd!(specs, "hset", handle_hset, leading(3));
+    cmd!(specs, "hmset", handle_hmset, leading(3));
+    cmd!(specs, "hsetnx", handle_hsetnx, leading(3));
+    cmd!(specs, "hget", handle_hget, leading(2));
+    cmd!(specs, "hmget", handle_hmget, leading(2));
+    cmd!(specs, "hgetall", handle_hgetall, leading(1));
+    cmd!(specs, "hkeys", handle_hkeys, leading(1));
+    cmd!(specs, "hvals", handle_hvals, leading(1));
+    cmd!(specs, "hlen", handle_hlen, leading(1));
+    cmd!(specs, "hexists", handle_hexists, leading(2));
+    cmd!(specs, "hstrlen", handle_hstrlen, leading(2));
+    cmd!(specs, "hdel", handle_hdel, leading(2));
+    cmd!(specs, "hincrby", handle_hincrby, leading(3));
+    cmd!(specs, "hincrbyfloat", handle_hincrbyfloat, leading(3));
+    cmd!(
+        specs,
+        "hrandfield",
+        handle_hrandfield,
+        leading(1),
+        flag("withvalues")
+    );
+    cmd!(
+        specs,
+        "hscan",
+        handle_hscan,
+        leading(2),
+        named("match", 1),
+        named("count", 1),
+        flag("novalues")
+    );
+}
+
+pub(super) type Hash = Dict<Vec<u8>, Vec<u8>>;
+
+pub(super) fn as_hash(value: &Value) -> Result<&Hash, ReplyError> {
+    match value {
+        Value::Hash(hash) => Ok(hash),
+        _ => Err(ReplyError::wrong_type()),
+    }
+}
+
+pub(super) fn as_hash_mut(value: &mut Value) -> Result<&mut Hash, ReplyError> {
+    match value {
+        Value::Hash(hash) => Ok(hash),
+        _ => Err(ReplyError::wrong_type()),
+    }
+}
+
+impl<'db, Stream: AsyncReadRent + AsyncWriteRent> Connection<'db, Stream> {
+    /// Sets the field-value pairs following the key. Returns the number of new fields.
+    fn set_fields(&mut self, command: ParsedArgs, name: &str) -> Result<usize, ReplyError> {
+        if command.args.len().is_multiple_of(2) {
+            return Err(ReplyError::wrong_arity(name));
+        }
+        let mut args = command.args.into_iter();
+        let key = args.next().unwrap();
+
+        let mut lock = self.db.write_key(self.db_index, &key);
+        if let Some(value) = lock.get(&key) {
+            as_hash(value)?;
+        } else {
+            lock.set(key.to_vec().into_boxed_slice(), Value::Hash(Hash::new()));
+        }
+
+        let hash = as_hash_mut(lock.get_mut(&key).unwrap())?;
+        let mut added = 0;
+        while let (Some(field), Some(value)) = (args.next(), args.next()) {
+            if hash.insert(field.to_vec(), value.to_vec()).is_none() {
+                added += 1;
+            }
+        }
+        lock.signal_modified(&key);
+        Ok(added)
+    }
+
+    async fn handle_hset(&mut self, command: ParsedArgs) -> CmdResult {
+        let added = self.set_fields(command, "hset")?;
+        self.stream.write_integer(added as i64).await?;
+        Ok(())
+    }
+
+    async fn handle_hmset(&mut self, command: ParsedArgs) -> CmdResult {
+        self.set_fields(command, "hmset")?;
+        self.stream.write_simple_string("OK").await?;
+        Ok(())
+    }
+
+    async fn handle_hsetnx(&mut self, command: ParsedArgs) -> CmdResult {
+        if command.args.len() != 3 {
+            return Err(ReplyError::wrong_arity("hsetnx").into());
+        }
+        let key = &command.args[0];
+
+        let set;
+        {
+            let mut lock = self.db.write_key(self.db_index, key);
+            if let Some(value) = lock.get(key) {
+                as_hash(value)?;
+            } else {
+                lock.set(key.to_vec().into_boxed_slice(), Value::Hash(Hash::new()));
+            }
+
+            let hash = as_hash_mut(lock.get_mut(key).unwrap())?;
+            set = !hash.contains_key(command.args[1].as_ref());
+            if set {
+                hash.insert(command.args[1].to_vec(), command.args[2].to_vec());
+                lock.signal_modified(key);
+            }
+        }
+
+        self.stream.write_integer(set as i64).await?;
+        Ok(())
+    }
+
+    async fn handle_hget(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = &command.args[0];
+
+        let value = match self.db.read_key(self.db_index, key).get(key) {
+            Some(value) => as_hash(value)?.get(command.args[1].as_ref()).cloned(),
+            None => None,
+        };
+
+        self.stream.write_bulk_string_opt(value).await?;
+        Ok(())
+    }
+
+    async fn handle_hmget(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = &command.args[0];
+        let fields = &command.args[1..];
+
+        let values: Vec<Option<Vec<u8>>> = match self.db.read_key(self.db_index, key).get(key) {
+            Some(value) => {
+                let hash = as_hash(value)?;
+                fields
+                    .iter()
+                    .map(|field| hash.get(field.as_ref()).cloned())
+                    .collect()
+            }
+            None => vec![None; fields.len()],
+        };
+
+        self.stream.write_array(values.len() as i64).await?;
+        for value in values {
+            self.stream.write_bulk_string_opt(value).await?;
+        }
+        Ok(())
+    }
+
+    /// Replies with the fields and/or values of the hash as a flat array, or as a map with
+    /// both.
+    async fn reply_hash_contents(&mut self, key: &[u8], fields: bool, values: bool) -> CmdResult {
+        let elements: Vec<Vec<u8>> = match self.db.read_key(self.db_index, key).get(key) {
+            Some(value) => {
+                let mut elements = Vec::new();
+                for (field, value) in as_hash(value)?.iter() {
+                    if fields {
+                        elements.push(field.clone());
+                    }
+                    if values {
+                        elements.push(value.clone());
+                    }
+                }
+                elements
+            }
+            None => Vec::new(),
+        };
+
+        if fields && values {
+            self.stream.write_map(elements.len() as i64 / 2).await?;
+        } else {
+            self.stream.write_array(elements.len() as i64).await?;
+        }
+        for element in elements {
+            self.stream.write_bulk_string(element).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_hgetall(&mut self, command: ParsedArgs) -> CmdResult {
+        self.reply_hash_contents(&command.args[0], true, true).await
+    }
+
+    async fn handle_hkeys(&mut self, command: ParsedArgs) -> CmdResult {
+        self.reply_hash_contents(&command.args[0], true, false)
+            .await
+    }
+
+    async fn handle_hvals(&mut self, command: ParsedArgs) -> CmdResult {
+        self.reply_hash_contents(&command.args[0], false, true)
+            .await
+    }
+
+    async fn handle_hlen(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = &command.args[0];
+
+        let len = match self.db.read_key(self.db_index, key).get(key) {
+            Some(value) => as_hash(value)?.len(),
+            None => 0,
+        };
+
+        self.stream.write_integer(len as i64).await?;
+        Ok(())
+    }
+
+    async fn handle_hexists(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = &command.args[0];
+
+        let exists = match self.db.read_key(self.db_index, key).get(key) {
+            Some(value) => as_hash(value)?.contains_key(command.args[1].as_ref()),
+            None => false,
+        };
+
+        self.stream.write_integer(exists as i64).await?;
+        Ok(())
+    }
+
+    async fn handle_hstrlen(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = &command.args[0];
+
+        let len = match self.db.read_key(self.db_index, key).get(key) {
+            Some(value) => as_hash(value)?
+                .get(command.args[1].as_ref())
+                .map_or(0, |value| value.len()),
+            None => 0,
+        };
+
+        self.stream.write_integer(len as i64).await?;
+        Ok(())
+    }
+
+    async fn handle_hdel(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = &command.args[0];
+
+        let mut removed = 0;
+        {
+            let mut lock = self.db.write_key(self.db_index, key);
+            if let Some(value) = lock.get_mut(key) {
+                let hash = as_hash_mut(value)?;
+                for field in &command.args[1..] {
+                    if hash.remove(field.as_ref()).is_some() {
+                        removed += 1;
+                    }
+                }
+                if hash.is_empty() {
+                    lock.remove(key);
+                } else if removed > 0 {
+                    lock.signal_modified(key);
+                }
+            }
+        }
+
+        self.stream.write_integer(removed).await?;
+        Ok(())
+    }
+
+    /// Replaces the value of a hash field with `update(old value)`, creating the hash and
+    /// the field if needed. Returns the new value.
+    fn update_field(
+        &mut self,
+        key: &[u8],
+        field: &[u8],
+        update: impl FnOnce(Option<&[u8]>) -> Result<Vec<u8>, ReplyError>,
+    ) -> Result<Vec<u8>, ReplyError> {
+        let mut lock = self.db.write_key(self.db_index, key);
+        let old = match lock.get(key) {
+            Some(value) => as_hash(value)?.get(field).cloned(),
+            None => None,
+        };
+        let new = update(old.as_deref())?;
+
+        if lock.get(key).is_none() {
+            lock.set(key.into(), Value::Hash(Hash::new()));
+        }
+        as_hash_mut(lock.get_mut(key).unwrap())?.insert(field.to_vec(), new.clone());
+        lock.signal_modified(key);
+        Ok(new)
+    }
+
+    async fn handle_hincrby(&mut self, command: ParsedArgs) -> CmdResult {
+        if command.args.len() != 3 {
+            return Err(ReplyError::wrong_arity("hincrby").into());
+        }
+        let increment: i64 = parse_int(&command.args[2])?;
+
+        let mut result = 0;
+        self.update_field(&command.args[0], &command.args[1], |old| {
+            let old: i64 = match old {
+                Some(old) => {
+                    parse_int(old).map_err(|_| ReplyError::err("hash value is not an integer"))?
+                }
+                None => 0,
+            };
+            result = old
+                .checked_add(increment)
+                .ok_or_else(|| ReplyError::err("increment or decrement would overflow"))?;
+            Ok(result.to_string().into_bytes())
+        })?;
+
+        self.stream.write_integer(result).await?;
+        Ok(())
+    }
+
+    async fn handle_hincrbyfloat(&mut self, command: ParsedArgs) -> CmdResult {
+        if command.args.len() != 3 {
+            return Err(ReplyError::wrong_arity("hincrbyfloat").into());
+        }
+        let increment = parse_float(&command.args[2])?;
+
+        let value = self.update_field(&command.args[0], &command.args[1], |old| {
+            let old = match old {
+                Some(old) => {
+                    parse_float(old).map_err(|_| ReplyError::err("hash value is not a float"))?
+                }
+                None => 0.0,
+            };
+            let result = old + increment;
+            if !result.is_finite() {
+                return Err(ReplyError::err("increment would produce NaN or Infinity"));
+            }
+            Ok(format_float(result).into_bytes())
+        })?;
+
+        self.stream.write_bulk_string(value).await?;
+        Ok(())
+    }
+
+    async fn handle_hrandfield(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = &command.args[0];
+        let with_values = command.named_args.contains_key("withvalues");
+        let count = match command.args.get(1) {
+            Some(count) => Some(parse_int::<i64>(count)?),
+            None if with_values => return Err(ReplyError::syntax().into()),
+            None => None,
+        };
+        if command.args.len() > 2 {
+            return Err(ReplyError::syntax().into());
+        }
+        // The reply length must fit even when each field is followed by its value.
+        if count.is_some_and(|count| count < -(i64::MAX / 2)) {
+            return Err(ReplyError::err("value is out of range").into());
+        }
+
+        let mut entries: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+        {
+            let lock = self.db.read_key(self.db_index, key);
+            let hash = match lock.get(key) {
+                Some(value) => Some(as_hash(value)?),
+                None => None,
+            };
+            let mut rng = rand::thread_rng();
+            let pick = |rng: &mut ThreadRng| {
+                let (field, value) = hash.unwrap().random_entry(rng).unwrap();
+                (field.clone(), value.clone())
+            };
+
+            match (hash, count) {
+                (None, _) => {}
+                (Some(_), None) => entries.push(pick(&mut rng)),
+                // A negative count allows the same field to be returned multiple times.
+                (Some(_), Some(count)) if count < 0 => {
+                    entries = (0..count.unsigned_abs()).map(|_| pick(&mut rng)).collect();
+                }
+                (Some(hash), Some(count)) if count as usize >= hash.len() => {
+                    entries = hash
+                        .iter()
+                        .map(|(field, value)| (field.clone(), value.clone()))
+                        .collect();
+                }
+                // Like Redis, when most of the fields are returned, they are sampled from all
+                // of them.
+                (Some(hash), Some(count)) if count as usize * 3 > hash.len() => {
+                    let all: Vec<_> = hash.iter().collect();
+                    entries = index::sample(&mut rng, all.len(), count as usize)
+                        .into_iter()
+                        .map(|i| (all[i].0.clone(), all[i].1.clone()))
+                        .collect();
+                }
+                // Otherwise random fields are picked until there are enough different ones,
+                // which takes few attempts as most fields are not picked yet.
+                (Some(_), Some(count)) => {
+                    let mut picked = HashSet::new();
+                    while entries.len() < count as usize {
+                        let (field, value) = pick(&mut rng);
+                        if picked.insert(field.clone()) {
+                            entries.push((field, value));
+                        }
+                    }
+                }
+            }
+        }
+
+        let Some(_) = count else {
+            let field = entries.into_iter().next().map(|(field, _)| field);
+            self.stream.write_bulk_string_opt(field).await?;
+            return Ok(());
+        };
+
+        // RESP3 clients get each field with its value in a nested array.
+        let pairs = with_values && self.stream.protocol == Protocol::Resp3;
+        let len = entries.len() * if with_values && !pairs { 2 } else { 1 };
+        self.stream.write_array(len as i64).await?;
+        for (field, value) in &entries {
+            if pairs {
+                self.stream.write_array(2).await?;
+            }
+            self.stream.write_bulk_string(field).await?;
+            if with_values {
+                self.stream.write_bulk_string(value).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_hscan(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = &command.args[0];
+        let mut cursor = parse_cursor(&command.args[1])?;
+        if command.args.len() > 2 {
+            return Err(ReplyError::syntax().into());
+        }
+        let count = parse_scan_count(&command)?;
+        let pattern = command.named_args.get("match").map(|pattern| &pattern[0]);
+        let with_values = !command.named_args.contains_key("novalues");
+
+        let mut entries: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+        {
+            let lock = self.db.read_key(self.db_index, key);
+            match lock.get(key) {
+                Some(value) => {
+                    let hash = as_hash(value)?;
+                    cursor = scan_batch(cursor, count, &mut entries, |cursor, entries| {
+                        hash.scan(cursor, |field, value| {
+                            entries.push((field.clone(), value.clone()));
+                        })
+                    });
+                }
+                None => cursor = 0,
+            }
+        }
+
+        if let Some(pattern) = pattern {
+            entries.retain(|(field, _)| glob::matches(pattern, field, false));
+        }
+
+        self.stream.write_array(2).await?;
+        self.stream
+            .write_bulk_string(cursor.to_string().into_bytes())
+            .await?;
+        let len = entries.len() * if with_values { 2 } else { 1 };
+        self.stream.write_array(len as i64).await?;
+        for (field, value) in entries {
+            self.stream.write_bulk_string(field).await?;
+            if with_values {
+                self.stream.write_bulk_string(value).await?;
+            }
+        }
+        Ok(())
+    }
+}
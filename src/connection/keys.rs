@@ -0,0 +1,662 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use monoio::io::{AsyncReadRent, AsyncWriteRent};
+
+use crate::{
+    database::{Dataset, Value},
+    error::{parse_int, CmdResult, ReplyError},
+    glob, lazy_free,
+    protocol::RedisWrite,
+    set::Set,
+};
+
+use super::{
+    parse_cursor, parse_scan_count, scan_batch, CmdListItem, CmdSpec, CmdSpecs, Connection,
+    ParsedArgs,
+};
+
+pub(super) fn register<'db, Stream: AsyncReadRent + AsyncWriteRent>(
+    specs: &mut CmdSpecs<'db, Stream>,
+) {
+    cmd!(specs, "del", handle_del, leading(1));
+    cmd!(specs, "unlink", handle_unlink, leading(1));
+    cmd!(specs, "exists", handle_exists, leading(1));
+    cmd!(specs, "touch", handle_touch, leading(1));
+    cmd!(specs, "type", handle_type, leading(1));
+    cmd!(specs, "rename", handle_rename, leading(2));
+    cmd!(specs, "renamenx", handle_renamenx, leading(2));
+    cmd!(
+        specs,
+        "copy",
+        handle_copy,
+        leading(2),
+        named("db", 1),
+        flag("replace")
+    );
+    cmd!(specs, "randomkey", handle_randomkey);
+    cmd!(specs, "keys", handle_keys, leading(1));
+    cmd!(
+        specs,
+        "scan",
+        handle_scan,
+        leading(1),
+        named("match", 1),
+        named("count", 1),
+        named("type", 1)
+    );
+    cmd!(specs, "expire", handle_expire, leading(2));
+    cmd!(specs, "pexpire", handle_pexpire, leading(2));
+    cmd!(specs, "expireat", handle_expireat, leading(2));
+    cmd!(specs, "pexpireat", handle_pexpireat, leading(2));
+    cmd!(specs, "persist", handle_persist, leading(1));
+    cmd!(specs, "ttl", handle_ttl, leading(1));
+    cmd!(specs, "pttl", handle_pttl, leading(1));
+    cmd!(specs, "expiretime", handle_expiretime, leading(1));
+    cmd!(specs, "pexpiretime", handle_pexpiretime, leading(1));
+
+    let mut sub_specs: CmdSpecs<'db, Stream> = HashMap::new();
+    cmd!(sub_specs, "encoding", handle_object_encoding, leading(1));
+    cmd!(sub_specs, "idletime", handle_object_idletime, leading(1));
+    cmd!(sub_specs, "freq", handle_object_freq, leading(1));
+    cmd!(sub_specs, "refcount", handle_object_refcount, leading(1));
+    specs.insert("object", CmdListItem::SubSpecs(sub_specs));
+}
+
+/// Values freed with more work than this are dropped on the lazy-free thread by UNLINK, like
+/// `LAZYFREE_THRESHOLD` in Redis.
+const LAZYFREE_THRESHOLD: usize = 64;
+
+/// Rough number of allocations to free to drop the value.
+fn free_effort(value: &Value) -> usize {
+    match value {
+        Value::String(_) => 1,
+        Value::List(list) => list.len(),
+        Value::Set(set) => set.len(),
+        Value::Hash(hash) => hash.len(),
+        Value::SortedSet(set) => set.len(),
+        Value::Stream(stream) => stream.len(),
+    }
+}
+
+/// Limits of the compact encodings, the defaults of the `*-max-listpack-*` settings.
+const MAX_LISTPACK_ENTRIES: usize = 128;
+const MAX_LISTPACK_VALUE: usize = 64;
+const MAX_LIST_LISTPACK_BYTES: usize = 8 * 1024;
+
+/// Name of the encoding Redis would use for the value, as reported by OBJECT ENCODING.
+fn encoding(value: &Value) -> &'static str {
+    match value {
+        Value::String(data) if data.len() <= 20 && parse_canonical_int(data).is_some() => "int",
+        Value::String(data) if data.len() <= 44 => "embstr",
+        Value::String(_) => "raw",
+        Value::List(list) => {
+            let bytes: usize = list.iter().map(|element| element.len() + 2).sum();
+            if bytes <= MAX_LIST_LISTPACK_BYTES {
+                "listpack"
+            } else {
+                "quicklist"
+            }
+        }
+        Value::Set(Set::IntSet(_)) => "intset",
+        Value::Set(set) if fits_listpack(set.len(), set.iter().map(|m| m.len())) => "listpack",
+        Value::Set(_) => "hashtable",
+        Value::Hash(hash)
+            if fits_listpack(hash.len(), hash.iter().map(|(f, v)| f.len().max(v.len()))) =>
+        {
+            "listpack"
+        }
+        Value::Hash(_) => "hashtable",
+        Value::SortedSet(set) if fits_listpack(set.len(), set.iter().map(|(m, _)| m.len())) => {
+            "listpack"
+        }
+        Value::SortedSet(_) => "skiplist",
+        Value::Stream(_) => "stream",
+    }
+}
+
+fn fits_listpack(len: usize, mut value_lens: impl Iterator<Item = usize>) -> bool {
+    len <= MAX_LISTPACK_ENTRIES && value_lens.all(|len| len <= MAX_LISTPACK_VALUE)
+}
+
+fn parse_canonical_int(data: &[u8]) -> Option<i64> {
+    let value: i64 = std::str::from_utf8(data).ok()?.parse().ok()?;
+    (value.to_string().as_bytes() == data).then_some(value)
+}
+
+/// Stores a copy of a value for COPY. Returns false if the destination exists and is not
+/// replaced.
+fn store_copy(
+    destination: &mut Dataset,
+    key: &[u8],
+    (value, expiry): (Value, Option<SystemTime>),
+    replace: bool,
+) -> bool {
+    if destination.get(key).is_some() {
+        if !replace {
+            return false;
+        }
+        destination.remove(key);
+    }
+    destination.set(key.into(), value);
+    if let Some(expiry) = expiry {
+        destination.set_expiry(key.into(), expiry);
+    }
+    true
+}
+
+fn unix_ms(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+/// Conditions of the EXPIRE family. A key without TTL has an infinite TTL for GT and LT.
+#[derive(Default)]
+struct ExpireFlags {
+    /// `NX`: only if the key has no TTL.
+    nx: bool,
+    /// `XX`: only if the key has a TTL.
+    xx: bool,
+    /// `GT`: only if the new expire time is later.
+    gt: bool,
+    /// `LT`: only if the new expire time is earlier.
+    lt: bool,
+}
+
+impl ExpireFlags {
+    fn parse(args: &[impl AsRef<[u8]>]) -> Result<Self, ReplyError> {
+        let mut flags = Self::default();
+        for arg in args {
+            match arg.as_ref().to_ascii_lowercase().as_slice() {
+                b"nx" => flags.nx = true,
+                b"xx" => flags.xx = true,
+                b"gt" => flags.gt = true,
+                b"lt" => flags.lt = true,
+                _ => {
+                    return Err(ReplyError::err(format!(
+                        "Unsupported option {}",
+                        String::from_utf8_lossy(arg.as_ref())
+                    )))
+                }
+            }
+        }
+
+        if flags.nx && (flags.xx || flags.gt || flags.lt) {
+            return Err(ReplyError::err(
+                "NX and XX, GT or LT options at the same time are not compatible",
+            ));
+        }
+        if flags.gt && flags.lt {
+            return Err(ReplyError::err(
+                "GT and LT options at the same time are not compatible",
+            ));
+        }
+        Ok(flags)
+    }
+
+    /// Whether the expire time `when` can replace `current`, both in Unix milliseconds.
+    fn allow(&self, current: Option<i64>, when: i64) -> bool {
+        !(self.nx && current.is_some()
+            || self.xx && current.is_none()
+            || self.gt && current.is_none_or(|current| when <= current)
+            || self.lt && current.is_some_and(|current| when >= current))
+    }
+}
+
+impl<'db, Stream: AsyncReadRent + AsyncWriteRent> Connection<'db, Stream> {
+    /// Removes the keys. Returns the removed values.
+    fn remove_keys(&mut self, keys: &[impl AsRef<[u8]>]) -> Vec<Value> {
+        let mut lock = self.db.write_keys(self.db_index, keys);
+        keys.iter()
+            .filter_map(|key| {
+                let key = key.as_ref();
+                lock.expire_if_needed(key);
+                lock.remove(key)
+            })
+            .collect()
+    }
+
+    async fn handle_del(&mut self, command: ParsedArgs) -> CmdResult {
+        let removed = self.remove_keys(&command.args);
+        let count = removed.len();
+        drop(removed);
+
+        self.stream.write_integer(count as i64).await?;
+        Ok(())
+    }
+
+    async fn handle_unlink(&mut self, command: ParsedArgs) -> CmdResult {
+        let removed = self.remove_keys(&command.args);
+        let count = removed.len();
+        let effort: usize = removed.iter().map(free_effort).sum();
+        if effort > LAZYFREE_THRESHOLD {
+            lazy_free::free_later(removed);
+        }
+
+        self.stream.write_integer(count as i64).await?;
+        Ok(())
+    }
+
+    /// Counts the keys that exist, each time they are given. Updates their access time.
+    fn count_existing(&self, keys: &[impl AsRef<[u8]>]) -> usize {
+        let lock = self.db.read_keys(self.db_index, keys);
+        keys.iter()
+            .filter(|key| lock.get(key.as_ref()).is_some())
+            .count()
+    }
+
+    async fn handle_exists(&mut self, command: ParsedArgs) -> CmdResult {
+        let count = self.count_existing(&command.args);
+        self.stream.write_integer(count as i64).await?;
+        Ok(())
+    }
+
+    async fn handle_touch(&mut self, command: ParsedArgs) -> CmdResult {
+        let count = self.count_existing(&command.args);
+        self.stream.write_integer(count as i64).await?;
+        Ok(())
+    }
+
+    async fn handle_type(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = &command.args[0];
+        if command.args.len() > 1 {
+            return Err(ReplyError::wrong_arity("type").into());
+        }
+
+        let type_name = match self.db.read_key(self.db_index, key).peek(key) {
+            Some(value) => value.type_name(),
+            None => "none",
+        };
+
+        self.stream.write_simple_string(type_name).await?;
+        Ok(())
+    }
+
+    /// Renames the key, keeping its TTL. With `only_new`, fails if the new name exists.
+    /// Returns true if the key was renamed.
+    fn rename(
+        &mut self,
+        command: &ParsedArgs,
+        name: &str,
+        only_new: bool,
+    ) -> Result<bool, ReplyError> {
+        let (key, new_key) = (&command.args[0], &command.args[1]);
+        if command.args.len() > 2 {
+            return Err(ReplyError::wrong_arity(name));
+        }
+
+        let renamed = {
+            let mut lock = self.db.write_keys(self.db_index, &[key, new_key]);
+            lock.expire_if_needed(key);
+            lock.expire_if_needed(new_key);
+            if lock.get(key).is_none() {
+                return Err(ReplyError::err("no such key"));
+            }
+            if key == new_key {
+                return Ok(!only_new);
+            }
+            if only_new && lock.get(new_key).is_some() {
+                return Ok(false);
+            }
+
+            let expiry = lock.get_expiry(key);
+            let value = lock.remove(key).unwrap();
+            lock.remove(new_key);
+            lock.set(new_key.to_vec().into(), value);
+            if let Some(expiry) = expiry {
+                lock.set_expiry(new_key.to_vec().into(), expiry);
+            }
+            self.db.signal_ready(&mut lock, self.db_index, new_key);
+            true
+        };
+
+        Ok(renamed)
+    }
+
+    async fn handle_rename(&mut self, command: ParsedArgs) -> CmdResult {
+        self.rename(&command, "rename", false)?;
+        self.stream.write_simple_string("OK").await?;
+        Ok(())
+    }
+
+    async fn handle_renamenx(&mut self, command: ParsedArgs) -> CmdResult {
+        let renamed = self.rename(&command, "renamenx", true)?;
+        self.stream.write_integer(renamed as i64).await?;
+        Ok(())
+    }
+
+    async fn handle_copy(&mut self, command: ParsedArgs) -> CmdResult {
+        let (key, new_key) = (&command.args[0], &command.args[1]);
+        if command.args.len() > 2 {
+            return Err(ReplyError::syntax().into());
+        }
+        let target = match command.named_args.get("db") {
+            Some(index) => self.parse_db_index(&index[0])?,
+            None => self.db_index,
+        };
+        let replace = command.named_args.contains_key("replace");
+        if target == self.db_index && key == new_key {
+            return Err(ReplyError::err("source and destination objects are the same").into());
+        }
+
+        let copied = if target == self.db_index {
+            let mut lock = self.db.write_keys(self.db_index, &[key, new_key]);
+            lock.expire_if_needed(key);
+            lock.expire_if_needed(new_key);
+            let copied = match lock.get(key) {
+                Some(value) => {
+                    let copy = (value.clone(), lock.get_expiry(key));
+                    store_copy(&mut lock, new_key, copy, replace)
+                }
+                None => false,
+            };
+            if copied {
+                self.db.signal_ready(&mut lock, target, new_key);
+            }
+            copied
+        } else {
+            let (mut source, mut destination) = self.db.write_two(self.db_index, target);
+            source.expire_if_needed(key);
+            destination.expire_if_needed(new_key);
+            let copied = match source.get(key) {
+                Some(value) => {
+                    let copy = (value.clone(), source.get_expiry(key));
+                    store_copy(&mut destination, new_key, copy, replace)
+                }
+                None => false,
+            };
+            if copied {
+                self.db.signal_ready(&mut destination, target, new_key);
+            }
+            copied
+        };
+
+        self.stream.write_integer(copied as i64).await?;
+        Ok(())
+    }
+
+    async fn handle_randomkey(&mut self, command: ParsedArgs) -> CmdResult {
+        if !command.args.is_empty() {
+            return Err(ReplyError::wrong_arity("randomkey").into());
+        }
+
+        let key = self
+            .db
+            .read(self.db_index)
+            .random_key()
+            .map(|key| key.to_vec());
+
+        self.stream.write_bulk_string_opt(key).await?;
+        Ok(())
+    }
+
+    /// Checks the arguments of the OBJECT subcommands and returns the key.
+    fn object_key<'a>(command: &'a ParsedArgs, name: &str) -> Result<&'a [u8], ReplyError> {
+        if command.args.len() > 1 {
+            return Err(ReplyError::wrong_arity(&format!("object|{}", name)));
+        }
+        Ok(&command.args[0])
+    }
+
+    async fn handle_object_encoding(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = Self::object_key(&command, "encoding")?;
+
+        let encoding = self
+            .db
+            .read_key(self.db_index, key)
+            .peek(key)
+            .map(|value| encoding(value).as_bytes());
+
+        self.stream.write_bulk_string_opt(encoding).await?;
+        Ok(())
+    }
+
+    async fn handle_object_idletime(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = Self::object_key(&command, "idletime")?;
+
+        let idle_time = self.db.read_key(self.db_index, key).idle_time(key);
+
+        match idle_time {
+            Some(idle_time) => self.stream.write_integer(idle_time as i64).await?,
+            None => self.stream.write_null_bulk_string().await?,
+        }
+        Ok(())
+    }
+
+    async fn handle_object_freq(&mut self, command: ParsedArgs) -> CmdResult {
+        Self::object_key(&command, "freq")?;
+        // Access frequencies are only tracked by Redis with an LFU eviction policy, and
+        // there is no eviction here.
+        Err(ReplyError::err(
+            "An LFU maxmemory policy is not selected, access frequency not tracked. Please \
+             note that when switching between policies at runtime LRU and LFU data will take \
+             some time to adjust.",
+        )
+        .into())
+    }
+
+    async fn handle_object_refcount(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = Self::object_key(&command, "refcount")?;
+
+        let refcount = self
+            .db
+            .read_key(self.db_index, key)
+            .peek(key)
+            .map(|value| match value {
+                // Redis shares the objects of small integers, with an infinite refcount.
+                Value::String(data)
+                    if parse_canonical_int(data).is_some_and(|int| (0..10000).contains(&int)) =>
+                {
+                    i32::MAX as i64
+                }
+                _ => 1,
+            });
+
+        match refcount {
+            Some(refcount) => self.stream.write_integer(refcount).await?,
+            None => self.stream.write_null_bulk_string().await?,
+        }
+        Ok(())
+    }
+
+    async fn handle_keys(&mut self, command: ParsedArgs) -> CmdResult {
+        let pattern = &command.args[0];
+        if command.args.len() > 1 {
+            return Err(ReplyError::wrong_arity("keys").into());
+        }
+        let all = pattern.as_ref() == b"*";
+
+        let keys: Vec<Vec<u8>> = {
+            let lock = self.db.read(self.db_index);
+            lock.all_keys()
+                .into_iter()
+                .filter(|key| all || glob::matches(pattern, key, false))
+                .map(|key| key.to_vec())
+                .collect()
+        };
+
+        self.stream.write_array(keys.len() as i64).await?;
+        for key in keys {
+            self.stream.write_bulk_string(key).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_scan(&mut self, command: ParsedArgs) -> CmdResult {
+        let mut cursor = parse_cursor(&command.args[0])?;
+        if command.args.len() > 1 {
+            return Err(ReplyError::syntax().into());
+        }
+        let count = parse_scan_count(&command)?;
+        let pattern = command.named_args.get("match").map(|pattern| &pattern[0]);
+        let type_name = command
+            .named_args
+            .get("type")
+            .map(|type_name| type_name[0].to_ascii_lowercase());
+
+        let mut keys: Vec<Vec<u8>> = Vec::new();
+        {
+            let lock = self.db.read(self.db_index);
+            cursor = scan_batch(cursor, count, &mut keys, |cursor, keys| {
+                lock.scan(cursor, |key, value| {
+                    let type_matches = type_name
+                        .as_ref()
+                        .is_none_or(|type_name| type_name == value.type_name().as_bytes());
+                    if type_matches {
+                        keys.push(key.to_vec());
+                    }
+                })
+            });
+        }
+
+        if let Some(pattern) = pattern {
+            keys.retain(|key| glob::matches(pattern, key, false));
+        }
+
+        self.stream.write_array(2).await?;
+        self.stream
+            .write_bulk_string(cursor.to_string().into_bytes())
+            .await?;
+        self.stream.write_array(keys.len() as i64).await?;
+        for key in keys {
+            self.stream.write_bulk_string(key).await?;
+        }
+        Ok(())
+    }
+
+    /// Sets the expire time of the key, given in `unit` milliseconds, relative to now or
+    /// absolute. Times in the past delete the key.
+    async fn expire(
+        &mut self,
+        command: ParsedArgs,
+        name: &str,
+        unit: i64,
+        relative: bool,
+    ) -> CmdResult {
+        let key = &command.args[0];
+        let when: i64 = parse_int(&command.args[1])?;
+        let flags = ExpireFlags::parse(&command.args[2..])?;
+
+        let invalid = || ReplyError::err(format!("invalid expire time in '{}' command", name));
+        let mut when = when.checked_mul(unit).ok_or_else(invalid)?;
+        let now = unix_ms(SystemTime::now());
+        if relative {
+            when = when.checked_add(now).ok_or_else(invalid)?;
+        }
+
+        let updated = {
+            let mut lock = self.db.write_key(self.db_index, key);
+            if lock.get(key).is_none() {
+                false
+            } else {
+                let current = lock.get_expiry(key).map(unix_ms);
+                if !flags.allow(current, when) {
+                    false
+                } else if when <= now {
+                    lock.remove(key);
+                    true
+                } else {
+                    let expiry = UNIX_EPOCH + Duration::from_millis(when as u64);
+                    lock.set_expiry(key.to_vec().into(), expiry);
+                    true
+                }
+            }
+        };
+
+        self.stream.write_integer(updated as i64).await?;
+        Ok(())
+    }
+
+    async fn handle_expire(&mut self, command: ParsedArgs) -> CmdResult {
+        self.expire(command, "expire", 1000, true).await
+    }
+
+    async fn handle_pexpire(&mut self, command: ParsedArgs) -> CmdResult {
+        self.expire(command, "pexpire", 1, true).await
+    }
+
+    async fn handle_expireat(&mut self, command: ParsedArgs) -> CmdResult {
+        self.expire(command, "expireat", 1000, false).await
+    }
+
+    async fn handle_pexpireat(&mut self, command: ParsedArgs) -> CmdResult {
+        self.expire(command, "pexpireat", 1, false).await
+    }
+
+    async fn handle_persist(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = &command.args[0];
+        if command.args.len() > 1 {
+            return Err(ReplyError::wrong_arity("persist").into());
+        }
+
+        let removed = {
+            let mut lock = self.db.write_key(self.db_index, key);
+            let has_ttl = lock.get(key).is_some() && lock.get_expiry(key).is_some();
+            if has_ttl {
+                lock.unset_expiry(key);
+            }
+            has_ttl
+        };
+
+        self.stream.write_integer(removed as i64).await?;
+        Ok(())
+    }
+
+    /// Replies with the TTL of the key, or its expire time if `absolute`. -2 means the key
+    /// does not exist and -1 that it has no TTL.
+    async fn reply_ttl(
+        &mut self,
+        command: ParsedArgs,
+        name: &str,
+        millis: bool,
+        absolute: bool,
+    ) -> CmdResult {
+        let key = &command.args[0];
+        if command.args.len() > 1 {
+            return Err(ReplyError::wrong_arity(name).into());
+        }
+
+        let expiry = {
+            let lock = self.db.read_key(self.db_index, key);
+            lock.get(key).map(|_| lock.get_expiry(key))
+        };
+
+        let reply = match expiry {
+            None => -2,
+            Some(None) => -1,
+            Some(Some(expiry)) => {
+                let mut ms = unix_ms(expiry);
+                if !absolute {
+                    ms = (ms - unix_ms(SystemTime::now())).max(0);
+                }
+                if millis {
+                    ms
+                } else {
+                    (ms + 500) / 1000
+                }
+            }
+        };
+
+        self.stream.write_integer(reply).await?;
+        Ok(())
+    }
+
+    async fn handle_ttl(&mut self, command: ParsedArgs) -> CmdResult {
+        self.reply_ttl(command, "ttl", false, false).await
+    }
+
+    async fn handle_pttl(&mut self, command: ParsedArgs) -> CmdResult {
+        self.reply_ttl(command, "pttl", true, false).await
+    }
+
+    async fn handle_expiretime(&mut self, command: ParsedArgs) -> CmdResult {
+        self.reply_ttl(command, "expiretime", false, true).await
+    }
+
+    async fn handle_pexpiretime(&mut self, command: ParsedArgs) -> CmdResult {
+        self.reply_ttl(command, "pexpiretime", true, true).await
+    }
+}
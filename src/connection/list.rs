@@ -0,0 +1,597 @@
+use std::collections::VecDeque;
+
+use monoio::io::{AsyncReadRent, AsyncWriteRent};
+
+use bytes::BytesMut;
+
+use crate::{
+    database::{Dataset, Value},
+    error::{parse_int, CmdResult, ReplyError},
+    protocol::RedisWrite,
+};
+
+use super::{parse_timeout, CmdListItem, CmdSpec, CmdSpecs, Connection, ParsedArgs};
+
+pub(super) fn register<'db, Stream: AsyncReadRent + AsyncWriteRent>(
+    specs: &mut CmdSpecs<'db, Stream>,
+) {
+    cmd!(specs, "lpush", handle_lpush, leading(2));
+    cmd!(specs, "rpush", handle_rpush, leading(2));
+    cmd!(specs, "lpushx", handle_lpushx, leading(2));
+    cmd!(specs, "rpushx", handle_rpushx, leading(2));
+    cmd!(specs, "lpop", handle_lpop, leading(1));
+    cmd!(specs, "rpop", handle_rpop, leading(1));
+    cmd!(specs, "lrange", handle_lrange, leading(3));
+    cmd!(specs, "llen", handle_llen, leading(1));
+    cmd!(specs, "lindex", handle_lindex, leading(2));
+    cmd!(specs, "lset", handle_lset, leading(3));
+    cmd!(specs, "lrem", handle_lrem, leading(3));
+    cmd!(specs, "ltrim", handle_ltrim, leading(3));
+    cmd!(specs, "linsert", handle_linsert, leading(4));
+    cmd!(specs, "lmove", handle_lmove, leading(4));
+    cmd!(specs, "rpoplpush", handle_rpoplpush, leading(2));
+    cmd!(specs, "blpop", handle_blpop, leading(2));
+    cmd!(specs, "brpop", handle_brpop, leading(2));
+    cmd!(specs, "blmove", handle_blmove, leading(5));
+    cmd!(specs, "brpoplpush", handle_brpoplpush, leading(3));
+}
+
+pub(super) type List = VecDeque<Vec<u8>>;
+
+#[derive(Clone, Copy, PartialEq)]
+pub(super) enum End {
+    Left,
+    Right,
+}
+
+pub(super) fn as_list(value: &Value) -> Result<&List, ReplyError> {
+    match value {
+        Value::List(list) => Ok(list),
+        _ => Err(ReplyError::wrong_type()),
+    }
+}
+
+pub(super) fn as_list_mut(value: &mut Value) -> Result<&mut List, ReplyError> {
+    match value {
+        Value::List(list) => Ok(list),
+        _ => Err(ReplyError::wrong_type()),
+    }
+}
+
+/// Converts an inclusive `start..=end` range that may use negative indices counting from the
+/// end into indices of a sequence of length `len`. Returns None for an empty range.
+pub(super) fn normalize_range(start: i64, end: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 { (len + start).max(0) } else { start };
+    let end = if end < 0 { len + end } else { end.min(len - 1) };
+    if start > end || start >= len {
+        return None;
+    }
+    Some((start as usize, end as usize))
+}
+
+fn parse_end(arg: &[u8]) -> Result<End, ReplyError> {
+    match arg.to_ascii_lowercase().as_slice() {
+        b"left" => Ok(End::Left),
+        b"right" => Ok(End::Right),
+        _ => Err(ReplyError::syntax()),
+    }
+}
+
+/// Key and element popped from it.
+type Popped = (Vec<u8>, Vec<u8>);
+
+/// Pops an element from the first non-empty list among `keys`.
+fn pop_first(
+    lock: &mut Dataset,
+    keys: &[BytesMut],
+    end: End,
+) -> Result<Option<Popped>, ReplyError> {
+    for key in keys {
+        lock.expire_if_needed(key);
+        let Some(value) = lock.get_mut(key) else {
+            continue;
+        };
+
+        let list = as_list_mut(value)?;
+        let element = match end {
+            End::Left => list.pop_front(),
+            End::Right => list.pop_back(),
+        }
+        .unwrap();
+        if list.is_empty() {
+            lock.remove(key);
+        } else {
+            lock.signal_modified(key);
+        }
+        return Ok(Some((key.to_vec(), element)));
+    }
+    Ok(None)
+}
+
+/// Atomically pops an element from `source` and pushes it to `destination`.
+/// Returns None if `source` does not exist.
+fn move_element(
+    lock: &mut Dataset,
+    source: &[u8],
+    destination: &[u8],
+    from: End,
+    to: End,
+) -> Result<Option<Vec<u8>>, ReplyError> {
+    lock.expire_if_needed(source);
+    lock.expire_if_needed(destination);
+
+    let Some(value) = lock.get(source) else {
+        return Ok(None);
+    };
+    as_list(value)?;
+    if let Some(value) = lock.get(destination) {
+        as_list(value)?;
+    }
+
+    let list = as_list_mut(lock.get_mut(source).unwrap())?;
+    let element = match from {
+        End::Left => list.pop_front(),
+        End::Right => list.pop_back(),
+    }
+    .unwrap();
+    if list.is_empty() {
+        lock.remove(source);
+    } else {
+        lock.signal_modified(source);
+    }
+
+    if lock.get(destination).is_none() {
+        lock.set(destination.into(), Value::List(List::new()));
+    }
+    let list = as_list_mut(lock.get_mut(destination).unwrap())?;
+    match to {
+        End::Left => list.push_front(element.clone()),
+        End::Right => list.push_back(element.clone()),
+    }
+    lock.signal_modified(destination);
+    Ok(Some(element))
+}
+
+/// Converts an index that may be negative into an index of a sequence of length `len`.
+fn normalize_index(index: i64, len: usize) -> Option<usize> {
+    let index = if index < 0 { len as i64 + index } else { index };
+    if index < 0 || index >= len as i64 {
+        return None;
+    }
+    Some(index as usize)
+}
+
+impl<'db, Stream: AsyncReadRent + AsyncWriteRent> Connection<'db, Stream> {
+    async fn push(&mut self, command: ParsedArgs, end: End, only_existing: bool) -> CmdResult {
+        let mut args = command.args.into_iter();
+        let key = args.next().unwrap();
+
+        let mut len = 0;
+        {
+            let mut lock = self.db.write_key(self.db_index, &key);
+            if lock.get(&key).is_none() && !only_existing {
+                lock.set(key.to_vec().into_boxed_slice(), Value::List(List::new()));
+            }
+
+            if let Some(value) = lock.get_mut(&key) {
+                let list = as_list_mut(value)?;
+                for element in args {
+                    match end {
+                        End::Left => list.push_front(element.to_vec()),
+                        End::Right => list.push_back(element.to_vec()),
+                    }
+                }
+                len = list.len();
+                lock.signal_modified(&key);
+            }
+            if len > 0 {
+                self.db.signal_ready(&mut lock, self.db_index, &key);
+            }
+        }
+
+        self.stream.write_integer(len as i64).await?;
+        Ok(())
+    }
+
+    async fn handle_lpush(&mut self, command: ParsedArgs) -> CmdResult {
+        self.push(command, End::Left, false).await
+    }
+
+    async fn handle_rpush(&mut self, command: ParsedArgs) -> CmdResult {
+        self.push(command, End::Right, false).await
+    }
+
+    async fn handle_lpushx(&mut self, command: ParsedArgs) -> CmdResult {
+        self.push(command, End::Left, true).await
+    }
+
+    async fn handle_rpushx(&mut self, command: ParsedArgs) -> CmdResult {
+        self.push(command, End::Right, true).await
+    }
+
+    async fn pop(&mut self, command: ParsedArgs, end: End) -> CmdResult {
+        if command.args.len() > 2 {
+            let name = match end {
+                End::Left => "lpop",
+                End::Right => "rpop",
+            };
+            return Err(ReplyError::wrong_arity(name).into());
+        }
+        let key = &command.args[0];
+        let count = match command.args.get(1) {
+            Some(count) => {
+                let count: i64 = parse_int(count)?;
+                if count < 0 {
+                    return Err(ReplyError::err("value is out of range, must be positive").into());
+                }
+                Some(count as usize)
+            }
+            None => None,
+        };
+
+        let popped: Option<Vec<Vec<u8>>>;
+        {
+            let mut lock = self.db.write_key(self.db_index, key);
+            popped = match lock.get_mut(key) {
+                Some(value) => {
+                    let list = as_list_mut(value)?;
+                    let n = count.unwrap_or(1).min(list.len());
+                    let popped = match end {
+                        End::Left => list.drain(..n).collect(),
+                        End::Right => list.drain(list.len() - n..).rev().collect(),
+                    };
+                    if list.is_empty() {
+                        lock.remove(key);
+                    } else if n > 0 {
+                        lock.signal_modified(key);
+                    }
+                    Some(popped)
+                }
+                None => None,
+            };
+        }
+
+        match (count, popped) {
+            (None, popped) => {
+                let element = popped.and_then(|popped| popped.into_iter().next());
+                self.stream.write_bulk_string_opt(element).await?;
+            }
+            (Some(_), None) => self.stream.write_null_array().await?,
+            (Some(_), Some(popped)) => {
+                self.stream.write_array(popped.len() as i64).await?;
+                for element in popped {
+                    self.stream.write_bulk_string(element).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_lpop(&mut self, command: ParsedArgs) -> CmdResult {
+        self.pop(command, End::Left).await
+    }
+
+    async fn handle_rpop(&mut self, command: ParsedArgs) -> CmdResult {
+        self.pop(command, End::Right).await
+    }
+
+    async fn handle_lrange(&mut self, command: ParsedArgs) -> CmdResult {
+        if command.args.len() > 3 {
+            return Err(ReplyError::wrong_arity("lrange").into());
+        }
+        let key = &command.args[0];
+        let start: i64 = parse_int(&command.args[1])?;
+        let end: i64 = parse_int(&command.args[2])?;
+
+        let elements: Vec<Vec<u8>>;
+        {
+            let lock = self.db.read_key(self.db_index, key);
+            elements = match lock.get(key) {
+                Some(value) => {
+                    let list = as_list(value)?;
+                    match normalize_range(start, end, list.len()) {
+                        Some((start, end)) => list.range(start..=end).cloned().collect(),
+                        None => Vec::new(),
+                    }
+                }
+                None => Vec::new(),
+            };
+        }
+
+        self.stream.write_array(elements.len() as i64).await?;
+        for element in elements {
+            self.stream.write_bulk_string(element).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_llen(&mut self, command: ParsedArgs) -> CmdResult {
+        if command.args.len() > 1 {
+            return Err(ReplyError::wrong_arity("llen").into());
+        }
+        let key = &command.args[0];
+
+        let len = match self.db.read_key(self.db_index, key).get(key) {
+            Some(value) => as_list(value)?.len(),
+            None => 0,
+        };
+
+        self.stream.write_integer(len as i64).await?;
+        Ok(())
+    }
+
+    async fn handle_lindex(&mut self, command: ParsedArgs) -> CmdResult {
+        if command.args.len() > 2 {
+            return Err(ReplyError::wrong_arity("lindex").into());
+        }
+        let key = &command.args[0];
+        let index: i64 = parse_int(&command.args[1])?;
+
+        let element = match self.db.read_key(self.db_index, key).get(key) {
+            Some(value) => {
+                let list = as_list(value)?;
+                normalize_index(index, list.len()).map(|index| list[index].clone())
+            }
+            None => None,
+        };
+
+        self.stream.write_bulk_string_opt(element).await?;
+        Ok(())
+    }
+
+    async fn handle_lset(&mut self, command: ParsedArgs) -> CmdResult {
+        if command.args.len() > 3 {
+            return Err(ReplyError::wrong_arity("lset").into());
+        }
+        let key = &command.args[0];
+        let index: i64 = parse_int(&command.args[1])?;
+        let element = command.args[2].to_vec();
+
+        {
+            let mut lock = self.db.write_key(self.db_index, key);
+            let list = match lock.get_mut(key) {
+                Some(value) => as_list_mut(value)?,
+                None => return Err(ReplyError::err("no such key").into()),
+            };
+            let Some(index) = normalize_index(index, list.len()) else {
+                return Err(ReplyError::err("index out of range").into());
+            };
+            list[index] = element;
+            lock.signal_modified(key);
+        }
+
+        self.stream.write_simple_string("OK").await?;
+        Ok(())
+    }
+
+    async fn handle_lrem(&mut self, command: ParsedArgs) -> CmdResult {
+        if command.args.len() > 3 {
+            return Err(ReplyError::wrong_arity("lrem").into());
+        }
+        let key = &command.args[0];
+        let count: i64 = parse_int(&command.args[1])?;
+        let element = &command.args[2];
+
+        let mut removed = 0;
+        {
+            let mut lock = self.db.write_key(self.db_index, key);
+            if let Some(value) = lock.get_mut(key) {
+                let list = as_list_mut(value)?;
+                let mut budget = if count == 0 {
+                    usize::MAX
+                } else {
+                    count.unsigned_abs() as usize
+                };
+                let mut keep = |item: &[u8]| {
+                    if budget > 0 && item == element.as_ref() {
+                        budget -= 1;
+                        return false;
+                    }
+                    true
+                };
+
+                let len = list.len();
+                if count >= 0 {
+                    list.retain(|item| keep(item));
+                } else {
+                    // Kept elements are moved to the back, starting from the last one, so the
+                    // last matches are the ones removed.
+                    let mut kept = len;
+                    for i in (0..len).rev() {
+                        if keep(&list[i]) {
+                            kept -= 1;
+                            list.swap(i, kept);
+                        }
+                    }
+                    list.drain(..kept);
+                }
+                removed = len - list.len();
+
+                if list.is_empty() {
+                    lock.remove(key);
+                } else if removed > 0 {
+                    lock.signal_modified(key);
+                }
+            }
+        }
+
+        self.stream.write_integer(removed as i64).await?;
+        Ok(())
+    }
+
+    async fn handle_ltrim(&mut self, command: ParsedArgs) -> CmdResult {
+        if command.args.len() > 3 {
+            return Err(ReplyError::wrong_arity("ltrim").into());
+        }
+        let key = &command.args[0];
+        let start: i64 = parse_int(&command.args[1])?;
+        let end: i64 = parse_int(&command.args[2])?;
+
+        {
+            let mut lock = self.db.write_key(self.db_index, key);
+            if let Some(value) = lock.get_mut(key) {
+                let list = as_list_mut(value)?;
+                match normalize_range(start, end, list.len()) {
+                    Some((start, end)) => {
+                        list.truncate(end + 1);
+                        list.drain(..start);
+                    }
+                    None => list.clear(),
+                }
+
+                if list.is_empty() {
+                    lock.remove(key);
+                } else {
+                    lock.signal_modified(key);
+                }
+            }
+        }
+
+        self.stream.write_simple_string("OK").await?;
+        Ok(())
+    }
+
+    async fn handle_linsert(&mut self, command: ParsedArgs) -> CmdResult {
+        if command.args.len() > 4 {
+            return Err(ReplyError::wrong_arity("linsert").into());
+        }
+        let key = &command.args[0];
+        let after = match command.args[1].to_ascii_lowercase().as_slice() {
+            b"before" => false,
+            b"after" => true,
+            _ => return Err(ReplyError::syntax().into()),
+        };
+        let pivot = &command.args[2];
+        let element = command.args[3].to_vec();
+
+        let len: i64;
+        {
+            let mut lock = self.db.write_key(self.db_index, key);
+            len = match lock.get_mut(key) {
+                Some(value) => {
+                    let list = as_list_mut(value)?;
+                    match list.iter().position(|e| e == pivot.as_ref()) {
+                        Some(index) => {
+                            list.insert(if after { index + 1 } else { index }, element);
+                            let len = list.len() as i64;
+                            lock.signal_modified(key);
+                            len
+                        }
+                        None => -1,
+                    }
+                }
+                None => 0,
+            };
+        }
+
+        self.stream.write_integer(len).await?;
+        Ok(())
+    }
+
+    async fn lmove(
+        &mut self,
+        source: &[u8],
+        destination: &[u8],
+        from: End,
+        to: End,
+        timeout: Option<&[u8]>,
+    ) -> CmdResult {
+        let element = match timeout {
+            None => {
+                let mut lock = self.db.write_keys(self.db_index, &[source, destination]);
+                let element = move_element(&mut lock, source, destination, from, to)?;
+                if element.is_some() {
+                    self.db.signal_ready(&mut lock, self.db_index, destination);
+                }
+                element
+            }
+            Some(timeout) => {
+                let deadline = parse_timeout(timeout)?;
+                let (from_key, to_key) = (source.to_vec(), destination.to_vec());
+                let element = self
+                    .block_on_keys(&[source], &[destination], deadline, move |lock| {
+                        move_element(lock, &from_key, &to_key, from, to)
+                    })
+                    .await?;
+                if element.is_some() {
+                    let mut lock = self.db.write_key(self.db_index, destination);
+                    self.db.signal_ready(&mut lock, self.db_index, destination);
+                }
+                element
+            }
+        };
+
+        self.stream.write_bulk_string_opt(element).await?;
+        Ok(())
+    }
+
+    async fn handle_lmove(&mut self, command: ParsedArgs) -> CmdResult {
+        if command.args.len() > 4 {
+            return Err(ReplyError::wrong_arity("lmove").into());
+        }
+        let from = parse_end(&command.args[2])?;
+        let to = parse_end(&command.args[3])?;
+        self.lmove(&command.args[0], &command.args[1], from, to, None)
+            .await
+    }
+
+    async fn handle_rpoplpush(&mut self, command: ParsedArgs) -> CmdResult {
+        if command.args.len() > 2 {
+            return Err(ReplyError::wrong_arity("rpoplpush").into());
+        }
+        self.lmove(&command.args[0], &command.args[1], End::Right, End::Left, None)
+            .await
+    }
+
+    async fn handle_blmove(&mut self, command: ParsedArgs) -> CmdResult {
+        if command.args.len() > 5 {
+            return Err(ReplyError::wrong_arity("blmove").into());
+        }
+        let from = parse_end(&command.args[2])?;
+        let to = parse_end(&command.args[3])?;
+        let timeout = Some(command.args[4].as_ref());
+        self.lmove(&command.args[0], &command.args[1], from, to, timeout)
+            .await
+    }
+
+    async fn handle_brpoplpush(&mut self, command: ParsedArgs) -> CmdResult {
+        if command.args.len() > 3 {
+            return Err(ReplyError::wrong_arity("brpoplpush").into());
+        }
+        let timeout = Some(command.args[2].as_ref());
+        self.lmove(&command.args[0], &command.args[1], End::Right, End::Left, timeout)
+            .await
+    }
+
+    async fn blocking_pop(&mut self, mut command: ParsedArgs, end: End) -> CmdResult {
+        let timeout = command.args.pop().unwrap();
+        let deadline = parse_timeout(&timeout)?;
+        let keys = command.args;
+        let owned_keys = keys.clone();
+
+        let popped = self
+            .block_on_keys(&keys, &[], deadline, move |lock| {
+                pop_first(lock, &owned_keys, end)
+            })
+            .await?;
+
+        match popped {
+            Some((key, element)) => {
+                self.stream.write_array(2).await?;
+                self.stream.write_bulk_string(key).await?;
+                self.stream.write_bulk_string(element).await?;
+            }
+            None => self.stream.write_null_array().await?,
+        }
+        Ok(())
+    }
+
+    async fn handle_blpop(&mut self, command: ParsedArgs) -> CmdResult {
+        self.blocking_pop(command, End::Left).await
+    }
+
+    async fn handle_brpop(&mut self, command: ParsedArgs) -> CmdResult {
+        self.blocking_pop(command, End::Right).await
+    }
+}
@@ -0,0 +1,512 @@
+use std::borrow::Cow;
+
+use monoio::io::{AsyncReadRent, AsyncWriteRent};
+
+use bytes::BytesMut;
+
+use crate::{
+    database::{Dataset, Value},
+    error::{parse_int, CmdResult, ReplyError},
+    glob,
+    protocol::RedisWrite,
+    set::Set,
+};
+
+use super::{
+    parse_cursor, parse_scan_count, scan_batch, sorted_set::SetOp, CmdListItem, CmdSpec, CmdSpecs,
+    Connection, ParsedArgs,
+};
+
+pub(super) fn register<'db, Stream: AsyncReadRent + AsyncWriteRent>(
+    specs: &mut CmdSpecs<'db, Stream>,
+) {
+    cmd!(specs, "sadd", handle_sadd, leading(2));
+    cmd!(specs, "srem", handle_srem, leading(2));
+    cmd!(specs, "smembers", handle_smembers, leading(1));
+    cmd!(specs, "sismember", handle_sismember, leading(2));
+    cmd!(specs, "smismember", handle_smismember, leading(2));
+    cmd!(specs, "scard", handle_scard, leading(1));
+    cmd!(specs, "smove", handle_smove, leading(3));
+    cmd!(specs, "spop", handle_spop, leading(1));
+    cmd!(specs, "srandmember", handle_srandmember, leading(1));
+    cmd!(specs, "sinter", handle_sinter, leading(1));
+    cmd!(specs, "sunion", handle_sunion, leading(1));
+    cmd!(specs, "sdiff", handle_sdiff, leading(1));
+    cmd!(specs, "sinterstore", handle_sinterstore, leading(2));
+    cmd!(specs, "sunionstore", handle_sunionstore, leading(2));
+    cmd!(specs, "sdiffstore", handle_sdiffstore, leading(2));
+    cmd!(specs, "sintercard", handle_sintercard, leading(2));
+    cmd!(
+        specs,
+        "sscan",
+        handle_sscan,
+        leading(2),
+        named("match", 1),
+        named("count", 1)
+    );
+}
+
+pub(super) fn as_set(value: &Value) -> Result<&Set, ReplyError> {
+    match value {
+        Value::Set(set) => Ok(set),
+        _ => Err(ReplyError::wrong_type()),
+    }
+}
+
+pub(super) fn as_set_mut(value: &mut Value) -> Result<&mut Set, ReplyError> {
+    match value {
+        Value::Set(set) => Ok(set),
+        _ => Err(ReplyError::wrong_type()),
+    }
+}
+
+/// Looks up the sets stored at `keys`, None for missing keys. Fails if any key holds
+/// another type.
+fn lookup_sets<'a>(
+    lock: &'a Dataset,
+    keys: &[BytesMut],
+) -> Result<Vec<Option<&'a Set>>, ReplyError> {
+    keys.iter()
+        .map(|key| lock.get(key).map(as_set).transpose())
+        .collect()
+}
+
+/// Computes the union, intersection or difference of the sets at `keys`. Missing keys are
+/// empty sets.
+fn compute_set_op(lock: &Dataset, op: SetOp, keys: &[BytesMut]) -> Result<Set, ReplyError> {
+    let sources = lookup_sets(lock, keys)?;
+    let mut result = Set::new();
+
+    match op {
+        SetOp::Union => {
+            for set in sources.into_iter().flatten() {
+                for member in set.iter() {
+                    result.insert(&member);
+                }
+            }
+        }
+        SetOp::Inter => {
+            let Some(mut sources) = sources.into_iter().collect::<Option<Vec<&Set>>>() else {
+                return Ok(result);
+            };
+            // Iterate over the smallest set and probe the others.
+            sources.sort_by_key(|set| set.len());
+            for member in sources[0].iter() {
+                if sources[1..].iter().all(|set| set.contains(&member)) {
+                    result.insert(&member);
+                }
+            }
+        }
+        SetOp::Diff => {
+            let Some(first) = sources[0] else {
+                return Ok(result);
+            };
+            for member in first.iter() {
+                if !sources[1..]
+                    .iter()
+                    .flatten()
+                    .any(|set| set.contains(&member))
+                {
+                    result.insert(&member);
+                }
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Parses the optional count of SPOP and SRANDMEMBER.
+fn parse_member_count(command: &ParsedArgs) -> Result<Option<i64>, ReplyError> {
+    if command.args.len() > 2 {
+        return Err(ReplyError::syntax());
+    }
+    command
+        .args
+        .get(1)
+        .map(|count| parse_int(count))
+        .transpose()
+}
+
+impl<'db, Stream: AsyncReadRent + AsyncWriteRent> Connection<'db, Stream> {
+    async fn reply_members(&mut self, members: Vec<Vec<u8>>) -> CmdResult {
+        self.stream.write_set(members.len() as i64).await?;
+        for member in members {
+            self.stream.write_bulk_string(member).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_sadd(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = &command.args[0];
+
+        let mut added = 0;
+        {
+            let mut lock = self.db.write_key(self.db_index, key);
+            if let Some(value) = lock.get(key) {
+                as_set(value)?;
+            } else {
+                lock.set(key.to_vec().into_boxed_slice(), Value::Set(Set::new()));
+            }
+
+            let set = as_set_mut(lock.get_mut(key).unwrap())?;
+            for member in &command.args[1..] {
+                if set.insert(member) {
+                    added += 1;
+                }
+            }
+            if added > 0 {
+                lock.signal_modified(key);
+            }
+        }
+
+        self.stream.write_integer(added).await?;
+        Ok(())
+    }
+
+    async fn handle_srem(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = &command.args[0];
+
+        let mut removed = 0;
+        {
+            let mut lock = self.db.write_key(self.db_index, key);
+            if let Some(value) = lock.get_mut(key) {
+                let set = as_set_mut(value)?;
+                for member in &command.args[1..] {
+                    if set.remove(member) {
+                        removed += 1;
+                    }
+                }
+                if set.is_empty() {
+                    lock.remove(key);
+                } else if removed > 0 {
+                    lock.signal_modified(key);
+                }
+            }
+        }
+
+        self.stream.write_integer(removed).await?;
+        Ok(())
+    }
+
+    async fn handle_smembers(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = &command.args[0];
+
+        let members: Vec<Vec<u8>> = match self.db.read_key(self.db_index, key).get(key) {
+            Some(value) => as_set(value)?.iter().map(Cow::into_owned).collect(),
+            None => Vec::new(),
+        };
+
+        self.reply_members(members).await
+    }
+
+    async fn handle_sismember(&mut self, command: ParsedArgs) -> CmdResult {
+        if command.args.len() != 2 {
+            return Err(ReplyError::wrong_arity("sismember").into());
+        }
+        let key = &command.args[0];
+
+        let exists = match self.db.read_key(self.db_index, key).get(key) {
+            Some(value) => as_set(value)?.contains(&command.args[1]),
+            None => false,
+        };
+
+        self.stream.write_integer(exists as i64).await?;
+        Ok(())
+    }
+
+    async fn handle_smismember(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = &command.args[0];
+        let members = &command.args[1..];
+
+        let exists: Vec<bool> = match self.db.read_key(self.db_index, key).get(key) {
+            Some(value) => {
+                let set = as_set(value)?;
+                members.iter().map(|member| set.contains(member)).collect()
+            }
+            None => vec![false; members.len()],
+        };
+
+        self.stream.write_array(exists.len() as i64).await?;
+        for exists in exists {
+            self.stream.write_integer(exists as i64).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_scard(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = &command.args[0];
+
+        let len = match self.db.read_key(self.db_index, key).get(key) {
+            Some(value) => as_set(value)?.len(),
+            None => 0,
+        };
+
+        self.stream.write_integer(len as i64).await?;
+        Ok(())
+    }
+
+    async fn handle_smove(&mut self, command: ParsedArgs) -> CmdResult {
+        if command.args.len() != 3 {
+            return Err(ReplyError::wrong_arity("smove").into());
+        }
+        let (source, destination, member) = (&command.args[0], &command.args[1], &command.args[2]);
+
+        let moved;
+        {
+            let mut lock = self.db.write_keys(self.db_index, &command.args[..2]);
+            lock.expire_if_needed(source);
+            lock.expire_if_needed(destination);
+            let sources = lookup_sets(&lock, &command.args[..2])?;
+
+            moved = match sources[0] {
+                Some(set) if source == destination => set.contains(member),
+                Some(_) => {
+                    let set = as_set_mut(lock.get_mut(source).unwrap())?;
+                    let removed = set.remove(member);
+                    if set.is_empty() {
+                        lock.remove(source);
+                    } else if removed {
+                        lock.signal_modified(source);
+                    }
+                    if removed {
+                        if lock.get(destination).is_none() {
+                            lock.set(
+                                destination.to_vec().into_boxed_slice(),
+                                Value::Set(Set::new()),
+                            );
+                        }
+                        if as_set_mut(lock.get_mut(destination).unwrap())?.insert(member) {
+                            lock.signal_modified(destination);
+                        }
+                    }
+                    removed
+                }
+                None => false,
+            };
+        }
+
+        self.stream.write_integer(moved as i64).await?;
+        Ok(())
+    }
+
+    async fn handle_spop(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = &command.args[0];
+        let count = parse_member_count(&command)?;
+        if count.is_some_and(|count| count < 0) {
+            return Err(ReplyError::err("value is out of range, must be positive").into());
+        }
+
+        let mut members = Vec::new();
+        {
+            let mut lock = self.db.write_key(self.db_index, key);
+            if let Some(value) = lock.get_mut(key) {
+                let set = as_set_mut(value)?;
+                let count = count.unwrap_or(1) as usize;
+                members = set.random_distinct(&mut rand::thread_rng(), count);
+                for member in &members {
+                    set.remove(member);
+                }
+                if set.is_empty() {
+                    lock.remove(key);
+                } else if !members.is_empty() {
+                    lock.signal_modified(key);
+                }
+            }
+        }
+
+        match count {
+            Some(_) => self.reply_members(members).await,
+            None => {
+                let member = members.into_iter().next();
+                self.stream.write_bulk_string_opt(member).await?;
+                Ok(())
+            }
+        }
+    }
+
+    async fn handle_srandmember(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = &command.args[0];
+        let count = parse_member_count(&command)?;
+        if count.is_some_and(|count| count < -(i64::MAX / 2)) {
+            return Err(ReplyError::err("value is out of range").into());
+        }
+
+        let mut rng = rand::thread_rng();
+        let members = match self.db.read_key(self.db_index, key).get(key) {
+            Some(value) => {
+                let set = as_set(value)?;
+                match count {
+                    // A negative count allows the same member to be returned multiple times.
+                    Some(count) if count < 0 => (0..count.unsigned_abs())
+                        .map(|_| set.random_member(&mut rng).unwrap())
+                        .collect(),
+                    Some(count) => set.random_distinct(&mut rng, count as usize),
+                    None => set.random_member(&mut rng).into_iter().collect(),
+                }
+            }
+            None => Vec::new(),
+        };
+
+        // With a negative count the members may repeat, so it's not a set.
+        match count {
+            Some(_) => {
+                self.stream.write_array(members.len() as i64).await?;
+                for member in members {
+                    self.stream.write_bulk_string(member).await?;
+                }
+            }
+            None => {
+                let member = members.into_iter().next();
+                self.stream.write_bulk_string_opt(member).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn set_op(&mut self, command: ParsedArgs, op: SetOp) -> CmdResult {
+        let members: Vec<Vec<u8>> = {
+            let lock = self.db.read_keys(self.db_index, &command.args);
+            compute_set_op(&lock, op, &command.args)?
+                .iter()
+                .map(Cow::into_owned)
+                .collect()
+        };
+
+        self.reply_members(members).await
+    }
+
+    async fn set_op_store(&mut self, command: ParsedArgs, op: SetOp) -> CmdResult {
+        let destination = &command.args[0];
+
+        let len;
+        {
+            let mut lock = self.db.write_keys(self.db_index, &command.args);
+            let result = compute_set_op(&lock, op, &command.args[1..])?;
+            len = result.len();
+            lock.remove(destination);
+            if !result.is_empty() {
+                lock.set(destination.to_vec().into_boxed_slice(), Value::Set(result));
+            }
+        }
+
+        self.stream.write_integer(len as i64).await?;
+        Ok(())
+    }
+
+    async fn handle_sinter(&mut self, command: ParsedArgs) -> CmdResult {
+        self.set_op(command, SetOp::Inter).await
+    }
+
+    async fn handle_sunion(&mut self, command: ParsedArgs) -> CmdResult {
+        self.set_op(command, SetOp::Union).await
+    }
+
+    async fn handle_sdiff(&mut self, command: ParsedArgs) -> CmdResult {
+        self.set_op(command, SetOp::Diff).await
+    }
+
+    async fn handle_sinterstore(&mut self, command: ParsedArgs) -> CmdResult {
+        self.set_op_store(command, SetOp::Inter).await
+    }
+
+    async fn handle_sunionstore(&mut self, command: ParsedArgs) -> CmdResult {
+        self.set_op_store(command, SetOp::Union).await
+    }
+
+    async fn handle_sdiffstore(&mut self, command: ParsedArgs) -> CmdResult {
+        self.set_op_store(command, SetOp::Diff).await
+    }
+
+    async fn handle_sintercard(&mut self, command: ParsedArgs) -> CmdResult {
+        let args = &command.args;
+        let numkeys: i64 = parse_int(&args[0])?;
+        if numkeys < 1 {
+            return Err(ReplyError::err("numkeys should be greater than 0").into());
+        }
+        let numkeys = numkeys as usize;
+        if numkeys > args.len() - 1 {
+            return Err(
+                ReplyError::err("Number of keys can't be greater than number of args").into(),
+            );
+        }
+
+        // 0 means no limit.
+        let mut limit = 0;
+        let mut options = args[numkeys + 1..].iter();
+        while let Some(option) = options.next() {
+            match option.to_ascii_lowercase().as_slice() {
+                b"limit" => {
+                    let arg = options.next().ok_or_else(ReplyError::syntax)?;
+                    let value: i64 = parse_int(arg)?;
+                    if value < 0 {
+                        return Err(ReplyError::err("LIMIT can't be negative").into());
+                    }
+                    limit = value as usize;
+                }
+                _ => return Err(ReplyError::syntax().into()),
+            }
+        }
+
+        let count = {
+            let lock = self.db.read_keys(self.db_index, &args[1..=numkeys]);
+            match lookup_sets(&lock, &args[1..=numkeys])?
+                .into_iter()
+                .collect::<Option<Vec<&Set>>>()
+            {
+                Some(mut sets) => {
+                    sets.sort_by_key(|set| set.len());
+                    let members = sets[0]
+                        .iter()
+                        .filter(|member| sets[1..].iter().all(|set| set.contains(member)));
+                    if limit > 0 {
+                        members.take(limit).count()
+                    } else {
+                        members.count()
+                    }
+                }
+                None => 0,
+            }
+        };
+
+        self.stream.write_integer(count as i64).await?;
+        Ok(())
+    }
+
+    async fn handle_sscan(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = &command.args[0];
+        let mut cursor = parse_cursor(&command.args[1])?;
+        if command.args.len() > 2 {
+            return Err(ReplyError::syntax().into());
+        }
+        let count = parse_scan_count(&command)?;
+        let pattern = command.named_args.get("match").map(|pattern| &pattern[0]);
+
+        let mut members: Vec<Vec<u8>> = Vec::new();
+        {
+            let lock = self.db.read_key(self.db_index, key);
+            match lock.get(key) {
+                Some(value) => {
+                    let set = as_set(value)?;
+                    cursor = scan_batch(cursor, count, &mut members, |cursor, members| {
+                        set.scan(cursor, |member| members.push(member.to_vec()))
+                    });
+                }
+                None => cursor = 0,
+            }
+        }
+
+        if let Some(pattern) = pattern {
+            members.retain(|member| glob::matches(pattern, member, false));
+        }
+
+        self.stream.write_array(2).await?;
+        self.stream
+            .write_bulk_string(cursor.to_string().into_bytes())
+            .await?;
+        self.stream.write_array(members.len() as i64).await?;
+        for member in members {
+            self.stream.write_bulk_string(member).await?;
+        }
+        Ok(())
+    }
+}
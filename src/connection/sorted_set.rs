@@ -0,0 +1,1171 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use monoio::io::{AsyncReadRent, AsyncWriteRent};
+
+use bytes::BytesMut;
+
+use crate::{
+    database::{Dataset, Value},
+    error::{parse_float, parse_int, CmdResult, ReplyError},
+    glob,
+    protocol::{format_double, Protocol, RedisWrite},
+    set::Set,
+    sorted_set::SortedSet,
+};
+
+use super::{
+    list::normalize_range, parse_cursor, parse_scan_count, parse_timeout, scan_batch, CmdListItem,
+    CmdSpec, CmdSpecs, Connection, ParsedArgs,
+};
+
+pub(super) fn register<'db, Stream: AsyncReadRent + AsyncWriteRent>(
+    specs: &mut CmdSpecs<'db, Stream>,
+) {
+    // ZADD options precede the elements, which may have the same names, so they are parsed
+    // by the handler.
+    cmd!(specs, "zadd", handle_zadd, leading(3));
+    cmd!(specs, "zincrby", handle_zincrby, leading(3));
+    cmd!(specs, "zscore", handle_zscore, leading(2));
+    cmd!(specs, "zmscore", handle_zmscore, leading(2));
+    cmd!(specs, "zcard", handle_zcard, leading(1));
+    cmd!(specs, "zcount", handle_zcount, leading(3));
+    cmd!(specs, "zlexcount", handle_zlexcount, leading(3));
+    cmd!(specs, "zrank", handle_zrank, leading(2), flag("withscore"));
+    cmd!(
+        specs,
+        "zrevrank",
+        handle_zrevrank,
+        leading(2),
+        flag("withscore")
+    );
+    cmd!(specs, "zrem", handle_zrem, leading(2));
+    cmd!(
+        specs,
+        "zrange",
+        handle_zrange,
+        leading(3),
+        flag("byscore"),
+        flag("bylex"),
+        flag("rev"),
+        named("limit", 2),
+        flag("withscores")
+    );
+    cmd!(
+        specs,
+        "zrangestore",
+        handle_zrangestore,
+        leading(4),
+        flag("byscore"),
+        flag("bylex"),
+        flag("rev"),
+        named("limit", 2)
+    );
+    cmd!(
+        specs,
+        "zrevrange",
+        handle_zrevrange,
+        leading(3),
+        flag("withscores")
+    );
+    cmd!(
+        specs,
+        "zrangebyscore",
+        handle_zrangebyscore,
+        leading(3),
+        named("limit", 2),
+        flag("withscores")
+    );
+    cmd!(
+        specs,
+        "zrevrangebyscore",
+        handle_zrevrangebyscore,
+        leading(3),
+        named("limit", 2),
+        flag("withscores")
+    );
+    cmd!(
+        specs,
+        "zrangebylex",
+        handle_zrangebylex,
+        leading(3),
+        named("limit", 2)
+    );
+    cmd!(
+        specs,
+        "zrevrangebylex",
+        handle_zrevrangebylex,
+        leading(3),
+        named("limit", 2)
+    );
+    cmd!(specs, "zremrangebyrank", handle_zremrangebyrank, leading(3));
+    cmd!(
+        specs,
+        "zremrangebyscore",
+        handle_zremrangebyscore,
+        leading(3)
+    );
+    cmd!(specs, "zremrangebylex", handle_zremrangebylex, leading(3));
+    cmd!(specs, "zpopmin", handle_zpopmin, leading(1));
+    cmd!(specs, "zpopmax", handle_zpopmax, leading(1));
+    cmd!(specs, "bzpopmin", handle_bzpopmin, leading(2));
+    cmd!(specs, "bzpopmax", handle_bzpopmax, leading(2));
+    // Like ZADD, the options follow a variable number of keys.
+    cmd!(specs, "zunionstore", handle_zunionstore, leading(3));
+    cmd!(specs, "zinterstore", handle_zinterstore, leading(3));
+    cmd!(specs, "zdiffstore", handle_zdiffstore, leading(3));
+    cmd!(specs, "zunion", handle_zunion, leading(2));
+    cmd!(specs, "zinter", handle_zinter, leading(2));
+    cmd!(specs, "zdiff", handle_zdiff, leading(2));
+    cmd!(
+        specs,
+        "zscan",
+        handle_zscan,
+        leading(2),
+        named("match", 1),
+        named("count", 1)
+    );
+}
+
+pub(super) fn as_sorted_set(value: &Value) -> Result<&SortedSet, ReplyError> {
+    match value {
+        Value::SortedSet(set) => Ok(set),
+        _ => Err(ReplyError::wrong_type()),
+    }
+}
+
+pub(super) fn as_sorted_set_mut(value: &mut Value) -> Result<&mut SortedSet, ReplyError> {
+    match value {
+        Value::SortedSet(set) => Ok(set),
+        _ => Err(ReplyError::wrong_type()),
+    }
+}
+
+/// Member and score.
+type Element = (Vec<u8>, f64);
+
+#[derive(Clone, Copy)]
+struct ScoreBound {
+    value: f64,
+    exclusive: bool,
+}
+
+fn parse_score_bound(arg: &[u8]) -> Result<ScoreBound, ReplyError> {
+    let (arg, exclusive) = match arg.strip_prefix(b"(") {
+        Some(arg) => (arg, true),
+        None => (arg, false),
+    };
+    let value = parse_float(arg).map_err(|_| ReplyError::err("min or max is not a float"))?;
+    Ok(ScoreBound { value, exclusive })
+}
+
+enum LexBound {
+    Min,
+    Max,
+    Inclusive(Vec<u8>),
+    Exclusive(Vec<u8>),
+}
+
+fn parse_lex_bound(arg: &[u8]) -> Result<LexBound, ReplyError> {
+    match arg.first() {
+        Some(b'-') if arg.len() == 1 => Ok(LexBound::Min),
+        Some(b'+') if arg.len() == 1 => Ok(LexBound::Max),
+        Some(b'[') => Ok(LexBound::Inclusive(arg[1..].to_vec())),
+        Some(b'(') => Ok(LexBound::Exclusive(arg[1..].to_vec())),
+        _ => Err(ReplyError::err("min or max not valid string range item")),
+    }
+}
+
+enum Range {
+    /// Inclusive ranks, which may be negative to count from the end.
+    Rank(i64, i64),
+    Score(ScoreBound, ScoreBound),
+    /// Lexicographical range, only meaningful if all elements have the same score.
+    Lex(LexBound, LexBound),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum RangeKind {
+    Rank,
+    Score,
+    Lex,
+}
+
+impl Range {
+    /// Parses the bounds of a range. Reverse score and lex ranges take the maximum first.
+    fn parse(kind: RangeKind, start: &[u8], stop: &[u8], rev: bool) -> Result<Self, ReplyError> {
+        let (min, max) = if rev { (stop, start) } else { (start, stop) };
+        match kind {
+            RangeKind::Rank => Ok(Range::Rank(parse_int(start)?, parse_int(stop)?)),
+            RangeKind::Score => Ok(Range::Score(
+                parse_score_bound(min)?,
+                parse_score_bound(max)?,
+            )),
+            RangeKind::Lex => Ok(Range::Lex(parse_lex_bound(min)?, parse_lex_bound(max)?)),
+        }
+    }
+
+    /// Returns the ascending ranks of the elements within the range, as `start..end`.
+    /// Rank ranges count from the highest score with `rev`.
+    fn ranks(&self, set: &SortedSet, rev: bool) -> (usize, usize) {
+        let (start, end) = match self {
+            Range::Rank(start, stop) => match normalize_range(*start, *stop, set.len()) {
+                Some((start, stop)) if rev => (set.len() - 1 - stop, set.len() - start),
+                Some((start, stop)) => (start, stop + 1),
+                None => (0, 0),
+            },
+            Range::Score(min, max) => {
+                let start = set.count_while(|score, _| {
+                    score < min.value || (min.exclusive && score == min.value)
+                });
+                let end = set.count_while(|score, _| {
+                    score < max.value || (!max.exclusive && score == max.value)
+                });
+                (start, end)
+            }
+            Range::Lex(min, max) => {
+                let start = set.count_while(|_, member| match min {
+                    LexBound::Min => false,
+                    LexBound::Max => true,
+                    LexBound::Inclusive(min) => member < min.as_slice(),
+                    LexBound::Exclusive(min) => member <= min.as_slice(),
+                });
+                let end = set.count_while(|_, member| match max {
+                    LexBound::Min => false,
+                    LexBound::Max => true,
+                    LexBound::Inclusive(max) => member <= max.as_slice(),
+                    LexBound::Exclusive(max) => member < max.as_slice(),
+                });
+                (start, end)
+            }
+        };
+        (start, end.max(start))
+    }
+}
+
+struct RangeQuery {
+    range: Range,
+    rev: bool,
+    /// Offset and count. A negative count returns all elements after the offset.
+    limit: Option<(i64, i64)>,
+}
+
+impl RangeQuery {
+    fn select(&self, set: &SortedSet) -> Vec<Element> {
+        let (start, end) = self.range.ranks(set, self.rev);
+        let (offset, count) = self.limit.unwrap_or((0, -1));
+        if offset < 0 || offset as u64 >= (end - start) as u64 {
+            return Vec::new();
+        }
+        let offset = offset as usize;
+        let available = end - start - offset;
+        let count = if count < 0 {
+            available
+        } else {
+            (count as usize).min(available)
+        };
+
+        let to_owned = |(member, score): (&[u8], f64)| (member.to_vec(), score);
+        if self.rev {
+            set.iter_rev_from(end - 1 - offset)
+                .take(count)
+                .map(to_owned)
+                .collect()
+        } else {
+            set.iter_from(start + offset)
+                .take(count)
+                .map(to_owned)
+                .collect()
+        }
+    }
+}
+
+/// Parses the options of ZRANGE and ZRANGESTORE.
+fn parse_zrange(command: &ParsedArgs, start: &[u8], stop: &[u8]) -> Result<RangeQuery, ReplyError> {
+    let by_score = command.named_args.contains_key("byscore");
+    let by_lex = command.named_args.contains_key("bylex");
+    let kind = match (by_score, by_lex) {
+        (true, true) => return Err(ReplyError::syntax()),
+        (true, false) => RangeKind::Score,
+        (false, true) => RangeKind::Lex,
+        (false, false) => RangeKind::Rank,
+    };
+    let limit = parse_limit(command)?;
+    if limit.is_some() && kind == RangeKind::Rank {
+        return Err(ReplyError::err(
+            "syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX",
+        ));
+    }
+    if command.named_args.contains_key("withscores") && kind == RangeKind::Lex {
+        return Err(ReplyError::err(
+            "syntax error, WITHSCORES not supported in combination with BYLEX",
+        ));
+    }
+
+    let rev = command.named_args.contains_key("rev");
+    Ok(RangeQuery {
+        range: Range::parse(kind, start, stop, rev)?,
+        rev,
+        limit,
+    })
+}
+
+fn parse_limit(command: &ParsedArgs) -> Result<Option<(i64, i64)>, ReplyError> {
+    match command.named_args.get("limit") {
+        Some(limit) => Ok(Some((parse_int(&limit[0])?, parse_int(&limit[1])?))),
+        None => Ok(None),
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Aggregate {
+    Sum,
+    Min,
+    Max,
+}
+
+impl Aggregate {
+    fn apply(self, a: f64, b: f64) -> f64 {
+        match self {
+            // inf + -inf is NaN, which is not a valid score.
+            Aggregate::Sum => zero_if_nan(a + b),
+            Aggregate::Min => a.min(b),
+            Aggregate::Max => a.max(b),
+        }
+    }
+}
+
+fn zero_if_nan(value: f64) -> f64 {
+    if value.is_nan() {
+        0.0
+    } else {
+        value
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(super) enum SetOp {
+    Union,
+    Inter,
+    Diff,
+}
+
+/// Keys and options of ZUNION, ZINTER, ZDIFF and their STORE variants.
+struct SetOpArgs {
+    keys: Vec<BytesMut>,
+    weights: Vec<f64>,
+    aggregate: Aggregate,
+    with_scores: bool,
+}
+
+/// Parses `numkeys key [key ...]` followed by the options of the operation.
+fn parse_set_op(
+    name: &str,
+    op: SetOp,
+    store: bool,
+    args: &[BytesMut],
+) -> Result<SetOpArgs, ReplyError> {
+    let numkeys: i64 = parse_int(&args[0])?;
+    if numkeys < 1 {
+        return Err(ReplyError::err(format!(
+            "at least 1 input key is needed for '{}' command",
+            name
+        )));
+    }
+    let numkeys = numkeys as usize;
+    if numkeys > args.len() - 1 {
+        return Err(ReplyError::syntax());
+    }
+
+    let mut parsed = SetOpArgs {
+        keys: args[1..=numkeys].to_vec(),
+        weights: vec![1.0; numkeys],
+        aggregate: Aggregate::Sum,
+        with_scores: false,
+    };
+    let mut options = args[numkeys + 1..].iter();
+    while let Some(option) = options.next() {
+        match option.to_ascii_lowercase().as_slice() {
+            b"weights" if op != SetOp::Diff => {
+                for weight in parsed.weights.iter_mut() {
+                    let arg = options.next().ok_or_else(ReplyError::syntax)?;
+                    *weight = parse_float(arg)
+                        .map_err(|_| ReplyError::err("weight value is not a float"))?;
+                }
+            }
+            b"aggregate" if op != SetOp::Diff => {
+                let arg = options.next().ok_or_else(ReplyError::syntax)?;
+                parsed.aggregate = match arg.to_ascii_lowercase().as_slice() {
+                    b"sum" => Aggregate::Sum,
+                    b"min" => Aggregate::Min,
+                    b"max" => Aggregate::Max,
+                    _ => return Err(ReplyError::syntax()),
+                };
+            }
+            b"withscores" if !store => parsed.with_scores = true,
+            _ => return Err(ReplyError::syntax()),
+        }
+    }
+    Ok(parsed)
+}
+
+/// Input of a set operation. Plain sets count as sorted sets with all scores set to 1.
+enum Source<'a> {
+    Sorted(&'a SortedSet),
+    Plain(&'a Set),
+}
+
+impl<'a> Source<'a> {
+    fn score(&self, member: &[u8]) -> Option<f64> {
+        match self {
+            Source::Sorted(set) => set.score(member),
+            Source::Plain(set) => set.contains(member).then_some(1.0),
+        }
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (Cow<'a, [u8]>, f64)> + 'a> {
+        match *self {
+            Source::Sorted(set) => Box::new(
+                set.iter()
+                    .map(|(member, score)| (Cow::Borrowed(member), score)),
+            ),
+            Source::Plain(set) => Box::new(set.iter().map(|member| (member, 1.0))),
+        }
+    }
+}
+
+/// Computes a set operation over sorted sets or sets of the dataset. Missing keys are
+/// empty sets.
+fn compute_set_op(lock: &Dataset, op: SetOp, args: &SetOpArgs) -> Result<SortedSet, ReplyError> {
+    let mut sources = Vec::with_capacity(args.keys.len());
+    for key in &args.keys {
+        sources.push(match lock.get(key) {
+            Some(Value::Set(set)) => Some(Source::Plain(set)),
+            Some(value) => Some(Source::Sorted(as_sorted_set(value)?)),
+            None => None,
+        });
+    }
+
+    let mut result = SortedSet::new();
+    match op {
+        SetOp::Union => {
+            let mut scores: HashMap<Cow<[u8]>, f64> = HashMap::new();
+            for (source, weight) in sources.iter().zip(&args.weights) {
+                for (member, score) in source.iter().flat_map(|set| set.iter()) {
+                    let score = zero_if_nan(score * weight);
+                    scores
+                        .entry(member)
+                        .and_modify(|total| *total = args.aggregate.apply(*total, score))
+                        .or_insert(score);
+                }
+            }
+            for (member, score) in scores {
+                result.insert(&member, score);
+            }
+        }
+        SetOp::Inter => {
+            let Some(Some(first)) = sources.first() else {
+                return Ok(result);
+            };
+            'members: for (member, score) in first.iter() {
+                let mut total = zero_if_nan(score * args.weights[0]);
+                for (source, weight) in sources.iter().zip(&args.weights).skip(1) {
+                    let Some(score) = source.as_ref().and_then(|set| set.score(&member)) else {
+                        continue 'members;
+                    };
+                    total = args.aggregate.apply(total, zero_if_nan(score * weight));
+                }
+                result.insert(&member, total);
+            }
+        }
+        SetOp::Diff => {
+            let Some(Some(first)) = sources.first() else {
+                return Ok(result);
+            };
+            for (member, score) in first.iter() {
+                let in_other = sources[1..].iter().any(|source| {
+                    source
+                        .as_ref()
+                        .is_some_and(|set| set.score(&member).is_some())
+                });
+                if !in_other {
+                    result.insert(&member, score);
+                }
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Pops the lowest or highest scored element from the first non-empty sorted set among
+/// `keys`. Returns the key, member and score.
+fn pop_first(
+    lock: &mut Dataset,
+    keys: &[BytesMut],
+    highest: bool,
+) -> Result<Option<(Vec<u8>, Element)>, ReplyError> {
+    for key in keys {
+        lock.expire_if_needed(key);
+        let Some(value) = lock.get_mut(key) else {
+            continue;
+        };
+
+        let set = as_sorted_set_mut(value)?;
+        let element = set.pop(highest).unwrap();
+        if set.is_empty() {
+            lock.remove(key);
+        } else {
+            lock.signal_modified(key);
+        }
+        return Ok(Some((key.to_vec(), element)));
+    }
+    Ok(None)
+}
+
+/// Replaces `destination` with the set, deleting it if the set is empty.
+fn store(lock: &mut Dataset, destination: &[u8], set: SortedSet) {
+    lock.remove(destination);
+    if !set.is_empty() {
+        lock.set(destination.into(), Value::SortedSet(set));
+    }
+}
+
+impl<'db, Stream: AsyncReadRent + AsyncWriteRent> Connection<'db, Stream> {
+    /// Replies with the members, and their scores with `with_scores`. RESP3 clients get each
+    /// member with its score in a nested array.
+    async fn reply_zset_elements(
+        &mut self,
+        elements: Vec<Element>,
+        with_scores: bool,
+    ) -> CmdResult {
+        let pairs = with_scores && self.stream.protocol == Protocol::Resp3;
+        let len = elements.len() * if with_scores && !pairs { 2 } else { 1 };
+        self.stream.write_array(len as i64).await?;
+        for (member, score) in elements {
+            if pairs {
+                self.stream.write_array(2).await?;
+            }
+            self.stream.write_bulk_string(member).await?;
+            if with_scores {
+                self.stream.write_double(score).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_zadd(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = &command.args[0];
+
+        let (mut nx, mut xx, mut gt, mut lt, mut ch, mut incr) =
+            (false, false, false, false, false, false);
+        let mut index = 1;
+        while let Some(arg) = command.args.get(index) {
+            match arg.to_ascii_lowercase().as_slice() {
+                b"nx" => nx = true,
+                b"xx" => xx = true,
+                b"gt" => gt = true,
+                b"lt" => lt = true,
+                b"ch" => ch = true,
+                b"incr" => incr = true,
+                _ => break,
+            }
+            index += 1;
+        }
+
+        let elements = &command.args[index..];
+        if elements.is_empty() || !elements.len().is_multiple_of(2) {
+            return Err(ReplyError::syntax().into());
+        }
+        if nx && xx {
+            return Err(
+                ReplyError::err("XX and NX options at the same time are not compatible").into(),
+            );
+        }
+        if (nx && (gt || lt)) || (gt && lt) {
+            return Err(ReplyError::err(
+                "GT, LT, and/or NX options at the same time are not compatible",
+            )
+            .into());
+        }
+        if incr && elements.len() > 2 {
+            return Err(
+                ReplyError::err("INCR option supports a single increment-element pair").into(),
+            );
+        }
+        let mut pairs = Vec::with_capacity(elements.len() / 2);
+        for pair in elements.chunks(2) {
+            pairs.push((parse_float(&pair[0])?, &pair[1]));
+        }
+
+        let (mut added, mut changed) = (0, 0);
+        // Score of the last element with INCR, None if it was not updated.
+        let mut incr_result = None;
+        {
+            let mut lock = self.db.write_key(self.db_index, key);
+            match lock.get(key) {
+                Some(value) => {
+                    as_sorted_set(value)?;
+                }
+                None if xx => {}
+                None => lock.set(
+                    key.to_vec().into_boxed_slice(),
+                    Value::SortedSet(SortedSet::new()),
+                ),
+            }
+
+            if let Some(value) = lock.get_mut(key) {
+                let set = as_sorted_set_mut(value)?;
+                for (score, member) in pairs {
+                    match set.score(member) {
+                        Some(current) => {
+                            if nx {
+                                continue;
+                            }
+                            let score = if incr { current + score } else { score };
+                            if score.is_nan() {
+                                return Err(ReplyError::err(
+                                    "resulting score is not a number (NaN)",
+                                )
+                                .into());
+                            }
+                            if (gt && score <= current) || (lt && score >= current) {
+                                continue;
+                            }
+                            if score != current {
+                                set.insert(member, score);
+                                changed += 1;
+                            }
+                            incr_result = Some(score);
+                        }
+                        None => {
+                            if xx {
+                                continue;
+                            }
+                            set.insert(member, score);
+                            added += 1;
+                            incr_result = Some(score);
+                        }
+                    }
+                }
+                if set.is_empty() {
+                    lock.remove(key);
+                } else if added + changed > 0 {
+                    lock.signal_modified(key);
+                }
+            }
+            if added > 0 {
+                self.db.signal_ready(&mut lock, self.db_index, key);
+            }
+        }
+
+        if incr {
+            match incr_result {
+                Some(score) => self.stream.write_double(score).await?,
+                None => self.stream.write_null_bulk_string().await?,
+            }
+        } else {
+            let count = if ch { added + changed } else { added };
+            self.stream.write_integer(count).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_zincrby(&mut self, command: ParsedArgs) -> CmdResult {
+        if command.args.len() != 3 {
+            return Err(ReplyError::wrong_arity("zincrby").into());
+        }
+        let key = &command.args[0];
+        let increment = parse_float(&command.args[1])?;
+        let member = &command.args[2];
+
+        let score;
+        {
+            let mut lock = self.db.write_key(self.db_index, key);
+            if let Some(value) = lock.get(key) {
+                as_sorted_set(value)?;
+            }
+
+            let current = match lock.get(key) {
+                Some(value) => as_sorted_set(value)?.score(member).unwrap_or(0.0),
+                None => 0.0,
+            };
+            score = current + increment;
+            if score.is_nan() {
+                return Err(ReplyError::err("resulting score is not a number (NaN)").into());
+            }
+
+            if lock.get(key).is_none() {
+                lock.set(
+                    key.to_vec().into_boxed_slice(),
+                    Value::SortedSet(SortedSet::new()),
+                );
+            }
+            as_sorted_set_mut(lock.get_mut(key).unwrap())?.insert(member, score);
+            lock.signal_modified(key);
+            self.db.signal_ready(&mut lock, self.db_index, key);
+        }
+
+        self.stream.write_double(score).await?;
+        Ok(())
+    }
+
+    async fn handle_zscore(&mut self, command: ParsedArgs) -> CmdResult {
+        if command.args.len() != 2 {
+            return Err(ReplyError::wrong_arity("zscore").into());
+        }
+        let key = &command.args[0];
+
+        let score = match self.db.read_key(self.db_index, key).get(key) {
+            Some(value) => as_sorted_set(value)?.score(&command.args[1]),
+            None => None,
+        };
+
+        match score {
+            Some(score) => self.stream.write_double(score).await?,
+            None => self.stream.write_null_bulk_string().await?,
+        }
+        Ok(())
+    }
+
+    async fn handle_zmscore(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = &command.args[0];
+        let members = &command.args[1..];
+
+        let scores: Vec<Option<f64>> = match self.db.read_key(self.db_index, key).get(key) {
+            Some(value) => {
+                let set = as_sorted_set(value)?;
+                members.iter().map(|member| set.score(member)).collect()
+            }
+            None => vec![None; members.len()],
+        };
+
+        self.stream.write_array(scores.len() as i64).await?;
+        for score in scores {
+            match score {
+                Some(score) => self.stream.write_double(score).await?,
+                None => self.stream.write_null_bulk_string().await?,
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_zcard(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = &command.args[0];
+
+        let len = match self.db.read_key(self.db_index, key).get(key) {
+            Some(value) => as_sorted_set(value)?.len(),
+            None => 0,
+        };
+
+        self.stream.write_integer(len as i64).await?;
+        Ok(())
+    }
+
+    async fn zset_count(&mut self, command: ParsedArgs, kind: RangeKind) -> CmdResult {
+        let key = &command.args[0];
+        let range = Range::parse(kind, &command.args[1], &command.args[2], false)?;
+
+        let count = match self.db.read_key(self.db_index, key).get(key) {
+            Some(value) => {
+                let (start, end) = range.ranks(as_sorted_set(value)?, false);
+                end - start
+            }
+            None => 0,
+        };
+
+        self.stream.write_integer(count as i64).await?;
+        Ok(())
+    }
+
+    async fn handle_zcount(&mut self, command: ParsedArgs) -> CmdResult {
+        self.zset_count(command, RangeKind::Score).await
+    }
+
+    async fn handle_zlexcount(&mut self, command: ParsedArgs) -> CmdResult {
+        self.zset_count(command, RangeKind::Lex).await
+    }
+
+    async fn zset_rank(&mut self, command: ParsedArgs, rev: bool) -> CmdResult {
+        let key = &command.args[0];
+        let member = &command.args[1];
+        if command.args.len() > 2 {
+            return Err(ReplyError::syntax().into());
+        }
+
+        let rank = match self.db.read_key(self.db_index, key).get(key) {
+            Some(value) => {
+                let set = as_sorted_set(value)?;
+                set.rank(member).map(|rank| {
+                    let rank = if rev { set.len() - 1 - rank } else { rank };
+                    (rank, set.score(member).unwrap())
+                })
+            }
+            None => None,
+        };
+
+        match rank {
+            None => self.stream.write_null_bulk_string().await?,
+            Some((rank, score)) if command.named_args.contains_key("withscore") => {
+                self.stream.write_array(2).await?;
+                self.stream.write_integer(rank as i64).await?;
+                self.stream.write_double(score).await?;
+            }
+            Some((rank, _)) => self.stream.write_integer(rank as i64).await?,
+        }
+        Ok(())
+    }
+
+    async fn handle_zrank(&mut self, command: ParsedArgs) -> CmdResult {
+        self.zset_rank(command, false).await
+    }
+
+    async fn handle_zrevrank(&mut self, command: ParsedArgs) -> CmdResult {
+        self.zset_rank(command, true).await
+    }
+
+    async fn handle_zrem(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = &command.args[0];
+
+        let mut removed = 0;
+        {
+            let mut lock = self.db.write_key(self.db_index, key);
+            if let Some(value) = lock.get_mut(key) {
+                let set = as_sorted_set_mut(value)?;
+                for member in &command.args[1..] {
+                    if set.remove(member) {
+                        removed += 1;
+                    }
+                }
+                if set.is_empty() {
+                    lock.remove(key);
+                } else if removed > 0 {
+                    lock.signal_modified(key);
+                }
+            }
+        }
+
+        self.stream.write_integer(removed).await?;
+        Ok(())
+    }
+
+    async fn zset_range(&mut self, key: &[u8], query: RangeQuery, with_scores: bool) -> CmdResult {
+        let elements = match self.db.read_key(self.db_index, key).get(key) {
+            Some(value) => query.select(as_sorted_set(value)?),
+            None => Vec::new(),
+        };
+        self.reply_zset_elements(elements, with_scores).await
+    }
+
+    async fn handle_zrange(&mut self, command: ParsedArgs) -> CmdResult {
+        if command.args.len() > 3 {
+            return Err(ReplyError::syntax().into());
+        }
+        let query = parse_zrange(&command, &command.args[1], &command.args[2])?;
+        let with_scores = command.named_args.contains_key("withscores");
+        self.zset_range(&command.args[0], query, with_scores).await
+    }
+
+    async fn handle_zrangestore(&mut self, command: ParsedArgs) -> CmdResult {
+        if command.args.len() > 4 {
+            return Err(ReplyError::syntax().into());
+        }
+        let destination = &command.args[0];
+        let source = &command.args[1];
+        let query = parse_zrange(&command, &command.args[2], &command.args[3])?;
+
+        let len;
+        {
+            let mut lock = self.db.write_keys(self.db_index, &[source, destination]);
+            lock.expire_if_needed(source);
+            let elements = match lock.get(source) {
+                Some(value) => query.select(as_sorted_set(value)?),
+                None => Vec::new(),
+            };
+            let mut set = SortedSet::new();
+            for (member, score) in elements {
+                set.insert(&member, score);
+            }
+            len = set.len();
+            store(&mut lock, destination, set);
+            if len > 0 {
+                self.db.signal_ready(&mut lock, self.db_index, destination);
+            }
+        }
+
+        self.stream.write_integer(len as i64).await?;
+        Ok(())
+    }
+
+    /// Handles the commands that predate the unified ZRANGE.
+    async fn zset_legacy_range(
+        &mut self,
+        command: ParsedArgs,
+        kind: RangeKind,
+        rev: bool,
+    ) -> CmdResult {
+        if command.args.len() > 3 {
+            return Err(ReplyError::syntax().into());
+        }
+        let query = RangeQuery {
+            range: Range::parse(kind, &command.args[1], &command.args[2], rev)?,
+            rev,
+            limit: parse_limit(&command)?,
+        };
+        let with_scores = command.named_args.contains_key("withscores");
+        self.zset_range(&command.args[0], query, with_scores).await
+    }
+
+    async fn handle_zrevrange(&mut self, command: ParsedArgs) -> CmdResult {
+        self.zset_legacy_range(command, RangeKind::Rank, true).await
+    }
+
+    async fn handle_zrangebyscore(&mut self, command: ParsedArgs) -> CmdResult {
+        self.zset_legacy_range(command, RangeKind::Score, false)
+            .await
+    }
+
+    async fn handle_zrevrangebyscore(&mut self, command: ParsedArgs) -> CmdResult {
+        self.zset_legacy_range(command, RangeKind::Score, true)
+            .await
+    }
+
+    async fn handle_zrangebylex(&mut self, command: ParsedArgs) -> CmdResult {
+        self.zset_legacy_range(command, RangeKind::Lex, false).await
+    }
+
+    async fn handle_zrevrangebylex(&mut self, command: ParsedArgs) -> CmdResult {
+        self.zset_legacy_range(command, RangeKind::Lex, true).await
+    }
+
+    async fn zset_remove_range(&mut self, command: ParsedArgs, kind: RangeKind) -> CmdResult {
+        let key = &command.args[0];
+        let range = Range::parse(kind, &command.args[1], &command.args[2], false)?;
+
+        let mut removed = 0;
+        {
+            let mut lock = self.db.write_key(self.db_index, key);
+            if let Some(value) = lock.get_mut(key) {
+                let set = as_sorted_set_mut(value)?;
+                let (start, end) = range.ranks(set, false);
+                let members: Vec<Vec<u8>> = set
+                    .iter_from(start)
+                    .take(end - start)
+                    .map(|(member, _)| member.to_vec())
+                    .collect();
+                for member in members {
+                    set.remove(&member);
+                    removed += 1;
+                }
+                if set.is_empty() {
+                    lock.remove(key);
+                } else if removed > 0 {
+                    lock.signal_modified(key);
+                }
+            }
+        }
+
+        self.stream.write_integer(removed).await?;
+        Ok(())
+    }
+
+    async fn handle_zremrangebyrank(&mut self, command: ParsedArgs) -> CmdResult {
+        self.zset_remove_range(command, RangeKind::Rank).await
+    }
+
+    async fn handle_zremrangebyscore(&mut self, command: ParsedArgs) -> CmdResult {
+        self.zset_remove_range(command, RangeKind::Score).await
+    }
+
+    async fn handle_zremrangebylex(&mut self, command: ParsedArgs) -> CmdResult {
+        self.zset_remove_range(command, RangeKind::Lex).await
+    }
+
+    async fn zset_pop(&mut self, command: ParsedArgs, highest: bool) -> CmdResult {
+        let key = &command.args[0];
+        let count = match command.args.get(1) {
+            Some(count) => {
+                let count: i64 = parse_int(count)?;
+                if count < 0 {
+                    return Err(ReplyError::err("value is out of range, must be positive").into());
+                }
+                count as usize
+            }
+            None => 1,
+        };
+        if command.args.len() > 2 {
+            return Err(ReplyError::syntax().into());
+        }
+
+        let mut popped = Vec::new();
+        {
+            let mut lock = self.db.write_key(self.db_index, key);
+            if let Some(value) = lock.get_mut(key) {
+                let set = as_sorted_set_mut(value)?;
+                while popped.len() < count {
+                    let Some(element) = set.pop(highest) else {
+                        break;
+                    };
+                    popped.push(element);
+                }
+                if set.is_empty() {
+                    lock.remove(key);
+                } else if !popped.is_empty() {
+                    lock.signal_modified(key);
+                }
+            }
+        }
+
+        if command.args.len() > 1 {
+            return self.reply_zset_elements(popped, true).await;
+        }
+
+        // Without a count the member and its score are not nested, even for RESP3.
+        self.stream.write_array(popped.len() as i64 * 2).await?;
+        for (member, score) in popped {
+            self.stream.write_bulk_string(member).await?;
+            self.stream.write_double(score).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_zpopmin(&mut self, command: ParsedArgs) -> CmdResult {
+        self.zset_pop(command, false).await
+    }
+
+    async fn handle_zpopmax(&mut self, command: ParsedArgs) -> CmdResult {
+        self.zset_pop(command, true).await
+    }
+
+    async fn zset_blocking_pop(&mut self, mut command: ParsedArgs, highest: bool) -> CmdResult {
+        let timeout = command.args.pop().unwrap();
+        let deadline = parse_timeout(&timeout)?;
+        let keys = command.args;
+        let owned_keys = keys.clone();
+
+        let popped = self
+            .block_on_keys(&keys, &[], deadline, move |lock| {
+                pop_first(lock, &owned_keys, highest)
+            })
+            .await?;
+
+        match popped {
+            Some((key, (member, score))) => {
+                self.stream.write_array(3).await?;
+                self.stream.write_bulk_string(key).await?;
+                self.stream.write_bulk_string(member).await?;
+                self.stream.write_double(score).await?;
+            }
+            None => self.stream.write_null_array().await?,
+        }
+        Ok(())
+    }
+
+    async fn handle_bzpopmin(&mut self, command: ParsedArgs) -> CmdResult {
+        self.zset_blocking_pop(command, false).await
+    }
+
+    async fn handle_bzpopmax(&mut self, command: ParsedArgs) -> CmdResult {
+        self.zset_blocking_pop(command, true).await
+    }
+
+    async fn zset_op_store(&mut self, command: ParsedArgs, name: &str, op: SetOp) -> CmdResult {
+        let destination = &command.args[0];
+        let args = parse_set_op(name, op, true, &command.args[1..])?;
+
+        let len;
+        {
+            let keys = [&args.keys[..], std::slice::from_ref(destination)].concat();
+            let mut lock = self.db.write_keys(self.db_index, &keys);
+            let result = compute_set_op(&lock, op, &args)?;
+            len = result.len();
+            store(&mut lock, destination, result);
+            if len > 0 {
+                self.db.signal_ready(&mut lock, self.db_index, destination);
+            }
+        }
+
+        self.stream.write_integer(len as i64).await?;
+        Ok(())
+    }
+
+    async fn zset_op(&mut self, command: ParsedArgs, name: &str, op: SetOp) -> CmdResult {
+        let args = parse_set_op(name, op, false, &command.args)?;
+
+        let elements: Vec<Element> = {
+            let lock = self.db.read_keys(self.db_index, &args.keys);
+            compute_set_op(&lock, op, &args)?
+                .iter()
+                .map(|(member, score)| (member.to_vec(), score))
+                .collect()
+        };
+
+        self.reply_zset_elements(elements, args.with_scores).await
+    }
+
+    async fn handle_zunionstore(&mut self, command: ParsedArgs) -> CmdResult {
+        self.zset_op_store(command, "zunionstore", SetOp::Union)
+            .await
+    }
+
+    async fn handle_zinterstore(&mut self, command: ParsedArgs) -> CmdResult {
+        self.zset_op_store(command, "zinterstore", SetOp::Inter)
+            .await
+    }
+
+    async fn handle_zdiffstore(&mut self, command: ParsedArgs) -> CmdResult {
+        self.zset_op_store(command, "zdiffstore", SetOp::Diff).await
+    }
+
+    async fn handle_zunion(&mut self, command: ParsedArgs) -> CmdResult {
+        self.zset_op(command, "zunion", SetOp::Union).await
+    }
+
+    async fn handle_zinter(&mut self, command: ParsedArgs) -> CmdResult {
+        self.zset_op(command, "zinter", SetOp::Inter).await
+    }
+
+    async fn handle_zdiff(&mut self, command: ParsedArgs) -> CmdResult {
+        self.zset_op(command, "zdiff", SetOp::Diff).await
+    }
+
+    async fn handle_zscan(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = &command.args[0];
+        let mut cursor = parse_cursor(&command.args[1])?;
+        if command.args.len() > 2 {
+            return Err(ReplyError::syntax().into());
+        }
+        let count = parse_scan_count(&command)?;
+        let pattern = command.named_args.get("match").map(|pattern| &pattern[0]);
+
+        let mut entries: Vec<(Vec<u8>, f64)> = Vec::new();
+        {
+            let lock = self.db.read_key(self.db_index, key);
+            match lock.get(key) {
+                Some(value) => {
+                    let set = as_sorted_set(value)?;
+                    cursor = scan_batch(cursor, count, &mut entries, |cursor, entries| {
+                        set.scan(cursor, |member, score| entries.push((member.to_vec(), score)))
+                    });
+                }
+                None => cursor = 0,
+            }
+        }
+
+        if let Some(pattern) = pattern {
+            entries.retain(|(member, _)| glob::matches(pattern, member, false));
+        }
+
+        self.stream.write_array(2).await?;
+        self.stream
+            .write_bulk_string(cursor.to_string().into_bytes())
+            .await?;
+        self.stream.write_array(2 * entries.len() as i64).await?;
+        // Like the rest of the SCAN family, scores are replied as strings.
+        for (member, score) in entries {
+            self.stream.write_bulk_string(member).await?;
+            self.stream
+                .write_bulk_string(format_double(score).into_bytes())
+                .await?;
+        }
+        Ok(())
+    }
+}
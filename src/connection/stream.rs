@@ -0,0 +1,1260 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use monoio::{
+    io::{AsyncReadRent, AsyncWriteRent},
+    time::Instant,
+};
+
+use bytes::BytesMut;
+
+use crate::{
+    database::{Dataset, Value},
+    error::{parse_int, CmdResult, ReplyError},
+    protocol::{Protocol, RedisWrite},
+    stream::{Claim, Claimed, ConsumerGroup, Fields, NewId, Stream, StreamId, NODE_MAX_ENTRIES},
+};
+
+use super::{deadline_after, CmdListItem, CmdSpec, CmdSpecs, Connection, ParsedArgs};
+
+pub(super) fn register<'db, Stream: AsyncReadRent + AsyncWriteRent>(
+    specs: &mut CmdSpecs<'db, Stream>,
+) {
+    cmd!(specs, "xadd", handle_xadd, leading(4));
+    cmd!(specs, "xlen", handle_xlen, leading(1));
+    cmd!(
+        specs,
+        "xrange",
+        handle_xrange,
+        leading(3),
+        named("count", 1)
+    );
+    cmd!(
+        specs,
+        "xrevrange",
+        handle_xrevrange,
+        leading(3),
+        named("count", 1)
+    );
+    cmd!(specs, "xread", handle_xread, leading(3));
+    cmd!(specs, "xreadgroup", handle_xreadgroup, leading(6));
+    cmd!(specs, "xdel", handle_xdel, leading(2));
+    cmd!(specs, "xtrim", handle_xtrim, leading(3));
+    cmd!(specs, "xack", handle_xack, leading(3));
+    cmd!(specs, "xpending", handle_xpending, leading(2));
+    cmd!(specs, "xclaim", handle_xclaim, leading(5));
+    cmd!(specs, "xautoclaim", handle_xautoclaim, leading(5));
+
+    let mut sub_specs: CmdSpecs<'db, Stream> = HashMap::new();
+    cmd!(
+        sub_specs,
+        "create",
+        handle_xgroup_create,
+        leading(3),
+        flag("mkstream"),
+        named("entriesread", 1)
+    );
+    cmd!(
+        sub_specs,
+        "setid",
+        handle_xgroup_setid,
+        leading(3),
+        named("entriesread", 1)
+    );
+    cmd!(sub_specs, "destroy", handle_xgroup_destroy, leading(2));
+    cmd!(
+        sub_specs,
+        "createconsumer",
+        handle_xgroup_createconsumer,
+        leading(3)
+    );
+    cmd!(
+        sub_specs,
+        "delconsumer",
+        handle_xgroup_delconsumer,
+        leading(3)
+    );
+    specs.insert("xgroup", CmdListItem::SubSpecs(sub_specs));
+}
+
+pub(super) fn as_stream(value: &Value) -> Result<&Stream, ReplyError> {
+    match value {
+        Value::Stream(stream) => Ok(stream),
+        _ => Err(ReplyError::wrong_type()),
+    }
+}
+
+pub(super) fn as_stream_mut(value: &mut Value) -> Result<&mut Stream, ReplyError> {
+    match value {
+        Value::Stream(stream) => Ok(stream),
+        _ => Err(ReplyError::wrong_type()),
+    }
+}
+
+fn invalid_id() -> ReplyError {
+    ReplyError::err("Invalid stream ID specified as stream command argument")
+}
+
+/// Parses an `ms-seq` ID. The sequence number may be omitted, in which case it is
+/// `missing_seq`.
+pub(super) fn parse_id(arg: &[u8], missing_seq: u64) -> Result<StreamId, ReplyError> {
+    let arg = std::str::from_utf8(arg).map_err(|_| invalid_id())?;
+    let (ms, seq) = match arg.split_once('-') {
+        Some((ms, seq)) => (ms, Some(seq)),
+        None => (arg, None),
+    };
+    let ms = ms.parse().map_err(|_| invalid_id())?;
+    let seq = match seq {
+        Some(seq) => seq.parse().map_err(|_| invalid_id())?,
+        None => missing_seq,
+    };
+    Ok(StreamId::new(ms, seq))
+}
+
+/// Parses the ID argument of XADD.
+fn parse_new_id(arg: &[u8]) -> Result<NewId, ReplyError> {
+    if arg == b"*" {
+        return Ok(NewId::Auto);
+    }
+    if let Some(ms) = arg.strip_suffix(b"-*") {
+        let ms = std::str::from_utf8(ms)
+            .ok()
+            .and_then(|ms| ms.parse().ok())
+            .ok_or_else(invalid_id)?;
+        return Ok(NewId::AutoSeq(ms));
+    }
+    let id = parse_id(arg, 0)?;
+    if id == StreamId::MIN {
+        return Err(ReplyError::err(
+            "The ID specified in XADD must be greater than 0-0",
+        ));
+    }
+    Ok(NewId::Explicit(id))
+}
+
+/// Parses a bound of XRANGE. A `(` prefix makes it exclusive. Incomplete IDs include the
+/// whole millisecond.
+fn parse_range_bound(arg: &[u8], start: bool) -> Result<StreamId, ReplyError> {
+    let missing_seq = if start { 0 } else { u64::MAX };
+    match arg {
+        b"-" => Ok(StreamId::MIN),
+        b"+" => Ok(StreamId::MAX),
+        [b'(', id @ ..] => {
+            let id = parse_id(id, missing_seq)?;
+            if start {
+                id.next()
+                    .ok_or_else(|| ReplyError::err("invalid start ID for the interval"))
+            } else {
+                id.prev()
+                    .ok_or_else(|| ReplyError::err("invalid end ID for the interval"))
+            }
+        }
+        _ => parse_id(arg, missing_seq),
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+enum TrimStrategy {
+    MaxLen(usize),
+    MinId(StreamId),
+}
+
+/// Trimming options of XADD and XTRIM: `MAXLEN|MINID [=|~] threshold [LIMIT count]`.
+#[derive(Default)]
+struct TrimArgs {
+    strategy: Option<TrimStrategy>,
+    approx: bool,
+    limit: Option<usize>,
+}
+
+impl TrimArgs {
+    /// Parses the option at `args[i]` if it is a trimming option. Returns the number of
+    /// arguments consumed, 0 if it is not a trimming option.
+    fn parse_option(&mut self, args: &[BytesMut], i: usize) -> Result<usize, ReplyError> {
+        let option = args[i].to_ascii_lowercase();
+        let mut consumed = 1;
+        let mut next = || {
+            let arg = args.get(i + consumed).ok_or_else(ReplyError::syntax);
+            consumed += 1;
+            arg
+        };
+
+        match option.as_slice() {
+            b"maxlen" | b"minid" => {
+                let max_len = option == b"maxlen";
+                if matches!(
+                    (&self.strategy, max_len),
+                    (Some(TrimStrategy::MinId(_)), true) | (Some(TrimStrategy::MaxLen(_)), false)
+                ) {
+                    return Err(ReplyError::err(
+                        "syntax error, MAXLEN and MINID options at the same time are not compatible",
+                    ));
+                }
+
+                let mut threshold = next()?;
+                self.approx = threshold.as_ref() == b"~";
+                if matches!(threshold.as_ref(), b"~" | b"=") {
+                    threshold = next()?;
+                }
+                self.strategy = Some(if max_len {
+                    let max_len: i64 = parse_int(threshold)?;
+                    if max_len < 0 {
+                        return Err(ReplyError::err("The MAXLEN argument must be >= 0."));
+                    }
+                    TrimStrategy::MaxLen(max_len as usize)
+                } else {
+                    TrimStrategy::MinId(parse_id(threshold, 0)?)
+                });
+            }
+            b"limit" => {
+                let limit: i64 = parse_int(next()?)?;
+                if limit < 0 {
+                    return Err(ReplyError::err("The LIMIT argument must be >= 0."));
+                }
+                self.limit = Some(limit as usize);
+            }
+            _ => return Ok(0),
+        }
+        Ok(consumed)
+    }
+
+    fn validate(&self) -> Result<(), ReplyError> {
+        if self.limit.is_some() && !self.approx {
+            return Err(ReplyError::err(
+                "syntax error, LIMIT cannot be used without the special ~ option",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Trims the stream. Returns the number of entries removed.
+    fn apply(&self, stream: &mut Stream) -> usize {
+        let limit = match self.limit {
+            // LIMIT 0 disables the limit.
+            Some(0) => None,
+            Some(limit) => Some(limit),
+            // Approximate trimming is limited by default, like in Redis.
+            None if self.approx => Some(100 * NODE_MAX_ENTRIES),
+            None => None,
+        };
+        match self.strategy {
+            Some(TrimStrategy::MaxLen(max_len)) => stream.trim_max_len(max_len, self.approx, limit),
+            Some(TrimStrategy::MinId(min_id)) => stream.trim_min_id(min_id, self.approx, limit),
+            None => 0,
+        }
+    }
+}
+
+/// Entries read from a stream. Pending entries deleted from the stream have no fields.
+type Entries = Vec<(StreamId, Option<Fields>)>;
+
+/// Entries of the streams read by XREAD and XREADGROUP, per key.
+type StreamEntries = Vec<(Vec<u8>, Entries)>;
+
+/// Error of the commands naming a consumer group that does not exist.
+fn no_group(key: &[u8], group: &[u8], context: &str) -> ReplyError {
+    ReplyError::new(
+        "NOGROUP",
+        format!(
+            "No such key '{}' or consumer group '{}'{}",
+            String::from_utf8_lossy(key),
+            String::from_utf8_lossy(group),
+            context
+        ),
+    )
+}
+
+fn lookup_group<'a>(
+    lock: &'a Dataset,
+    key: &[u8],
+    group: &[u8],
+) -> Result<&'a ConsumerGroup, ReplyError> {
+    let stream = lock.get(key).map(as_stream).transpose()?;
+    stream
+        .and_then(|stream| stream.group(group))
+        .ok_or_else(|| no_group(key, group, ""))
+}
+
+/// Returns the stream at `key` if it has the group.
+fn stream_with_group<'a>(
+    lock: &'a mut Dataset,
+    key: &[u8],
+    group: &[u8],
+) -> Result<Option<&'a mut Stream>, ReplyError> {
+    let stream = lock.get_mut(key).map(as_stream_mut).transpose()?;
+    Ok(stream.filter(|stream| stream.group(group).is_some()))
+}
+
+/// Returns the entries after the IDs in the streams at `keys`, or None if there are none.
+fn read_after(
+    lock: &Dataset,
+    keys: &[BytesMut],
+    ids: &[StreamId],
+    count: Option<usize>,
+) -> Result<Option<StreamEntries>, ReplyError> {
+    let mut result = Vec::new();
+    for (key, id) in keys.iter().zip(ids) {
+        let Some(value) = lock.get(key) else {
+            continue;
+        };
+        let stream = as_stream(value)?;
+        let Some(start) = id.next() else {
+            continue;
+        };
+        let entries: Entries = stream
+            .range(start..=StreamId::MAX)
+            .take(count.unwrap_or(usize::MAX))
+            .map(|(id, fields)| (*id, Some(fields.clone())))
+            .collect();
+        if !entries.is_empty() {
+            result.push((key.to_vec(), entries));
+        }
+    }
+    Ok((!result.is_empty()).then_some(result))
+}
+
+/// ID argument of XREADGROUP.
+enum GroupReadId {
+    /// `>`: entries never delivered to the group.
+    New,
+    /// History of the entries pending for the consumer after the ID.
+    Pending(StreamId),
+}
+
+/// Options of XREAD and XREADGROUP.
+struct ReadArgs<'a> {
+    /// Group and consumer names, for XREADGROUP.
+    group: Option<(&'a [u8], &'a [u8])>,
+    count: Option<usize>,
+    /// Set if blocking, to the deadline or to None to block forever.
+    block: Option<Option<Instant>>,
+    no_ack: bool,
+    keys: &'a [BytesMut],
+    ids: &'a [BytesMut],
+}
+
+impl<'a> ReadArgs<'a> {
+    fn parse(args: &'a [BytesMut], name: &str) -> Result<Self, ReplyError> {
+        let with_group = name == "xreadgroup";
+        let mut read = ReadArgs {
+            group: None,
+            count: None,
+            block: None,
+            no_ack: false,
+            keys: &[],
+            ids: &[],
+        };
+        let mut streams = None;
+        let mut i = 0;
+        while i < args.len() {
+            let option = args[i].to_ascii_lowercase();
+            if option == b"noack" {
+                if !with_group {
+                    return Err(ReplyError::err(
+                        "The NOACK option is only supported by XREADGROUP. You called XREAD instead.",
+                    ));
+                }
+                read.no_ack = true;
+                i += 1;
+                continue;
+            }
+
+            let value = args.get(i + 1).ok_or_else(ReplyError::syntax)?;
+            match option.as_slice() {
+                b"count" => {
+                    let value: i64 = parse_int(value)?;
+                    // 0 or less means no limit.
+                    read.count = (value > 0).then_some(value as usize);
+                }
+                b"block" => {
+                    let timeout: i64 = parse_int(value).map_err(|_| {
+                        ReplyError::err("timeout is not an integer or out of range")
+                    })?;
+                    if timeout < 0 {
+                        return Err(ReplyError::err("timeout is negative"));
+                    }
+                    // A timeout of 0 blocks forever.
+                    let deadline = match timeout {
+                        0 => None,
+                        _ => Some(deadline_after(Duration::from_millis(timeout as u64))?),
+                    };
+                    read.block = Some(deadline);
+                }
+                b"group" => {
+                    if !with_group {
+                        return Err(ReplyError::err(
+                            "The GROUP option is only supported by XREADGROUP. You called XREAD instead.",
+                        ));
+                    }
+                    let consumer = args.get(i + 2).ok_or_else(ReplyError::syntax)?;
+                    read.group = Some((value, consumer));
+                    i += 1;
+                }
+                b"streams" => {
+                    streams = Some(&args[i + 1..]);
+                    break;
+                }
+                _ => return Err(ReplyError::syntax()),
+            }
+            i += 2;
+        }
+
+        let streams = streams.ok_or_else(ReplyError::syntax)?;
+        if with_group && read.group.is_none() {
+            return Err(ReplyError::err("Missing GROUP option for XREADGROUP"));
+        }
+        if !streams.len().is_multiple_of(2) {
+            let id = if with_group { "'>'" } else { "'$'" };
+            return Err(ReplyError::err(format!(
+                "Unbalanced '{}' list of streams: for each stream key an ID or {} must be specified.",
+                name, id
+            )));
+        }
+        (read.keys, read.ids) = streams.split_at(streams.len() / 2);
+        Ok(read)
+    }
+
+    fn group_ids(&self) -> Result<Vec<GroupReadId>, ReplyError> {
+        self.ids
+            .iter()
+            .map(|id| match id.as_ref() {
+                b">" => Ok(GroupReadId::New),
+                b"$" => Err(ReplyError::err(
+                    "The $ ID is meaningless in the context of XREADGROUP: you want to read the \
+                     history of this consumer by specifying a proper ID, or use the > ID to get \
+                     new messages. The $ ID would just return an empty result set.",
+                )),
+                id => Ok(GroupReadId::Pending(parse_id(id, 0)?)),
+            })
+            .collect()
+    }
+
+    /// Reads for XREADGROUP, or returns None if it should block. The history of the
+    /// consumer is returned for every key, even if empty.
+    fn read_group(
+        &self,
+        lock: &mut Dataset,
+        ids: &[GroupReadId],
+    ) -> Result<Option<StreamEntries>, ReplyError> {
+        let (group, consumer) = self.group.unwrap();
+        for key in self.keys {
+            lock.expire_if_needed(key);
+            if stream_with_group(lock, key, group)?.is_none() {
+                return Err(no_group(key, group, " in XREADGROUP with GROUP option"));
+            }
+        }
+
+        let now = now_ms();
+        let mut result = Vec::new();
+        for (key, id) in self.keys.iter().zip(ids) {
+            let stream = stream_with_group(lock, key, group)?.unwrap();
+            match id {
+                GroupReadId::New => {
+                    let entries =
+                        stream.read_group_new(group, consumer, self.count, self.no_ack, now);
+                    if !entries.is_empty() {
+                        let entries = entries
+                            .into_iter()
+                            .map(|(id, fields)| (id, Some(fields)))
+                            .collect();
+                        result.push((key.to_vec(), entries));
+                        lock.signal_modified(key);
+                    }
+                }
+                GroupReadId::Pending(after) => {
+                    let entries =
+                        stream.read_group_pending(group, consumer, *after, self.count, now);
+                    result.push((key.to_vec(), entries));
+                }
+            }
+        }
+        Ok((!result.is_empty()).then_some(result))
+    }
+}
+
+/// Parses the ENTRIESREAD option of XGROUP CREATE and SETID.
+fn parse_entries_read(command: &ParsedArgs) -> Result<Option<u64>, ReplyError> {
+    let Some(value) = command.named_args.get("entriesread") else {
+        return Ok(None);
+    };
+    let value: i64 = parse_int(&value[0])?;
+    if value < -1 {
+        return Err(ReplyError::err(
+            "value for ENTRIESREAD must be positive or -1",
+        ));
+    }
+    Ok((value >= 0).then_some(value as u64))
+}
+
+/// Error of the XGROUP subcommands naming a group that does not exist.
+fn no_such_group(key: &[u8], group: &[u8]) -> ReplyError {
+    ReplyError::new(
+        "NOGROUP",
+        format!(
+            "No such consumer group '{}' for key name '{}'",
+            String::from_utf8_lossy(group),
+            String::from_utf8_lossy(key)
+        ),
+    )
+}
+
+fn key_required() -> ReplyError {
+    ReplyError::err(
+        "The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to \
+         use the MKSTREAM option to create an empty stream automatically.",
+    )
+}
+
+/// Parses the minimum idle time of XCLAIM and XAUTOCLAIM. Negative times are 0.
+fn parse_min_idle(arg: &[u8], name: &str) -> Result<u64, ReplyError> {
+    let min_idle: i64 = parse_int(arg)
+        .map_err(|_| ReplyError::err(format!("Invalid min-idle-time argument for {}", name)))?;
+    Ok(min_idle.max(0) as u64)
+}
+
+impl<'db, Stream: AsyncReadRent + AsyncWriteRent> Connection<'db, Stream> {
+    async fn reply_stream_entries(&mut self, entries: Entries) -> CmdResult {
+        self.stream.write_array(entries.len() as i64).await?;
+        for (id, fields) in entries {
+            self.stream.write_array(2).await?;
+            self.stream
+                .write_bulk_string(id.to_string().into_bytes())
+                .await?;
+            let Some(fields) = fields else {
+                self.stream.write_null_array().await?;
+                continue;
+            };
+            self.stream.write_array(2 * fields.len() as i64).await?;
+            for (field, value) in fields {
+                self.stream.write_bulk_string(field).await?;
+                self.stream.write_bulk_string(value).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn reply_stream_ids(&mut self, ids: Vec<StreamId>) -> CmdResult {
+        self.stream.write_array(ids.len() as i64).await?;
+        for id in ids {
+            self.stream
+                .write_bulk_string(id.to_string().into_bytes())
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn reply_read_streams(&mut self, result: Option<StreamEntries>) -> CmdResult {
+        let Some(result) = result else {
+            self.stream.write_null_array().await?;
+            return Ok(());
+        };
+        // RESP3 clients get a map of the keys to their entries.
+        let resp3 = self.stream.protocol == Protocol::Resp3;
+        if resp3 {
+            self.stream.write_map(result.len() as i64).await?;
+        } else {
+            self.stream.write_array(result.len() as i64).await?;
+        }
+        for (key, entries) in result {
+            if !resp3 {
+                self.stream.write_array(2).await?;
+            }
+            self.stream.write_bulk_string(key).await?;
+            self.reply_stream_entries(entries).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_xadd(&mut self, command: ParsedArgs) -> CmdResult {
+        let args = &command.args;
+        let key = &args[0];
+
+        let mut no_create = false;
+        let mut trim = TrimArgs::default();
+        let mut i = 1;
+        loop {
+            let arg = args.get(i).ok_or_else(|| ReplyError::wrong_arity("xadd"))?;
+            if arg.eq_ignore_ascii_case(b"nomkstream") {
+                no_create = true;
+                i += 1;
+                continue;
+            }
+            match trim.parse_option(args, i)? {
+                0 => break,
+                consumed => i += consumed,
+            }
+        }
+        trim.validate()?;
+
+        let new_id = parse_new_id(&args[i])?;
+        let auto = matches!(new_id, NewId::Auto);
+        let pairs = &args[i + 1..];
+        if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+            return Err(ReplyError::wrong_arity("xadd").into());
+        }
+
+        let id = 'add: {
+            let mut lock = self.db.write_key(self.db_index, key);
+            if let Some(value) = lock.get(key) {
+                as_stream(value)?;
+            } else if no_create {
+                break 'add None;
+            } else {
+                lock.set(
+                    key.to_vec().into_boxed_slice(),
+                    Value::Stream(crate::stream::Stream::new()),
+                );
+            }
+
+            let stream = as_stream_mut(lock.get_mut(key).unwrap())?;
+            let id = stream.next_id(new_id, now_ms()).ok_or_else(|| {
+                if auto {
+                    ReplyError::err(
+                        "The stream has exhausted the last possible ID, unable to add more items",
+                    )
+                } else {
+                    ReplyError::err(
+                        "The ID specified in XADD is equal or smaller than the target stream top item",
+                    )
+                }
+            })?;
+            let fields = pairs
+                .chunks_exact(2)
+                .map(|pair| (pair[0].to_vec(), pair[1].to_vec()))
+                .collect();
+            stream.insert(id, fields);
+            trim.apply(stream);
+            lock.signal_modified(key);
+            self.db.signal_ready(&mut lock, self.db_index, key);
+            Some(id)
+        };
+
+        self.stream
+            .write_bulk_string_opt(id.map(|id| id.to_string().into_bytes()))
+            .await?;
+        Ok(())
+    }
+
+    async fn handle_xlen(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = &command.args[0];
+
+        let len = match self.db.read_key(self.db_index, key).get(key) {
+            Some(value) => as_stream(value)?.len(),
+            None => 0,
+        };
+
+        self.stream.write_integer(len as i64).await?;
+        Ok(())
+    }
+
+    async fn stream_range(&mut self, command: ParsedArgs, rev: bool) -> CmdResult {
+        let key = &command.args[0];
+        let (start, end) = if rev {
+            (&command.args[2], &command.args[1])
+        } else {
+            (&command.args[1], &command.args[2])
+        };
+        let start = parse_range_bound(start, true)?;
+        let end = parse_range_bound(end, false)?;
+        if command.args.len() > 3 {
+            return Err(ReplyError::syntax().into());
+        }
+        let count = match command.named_args.get("count") {
+            Some(count) => parse_int::<i64>(&count[0])?.max(0) as usize,
+            None => usize::MAX,
+        };
+
+        let entries: Entries = match self.db.read_key(self.db_index, key).get(key) {
+            Some(value) if start <= end => {
+                let range = as_stream(value)?.range(start..=end);
+                let clone = |(id, fields): (&StreamId, &Fields)| (*id, Some(fields.clone()));
+                if rev {
+                    range.rev().take(count).map(clone).collect()
+                } else {
+                    range.take(count).map(clone).collect()
+                }
+            }
+            Some(value) => {
+                as_stream(value)?;
+                Vec::new()
+            }
+            None => Vec::new(),
+        };
+
+        self.reply_stream_entries(entries).await
+    }
+
+    async fn handle_xrange(&mut self, command: ParsedArgs) -> CmdResult {
+        self.stream_range(command, false).await
+    }
+
+    async fn handle_xrevrange(&mut self, command: ParsedArgs) -> CmdResult {
+        self.stream_range(command, true).await
+    }
+
+    async fn handle_xread(&mut self, command: ParsedArgs) -> CmdResult {
+        let read = ReadArgs::parse(&command.args, "xread")?;
+        let (keys, count) = (read.keys, read.count);
+
+        // `$` reads only entries added after the command.
+        let ids: Vec<StreamId> = {
+            let lock = self.db.read_keys(self.db_index, keys);
+            keys.iter()
+                .zip(read.ids)
+                .map(|(key, id)| match id.as_ref() {
+                    b"$" => match lock.get(key) {
+                        Some(value) => Ok(as_stream(value)?.last_id()),
+                        None => Ok(StreamId::MIN),
+                    },
+                    b">" => Err(ReplyError::err(
+                        "The > ID can be specified only when calling XREADGROUP using the \
+                         GROUP <group> <consumer> option.",
+                    )),
+                    id => parse_id(id, 0),
+                })
+                .collect::<Result<_, _>>()?
+        };
+
+        let result = match read.block {
+            Some(deadline) => {
+                let owned_keys = keys.to_vec();
+                self.block_on_keys(keys, &[], deadline, move |lock| {
+                    read_after(lock, &owned_keys, &ids, count)
+                })
+                .await?
+            }
+            None => read_after(&self.db.read_keys(self.db_index, keys), keys, &ids, count)?,
+        };
+        self.reply_read_streams(result).await
+    }
+
+    async fn handle_xreadgroup(&mut self, command: ParsedArgs) -> CmdResult {
+        let read = ReadArgs::parse(&command.args, "xreadgroup")?;
+        let ids = read.group_ids()?;
+
+        let result = match read.block {
+            Some(deadline) => {
+                let args = command.args.clone();
+                self.block_on_keys(read.keys, &[], deadline, move |lock| {
+                    ReadArgs::parse(&args, "xreadgroup")?.read_group(lock, &ids)
+                })
+                .await?
+            }
+            None => {
+                let mut lock = self.db.write_keys(self.db_index, read.keys);
+                read.read_group(&mut lock, &ids)?
+            }
+        };
+        self.reply_read_streams(result).await
+    }
+
+    async fn handle_xdel(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = &command.args[0];
+        let ids = command.args[1..]
+            .iter()
+            .map(|id| parse_id(id, 0))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut removed = 0;
+        {
+            let mut lock = self.db.write_key(self.db_index, key);
+            if let Some(value) = lock.get_mut(key) {
+                let stream = as_stream_mut(value)?;
+                for id in ids {
+                    if stream.remove(id) {
+                        removed += 1;
+                    }
+                }
+                if removed > 0 {
+                    lock.signal_modified(key);
+                }
+            }
+        }
+
+        self.stream.write_integer(removed).await?;
+        Ok(())
+    }
+
+    async fn handle_xtrim(&mut self, command: ParsedArgs) -> CmdResult {
+        let args = &command.args;
+        let key = &args[0];
+
+        let mut trim = TrimArgs::default();
+        let mut i = 1;
+        while i < args.len() {
+            match trim.parse_option(args, i)? {
+                0 => return Err(ReplyError::syntax().into()),
+                consumed => i += consumed,
+            }
+        }
+        if trim.strategy.is_none() {
+            return Err(ReplyError::syntax().into());
+        }
+        trim.validate()?;
+
+        let removed = {
+            let mut lock = self.db.write_key(self.db_index, key);
+            let removed = match lock.get_mut(key) {
+                Some(value) => trim.apply(as_stream_mut(value)?),
+                None => 0,
+            };
+            if removed > 0 {
+                lock.signal_modified(key);
+            }
+            removed
+        };
+
+        self.stream.write_integer(removed as i64).await?;
+        Ok(())
+    }
+
+    async fn handle_xack(&mut self, command: ParsedArgs) -> CmdResult {
+        let (key, group) = (&command.args[0], &command.args[1]);
+        let ids = command.args[2..]
+            .iter()
+            .map(|id| parse_id(id, 0))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut acknowledged = 0;
+        {
+            let mut lock = self.db.write_key(self.db_index, key);
+            if let Some(stream) = stream_with_group(&mut lock, key, group)? {
+                let group = stream.group_mut(group).unwrap();
+                for id in ids {
+                    if group.acknowledge(id) {
+                        acknowledged += 1;
+                    }
+                }
+                if acknowledged > 0 {
+                    lock.signal_modified(key);
+                }
+            }
+        }
+
+        self.stream.write_integer(acknowledged).await?;
+        Ok(())
+    }
+
+    /// Replies with the summary form of XPENDING.
+    async fn reply_pending_summary(&mut self, key: &[u8], group: &[u8]) -> CmdResult {
+        let (pending, bounds, consumers) = {
+            let lock = self.db.read_key(self.db_index, key);
+            let group = lookup_group(&lock, key, group)?;
+            let bounds = group
+                .pending
+                .first_key_value()
+                .zip(group.pending.last_key_value())
+                .map(|((first, _), (last, _))| (*first, *last));
+            let consumers: Vec<_> = group
+                .consumers
+                .iter()
+                .filter(|(_, consumer)| !consumer.pending.is_empty())
+                .map(|(name, consumer)| (name.clone(), consumer.pending.len()))
+                .collect();
+            (group.pending.len(), bounds, consumers)
+        };
+
+        self.stream.write_array(4).await?;
+        self.stream.write_integer(pending as i64).await?;
+        let Some((first, last)) = bounds else {
+            self.stream.write_null_bulk_string().await?;
+            self.stream.write_null_bulk_string().await?;
+            self.stream.write_null_array().await?;
+            return Ok(());
+        };
+        self.stream
+            .write_bulk_string(first.to_string().into_bytes())
+            .await?;
+        self.stream
+            .write_bulk_string(last.to_string().into_bytes())
+            .await?;
+        self.stream.write_array(consumers.len() as i64).await?;
+        for (name, pending) in consumers {
+            self.stream.write_array(2).await?;
+            self.stream.write_bulk_string(name).await?;
+            self.stream
+                .write_bulk_string(pending.to_string().into_bytes())
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_xpending(&mut self, command: ParsedArgs) -> CmdResult {
+        let args = &command.args;
+        let (key, group) = (&args[0], &args[1]);
+
+        // Extended form: [IDLE min-idle] start end count [consumer].
+        let mut extended = None;
+        if args.len() > 2 {
+            let mut i = 2;
+            let mut min_idle = 0;
+            if args[i].eq_ignore_ascii_case(b"idle") {
+                let idle: i64 = parse_int(args.get(i + 1).ok_or_else(ReplyError::syntax)?)?;
+                min_idle = idle.max(0) as u64;
+                i += 2;
+            }
+            if !matches!(args.len() - i, 3 | 4) {
+                return Err(ReplyError::syntax().into());
+            }
+            let start = parse_range_bound(&args[i], true)?;
+            let end = parse_range_bound(&args[i + 1], false)?;
+            let count = parse_int::<i64>(&args[i + 2])?.max(0) as usize;
+            let consumer = args.get(i + 3).map(|consumer| consumer.as_ref());
+            extended = Some((min_idle, start, end, count, consumer));
+        }
+
+        let Some((min_idle, start, end, count, consumer)) = extended else {
+            return self.reply_pending_summary(key, group).await;
+        };
+
+        let now = now_ms();
+        let entries: Vec<_> = {
+            let lock = self.db.read_key(self.db_index, key);
+            let group = lookup_group(&lock, key, group)?;
+            if start <= end {
+                group
+                    .pending
+                    .range(start..=end)
+                    .filter(|(_, entry)| consumer.is_none_or(|name| entry.consumer == name))
+                    .map(|(id, entry)| (*id, entry, now.saturating_sub(entry.delivery_time)))
+                    .filter(|(_, _, idle)| *idle >= min_idle)
+                    .take(count)
+                    .map(|(id, entry, idle)| {
+                        (id, entry.consumer.clone(), idle, entry.delivery_count)
+                    })
+                    .collect()
+            } else {
+                Vec::new()
+            }
+        };
+
+        self.stream.write_array(entries.len() as i64).await?;
+        for (id, consumer, idle, delivery_count) in entries {
+            self.stream.write_array(4).await?;
+            self.stream
+                .write_bulk_string(id.to_string().into_bytes())
+                .await?;
+            self.stream.write_bulk_string(consumer).await?;
+            self.stream.write_integer(idle as i64).await?;
+            self.stream.write_integer(delivery_count as i64).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_xclaim(&mut self, command: ParsedArgs) -> CmdResult {
+        let args = &command.args;
+        let (key, group, consumer) = (&args[0], &args[1], &args[2]);
+        let min_idle = parse_min_idle(&args[3], "XCLAIM")?;
+
+        // IDs are followed by the options.
+        let mut ids = Vec::new();
+        let mut i = 4;
+        while let Some(Ok(id)) = args.get(i).map(|id| parse_id(id, 0)) {
+            ids.push(id);
+            i += 1;
+        }
+
+        let now = now_ms();
+        let mut delivery_time = None;
+        let mut retry_count = None;
+        let mut force = false;
+        let mut just_id = false;
+        let mut last_id = None;
+        while i < args.len() {
+            let option = args[i].to_ascii_lowercase();
+            let value = args.get(i + 1);
+            let invalid = |name: &str| {
+                ReplyError::err(format!("Invalid {} option argument for XCLAIM", name))
+            };
+            match (option.as_slice(), value) {
+                (b"force", _) => force = true,
+                (b"justid", _) => just_id = true,
+                (b"idle", Some(value)) => {
+                    let idle: i64 = parse_int(value).map_err(|_| invalid("IDLE"))?;
+                    delivery_time = Some((now as i64).saturating_sub(idle));
+                    i += 1;
+                }
+                (b"time", Some(value)) => {
+                    delivery_time = Some(parse_int(value).map_err(|_| invalid("TIME"))?);
+                    i += 1;
+                }
+                (b"retrycount", Some(value)) => {
+                    let count: i64 = parse_int(value).map_err(|_| invalid("RETRYCOUNT"))?;
+                    retry_count = Some(count.max(0) as u64);
+                    i += 1;
+                }
+                (b"lastid", Some(value)) => {
+                    last_id = Some(parse_id(value, 0)?);
+                    i += 1;
+                }
+                _ => {
+                    return Err(ReplyError::err(format!(
+                        "Unrecognized XCLAIM option '{}'",
+                        String::from_utf8_lossy(&args[i])
+                    ))
+                    .into())
+                }
+            }
+            i += 1;
+        }
+        // Times in the future or before the epoch are replaced with the current time.
+        let delivery_time = match delivery_time {
+            Some(time) if (0..=now as i64).contains(&time) => time as u64,
+            _ => now,
+        };
+        let claim = Claim {
+            consumer,
+            min_idle,
+            delivery_time,
+            retry_count,
+            just_id,
+        };
+
+        let mut claimed = Vec::new();
+        {
+            let mut lock = self.db.write_key(self.db_index, key);
+            let stream = stream_with_group(&mut lock, key, group)?
+                .ok_or_else(|| no_group(key, group, ""))?;
+            let group_state = stream.group_mut(group).unwrap();
+            if let Some(last_id) = last_id {
+                group_state.last_id = group_state.last_id.max(last_id);
+            }
+            group_state.consumer(consumer, now);
+            for id in ids {
+                if let Claimed::Entry(id, fields) = stream.claim(group, id, &claim, force, now) {
+                    claimed.push((id, fields));
+                }
+            }
+            if !claimed.is_empty() {
+                lock.signal_modified(key);
+            }
+        }
+
+        if just_id {
+            self.reply_stream_ids(claimed.into_iter().map(|(id, _)| id).collect())
+                .await
+        } else {
+            self.reply_stream_entries(claimed).await
+        }
+    }
+
+    async fn handle_xautoclaim(&mut self, command: ParsedArgs) -> CmdResult {
+        let args = &command.args;
+        let (key, group, consumer) = (&args[0], &args[1], &args[2]);
+        let min_idle = parse_min_idle(&args[3], "XAUTOCLAIM")?;
+        let start = parse_range_bound(&args[4], true)?;
+
+        let mut count = 100;
+        let mut just_id = false;
+        let mut i = 5;
+        while i < args.len() {
+            match args[i].to_ascii_lowercase().as_slice() {
+                b"justid" => just_id = true,
+                b"count" => {
+                    let value: i64 = parse_int(args.get(i + 1).ok_or_else(ReplyError::syntax)?)?;
+                    // The number of attempts is a multiple of the count.
+                    if !(1..=i64::MAX / 10).contains(&value) {
+                        return Err(ReplyError::err("COUNT must be > 0").into());
+                    }
+                    count = value as usize;
+                    i += 1;
+                }
+                _ => return Err(ReplyError::syntax().into()),
+            }
+            i += 1;
+        }
+
+        let now = now_ms();
+        let claim = Claim {
+            consumer,
+            min_idle,
+            delivery_time: now,
+            retry_count: None,
+            just_id,
+        };
+        let mut claimed = Vec::new();
+        let mut deleted = Vec::new();
+        let cursor = {
+            let mut lock = self.db.write_key(self.db_index, key);
+            let stream = stream_with_group(&mut lock, key, group)?
+                .ok_or_else(|| no_group(key, group, ""))?;
+            let group_state = stream.group_mut(group).unwrap();
+            group_state.consumer(consumer, now);
+            // One more than the attempts, to find the cursor.
+            let attempts = count * 10;
+            let candidates: Vec<StreamId> = group_state
+                .pending
+                .range(start..)
+                .map(|(id, _)| *id)
+                .take(attempts + 1)
+                .collect();
+
+            let mut examined = 0;
+            for id in candidates.iter().take(attempts) {
+                if claimed.len() == count {
+                    break;
+                }
+                examined += 1;
+                match stream.claim(group, *id, &claim, false, now) {
+                    Claimed::Entry(id, fields) => claimed.push((id, fields)),
+                    Claimed::Deleted(id) => deleted.push(id),
+                    Claimed::Skipped => {}
+                }
+            }
+            if !claimed.is_empty() || !deleted.is_empty() {
+                lock.signal_modified(key);
+            }
+            candidates.get(examined).copied().unwrap_or(StreamId::MIN)
+        };
+
+        self.stream.write_array(3).await?;
+        self.stream
+            .write_bulk_string(cursor.to_string().into_bytes())
+            .await?;
+        if just_id {
+            self.reply_stream_ids(claimed.into_iter().map(|(id, _)| id).collect())
+                .await?;
+        } else {
+            self.reply_stream_entries(claimed).await?;
+        }
+        self.reply_stream_ids(deleted).await
+    }
+
+    async fn handle_xgroup_create(&mut self, command: ParsedArgs) -> CmdResult {
+        let (key, group) = (&command.args[0], &command.args[1]);
+        if command.args.len() > 3 {
+            return Err(ReplyError::syntax().into());
+        }
+        let last_id = match command.args[2].as_ref() {
+            b"$" => None,
+            id => Some(parse_id(id, 0)?),
+        };
+        let entries_read = parse_entries_read(&command)?;
+
+        {
+            let mut lock = self.db.write_key(self.db_index, key);
+            if lock.get(key).is_none() {
+                if !command.named_args.contains_key("mkstream") {
+                    return Err(key_required().into());
+                }
+                lock.set(
+                    key.to_vec().into_boxed_slice(),
+                    Value::Stream(crate::stream::Stream::new()),
+                );
+            }
+            let stream = as_stream_mut(lock.get_mut(key).unwrap())?;
+            let last_id = last_id.unwrap_or(stream.last_id());
+            if !stream.create_group(group, last_id, entries_read) {
+                return Err(
+                    ReplyError::new("BUSYGROUP", "Consumer Group name already exists").into(),
+                );
+            }
+            lock.signal_modified(key);
+        }
+
+        self.stream.write_simple_string("OK").await?;
+        Ok(())
+    }
+
+    async fn handle_xgroup_setid(&mut self, command: ParsedArgs) -> CmdResult {
+        let (key, group) = (&command.args[0], &command.args[1]);
+        if command.args.len() > 3 {
+            return Err(ReplyError::syntax().into());
+        }
+        let last_id = match command.args[2].as_ref() {
+            b"$" => None,
+            id => Some(parse_id(id, 0)?),
+        };
+        let entries_read = parse_entries_read(&command)?;
+
+        {
+            let mut lock = self.db.write_key(self.db_index, key);
+            let stream = as_stream_mut(lock.get_mut(key).ok_or_else(key_required)?)?;
+            let last_id = last_id.unwrap_or(stream.last_id());
+            let group_state = stream
+                .group_mut(group)
+                .ok_or_else(|| no_such_group(key, group))?;
+            group_state.last_id = last_id;
+            group_state.entries_read = entries_read;
+            lock.signal_modified(key);
+        }
+
+        self.stream.write_simple_string("OK").await?;
+        Ok(())
+    }
+
+    async fn handle_xgroup_destroy(&mut self, command: ParsedArgs) -> CmdResult {
+        let (key, group) = (&command.args[0], &command.args[1]);
+        if command.args.len() > 2 {
+            return Err(ReplyError::syntax().into());
+        }
+
+        let removed = {
+            let mut lock = self.db.write_key(self.db_index, key);
+            let stream = as_stream_mut(lock.get_mut(key).ok_or_else(key_required)?)?;
+            let removed = stream.remove_group(group);
+            if removed {
+                lock.signal_modified(key);
+            }
+            removed
+        };
+
+        self.stream.write_integer(removed as i64).await?;
+        Ok(())
+    }
+
+    async fn handle_xgroup_createconsumer(&mut self, command: ParsedArgs) -> CmdResult {
+        let (key, group, consumer) = (&command.args[0], &command.args[1], &command.args[2]);
+        if command.args.len() > 3 {
+            return Err(ReplyError::syntax().into());
+        }
+
+        let created = {
+            let mut lock = self.db.write_key(self.db_index, key);
+            let stream = as_stream_mut(lock.get_mut(key).ok_or_else(key_required)?)?;
+            let group_state = stream
+                .group_mut(group)
+                .ok_or_else(|| no_such_group(key, group))?;
+            let created = !group_state.consumers.contains_key(consumer.as_ref());
+            group_state.consumer(consumer, now_ms());
+            if created {
+                lock.signal_modified(key);
+            }
+            created
+        };
+
+        self.stream.write_integer(created as i64).await?;
+        Ok(())
+    }
+
+    async fn handle_xgroup_delconsumer(&mut self, command: ParsedArgs) -> CmdResult {
+        let (key, group, consumer) = (&command.args[0], &command.args[1], &command.args[2]);
+        if command.args.len() > 3 {
+            return Err(ReplyError::syntax().into());
+        }
+
+        let pending = {
+            let mut lock = self.db.write_key(self.db_index, key);
+            let stream = as_stream_mut(lock.get_mut(key).ok_or_else(key_required)?)?;
+            let removed = stream
+                .group_mut(group)
+                .ok_or_else(|| no_such_group(key, group))?
+                .remove_consumer(consumer);
+            if removed.is_some() {
+                lock.signal_modified(key);
+            }
+            removed.unwrap_or(0)
+        };
+
+        self.stream.write_integer(pending as i64).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,652 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use monoio::io::{AsyncReadRent, AsyncWriteRent};
+
+use bytes::BytesMut;
+
+use crate::{
+    database::{Dataset, Value},
+    error::{parse_float, parse_int, CmdResult, ReplyError},
+    protocol::{ProtocolLimits, RedisWrite},
+};
+
+use super::{format_float, CmdListItem, CmdSpec, CmdSpecs, Connection, ParsedArgs};
+
+pub(super) fn register<'db, Stream: AsyncReadRent + AsyncWriteRent>(
+    specs: &mut CmdSpecs<'db, Stream>,
+) {
+    cmd!(specs, "get", handle_get, leading(1));
+    cmd!(specs, "set", handle_set, leading(2));
+    cmd!(specs, "setnx", handle_setnx, leading(2));
+    cmd!(specs, "setex", handle_setex, leading(3));
+    cmd!(specs, "psetex", handle_psetex, leading(3));
+    cmd!(specs, "getset", handle_getset, leading(2));
+    cmd!(specs, "getdel", handle_getdel, leading(1));
+    cmd!(specs, "getex", handle_getex, leading(1));
+    cmd!(specs, "mget", handle_mget, leading(1));
+    cmd!(specs, "mset", handle_mset, leading(2));
+    cmd!(specs, "msetnx", handle_msetnx, leading(2));
+    cmd!(specs, "incr", handle_incr, leading(1));
+    cmd!(specs, "decr", handle_decr, leading(1));
+    cmd!(specs, "incrby", handle_incrby, leading(2));
+    cmd!(specs, "decrby", handle_decrby, leading(2));
+    cmd!(specs, "incrbyfloat", handle_incrbyfloat, leading(2));
+    cmd!(specs, "append", handle_append, leading(2));
+    cmd!(specs, "strlen", handle_strlen, leading(1));
+    cmd!(specs, "getrange", handle_getrange, leading(3));
+    cmd!(specs, "substr", handle_getrange, leading(3));
+    cmd!(specs, "setrange", handle_setrange, leading(3));
+}
+
+pub(super) fn as_string(value: &Value) -> Result<&Vec<u8>, ReplyError> {
+    match value {
+        Value::String(data) => Ok(data),
+        _ => Err(ReplyError::wrong_type()),
+    }
+}
+
+/// Returns the string at `key`, creating an empty one if the key does not exist.
+fn string_entry<'a>(lock: &'a mut Dataset, key: &[u8]) -> Result<&'a mut Vec<u8>, ReplyError> {
+    if lock.get(key).is_none() {
+        lock.set(key.into(), Value::String(Vec::new()));
+    }
+    match lock.get_mut(key).unwrap() {
+        Value::String(data) => Ok(data),
+        _ => Err(ReplyError::wrong_type()),
+    }
+}
+
+/// Checks that a string can grow to `len`. Strings can't be longer than a bulk string in a
+/// request.
+fn check_string_len(len: usize, limits: &ProtocolLimits) -> Result<(), ReplyError> {
+    if len > limits.max_bulk_len {
+        return Err(ReplyError::err(
+            "string exceeds maximum allowed size (proto-max-bulk-len)",
+        ));
+    }
+    Ok(())
+}
+
+/// Parses an integer like Redis does for counters: only the canonical form is accepted,
+/// without a `+` sign, leading zeros or spaces.
+fn parse_counter(arg: &[u8]) -> Result<i64, ReplyError> {
+    let value: i64 = parse_int(arg)?;
+    if value.to_string().as_bytes() != arg {
+        return Err(ReplyError::not_integer());
+    }
+    Ok(value)
+}
+
+/// Expiration option of SET and GETEX.
+#[derive(Clone, Copy)]
+pub(super) enum ExpireOption {
+    /// `EX`: seconds from now.
+    Seconds,
+    /// `PX`: milliseconds from now.
+    Milliseconds,
+    /// `EXAT`: Unix time in seconds.
+    UnixSeconds,
+    /// `PXAT`: Unix time in milliseconds.
+    UnixMilliseconds,
+}
+
+impl ExpireOption {
+    pub(super) fn from_name(name: &[u8]) -> Option<Self> {
+        match name.to_ascii_lowercase().as_slice() {
+            b"ex" => Some(Self::Seconds),
+            b"px" => Some(Self::Milliseconds),
+            b"exat" => Some(Self::UnixSeconds),
+            b"pxat" => Some(Self::UnixMilliseconds),
+            _ => None,
+        }
+    }
+
+    /// Parses the value of the option as an absolute expire time. Times must be positive.
+    pub(super) fn parse(self, arg: &[u8], command: &str) -> Result<SystemTime, ReplyError> {
+        let value: i64 = parse_int(arg)?;
+        let invalid = || ReplyError::err(format!("invalid expire time in '{}' command", command));
+        if value <= 0 {
+            return Err(invalid());
+        }
+        let millis = match self {
+            Self::Seconds | Self::UnixSeconds => value.checked_mul(1000).ok_or_else(invalid)?,
+            Self::Milliseconds | Self::UnixMilliseconds => value,
+        };
+        let base = match self {
+            Self::Seconds | Self::Milliseconds => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as i64,
+            Self::UnixSeconds | Self::UnixMilliseconds => 0,
+        };
+        let millis = millis.checked_add(base).ok_or_else(invalid)?;
+        Ok(UNIX_EPOCH + Duration::from_millis(millis as u64))
+    }
+}
+
+/// Condition for SET to write the value.
+#[derive(Clone, Copy, PartialEq)]
+enum SetCondition {
+    Always,
+    /// `NX`: only if the key does not exist.
+    IfMissing,
+    /// `XX`: only if the key exists.
+    IfExists,
+}
+
+/// What SET does with the TTL of the key.
+#[derive(Clone, Copy)]
+enum SetExpiry {
+    Clear,
+    /// `KEEPTTL`
+    Keep,
+    At(SystemTime),
+}
+
+struct SetArgs {
+    condition: SetCondition,
+    expiry: SetExpiry,
+    /// `GET`: reply with the old value.
+    get: bool,
+}
+
+impl SetArgs {
+    fn new() -> Self {
+        Self {
+            condition: SetCondition::Always,
+            expiry: SetExpiry::Clear,
+            get: false,
+        }
+    }
+
+    /// Parses the options of SET. Conflicting options are syntax errors.
+    fn parse(args: &[BytesMut]) -> Result<Self, ReplyError> {
+        let mut set = Self::new();
+        let mut i = 0;
+        while i < args.len() {
+            let option = args[i].to_ascii_lowercase();
+            match option.as_slice() {
+                b"nx" if set.condition != SetCondition::IfExists => {
+                    set.condition = SetCondition::IfMissing;
+                }
+                b"xx" if set.condition != SetCondition::IfMissing => {
+                    set.condition = SetCondition::IfExists;
+                }
+                b"get" => set.get = true,
+                b"keepttl" if matches!(set.expiry, SetExpiry::Clear | SetExpiry::Keep) => {
+                    set.expiry = SetExpiry::Keep;
+                }
+                _ => {
+                    let option = ExpireOption::from_name(&option)
+                        .filter(|_| matches!(set.expiry, SetExpiry::Clear))
+                        .ok_or_else(ReplyError::syntax)?;
+                    let value = args.get(i + 1).ok_or_else(ReplyError::syntax)?;
+                    set.expiry = SetExpiry::At(option.parse(value, "set")?);
+                    i += 1;
+                }
+            }
+            i += 1;
+        }
+        Ok(set)
+    }
+}
+
+impl<'db, Stream: AsyncReadRent + AsyncWriteRent> Connection<'db, Stream> {
+    async fn handle_get(&mut self, command: ParsedArgs) -> CmdResult {
+        let mut args = command.args.into_iter();
+        let key = args.next().unwrap();
+
+        let value;
+        {
+            let lock = self.db.read_key(self.db_index, &key);
+            match lock.get(&key) {
+                Some(Value::String(data)) => {
+                    value = Some(data.clone());
+                }
+                Some(_) => return Err(ReplyError::wrong_type().into()),
+                None => {
+                    value = None;
+                }
+            }
+        }
+
+        self.stream.write_bulk_string_opt(value).await?;
+        Ok(())
+    }
+
+    /// Sets the string value of the key. Returns whether it was set and, with `GET`, the
+    /// old value.
+    fn set_string(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        set: &SetArgs,
+    ) -> Result<(bool, Option<Vec<u8>>), ReplyError> {
+        let mut lock = self.db.write_key(self.db_index, key);
+        let old = match lock.get(key) {
+            Some(old) if set.get => Some(Some(as_string(old)?.clone())),
+            Some(_) => Some(None),
+            None => None,
+        };
+        let applied = match set.condition {
+            SetCondition::Always => true,
+            SetCondition::IfMissing => old.is_none(),
+            SetCondition::IfExists => old.is_some(),
+        };
+        let old = old.flatten();
+        if !applied {
+            return Ok((false, old));
+        }
+
+        lock.set(key.into(), Value::String(value.to_vec()));
+        match set.expiry {
+            SetExpiry::Clear => lock.unset_expiry(key),
+            SetExpiry::Keep => {}
+            // An expire time in the past deletes the key.
+            SetExpiry::At(expiry) if expiry <= SystemTime::now() => {
+                lock.remove(key);
+            }
+            SetExpiry::At(expiry) => lock.set_expiry(key.into(), expiry),
+        }
+        Ok((true, old))
+    }
+
+    async fn handle_set(&mut self, command: ParsedArgs) -> CmdResult {
+        let (key, value) = (&command.args[0], &command.args[1]);
+        let set = SetArgs::parse(&command.args[2..])?;
+
+        let (applied, old) = self.set_string(key, value, &set)?;
+
+        if set.get {
+            self.stream.write_bulk_string_opt(old).await?;
+        } else if applied {
+            self.stream.write_simple_string("OK").await?;
+        } else {
+            self.stream.write_null_bulk_string().await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_setnx(&mut self, command: ParsedArgs) -> CmdResult {
+        if command.args.len() > 2 {
+            return Err(ReplyError::wrong_arity("setnx").into());
+        }
+        let set = SetArgs {
+            condition: SetCondition::IfMissing,
+            ..SetArgs::new()
+        };
+
+        let (applied, _) = self.set_string(&command.args[0], &command.args[1], &set)?;
+
+        self.stream.write_integer(applied as i64).await?;
+        Ok(())
+    }
+
+    /// SETEX and PSETEX.
+    async fn set_with_expiry(
+        &mut self,
+        command: ParsedArgs,
+        option: ExpireOption,
+        name: &str,
+    ) -> CmdResult {
+        if command.args.len() > 3 {
+            return Err(ReplyError::wrong_arity(name).into());
+        }
+        let set = SetArgs {
+            expiry: SetExpiry::At(option.parse(&command.args[1], name)?),
+            ..SetArgs::new()
+        };
+
+        self.set_string(&command.args[0], &command.args[2], &set)?;
+
+        self.stream.write_simple_string("OK").await?;
+        Ok(())
+    }
+
+    async fn handle_setex(&mut self, command: ParsedArgs) -> CmdResult {
+        self.set_with_expiry(command, ExpireOption::Seconds, "setex")
+            .await
+    }
+
+    async fn handle_psetex(&mut self, command: ParsedArgs) -> CmdResult {
+        self.set_with_expiry(command, ExpireOption::Milliseconds, "psetex")
+            .await
+    }
+
+    async fn handle_getset(&mut self, command: ParsedArgs) -> CmdResult {
+        if command.args.len() > 2 {
+            return Err(ReplyError::wrong_arity("getset").into());
+        }
+        let set = SetArgs {
+            get: true,
+            ..SetArgs::new()
+        };
+
+        let (_, old) = self.set_string(&command.args[0], &command.args[1], &set)?;
+
+        self.stream.write_bulk_string_opt(old).await?;
+        Ok(())
+    }
+
+    async fn handle_getdel(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = &command.args[0];
+        if command.args.len() > 1 {
+            return Err(ReplyError::wrong_arity("getdel").into());
+        }
+
+        let value = {
+            let mut lock = self.db.write_key(self.db_index, key);
+            match lock.get(key) {
+                Some(value) => {
+                    as_string(value)?;
+                    match lock.remove(key) {
+                        Some(Value::String(data)) => Some(data),
+                        _ => unreachable!(),
+                    }
+                }
+                None => None,
+            }
+        };
+
+        self.stream.write_bulk_string_opt(value).await?;
+        Ok(())
+    }
+
+    async fn handle_getex(&mut self, command: ParsedArgs) -> CmdResult {
+        let args = &command.args;
+        let key = &args[0];
+
+        // None keeps the TTL, Some(None) removes it.
+        let mut expiry = None;
+        let mut i = 1;
+        while i < args.len() {
+            if expiry.is_some() {
+                return Err(ReplyError::syntax().into());
+            }
+            if args[i].eq_ignore_ascii_case(b"persist") {
+                expiry = Some(None);
+            } else {
+                let option = ExpireOption::from_name(&args[i]).ok_or_else(ReplyError::syntax)?;
+                let value = args.get(i + 1).ok_or_else(ReplyError::syntax)?;
+                expiry = Some(Some(option.parse(value, "getex")?));
+                i += 1;
+            }
+            i += 1;
+        }
+
+        let value = {
+            let mut lock = self.db.write_key(self.db_index, key);
+            match lock.get(key) {
+                Some(value) => {
+                    let value = as_string(value)?.clone();
+                    match expiry {
+                        Some(Some(expiry)) if expiry <= SystemTime::now() => {
+                            lock.remove(key);
+                        }
+                        Some(Some(expiry)) => lock.set_expiry(key.to_vec().into(), expiry),
+                        Some(None) => lock.unset_expiry(key),
+                        None => {}
+                    }
+                    Some(value)
+                }
+                None => None,
+            }
+        };
+
+        self.stream.write_bulk_string_opt(value).await?;
+        Ok(())
+    }
+
+    async fn handle_mget(&mut self, command: ParsedArgs) -> CmdResult {
+        let values: Vec<Option<Vec<u8>>> = {
+            let lock = self.db.read_keys(self.db_index, &command.args);
+            command
+                .args
+                .iter()
+                .map(|key| match lock.get(key) {
+                    Some(Value::String(data)) => Some(data.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        self.stream.write_array(values.len() as i64).await?;
+        for value in values {
+            self.stream.write_bulk_string_opt(value).await?;
+        }
+        Ok(())
+    }
+
+    /// Sets all key-value pairs of MSET and MSETNX at once. With `only_new`, nothing is set
+    /// if any of the keys exists. Returns true if the keys were set.
+    fn set_pairs(
+        &mut self,
+        command: &ParsedArgs,
+        name: &str,
+        only_new: bool,
+    ) -> Result<bool, ReplyError> {
+        let args = &command.args;
+        if !args.len().is_multiple_of(2) {
+            return Err(ReplyError::wrong_arity(name));
+        }
+
+        let keys: Vec<&BytesMut> = args.iter().step_by(2).collect();
+        let mut lock = self.db.write_keys(self.db_index, &keys);
+        if only_new
+            && args
+                .chunks_exact(2)
+                .any(|pair| lock.get(&pair[0]).is_some())
+        {
+            return Ok(false);
+        }
+        for pair in args.chunks_exact(2) {
+            lock.unset_expiry(&pair[0]);
+            lock.set(pair[0].to_vec().into(), Value::String(pair[1].to_vec()));
+        }
+        Ok(true)
+    }
+
+    async fn handle_mset(&mut self, command: ParsedArgs) -> CmdResult {
+        self.set_pairs(&command, "mset", false)?;
+        self.stream.write_simple_string("OK").await?;
+        Ok(())
+    }
+
+    async fn handle_msetnx(&mut self, command: ParsedArgs) -> CmdResult {
+        let set = self.set_pairs(&command, "msetnx", true)?;
+        self.stream.write_integer(set as i64).await?;
+        Ok(())
+    }
+
+    /// Adds `increment` to the integer stored at the key, keeping its TTL.
+    async fn increment(&mut self, key: &[u8], increment: i64) -> CmdResult {
+        let result = {
+            let mut lock = self.db.write_key(self.db_index, key);
+            let old = match lock.get(key) {
+                Some(value) => parse_counter(as_string(value)?)?,
+                None => 0,
+            };
+            let result = old
+                .checked_add(increment)
+                .ok_or_else(|| ReplyError::err("increment or decrement would overflow"))?;
+            *string_entry(&mut lock, key)? = result.to_string().into_bytes();
+            lock.signal_modified(key);
+            result
+        };
+
+        self.stream.write_integer(result).await?;
+        Ok(())
+    }
+
+    async fn handle_incr(&mut self, command: ParsedArgs) -> CmdResult {
+        if command.args.len() > 1 {
+            return Err(ReplyError::wrong_arity("incr").into());
+        }
+        self.increment(&command.args[0], 1).await
+    }
+
+    async fn handle_decr(&mut self, command: ParsedArgs) -> CmdResult {
+        if command.args.len() > 1 {
+            return Err(ReplyError::wrong_arity("decr").into());
+        }
+        self.increment(&command.args[0], -1).await
+    }
+
+    async fn handle_incrby(&mut self, command: ParsedArgs) -> CmdResult {
+        if command.args.len() > 2 {
+            return Err(ReplyError::wrong_arity("incrby").into());
+        }
+        let increment = parse_counter(&command.args[1])?;
+        self.increment(&command.args[0], increment).await
+    }
+
+    async fn handle_decrby(&mut self, command: ParsedArgs) -> CmdResult {
+        if command.args.len() > 2 {
+            return Err(ReplyError::wrong_arity("decrby").into());
+        }
+        let decrement = parse_counter(&command.args[1])?;
+        let increment = decrement
+            .checked_neg()
+            .ok_or_else(|| ReplyError::err("decrement would overflow"))?;
+        self.increment(&command.args[0], increment).await
+    }
+
+    async fn handle_incrbyfloat(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = &command.args[0];
+        if command.args.len() > 2 {
+            return Err(ReplyError::wrong_arity("incrbyfloat").into());
+        }
+        let increment = parse_float(&command.args[1])?;
+
+        let result = {
+            let mut lock = self.db.write_key(self.db_index, key);
+            let old = match lock.get(key) {
+                Some(value) => parse_float(as_string(value)?)?,
+                None => 0.0,
+            };
+            let result = old + increment;
+            if !result.is_finite() {
+                return Err(ReplyError::err("increment would produce NaN or Infinity").into());
+            }
+            let result = format_float(result).into_bytes();
+            *string_entry(&mut lock, key)? = result.clone();
+            lock.signal_modified(key);
+            result
+        };
+
+        self.stream.write_bulk_string(result).await?;
+        Ok(())
+    }
+
+    async fn handle_append(&mut self, command: ParsedArgs) -> CmdResult {
+        let (key, suffix) = (&command.args[0], &command.args[1]);
+        if command.args.len() > 2 {
+            return Err(ReplyError::wrong_arity("append").into());
+        }
+
+        let len = {
+            let mut lock = self.db.write_key(self.db_index, key);
+            if let Some(value) = lock.get(key) {
+                check_string_len(as_string(value)?.len() + suffix.len(), &self.limits)?;
+            }
+            let data = string_entry(&mut lock, key)?;
+            data.extend_from_slice(suffix);
+            let len = data.len();
+            lock.signal_modified(key);
+            len
+        };
+
+        self.stream.write_integer(len as i64).await?;
+        Ok(())
+    }
+
+    async fn handle_strlen(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = &command.args[0];
+        if command.args.len() > 1 {
+            return Err(ReplyError::wrong_arity("strlen").into());
+        }
+
+        let len = match self.db.read_key(self.db_index, key).get(key) {
+            Some(value) => as_string(value)?.len(),
+            None => 0,
+        };
+
+        self.stream.write_integer(len as i64).await?;
+        Ok(())
+    }
+
+    async fn handle_getrange(&mut self, command: ParsedArgs) -> CmdResult {
+        let key = &command.args[0];
+        let mut start: i64 = parse_int(&command.args[1])?;
+        let mut end: i64 = parse_int(&command.args[2])?;
+        if command.args.len() > 3 {
+            return Err(ReplyError::wrong_arity("getrange").into());
+        }
+
+        let range = {
+            let lock = self.db.read_key(self.db_index, key);
+            let data: &[u8] = match lock.get(key) {
+                Some(value) => as_string(value)?,
+                None => &[],
+            };
+            let len = data.len() as i64;
+            if start < 0 && end < 0 && start > end {
+                Vec::new()
+            } else {
+                if start < 0 {
+                    start = (len + start).max(0);
+                }
+                if end < 0 {
+                    end = (len + end).max(0);
+                }
+                end = end.min(len - 1);
+                if start > end || len == 0 {
+                    Vec::new()
+                } else {
+                    data[start as usize..=end as usize].to_vec()
+                }
+            }
+        };
+
+        self.stream.write_bulk_string(range).await?;
+        Ok(())
+    }
+
+    async fn handle_setrange(&mut self, command: ParsedArgs) -> CmdResult {
+        let (key, value) = (&command.args[0], &command.args[2]);
+        let offset: i64 = parse_int(&command.args[1])?;
+        if command.args.len() > 3 {
+            return Err(ReplyError::wrong_arity("setrange").into());
+        }
+        if offset < 0 {
+            return Err(ReplyError::err("offset is out of range").into());
+        }
+        let offset = offset as usize;
+
+        let len = 'set: {
+            let mut lock = self.db.write_key(self.db_index, key);
+            let old_len = match lock.get(key) {
+                Some(old) => as_string(old)?.len(),
+                // An empty value does not create the key.
+                None if value.is_empty() => break 'set 0,
+                None => 0,
+            };
+            if value.is_empty() {
+                break 'set old_len;
+            }
+            check_string_len(offset + value.len(), &self.limits)?;
+
+            let data = string_entry(&mut lock, key)?;
+            if data.len() < offset + value.len() {
+                data.resize(offset + value.len(), 0);
+            }
+            data[offset..offset + value.len()].copy_from_slice(value);
+            let len = data.len();
+            lock.signal_modified(key);
+            len
+        };
+
+        self.stream.write_integer(len as i64).await?;
+        Ok(())
+    }
+}
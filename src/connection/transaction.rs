@@ -0,0 +1,157 @@
+use monoio::io::{AsyncReadRent, AsyncWriteRent};
+
+use crate::{
+    database::WatchedKeys,
+    error::{CmdError, CmdResult, ReplyError},
+    protocol::RedisWrite,
+};
+
+use super::{CmdHandler, CmdListItem, CmdSpec, CmdSpecs, Connection, ParsedArgs};
+
+/// Commands that run right away inside MULTI instead of being queued.
+const NOT_QUEUED: [&str; 4] = ["multi", "exec", "discard", "watch"];
+
+pub(super) fn register<'db, Stream: AsyncReadRent + AsyncWriteRent>(
+    specs: &mut CmdSpecs<'db, Stream>,
+) {
+    cmd!(specs, "multi", handle_multi);
+    cmd!(specs, "exec", handle_exec);
+    cmd!(specs, "discard", handle_discard);
+    cmd!(specs, "watch", handle_watch, leading(1));
+    cmd!(specs, "unwatch", handle_unwatch);
+}
+
+/// Commands queued after MULTI, run by EXEC.
+pub(super) struct Transaction<'db, Stream: AsyncReadRent + AsyncWriteRent> {
+    commands: Vec<(CmdHandler<'db, Stream>, ParsedArgs)>,
+    /// Set when a command could not be queued, which makes EXEC fail.
+    failed: bool,
+}
+
+impl<'db, Stream: AsyncReadRent + AsyncWriteRent> Connection<'db, Stream> {
+    /// Queues a command given inside MULTI, or runs it if it is not queued. A command that
+    /// can't be parsed makes the transaction fail.
+    pub(super) async fn queue_command(
+        &mut self,
+        parsed: Result<(String, CmdHandler<'db, Stream>, ParsedArgs), ReplyError>,
+    ) -> CmdResult {
+        let Some(transaction) = &mut self.transaction else {
+            unreachable!("Commands are only queued inside MULTI");
+        };
+        let (name, handler, args) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                transaction.failed = true;
+                return Err(e.into());
+            }
+        };
+        if NOT_QUEUED.contains(&name.as_str()) {
+            return handler(self, args).await;
+        }
+
+        transaction.commands.push((handler, args));
+        self.stream.write_simple_string("QUEUED").await?;
+        Ok(())
+    }
+
+    async fn handle_multi(&mut self, command: ParsedArgs) -> CmdResult {
+        if !command.args.is_empty() {
+            return Err(ReplyError::wrong_arity("multi").into());
+        }
+        if self.transaction.is_some() {
+            return Err(ReplyError::err("MULTI calls can not be nested").into());
+        }
+
+        self.transaction = Some(Transaction {
+            commands: Vec::new(),
+            failed: false,
+        });
+        self.stream.write_simple_string("OK").await?;
+        Ok(())
+    }
+
+    async fn handle_exec(&mut self, command: ParsedArgs) -> CmdResult {
+        if !command.args.is_empty() {
+            return Err(ReplyError::wrong_arity("exec").into());
+        }
+        let Some(transaction) = self.transaction.take() else {
+            return Err(ReplyError::err("EXEC without MULTI").into());
+        };
+        let watched = self.watched.take();
+        if transaction.failed {
+            return Err(ReplyError::new(
+                "EXECABORT",
+                "Transaction discarded because of previous errors.",
+            )
+            .into());
+        }
+
+        // No other client touches the keys until all the commands have run, including
+        // between checking the watched keys and running the first command. The replies stay
+        // in the output buffer meanwhile, so the commands never wait for the socket.
+        let access = self.db.exclusive_access();
+        if watched.as_ref().is_some_and(WatchedKeys::is_modified) {
+            drop(access);
+            self.stream.write_null_array().await?;
+            return Ok(());
+        }
+        drop(watched);
+
+        self.executing = true;
+        self.stream.hold_output = true;
+        self.stream
+            .write_array(transaction.commands.len() as i64)
+            .await?;
+        for (handler, args) in transaction.commands {
+            match handler(self, args).await {
+                Ok(()) => {}
+                Err(CmdError::Reply(e)) => self.stream.write_error(e.to_bytes()).await?,
+                Err(CmdError::Fatal(e)) => return Err(CmdError::Fatal(e)),
+            }
+        }
+        self.stream.hold_output = false;
+        self.executing = false;
+        drop(access);
+        Ok(())
+    }
+
+    async fn handle_discard(&mut self, command: ParsedArgs) -> CmdResult {
+        if !command.args.is_empty() {
+            return Err(ReplyError::wrong_arity("discard").into());
+        }
+        if self.transaction.take().is_none() {
+            return Err(ReplyError::err("DISCARD without MULTI").into());
+        }
+
+        self.watched = None;
+        self.stream.write_simple_string("OK").await?;
+        Ok(())
+    }
+
+    async fn handle_watch(&mut self, command: ParsedArgs) -> CmdResult {
+        if self.transaction.is_some() {
+            return Err(ReplyError::err("WATCH inside MULTI is not allowed").into());
+        }
+
+        let dataset = self.db_index;
+        let watched = self
+            .watched
+            .get_or_insert_with(|| WatchedKeys::new(self.db));
+        for key in &command.args {
+            watched.watch(dataset, key);
+        }
+
+        self.stream.write_simple_string("OK").await?;
+        Ok(())
+    }
+
+    async fn handle_unwatch(&mut self, command: ParsedArgs) -> CmdResult {
+        if !command.args.is_empty() {
+            return Err(ReplyError::wrong_arity("unwatch").into());
+        }
+
+        self.watched = None;
+        self.stream.write_simple_string("OK").await?;
+        Ok(())
+    }
+}
@@ -1,59 +1,732 @@
-use std::{collections::HashMap, sync::{RwLock, RwLockReadGuard, RwLockWriteGuard}, time::SystemTime};
+use std::{
+    cell::Cell,
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    ops::{Deref, DerefMut},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
 
+use indexmap::IndexMap;
+use rand::Rng;
+
+use crate::{
+    blocking::BlockingKeys, dict::Dict, protocol::ProtocolLimits, set::Set, sorted_set::SortedSet,
+    stream::Stream,
+};
+
+#[derive(Clone)]
 pub(crate) enum Value {
     String(Vec<u8>),
+    List(VecDeque<Vec<u8>>),
+    Set(Set),
+    Hash(Dict<Vec<u8>, Vec<u8>>),
+    SortedSet(SortedSet),
+    Stream(Stream),
 }
 
-pub(crate) struct Dataset {
-    data: HashMap<Box<[u8]>, Value>,
-    expiry: HashMap<Box<[u8]>, SystemTime>,
+impl Value {
+    /// Name of the type as reported by TYPE and filtered by SCAN.
+    pub(crate) fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Set(_) => "set",
+            Value::Hash(_) => "hash",
+            Value::SortedSet(_) => "zset",
+            Value::Stream(_) => "stream",
+        }
+    }
 }
 
-impl Dataset {
-    pub(crate) fn new() -> Self {
+/// Seconds since the Unix epoch, the resolution of the access times.
+fn unix_secs() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as u32
+}
+
+/// Value stored under a key, with the time of its last access for OBJECT IDLETIME.
+struct Entry {
+    value: Value,
+    /// Atomic so that it can be updated by reads, under a read lock.
+    access_time: AtomicU32,
+}
+
+impl Entry {
+    fn new(value: Value) -> Self {
         Self {
-            data: HashMap::new(),
-            expiry: HashMap::new(),
+            value,
+            access_time: AtomicU32::new(unix_secs()),
         }
     }
 
-    pub(crate) fn get(&self, key: &[u8]) -> Option<&Value> {
-        // TODO: Instead clear keys on expiry
-        if let Some(expiry) = self.expiry.get(key) {
-            if expiry < &SystemTime::now() {
-                return None;
+    fn touch(&self) {
+        self.access_time.store(unix_secs(), Ordering::Relaxed);
+    }
+}
+
+impl Clone for Entry {
+    fn clone(&self) -> Self {
+        Self {
+            value: self.value.clone(),
+            access_time: AtomicU32::new(self.access_time.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Flags of the clients watching keys with WATCH, set when the key is modified.
+type Watchers = HashMap<Box<[u8]>, Vec<Arc<AtomicBool>>>;
+
+/// Invalidates the transactions of the clients watching the key.
+fn signal_watchers(watchers: &Watchers, key: &[u8]) {
+    if let Some(flags) = watchers.get(key) {
+        for flag in flags {
+            flag.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Keys of one shard of a dataset.
+struct Shard {
+    /// A `Dict` rather than a `HashMap` so that SCAN can iterate it with a cursor.
+    data: Dict<Box<[u8]>, Entry>,
+    /// Keys with a TTL. Indexable so that the active expiry cycle can sample random keys.
+    expiry: IndexMap<Box<[u8]>, SystemTime>,
+    expired_keys: u64,
+    watchers: Watchers,
+}
+
+impl Shard {
+    fn new() -> Self {
+        Self {
+            data: Dict::new(),
+            expiry: IndexMap::new(),
+            expired_keys: 0,
+            watchers: HashMap::new(),
+        }
+    }
+
+    fn watch(&mut self, key: &[u8], flag: &Arc<AtomicBool>) {
+        self.watchers
+            .entry(key.into())
+            .or_default()
+            .push(flag.clone());
+    }
+
+    fn unwatch(&mut self, key: &[u8], flag: &Arc<AtomicBool>) {
+        if let Some(flags) = self.watchers.get_mut(key) {
+            flags.retain(|watcher| !Arc::ptr_eq(watcher, flag));
+            if flags.is_empty() {
+                self.watchers.remove(key);
             }
         }
+    }
+
+    /// Returns the value of the key, hiding it if it has expired but not been removed yet.
+    /// Counts as an access of the key.
+    fn get(&self, key: &[u8]) -> Option<&Value> {
+        let entry = self.peek_entry(key)?;
+        entry.touch();
+        Some(&entry.value)
+    }
+
+    /// Like `get`, but without updating the access time of the key.
+    fn peek(&self, key: &[u8]) -> Option<&Value> {
+        self.peek_entry(key).map(|entry| &entry.value)
+    }
+
+    fn peek_entry(&self, key: &[u8]) -> Option<&Entry> {
+        if self.is_expired(key) {
+            return None;
+        }
         self.data.get(key)
     }
 
+    /// Seconds since the key was last accessed.
+    fn idle_time(&self, key: &[u8]) -> Option<u64> {
+        let access_time = self.peek_entry(key)?.access_time.load(Ordering::Relaxed);
+        Some(unix_secs().saturating_sub(access_time) as u64)
+    }
+
+    /// Mutable access to the value. Expired keys must have been removed by the caller,
+    /// e.g. with `Database::write_key`. Changes made through it must be followed by
+    /// `signal_modified`.
+    fn get_mut(&mut self, key: &[u8]) -> Option<&mut Value> {
+        let entry = self.data.get_mut(key)?;
+        entry.touch();
+        Some(&mut entry.value)
+    }
+
+    fn signal_modified(&self, key: &[u8]) {
+        signal_watchers(&self.watchers, key);
+    }
+
+    fn set(&mut self, key: Box<[u8]>, value: Value) {
+        // A new value must not inherit the TTL of an expired key that is still stored.
+        self.expire_if_needed(&key);
+        signal_watchers(&self.watchers, &key);
+        self.data.insert(key, Entry::new(value));
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Value> {
+        self.expiry.swap_remove(key);
+        let value = self.data.remove(key).map(|entry| entry.value)?;
+        signal_watchers(&self.watchers, key);
+        Some(value)
+    }
+
+    /// Returns a random key that has not expired, if any is found in a few attempts.
+    fn random_key(&self) -> Option<&[u8]> {
+        let mut rng = rand::thread_rng();
+        // The keyspace may consist of expired keys only.
+        for _ in 0..100 {
+            let (key, _) = self.data.random_entry(&mut rng)?;
+            if !self.is_expired(key) {
+                return Some(key);
+            }
+        }
+        None
+    }
+
+    fn get_expiry(&self, key: &[u8]) -> Option<SystemTime> {
+        self.expiry.get(key).copied()
+    }
+
+    fn set_expiry(&mut self, key: Box<[u8]>, expiry: SystemTime) {
+        signal_watchers(&self.watchers, &key);
+        self.expiry.insert(key, expiry);
+    }
+
+    fn unset_expiry(&mut self, key: &[u8]) {
+        if self.expiry.swap_remove(key).is_some() {
+            signal_watchers(&self.watchers, key);
+        }
+    }
+
+    fn is_expired(&self, key: &[u8]) -> bool {
+        match self.expiry.get(key) {
+            Some(expiry) => *expiry <= SystemTime::now(),
+            None => false,
+        }
+    }
+
+    /// Removes the key if its TTL has passed. Returns true if the key was removed.
+    fn expire_if_needed(&mut self, key: &[u8]) -> bool {
+        if !self.is_expired(key) {
+            return false;
+        }
+        self.remove(key);
+        self.expired_keys += 1;
+        true
+    }
+
+    /// Checks up to `count` random keys with a TTL and removes the expired ones.
+    /// Returns the number of keys sampled and the number of keys removed.
+    fn expire_random_keys(&mut self, count: usize) -> (usize, usize) {
+        let now = SystemTime::now();
+        let mut rng = rand::thread_rng();
+        let sampled = count.min(self.expiry.len());
+        let mut expired = 0;
+
+        for _ in 0..sampled {
+            let index = rng.gen_range(0..self.expiry.len());
+            let (key, expiry) = self.expiry.get_index(index).unwrap();
+            if *expiry <= now {
+                let key = key.clone();
+                self.remove(&key);
+                expired += 1;
+            }
+        }
+
+        self.expired_keys += expired as u64;
+        (sampled, expired)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&[u8], &Value, Option<SystemTime>)> {
+        self.data
+            .iter()
+            .map(|(key, entry)| (key.as_ref(), &entry.value, self.expiry.get(key).copied()))
+    }
+
+    /// Visits the keys of one bucket that have not expired, like `Dict::scan`.
+    fn scan(&self, cursor: u64, mut visit: impl FnMut(&[u8], &Value)) -> u64 {
+        self.data.scan(cursor, |key, entry| {
+            if !self.is_expired(key) {
+                visit(key, &entry.value);
+            }
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Moves the keys out, keeping the statistics and the watchers.
+    fn take_contents(&mut self) -> Shard {
+        for key in self.watchers.keys() {
+            if self.data.contains_key(key) {
+                signal_watchers(&self.watchers, key);
+            }
+        }
+        Shard {
+            data: std::mem::take(&mut self.data),
+            expiry: std::mem::take(&mut self.expiry),
+            ..Shard::new()
+        }
+    }
+
+    /// Copies the keys, without the statistics and the watchers.
+    fn copy_contents(&self) -> Shard {
+        Shard {
+            data: self.data.clone(),
+            expiry: self.expiry.clone(),
+            ..Shard::new()
+        }
+    }
+
+    /// Swaps the keys with another shard. Watchers of a key that exists in either shard are
+    /// signaled.
+    fn swap_contents(&mut self, other: &mut Shard) {
+        for (shard, other) in [(&*self, &*other), (&*other, &*self)] {
+            for key in shard.watchers.keys() {
+                if shard.data.contains_key(key) || other.data.contains_key(key) {
+                    signal_watchers(&shard.watchers, key);
+                }
+            }
+        }
+        std::mem::swap(&mut self.data, &mut other.data);
+        std::mem::swap(&mut self.expiry, &mut other.expiry);
+    }
+
+    fn expires_len(&self) -> usize {
+        self.expiry.len()
+    }
+
+    fn expired_keys(&self) -> u64 {
+        self.expired_keys
+    }
+}
+
+/// Number of shards the keys of a dataset are partitioned into by hash. Each has its own
+/// lock, so commands on keys in different shards run in parallel.
+pub(crate) const SHARDS: usize = 16;
+
+fn shard_index(key: &[u8]) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() as usize % SHARDS
+}
+
+/// A shard of a dataset, either locked in the `Database`, owned or borrowed.
+enum ShardRef<'a> {
+    Read(RwLockReadGuard<'a, Shard>),
+    Write(RwLockWriteGuard<'a, Shard>),
+    Owned(Shard),
+    Borrowed(&'a Shard),
+}
+
+impl Deref for ShardRef<'_> {
+    type Target = Shard;
+
+    fn deref(&self) -> &Shard {
+        match self {
+            ShardRef::Read(guard) => guard,
+            ShardRef::Write(guard) => guard,
+            ShardRef::Owned(shard) => shard,
+            ShardRef::Borrowed(shard) => shard,
+        }
+    }
+}
+
+impl DerefMut for ShardRef<'_> {
+    fn deref_mut(&mut self) -> &mut Shard {
+        match self {
+            ShardRef::Read(_) | ShardRef::Borrowed(_) => panic!("Shard is only locked for reading"),
+            ShardRef::Write(guard) => guard,
+            ShardRef::Owned(shard) => shard,
+        }
+    }
+}
+
+/// The keys of a dataset, as locked by `Database::read`, `Database::write` and the like, or
+/// owned, e.g. when loaded from an RDB file, or borrowed from `DatasetContents`.
+///
+/// Locks may cover only the shards of the keys a command uses. Accessing a key in another
+/// shard, or the whole dataset without all shards, is a bug and panics.
+pub(crate) struct Dataset<'a> {
+    /// Sorted by shard index.
+    shards: Vec<(usize, ShardRef<'a>)>,
+    /// Shared access to the `Database`, which keeps EXEC out while the shards are locked.
+    _access: Option<RwLockReadGuard<'a, ()>>,
+}
+
+impl Dataset<'static> {
+    pub(crate) fn new() -> Self {
+        Self {
+            shards: (0..SHARDS)
+                .map(|index| (index, ShardRef::Owned(Shard::new())))
+                .collect(),
+            _access: None,
+        }
+    }
+}
+
+impl<'a> Dataset<'a> {
+    fn shard(&self, key: &[u8]) -> &Shard {
+        let index = self.position(shard_index(key));
+        &self.shards[index].1
+    }
+
+    fn shard_mut(&mut self, key: &[u8]) -> &mut Shard {
+        let index = self.position(shard_index(key));
+        &mut self.shards[index].1
+    }
+
+    fn position(&self, shard: usize) -> usize {
+        if self.shards.len() == SHARDS {
+            return shard;
+        }
+        self.shards
+            .binary_search_by_key(&shard, |(index, _)| *index)
+            .expect("Shard of the key is not locked")
+    }
+
+    /// Whether the shards of all the keys are locked.
+    pub(crate) fn holds(&self, keys: &[impl AsRef<[u8]>]) -> bool {
+        keys.iter().all(|key| {
+            let shard = shard_index(key.as_ref());
+            self.shards.len() == SHARDS
+                || self.shards.binary_search_by_key(&shard, |(index, _)| *index).is_ok()
+        })
+    }
+
+    /// All the shards, for operations on the whole dataset.
+    fn all_shards(&self) -> impl Iterator<Item = &Shard> {
+        assert_eq!(self.shards.len(), SHARDS, "Not all shards are locked");
+        self.shards.iter().map(|(_, shard)| &**shard)
+    }
+
+    fn all_shards_mut(&mut self) -> Vec<&mut Shard> {
+        assert_eq!(self.shards.len(), SHARDS, "Not all shards are locked");
+        self.shards
+            .iter_mut()
+            .map(|(_, shard)| &mut **shard)
+            .collect()
+    }
+
+    /// Returns the value of the key, hiding it if it has expired but not been removed yet.
+    /// Counts as an access of the key.
+    pub(crate) fn get(&self, key: &[u8]) -> Option<&Value> {
+        self.shard(key).get(key)
+    }
+
+    /// Sets `flag` whenever the key is modified, until `unwatch`.
+    fn watch(&mut self, key: &[u8], flag: &Arc<AtomicBool>) {
+        self.shard_mut(key).watch(key, flag)
+    }
+
+    fn unwatch(&mut self, key: &[u8], flag: &Arc<AtomicBool>) {
+        self.shard_mut(key).unwatch(key, flag)
+    }
+
+    /// Like `get`, but without updating the access time of the key.
+    pub(crate) fn peek(&self, key: &[u8]) -> Option<&Value> {
+        self.shard(key).peek(key)
+    }
+
+    /// Seconds since the key was last accessed.
+    pub(crate) fn idle_time(&self, key: &[u8]) -> Option<u64> {
+        self.shard(key).idle_time(key)
+    }
+
+    /// Mutable access to the value. Expired keys must have been removed by the caller,
+    /// e.g. with `Database::write_key`. Changes made through it must be followed by
+    /// `signal_modified`.
+    pub(crate) fn get_mut(&mut self, key: &[u8]) -> Option<&mut Value> {
+        self.shard_mut(key).get_mut(key)
+    }
+
+    /// Invalidates the transactions watching the key, after its value was changed in place.
+    /// Commands that fail or change nothing don't call it.
+    pub(crate) fn signal_modified(&self, key: &[u8]) {
+        self.shard(key).signal_modified(key)
+    }
+
     pub(crate) fn set(&mut self, key: Box<[u8]>, value: Value) {
-        self.data.insert(key, value);
+        self.shard_mut(&key).set(key, value)
+    }
+
+    pub(crate) fn remove(&mut self, key: &[u8]) -> Option<Value> {
+        self.shard_mut(key).remove(key)
+    }
+
+    pub(crate) fn get_expiry(&self, key: &[u8]) -> Option<SystemTime> {
+        self.shard(key).get_expiry(key)
     }
 
     pub(crate) fn set_expiry(&mut self, key: Box<[u8]>, expiry: SystemTime) {
-        self.expiry.insert(key, expiry);
+        self.shard_mut(&key).set_expiry(key, expiry)
     }
 
     pub(crate) fn unset_expiry(&mut self, key: &[u8]) {
-        self.expiry.remove(key);
+        self.shard_mut(key).unset_expiry(key)
+    }
+
+    pub(crate) fn is_expired(&self, key: &[u8]) -> bool {
+        self.shard(key).is_expired(key)
+    }
+
+    /// Removes the key if its TTL has passed. Returns true if the key was removed.
+    pub(crate) fn expire_if_needed(&mut self, key: &[u8]) -> bool {
+        self.shard_mut(key).expire_if_needed(key)
+    }
+
+    /// Checks up to `count` random keys with a TTL in each locked shard and removes the
+    /// expired ones. Returns the number of keys sampled and the number of keys removed.
+    pub(crate) fn expire_random_keys(&mut self, count: usize) -> (usize, usize) {
+        self.shards
+            .iter_mut()
+            .map(|(_, shard)| shard.expire_random_keys(count))
+            .fold((0, 0), |(sampled, expired), (s, e)| {
+                (sampled + s, expired + e)
+            })
     }
 
+    /// Returns a random key that has not expired, if any is found in a few attempts.
+    pub(crate) fn random_key(&self) -> Option<&[u8]> {
+        // Picking the shard by its size keeps all keys equally likely.
+        let len = self.len();
+        if len == 0 {
+            return None;
+        }
+        let mut index = rand::thread_rng().gen_range(0..len);
+        self.all_shards()
+            .find(|shard| {
+                let found = index < shard.len();
+                index = index.saturating_sub(shard.len());
+                found
+            })
+            .and_then(Shard::random_key)
+    }
+
+    /// Iterates all keys with their values and expiry times, including expired keys.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&[u8], &Value, Option<SystemTime>)> {
+        self.all_shards().flat_map(Shard::iter)
+    }
+
+    /// Returns the keys that have not expired.
     pub(crate) fn all_keys(&self) -> Vec<&[u8]> {
-        self.data.keys().map(|key| key.as_ref()).collect()
+        let now = SystemTime::now();
+        self.iter()
+            .filter(|(_, _, expiry)| expiry.is_none_or(|expiry| expiry > now))
+            .map(|(key, _, _)| key)
+            .collect()
+    }
+
+    /// Visits the keys of one bucket that have not expired, like `Dict::scan`. Returns the
+    /// cursor for the next call, 0 once all keys have been visited.
+    ///
+    /// The shards are scanned one after the other, the low bits of the cursor tell which.
+    pub(crate) fn scan(&self, cursor: u64, visit: impl FnMut(&[u8], &Value)) -> u64 {
+        let shard = cursor as usize % SHARDS;
+        let shards: Vec<&Shard> = self.all_shards().collect();
+        let next = shards[shard].scan(cursor / SHARDS as u64, visit);
+        match next {
+            0 if shard + 1 == SHARDS => 0,
+            0 => shard as u64 + 1,
+            next => next * SHARDS as u64 + shard as u64,
+        }
+    }
+
+    /// Removes all keys, returning the old contents so they can be dropped elsewhere.
+    pub(crate) fn take(&mut self) -> DatasetContents {
+        let shards = self.all_shards_mut().into_iter().map(Shard::take_contents);
+        DatasetContents(shards.collect())
+    }
+
+    /// Swaps the keys of two datasets. Statistics stay with the dataset.
+    pub(crate) fn swap_contents(&mut self, other: &mut Dataset) {
+        for (shard, other) in self
+            .all_shards_mut()
+            .into_iter()
+            .zip(other.all_shards_mut())
+        {
+            shard.swap_contents(other);
+        }
+    }
+
+    /// Copies the keys, e.g. to save them without holding the locks.
+    pub(crate) fn snapshot(&self) -> DatasetContents {
+        DatasetContents(self.all_shards().map(Shard::copy_contents).collect())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.all_shards().map(Shard::len).sum()
+    }
+
+    pub(crate) fn expires_len(&self) -> usize {
+        self.all_shards().map(Shard::expires_len).sum()
+    }
+
+    pub(crate) fn expired_keys(&self) -> u64 {
+        self.all_shards().map(Shard::expired_keys).sum()
+    }
+}
+
+/// Keys of a dataset taken out of the `Database`, which unlike a `Dataset` can be sent to
+/// other threads.
+pub(crate) struct DatasetContents(Vec<Shard>);
+
+impl DatasetContents {
+    pub(crate) fn as_dataset(&self) -> Dataset<'_> {
+        let shards = self.0.iter().map(ShardRef::Borrowed).enumerate().collect();
+        Dataset {
+            shards,
+            _access: None,
+        }
+    }
+}
+
+thread_local! {
+    /// Set while the thread has exclusive access to the `Database`, so that its own accesses
+    /// to the datasets don't wait for itself.
+    static EXCLUSIVE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Exclusive access to all datasets, for EXEC. Released when dropped.
+///
+/// Only the thread holding it can lock shards. It must not wait for I/O meanwhile, as the
+/// tasks of other clients served by the same thread would block the thread.
+pub(crate) struct ExclusiveAccess<'a> {
+    _guard: RwLockWriteGuard<'a, ()>,
+}
+
+impl Drop for ExclusiveAccess<'_> {
+    fn drop(&mut self) {
+        EXCLUSIVE.set(false);
+    }
+}
+
+/// Keys watched by a client with WATCH. The watch is removed when dropped.
+pub(crate) struct WatchedKeys<'a> {
+    db: &'a Database,
+    keys: Vec<(usize, Box<[u8]>)>,
+    /// Set when any of the keys is modified.
+    modified: Arc<AtomicBool>,
+}
+
+impl<'a> WatchedKeys<'a> {
+    pub(crate) fn new(db: &'a Database) -> Self {
+        Self {
+            db,
+            keys: Vec::new(),
+            modified: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub(crate) fn watch(&mut self, dataset: usize, key: &[u8]) {
+        if self
+            .keys
+            .iter()
+            .any(|(index, watched)| *index == dataset && **watched == *key)
+        {
+            return;
+        }
+        // An expired key is removed first, as removing it later does not modify it.
+        let mut lock = self.db.write_key(dataset, key);
+        lock.watch(key, &self.modified);
+        self.keys.push((dataset, key.into()));
+    }
+
+    /// Whether any of the keys has been modified since it was watched. Keys whose TTL has
+    /// passed count as modified even if they have not been removed yet.
+    pub(crate) fn is_modified(&self) -> bool {
+        for (dataset, key) in &self.keys {
+            self.db.write_key(*dataset, key);
+        }
+        self.modified.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for WatchedKeys<'_> {
+    fn drop(&mut self) {
+        for (dataset, key) in &self.keys {
+            self.db
+                .write_keys(*dataset, &[key])
+                .unwatch(key, &self.modified);
+        }
+    }
+}
+
+/// State of RDB saves. Shared with the background save thread.
+pub(crate) struct SaveState {
+    /// Unix time of the last successful save.
+    last_save: AtomicU64,
+    /// Set while SAVE or BGSAVE writes the dump, as both write the same temporary file.
+    save_in_progress: AtomicBool,
+}
+
+impl SaveState {
+    fn new() -> Self {
+        let state = Self {
+            last_save: AtomicU64::new(0),
+            save_in_progress: AtomicBool::new(false),
+        };
+        state.mark_saved();
+        state
+    }
+
+    pub(crate) fn last_save(&self) -> u64 {
+        self.last_save.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn mark_saved(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.last_save.store(now, Ordering::Relaxed);
+    }
+
+    /// Marks a save as started. Returns false if one is already running.
+    pub(crate) fn start_save(&self) -> bool {
+        !self.save_in_progress.swap(true, Ordering::AcqRel)
+    }
+
+    pub(crate) fn finish_save(&self, success: bool) {
+        if success {
+            self.mark_saved();
+        }
+        self.save_in_progress.store(false, Ordering::Release);
     }
 }
 
 pub(crate) struct Database {
     config: RwLock<HashMap<Box<[u8]>, String>>,
-    datasets: Vec<RwLock<Dataset>>
+    /// The shards of each dataset.
+    datasets: Vec<Vec<RwLock<Shard>>>,
+    save_state: Arc<SaveState>,
+    blocking: BlockingKeys,
+    /// Shared by every lock of shards, taken exclusively by EXEC.
+    access: RwLock<()>,
 }
 
 impl Database {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(databases: usize) -> Self {
         Self {
             config: RwLock::new(HashMap::new()),
-            datasets: vec![RwLock::new(Dataset::new())]
+            datasets: (0..databases)
+                .map(|_| (0..SHARDS).map(|_| RwLock::new(Shard::new())).collect())
+                .collect(),
+            save_state: Arc::new(SaveState::new()),
+            blocking: BlockingKeys::new(),
+            access: RwLock::new(()),
         }
     }
 
@@ -66,15 +739,184 @@ impl Database {
         self.config.write().unwrap().insert(key, value);
     }
 
-    pub(crate) fn read(&self, dataset: usize) -> RwLockReadGuard<Dataset> {
-        self.datasets[dataset].read().unwrap()
+    /// Location of the RDB file, from the `dir` and `dbfilename` config.
+    pub(crate) fn rdb_path(&self) -> PathBuf {
+        let dir = self.get_config(b"dir").unwrap_or_else(|| ".".to_string());
+        let dbfilename = self
+            .get_config(b"dbfilename")
+            .unwrap_or_else(|| "dump.rdb".to_string());
+        PathBuf::from(dir).join(dbfilename)
+    }
+
+    /// Limits on requests, from the `proto-max-bulk-len`, `proto-max-multibulk-len` and
+    /// `client-query-buffer-limit` config.
+    pub(crate) fn protocol_limits(&self) -> ProtocolLimits {
+        let config = |key: &[u8], default: usize| {
+            self.get_config(key)
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+        let default = ProtocolLimits::default();
+        ProtocolLimits {
+            max_bulk_len: config(b"proto-max-bulk-len", default.max_bulk_len),
+            max_multibulk_len: config(b"proto-max-multibulk-len", default.max_multibulk_len),
+            query_buffer_limit: config(b"client-query-buffer-limit", default.query_buffer_limit),
+        }
+    }
+
+    /// Shared access to the datasets, unless this thread already has exclusive access.
+    fn shared_access(&self) -> Option<RwLockReadGuard<'_, ()>> {
+        (!EXCLUSIVE.get()).then(|| self.access.read().unwrap())
+    }
+
+    /// Waits until no other client has shards locked, and keeps them from locking any until
+    /// the returned guard is dropped.
+    pub(crate) fn exclusive_access(&self) -> ExclusiveAccess<'_> {
+        let guard = self.access.write().unwrap();
+        EXCLUSIVE.set(true);
+        ExclusiveAccess { _guard: guard }
+    }
+
+    /// Locks the shards, in order so that locking several shards can't deadlock. The access
+    /// is None when the caller already holds it, as taking it twice could deadlock with a
+    /// waiting EXEC.
+    fn lock_shards<'a>(
+        &'a self,
+        dataset: usize,
+        shards: impl Iterator<Item = usize>,
+        write: bool,
+        access: Option<RwLockReadGuard<'a, ()>>,
+    ) -> Dataset<'a> {
+        let locks = &self.datasets[dataset];
+        let shards = shards
+            .map(|index| {
+                let shard = if write {
+                    ShardRef::Write(locks[index].write().unwrap())
+                } else {
+                    ShardRef::Read(locks[index].read().unwrap())
+                };
+                (index, shard)
+            })
+            .collect();
+        Dataset {
+            shards,
+            _access: access,
+        }
+    }
+
+    /// Indexes of the shards of the keys, sorted and without duplicates.
+    fn key_shards(keys: &[impl AsRef<[u8]>]) -> impl Iterator<Item = usize> {
+        let mut shards: Vec<usize> = keys.iter().map(|key| shard_index(key.as_ref())).collect();
+        shards.sort_unstable();
+        shards.dedup();
+        shards.into_iter()
+    }
+
+    /// Read-locks the whole dataset.
+    pub(crate) fn read(&self, dataset: usize) -> Dataset<'_> {
+        self.lock_shards(dataset, 0..SHARDS, false, self.shared_access())
+    }
+
+    /// Read-locks the shards of the keys, which are all accessed atomically.
+    pub(crate) fn read_keys(&self, dataset: usize, keys: &[impl AsRef<[u8]>]) -> Dataset<'_> {
+        self.lock_shards(dataset, Self::key_shards(keys), false, self.shared_access())
+    }
+
+    /// Read-locks the shard of the key, first removing the key if it has expired.
+    pub(crate) fn read_key(&self, dataset: usize, key: &[u8]) -> Dataset<'_> {
+        let lock = self.read_keys(dataset, &[key]);
+        if !lock.is_expired(key) {
+            return lock;
+        }
+        drop(lock);
+
+        self.write_keys(dataset, &[key]).expire_if_needed(key);
+        self.read_keys(dataset, &[key])
+    }
+
+    /// Write-locks the whole dataset.
+    pub(crate) fn write(&self, dataset: usize) -> Dataset<'_> {
+        self.lock_shards(dataset, 0..SHARDS, true, self.shared_access())
+    }
+
+    /// Write-locks the shards of the keys, which are all accessed atomically. The whole dataset
+    /// is locked if a client is blocked on one of the keys, which the write may serve.
+    pub(crate) fn write_keys(&self, dataset: usize, keys: &[impl AsRef<[u8]>]) -> Dataset<'_> {
+        if self.blocking.is_blocked(dataset, keys) {
+            return self.write(dataset);
+        }
+        self.lock_shards(dataset, Self::key_shards(keys), true, self.shared_access())
+    }
+
+    /// Write-locks the shard of the key, first removing the key if it has expired.
+    pub(crate) fn write_key(&self, dataset: usize, key: &[u8]) -> Dataset<'_> {
+        let mut lock = self.write_keys(dataset, &[key]);
+        lock.expire_if_needed(key);
+        lock
+    }
+
+    /// Write-locks a single shard of the dataset, by index below `SHARDS`.
+    pub(crate) fn write_shard(&self, dataset: usize, index: usize) -> Dataset<'_> {
+        self.lock_shards(dataset, std::iter::once(index), true, self.shared_access())
+    }
+
+    /// Write-locks two different datasets, always in the same order to avoid deadlocks.
+    pub(crate) fn write_two(&self, first: usize, second: usize) -> (Dataset<'_>, Dataset<'_>) {
+        assert_ne!(first, second);
+        let lock = |dataset, access| self.lock_shards(dataset, 0..SHARDS, true, access);
+        if first < second {
+            let first = self.write(first);
+            (first, lock(second, None))
+        } else {
+            let second = self.write(second);
+            (lock(first, None), second)
+        }
+    }
+
+    /// Read-locks all datasets at once, e.g. to save them consistently.
+    pub(crate) fn read_all(&self) -> Vec<Dataset<'_>> {
+        let mut access = self.shared_access();
+        (0..self.dataset_count())
+            .map(|dataset| self.lock_shards(dataset, 0..SHARDS, false, access.take()))
+            .collect()
+    }
+
+    /// Write-locks all datasets at once, e.g. to clear them together.
+    pub(crate) fn write_all(&self) -> Vec<Dataset<'_>> {
+        let mut access = self.shared_access();
+        (0..self.dataset_count())
+            .map(|dataset| self.lock_shards(dataset, 0..SHARDS, true, access.take()))
+            .collect()
+    }
+
+    pub(crate) fn blocking(&self) -> &BlockingKeys {
+        &self.blocking
+    }
+
+    /// Serves clients blocked on the key. Called after a write that may serve them, before the
+    /// dataset lock is released.
+    pub(crate) fn signal_ready(&self, lock: &mut Dataset<'_>, dataset: usize, key: &[u8]) {
+        self.blocking.serve(dataset, key, lock);
+    }
+
+    pub(crate) fn save_state(&self) -> &Arc<SaveState> {
+        &self.save_state
     }
 
-    pub(crate) fn write(&self, dataset: usize) -> RwLockWriteGuard<Dataset> {
-        self.datasets[dataset].write().unwrap()
+    pub(crate) fn dataset_count(&self) -> usize {
+        self.datasets.len()
     }
 
-    pub(crate) fn swap_datasets(&mut self, datasets: Vec<Dataset>) {
-        self.datasets = datasets.into_iter().map(|data| RwLock::new(data)).collect();
+    pub(crate) fn swap_datasets(&mut self, datasets: Vec<Dataset<'static>>) {
+        self.datasets = datasets
+            .into_iter()
+            .map(|dataset| {
+                let shards = dataset.shards.into_iter().map(|(_, shard)| match shard {
+                    ShardRef::Owned(shard) => RwLock::new(shard),
+                    _ => unreachable!("Datasets of the database are owned"),
+                });
+                shards.collect()
+            })
+            .collect();
     }
 }
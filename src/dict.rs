@@ -0,0 +1,282 @@
+use std::{
+    borrow::Borrow,
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hash},
+};
+
+use rand::Rng;
+
+const INITIAL_SIZE: usize = 4;
+
+/// Hash table with separate chaining, modeled after the Redis `dict`.
+///
+/// Unlike `std::collections::HashMap` it exposes its buckets, which allows `scan` to iterate
+/// it with a cursor across calls while the table is modified and resized in between, and
+/// picking random entries in O(1).
+#[derive(Clone)]
+pub(crate) struct Dict<K, V> {
+    buckets: Vec<Vec<(u64, K, V)>>,
+    len: usize,
+    hasher: RandomState,
+}
+
+impl<K: Hash + Eq, V> Dict<K, V> {
+    pub(crate) fn new() -> Self {
+        Self {
+            buckets: Vec::new(),
+            len: 0,
+            hasher: RandomState::new(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn hash<Q: Hash + ?Sized>(&self, key: &Q) -> u64 {
+        self.hasher.hash_one(key)
+    }
+
+    fn bucket_index(&self, hash: u64) -> usize {
+        (hash as usize) & (self.buckets.len() - 1)
+    }
+
+    fn find<Q>(&self, key: &Q) -> Option<(usize, usize)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if self.len == 0 {
+            return None;
+        }
+        let hash = self.hash(key);
+        let bucket = self.bucket_index(hash);
+        self.buckets[bucket]
+            .iter()
+            .position(|(h, k, _)| *h == hash && k.borrow() == key)
+            .map(|position| (bucket, position))
+    }
+
+    pub(crate) fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.find(key)
+            .map(|(bucket, position)| &self.buckets[bucket][position].2)
+    }
+
+    pub(crate) fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.find(key)
+            .map(|(bucket, position)| &mut self.buckets[bucket][position].2)
+    }
+
+    pub(crate) fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.find(key).is_some()
+    }
+
+    /// Inserts the entry, returning the previous value of the key.
+    pub(crate) fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if let Some((bucket, position)) = self.find(&key) {
+            return Some(std::mem::replace(
+                &mut self.buckets[bucket][position].2,
+                value,
+            ));
+        }
+
+        if self.len >= self.buckets.len() {
+            self.resize((self.len + 1).next_power_of_two().max(INITIAL_SIZE));
+        }
+        let hash = self.hash(&key);
+        let bucket = self.bucket_index(hash);
+        self.buckets[bucket].push((hash, key, value));
+        self.len += 1;
+        None
+    }
+
+    pub(crate) fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.remove_entry(key).map(|(_, value)| value)
+    }
+
+    pub(crate) fn remove_entry<Q>(&mut self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (bucket, position) = self.find(key)?;
+        let (_, key, value) = self.buckets[bucket].swap_remove(position);
+        self.len -= 1;
+
+        // Shrink when less than 10% of the buckets are used, like Redis does.
+        if self.buckets.len() > INITIAL_SIZE && self.len * 10 < self.buckets.len() {
+            self.resize(self.len.next_power_of_two().max(INITIAL_SIZE));
+        }
+        Some((key, value))
+    }
+
+    fn resize(&mut self, size: usize) {
+        let old = std::mem::replace(&mut self.buckets, (0..size).map(|_| Vec::new()).collect());
+        for (hash, key, value) in old.into_iter().flatten() {
+            let bucket = self.bucket_index(hash);
+            self.buckets[bucket].push((hash, key, value));
+        }
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.buckets
+            .iter()
+            .flatten()
+            .map(|(_, key, value)| (key, value))
+    }
+
+    /// Visits the entries of one bucket and returns the cursor for the next call, 0 once
+    /// the whole table has been visited. Start with cursor 0.
+    ///
+    /// The cursor is advanced by incrementing its reversed bits, as in Redis' `dictScan`.
+    /// This guarantees that every entry present for the whole iteration is visited at least
+    /// once, even if the table is resized between calls. Entries may be visited twice.
+    pub(crate) fn scan(&self, cursor: u64, mut visit: impl FnMut(&K, &V)) -> u64 {
+        if self.len == 0 {
+            return 0;
+        }
+
+        let mask = (self.buckets.len() - 1) as u64;
+        for (_, key, value) in &self.buckets[(cursor & mask) as usize] {
+            visit(key, value);
+        }
+
+        let mut cursor = cursor | !mask;
+        cursor = cursor.reverse_bits();
+        cursor = cursor.wrapping_add(1);
+        cursor.reverse_bits()
+    }
+
+    /// Returns a random entry. Uniform over buckets rather than entries, like Redis.
+    pub(crate) fn random_entry(&self, rng: &mut impl Rng) -> Option<(&K, &V)> {
+        if self.len == 0 {
+            return None;
+        }
+        loop {
+            let bucket = &self.buckets[rng.gen_range(0..self.buckets.len())];
+            if !bucket.is_empty() {
+                let (_, key, value) = &bucket[rng.gen_range(0..bucket.len())];
+                return Some((key, value));
+            }
+        }
+    }
+}
+
+impl<K: Hash + Eq, V> Default for Dict<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    /// Scans `dict` to the end, calling `change` between calls, and returns the keys visited.
+    fn scan_all(
+        dict: &mut Dict<u64, ()>,
+        mut change: impl FnMut(&mut Dict<u64, ()>, usize),
+    ) -> HashSet<u64> {
+        let mut visited = HashSet::new();
+        let mut cursor = 0;
+        let mut step = 0;
+        loop {
+            cursor = dict.scan(cursor, |key, _| {
+                visited.insert(*key);
+            });
+            if cursor == 0 {
+                return visited;
+            }
+            change(dict, step);
+            step += 1;
+        }
+    }
+
+    fn dict_of(keys: std::ops::Range<u64>) -> Dict<u64, ()> {
+        let mut dict = Dict::new();
+        for key in keys {
+            dict.insert(key, ());
+        }
+        dict
+    }
+
+    #[test]
+    fn scan_visits_every_entry() {
+        let mut dict = dict_of(0..1000);
+        let visited = scan_all(&mut dict, |_, _| {});
+        assert_eq!(visited, (0..1000).collect());
+        assert_eq!(Dict::<u64, ()>::new().scan(0, |_, _| unreachable!()), 0);
+    }
+
+    #[test]
+    fn scan_survives_growth() {
+        let mut dict = dict_of(0..100);
+        let mut next = 100;
+        let visited = scan_all(&mut dict, |dict, _| {
+            // Grow the table several times over during the scan.
+            for _ in 0..50 {
+                if next < 2000 {
+                    dict.insert(next, ());
+                    next += 1;
+                }
+            }
+        });
+        assert_eq!(dict.len(), 2000);
+        assert!((0..100).all(|key| visited.contains(&key)));
+    }
+
+    #[test]
+    fn scan_survives_shrinking() {
+        let mut dict = dict_of(0..2000);
+        let mut next = 100;
+        let visited = scan_all(&mut dict, |dict, _| {
+            // Remove everything but the first 100 keys, shrinking the table as it empties.
+            for _ in 0..100 {
+                if next < 2000 {
+                    dict.remove(&next);
+                    next += 1;
+                }
+            }
+        });
+        assert_eq!(dict.len(), 100);
+        assert!((0..100).all(|key| visited.contains(&key)));
+    }
+
+    #[test]
+    fn scan_survives_growth_and_shrinking() {
+        let mut dict = dict_of(0..100);
+        let visited = scan_all(&mut dict, |dict, step| {
+            // Alternately grow the table with temporary keys and shrink it back.
+            for key in 1000..2000 {
+                if step % 2 == 0 {
+                    dict.insert(key, ());
+                } else {
+                    dict.remove(&key);
+                }
+            }
+        });
+        assert!((0..100).all(|key| visited.contains(&key)));
+    }
+}
@@ -0,0 +1,100 @@
+use std::{borrow::Cow, fmt, io};
+
+/// Error produced while handling a command.
+#[derive(Debug)]
+pub(crate) enum CmdError {
+    /// Reported back to the client as an error reply. The connection keeps serving.
+    Reply(ReplyError),
+    /// I/O or protocol failure. The connection is closed.
+    Fatal(anyhow::Error),
+}
+
+pub(crate) type CmdResult = Result<(), CmdError>;
+
+/// Error reply sent to the client, e.g. `-ERR syntax error` or `-WRONGTYPE ...`.
+#[derive(Debug, Clone)]
+pub(crate) struct ReplyError {
+    kind: &'static str,
+    message: Cow<'static, str>,
+}
+
+impl ReplyError {
+    pub(crate) fn new(kind: &'static str, message: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn err(message: impl Into<Cow<'static, str>>) -> Self {
+        Self::new("ERR", message)
+    }
+
+    pub(crate) fn syntax() -> Self {
+        Self::err("syntax error")
+    }
+
+    pub(crate) fn wrong_type() -> Self {
+        Self::new(
+            "WRONGTYPE",
+            "Operation against a key holding the wrong kind of value",
+        )
+    }
+
+    pub(crate) fn not_integer() -> Self {
+        Self::err("value is not an integer or out of range")
+    }
+
+    pub(crate) fn wrong_arity(command: &str) -> Self {
+        Self::err(format!(
+            "wrong number of arguments for '{}' command",
+            command
+        ))
+    }
+
+    /// Full reply line without the leading `-` and the trailing CRLF.
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        self.to_string().into_bytes()
+    }
+}
+
+impl fmt::Display for ReplyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.kind, self.message)
+    }
+}
+
+impl From<ReplyError> for CmdError {
+    fn from(e: ReplyError) -> Self {
+        CmdError::Reply(e)
+    }
+}
+
+impl From<io::Error> for CmdError {
+    fn from(e: io::Error) -> Self {
+        CmdError::Fatal(e.into())
+    }
+}
+
+impl From<anyhow::Error> for CmdError {
+    fn from(e: anyhow::Error) -> Self {
+        CmdError::Fatal(e)
+    }
+}
+
+/// Parses an integer argument, replying with the standard Redis error on failure.
+pub(crate) fn parse_int<T: std::str::FromStr>(arg: &[u8]) -> Result<T, ReplyError> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse::<T>().ok())
+        .ok_or_else(ReplyError::not_integer)
+}
+
+/// Parses a floating point argument. NaN is rejected, infinities are accepted.
+pub(crate) fn parse_float(arg: &[u8]) -> Result<f64, ReplyError> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse::<f64>().ok())
+        .filter(|value| !value.is_nan())
+        .ok_or_else(|| ReplyError::err("value is not a valid float"))
+}
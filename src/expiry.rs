@@ -0,0 +1,47 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::database::{Database, SHARDS};
+
+/// How often the active expiry cycle runs.
+const CYCLE_PERIOD: Duration = Duration::from_millis(100);
+/// Upper bound for the time spent in a single cycle.
+const CYCLE_TIME_LIMIT: Duration = Duration::from_millis(25);
+/// Number of keys with a TTL sampled per iteration.
+const KEYS_PER_LOOP: usize = 20;
+/// Keep sampling a dataset while more than this percentage of the sampled keys had expired.
+const ACCEPTABLE_STALE_PERCENT: usize = 25;
+
+/// Periodically removes expired keys that are never accessed again, in the same way
+/// Redis does: sample random keys with a TTL, remove the expired ones and repeat while
+/// a large enough fraction of the sample turned out to be expired.
+pub(crate) async fn run_active_expiry(db: Arc<Database>) {
+    let mut interval = monoio::time::interval(CYCLE_PERIOD);
+    loop {
+        interval.tick().await;
+        active_expire_cycle(&db);
+    }
+}
+
+fn active_expire_cycle(db: &Database) {
+    let start = Instant::now();
+
+    for dataset in 0..db.dataset_count() {
+        for shard in 0..SHARDS {
+            loop {
+                // The lock is released between iterations so clients are not starved.
+                let (sampled, expired) = db
+                    .write_shard(dataset, shard)
+                    .expire_random_keys(KEYS_PER_LOOP);
+                if sampled == 0 || expired * 100 <= sampled * ACCEPTABLE_STALE_PERCENT {
+                    break;
+                }
+                if start.elapsed() > CYCLE_TIME_LIMIT {
+                    return;
+                }
+            }
+        }
+    }
+}
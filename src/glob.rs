@@ -0,0 +1,208 @@
+//! Glob-style pattern matching, with the semantics of Redis' `stringmatchlen`.
+//!
+//! Supported: `*`, `?`, `[abc]`, `[^abc]`, `[a-z]` and `\` to escape the next character.
+
+/// Limit on nested `*` to protect against patterns that recurse too deeply.
+const MAX_NESTING: usize = 1000;
+
+/// Returns true if `string` matches `pattern`.
+pub(crate) fn matches(pattern: &[u8], string: &[u8], nocase: bool) -> bool {
+    let mut skip_longer_matches = false;
+    matches_impl(pattern, string, nocase, &mut skip_longer_matches, 0)
+}
+
+fn matches_impl(
+    mut pattern: &[u8],
+    mut string: &[u8],
+    nocase: bool,
+    skip_longer_matches: &mut bool,
+    nesting: usize,
+) -> bool {
+    if nesting > MAX_NESTING {
+        return false;
+    }
+    let eq = |a: u8, b: u8| {
+        if nocase {
+            a.eq_ignore_ascii_case(&b)
+        } else {
+            a == b
+        }
+    };
+
+    while !pattern.is_empty() && !string.is_empty() {
+        match pattern[0] {
+            b'*' => {
+                while pattern.get(1) == Some(&b'*') {
+                    pattern = &pattern[1..];
+                }
+                if pattern.len() == 1 {
+                    return true;
+                }
+                while !string.is_empty() {
+                    if matches_impl(
+                        &pattern[1..],
+                        string,
+                        nocase,
+                        skip_longer_matches,
+                        nesting + 1,
+                    ) {
+                        return true;
+                    }
+                    // If the rest of the pattern did not match the rest of the string for a
+                    // later `*`, it cannot match a shorter suffix either.
+                    if *skip_longer_matches {
+                        return false;
+                    }
+                    string = &string[1..];
+                }
+                *skip_longer_matches = true;
+                return false;
+            }
+            b'?' => string = &string[1..],
+            b'[' => {
+                pattern = &pattern[1..];
+                let negate = pattern.first() == Some(&b'^');
+                if negate {
+                    pattern = &pattern[1..];
+                }
+
+                let mut matched = false;
+                loop {
+                    if pattern.is_empty() {
+                        // Unterminated class: treat the end of the pattern as `]`.
+                        break;
+                    } else if pattern[0] == b'\\' && pattern.len() >= 2 {
+                        pattern = &pattern[1..];
+                        if pattern[0] == string[0] {
+                            matched = true;
+                        }
+                    } else if pattern[0] == b']' {
+                        break;
+                    } else if pattern.len() >= 3 && pattern[1] == b'-' {
+                        let (mut start, mut end, mut c) = (pattern[0], pattern[2], string[0]);
+                        if start > end {
+                            std::mem::swap(&mut start, &mut end);
+                        }
+                        if nocase {
+                            start = start.to_ascii_lowercase();
+                            end = end.to_ascii_lowercase();
+                            c = c.to_ascii_lowercase();
+                        }
+                        pattern = &pattern[2..];
+                        if start <= c && c <= end {
+                            matched = true;
+                        }
+                    } else if eq(pattern[0], string[0]) {
+                        matched = true;
+                    }
+                    pattern = &pattern[1..];
+                }
+
+                if matched == negate {
+                    return false;
+                }
+                string = &string[1..];
+            }
+            b'\\' if pattern.len() >= 2 => {
+                pattern = &pattern[1..];
+                if !eq(pattern[0], string[0]) {
+                    return false;
+                }
+                string = &string[1..];
+            }
+            c => {
+                if !eq(c, string[0]) {
+                    return false;
+                }
+                string = &string[1..];
+            }
+        }
+
+        pattern = pattern.get(1..).unwrap_or_default();
+        if string.is_empty() {
+            while pattern.first() == Some(&b'*') {
+                pattern = &pattern[1..];
+            }
+            break;
+        }
+    }
+
+    pattern.is_empty() && string.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(pattern: &str, string: &str) -> bool {
+        matches(pattern.as_bytes(), string.as_bytes(), false)
+    }
+
+    #[test]
+    fn star() {
+        assert!(check("*", "anything"));
+        // As in Redis, only the empty pattern matches the empty string.
+        assert!(!check("*", ""));
+        assert!(check("", ""));
+        assert!(check("h*llo", "hllo"));
+        assert!(check("h*llo", "heeeello"));
+        assert!(check("h**llo", "hello"));
+        assert!(check("user:*", "user:"));
+        assert!(!check("h*llo", "hellx"));
+        assert!(!check("*a", "b"));
+        // Many stars against a long string that doesn't match must finish quickly.
+        assert!(!check("a*a*a*a*a*a*a*a*a*a*a*a*b", &"a".repeat(1000)));
+    }
+
+    #[test]
+    fn question_mark() {
+        assert!(check("h?llo", "hello"));
+        assert!(check("h?llo", "hallo"));
+        assert!(!check("h?llo", "hllo"));
+        assert!(!check("?", ""));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(check("h[ae]llo", "hello"));
+        assert!(!check("h[ae]llo", "hillo"));
+        assert!(check("h[a-c]llo", "hbllo"));
+        assert!(!check("h[a-c]llo", "hdllo"));
+        // Reversed ranges are swapped.
+        assert!(check("h[c-a]llo", "hbllo"));
+        assert!(check("h[^e]llo", "hallo"));
+        assert!(!check("h[^e]llo", "hello"));
+        assert!(!check("h[^a-z]llo", "hbllo"));
+    }
+
+    #[test]
+    fn escapes() {
+        assert!(check("h\\*llo", "h*llo"));
+        assert!(!check("h\\*llo", "hello"));
+        assert!(check("\\?", "?"));
+        assert!(!check("\\?", "a"));
+        assert!(check("[\\]]", "]"));
+        assert!(check("[\\-]", "-"));
+        // A trailing backslash matches itself.
+        assert!(check("a\\", "a\\"));
+    }
+
+    #[test]
+    fn unterminated_class() {
+        // The end of the pattern closes the class.
+        assert!(check("h[ab", "ha"));
+        assert!(check("h[ab", "hb"));
+        assert!(!check("h[ab", "hc"));
+        assert!(!check("h[ab", "hab"));
+    }
+
+    #[test]
+    fn nocase() {
+        assert!(matches(b"HeLLo", b"hello", true));
+        assert!(!matches(b"HeLLo", b"hello", false));
+        assert!(matches(b"h[A-C]llo", b"hbllo", true));
+        assert!(!matches(b"h[A-C]llo", b"hbllo", false));
+        assert!(matches(b"h[E]llo", b"hello", true));
+        assert!(matches(b"H*O", b"hello", true));
+    }
+}
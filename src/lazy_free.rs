@@ -0,0 +1,59 @@
+//! Frees large values on a background thread, like Redis' lazyfree, so that the clients of
+//! the thread that removed them don't wait for their memory to be released.
+
+use std::{
+    sync::{
+        mpsc::{self, Sender},
+        OnceLock,
+    },
+    thread,
+};
+
+type Garbage = Box<dyn Send>;
+
+/// Channel to the lazy-free thread, which is started by the first value freed lazily. None if
+/// the thread could not be started.
+static WORKER: OnceLock<Option<Sender<Garbage>>> = OnceLock::new();
+
+/// Drops `value` on the lazy-free thread, or right away if that thread could not be started.
+pub(crate) fn free_later(value: impl Send + 'static) {
+    let worker = WORKER.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel::<Garbage>();
+        thread::Builder::new()
+            .name("lazy-free".into())
+            .spawn(move || receiver.into_iter().for_each(drop))
+            .ok()
+            .map(|_| sender)
+    });
+    if let Some(worker) = worker {
+        // Sending only fails if the thread is gone, then the value is dropped here.
+        let _ = worker.send(Box::new(value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc::SyncSender, thread::ThreadId};
+
+    use super::*;
+
+    struct Reporter(SyncSender<ThreadId>);
+
+    impl Drop for Reporter {
+        fn drop(&mut self) {
+            self.0.send(thread::current().id()).unwrap();
+        }
+    }
+
+    #[test]
+    fn values_are_dropped_on_one_other_thread() {
+        let (sender, receiver) = mpsc::sync_channel(2);
+        free_later(Reporter(sender.clone()));
+        free_later(Reporter(sender));
+
+        let first = receiver.recv().unwrap();
+        let second = receiver.recv().unwrap();
+        assert_ne!(first, thread::current().id());
+        assert_eq!(first, second);
+    }
+}
@@ -1,10 +1,16 @@
-use std::{thread, sync::Arc, path::{self, Path}};
+use std::{
+    future,
+    path::{self, Path},
+    sync::{Arc, Mutex},
+    task::{Poll, Waker},
+    thread,
+};
 
 use clap::Parser;
 use database::Database;
 use monoio::{
     net::{TcpListener, TcpStream},
-    spawn, fs,
+    spawn, fs, LegacyDriver, RuntimeBuilder,
 };
 
 use anyhow::Context;
@@ -12,11 +18,20 @@ use rdb::read_rdb;
 
 use crate::connection::Connection;
 
+mod blocking;
 mod buf_reader;
 mod connection;
 mod database;
+mod dict;
+mod error;
+mod expiry;
+mod glob;
+mod lazy_free;
 mod protocol;
 mod rdb;
+mod set;
+mod sorted_set;
+mod stream;
 
 #[derive(Parser)]
 struct Cli {
@@ -27,10 +42,36 @@ struct Cli {
     /// RDB storage file name
     #[clap(long)]
     dbfilename: Option<String>,
+
+    /// Number of logical databases
+    #[clap(long, default_value_t = 16)]
+    databases: usize,
+
+    /// Longest bulk string accepted in requests, in bytes
+    #[clap(long, default_value_t = 512 * 1024 * 1024)]
+    proto_max_bulk_len: usize,
+
+    /// Most arguments accepted in a request
+    #[clap(long, default_value_t = i32::MAX as usize)]
+    proto_max_multibulk_len: usize,
+
+    /// Largest request accepted from a client, in bytes
+    #[clap(long, default_value_t = 1024 * 1024 * 1024)]
+    client_query_buffer_limit: usize,
+
+    /// Number of threads serving clients, each with its own runtime and listener
+    #[clap(long, default_value_t = 1)]
+    threads: usize,
 }
 
+const ADDRESS: &str = "127.0.0.1:6379";
+
 async fn handle_connection_spawn(db: Arc<Database>, stream: TcpStream) {
     let addr = stream.peer_addr().unwrap();
+    // Replies are already batched in a single write, waiting for more to send is not useful.
+    if let Err(e) = stream.set_nodelay(true) {
+        println!("Failed to set TCP_NODELAY for {}: {:?}", addr, e);
+    }
     println!("New connection from {} on {:?}", addr, thread::current().id());
 
     let result = Connection::new(db.as_ref(), stream)
@@ -45,7 +86,7 @@ async fn handle_connection_spawn(db: Arc<Database>, stream: TcpStream) {
 
 
 async fn read_db(db: &mut Database, dir: &str, dbfilename: &str) -> anyhow::Result<()> {
-    let path = path::Path::join(&Path::new(dir), &dbfilename);
+    let path = path::Path::join(Path::new(dir), dbfilename);
     let file = match fs::File::open(path).await {
         Ok(file) => file,
         Err(e) => {
@@ -58,7 +99,8 @@ async fn read_db(db: &mut Database, dir: &str, dbfilename: &str) -> anyhow::Resu
         }
     };
 
-    db.swap_datasets(read_rdb(file).await?);
+    let databases = db.dataset_count();
+    db.swap_datasets(read_rdb(file, databases).await?);
 
     Ok(())
 }
@@ -66,12 +108,21 @@ async fn read_db(db: &mut Database, dir: &str, dbfilename: &str) -> anyhow::Resu
 async fn run() -> anyhow::Result<()> {
     let cli = Cli::parse();
 
-    let mut db = Database::new();
+    anyhow::ensure!(cli.databases > 0, "At least one database is required");
+    let mut db = Database::new(cli.databases);
     if let (Some(ref dir), Some(ref dbfilename)) = (&cli.dir, &cli.dbfilename) {
         read_db(&mut db, dir, dbfilename).await?;
     }
     let db = Arc::new(db);
-
+    spawn(expiry::run_active_expiry(db.clone()));
+
+    db.set_config(b"databases", cli.databases.to_string());
+    db.set_config(b"proto-max-bulk-len", cli.proto_max_bulk_len.to_string());
+    db.set_config(b"proto-max-multibulk-len", cli.proto_max_multibulk_len.to_string());
+    db.set_config(
+        b"client-query-buffer-limit",
+        cli.client_query_buffer_limit.to_string(),
+    );
     if let Some(dir) = cli.dir {
         db.set_config(b"dir", dir);
     }
@@ -79,7 +130,74 @@ async fn run() -> anyhow::Result<()> {
         db.set_config(b"dbfilename", dbfilename);
     }
 
-    let listener = TcpListener::bind("127.0.0.1:6379").context("Failed to bind")?;
+    // Each thread accepts connections on its own listener, bound to the same port with
+    // SO_REUSEPORT so the kernel spreads the connections between them. Connections stay on
+    // the thread that accepted them.
+    anyhow::ensure!(cli.threads > 0, "At least one thread is required");
+    let listener = TcpListener::bind(ADDRESS).context("Failed to bind")?;
+    let failure = Arc::new(Failure::default());
+    for i in 1..cli.threads {
+        let db = db.clone();
+        let failure = failure.clone();
+        thread::Builder::new()
+            .name(format!("redder-{}", i))
+            .spawn(move || {
+                if let Err(e) = serve_thread(db) {
+                    failure.report(e);
+                }
+            })
+            .context("Failed to start thread")?;
+    }
+    monoio::select! {
+        result = serve(db, listener) => result,
+        error = failure.wait() => Err(error),
+    }
+}
+
+/// Serves clients on a thread of its own, with its own runtime and listener.
+fn serve_thread(db: Arc<Database>) -> anyhow::Result<()> {
+    let mut runtime = RuntimeBuilder::<LegacyDriver>::new()
+        .enable_timer()
+        .build()
+        .context("Failed to build runtime")?;
+    runtime.block_on(async move {
+        let listener = TcpListener::bind(ADDRESS).context("Failed to bind")?;
+        serve(db, listener).await
+    })
+}
+
+/// The first error that stopped one of the other threads serving clients. The main thread
+/// waits for it to exit with the error.
+#[derive(Default)]
+struct Failure {
+    state: Mutex<(Option<anyhow::Error>, Option<Waker>)>,
+}
+
+impl Failure {
+    fn report(&self, error: anyhow::Error) {
+        let mut state = self.state.lock().unwrap();
+        state.0.get_or_insert(error);
+        if let Some(waker) = state.1.take() {
+            waker.wake();
+        }
+    }
+
+    async fn wait(&self) -> anyhow::Error {
+        future::poll_fn(|cx| {
+            let mut state = self.state.lock().unwrap();
+            match state.0.take() {
+                Some(error) => Poll::Ready(error),
+                None => {
+                    state.1 = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+}
+
+async fn serve(db: Arc<Database>, listener: TcpListener) -> anyhow::Result<()> {
     loop {
         let (stream, _) = listener
             .accept()
@@ -89,7 +207,7 @@ async fn run() -> anyhow::Result<()> {
     }
 }
 
-#[monoio::main(driver = "legacy")]
+#[monoio::main(driver = "legacy", timer_enabled = true)]
 async fn main() {
     run().await.unwrap();
 }
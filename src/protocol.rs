@@ -1,172 +1,467 @@
-use std::io;
+use std::{fmt, io};
 
 use bytes::BytesMut;
-use monoio::{io::{AsyncWriteRent, AsyncWriteRentExt}, buf::IoBuf};
+use monoio::io::{AsyncReadRent, AsyncWriteRent};
 
-use crate::buf_reader::{TcpBufReader, BufReader, BufReaderExt};
+use crate::buf_reader::{BufReader, BufReaderExt, TcpBufReader};
 
-#[derive(Debug)]
-pub(crate) enum RedisValue {
-    String(BytesMut),
-    Array(i64),
+/// Version of the protocol spoken with a client, switched with HELLO.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
 }
 
-pub(crate) trait RedisRead {
-    async fn read_value(&mut self) -> io::Result<RedisValue>;
+impl Protocol {
+    pub(crate) fn version(self) -> i64 {
+        match self {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        }
+    }
+}
+
+/// Longest inline request, or length line of a multibulk request, before giving up on finding
+/// its end.
+const MAX_INLINE_LEN: usize = 64 * 1024;
+
+/// Memory counted toward `client-query-buffer-limit` for each element of an array, on top of
+/// the bytes it was sent as.
+const ELEMENT_OVERHEAD: usize = std::mem::size_of::<RedisValue>();
+
+/// Adds `bytes` to the size of the request read so far, failing once it is over the limit.
+fn add_request_size(size: &mut usize, bytes: usize, limits: &ProtocolLimits) -> io::Result<()> {
+    *size = size.saturating_add(bytes);
+    if *size > limits.query_buffer_limit {
+        return Err(ProtocolError::io("client query buffer limit reached"));
+    }
+    Ok(())
 }
 
-pub(crate) trait RedisReadExt {
-    async fn read_string(&mut self) -> io::Result<BytesMut>;
-    async fn read_array(&mut self) -> io::Result<i64>;
-    async fn read_string_array(&mut self) -> io::Result<Vec<BytesMut>>;
+/// Limits on the requests of clients. Data is only buffered as it arrives, but a request is
+/// kept whole in memory until it is executed.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ProtocolLimits {
+    /// Longest bulk string, `proto-max-bulk-len`.
+    pub(crate) max_bulk_len: usize,
+    /// Most arguments in a request, `proto-max-multibulk-len`.
+    pub(crate) max_multibulk_len: usize,
+    /// Largest request, `client-query-buffer-limit`.
+    pub(crate) query_buffer_limit: usize,
 }
 
-impl<T: RedisRead> RedisReadExt for T {
-    async fn read_string(&mut self) -> io::Result<BytesMut> {
-        let value = self.read_value().await?;
-        match value {
-            RedisValue::String(s) => Ok(s),
-            _ => Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Expected string, got: {:?}", value),
-            )),
+impl Default for ProtocolLimits {
+    fn default() -> Self {
+        Self {
+            max_bulk_len: 512 * 1024 * 1024,
+            max_multibulk_len: i32::MAX as usize,
+            query_buffer_limit: 1024 * 1024 * 1024,
         }
     }
+}
 
-    async fn read_array(&mut self) -> io::Result<i64> {
-        let value = self.read_value().await?;
-        match value {
-            RedisValue::Array(length) => Ok(length),
-            _ => Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Expected array, got: {:?}", value),
-            )),
-        }
+/// Malformed request, replied with `-ERR Protocol error: ...`. The connection is closed after
+/// it, as where the next request starts is unknown.
+#[derive(Debug)]
+pub(crate) struct ProtocolError(String);
+
+impl ProtocolError {
+    fn io(message: impl Into<String>) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, ProtocolError(message.into()))
     }
 
-    async fn read_string_array(&mut self) -> io::Result<Vec<BytesMut>> {
-        let length = self.read_array().await?;
-        if length < 0 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Expected non-negative array length, got: {}", length),
-            ));
-        }
+    /// Finds the protocol error behind an I/O error returned by the reader.
+    pub(crate) fn from_io(e: &io::Error) -> Option<&ProtocolError> {
+        e.get_ref()?.downcast_ref()
+    }
+}
 
-        let mut values: Vec<BytesMut> = Vec::with_capacity(length as usize);
-        for _ in 0..length {
-            values.push(self.read_string().await?);
-        }
-        Ok(values)
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Protocol error: {}", self.0)
     }
 }
 
-trait FooBar {
-    async fn read_line(&mut self) -> io::Result<BytesMut>;
-    async fn parse_line(&mut self) -> io::Result<String>;
-    async fn parse_bulk_string(&mut self) -> io::Result<BytesMut>;
-    async fn parse_int<T>(&mut self) -> io::Result<T>
-    where
-        T: std::str::FromStr;
+impl std::error::Error for ProtocolError {}
+
+/// A RESP2 frame.
+#[derive(Debug, PartialEq)]
+pub(crate) enum RedisValue {
+    SimpleString(BytesMut),
+    Error(BytesMut),
+    Integer(i64),
+    /// None for a null bulk string.
+    BulkString(Option<BytesMut>),
+    /// None for a null array.
+    Array(Option<Vec<RedisValue>>),
+}
+
+pub(crate) trait RedisRead {
+    /// Reads the next frame, of any type. In a `request`, arrays may only hold bulk strings,
+    /// checked before reading each element, and null bulk strings are rejected.
+    async fn read_value(
+        &mut self,
+        request: bool,
+        limits: &ProtocolLimits,
+    ) -> io::Result<RedisValue>;
+    /// Reads the next request, either a multibulk array of bulk strings or an inline command
+    /// line. Returns None if the client disconnects in between requests.
+    async fn read_command(&mut self, limits: &ProtocolLimits) -> io::Result<Option<Vec<BytesMut>>>;
 }
 
-impl<R: BufReader> FooBar for R {
-    async fn read_line(&mut self) -> io::Result<BytesMut> {
-        let mut buf = self.read_until(b"\r\n").await?;
-        buf.truncate(buf.len() - 2);
-        return Ok(buf);
+trait RedisReadExt {
+    async fn read_line(
+        &mut self,
+        delimiter: &[u8],
+        too_big: &str,
+        limits: &ProtocolLimits,
+    ) -> io::Result<BytesMut>;
+    async fn read_length(
+        &mut self,
+        too_big: &str,
+        invalid: &str,
+        max: usize,
+        size: &mut usize,
+        limits: &ProtocolLimits,
+    ) -> io::Result<i64>;
+    async fn read_frame(
+        &mut self,
+        kind: u8,
+        request: bool,
+        size: &mut usize,
+        limits: &ProtocolLimits,
+    ) -> io::Result<RedisValue>;
+    async fn read_inline(&mut self, limits: &ProtocolLimits) -> io::Result<Vec<BytesMut>>;
+}
+
+impl<R: BufReader> RedisReadExt for R {
+    /// Reads up to the delimiter, which is consumed but not returned. The bytes stay in the
+    /// buffer until the whole line is there.
+    async fn read_line(
+        &mut self,
+        delimiter: &[u8],
+        too_big: &str,
+        limits: &ProtocolLimits,
+    ) -> io::Result<BytesMut> {
+        let mut searched = 0;
+        loop {
+            let buffer = self.buffer();
+            let line_len = buffer[searched..]
+                .windows(delimiter.len())
+                .position(|bytes| bytes == delimiter)
+                .map(|pos| searched + pos);
+            if line_len.unwrap_or(buffer.len()) > limits.query_buffer_limit {
+                return Err(ProtocolError::io("client query buffer limit reached"));
+            }
+            if let Some(line_len) = line_len {
+                let mut line = self.buffer_mut().split_to(line_len + delimiter.len());
+                line.truncate(line_len);
+                return Ok(line);
+            }
+            if buffer.len() > MAX_INLINE_LEN {
+                return Err(ProtocolError::io(too_big));
+            }
+            searched = (buffer.len() + 1).saturating_sub(delimiter.len());
+            self.fill_buf().await?;
+        }
     }
 
-    async fn parse_line(&mut self) -> io::Result<String> {
-        let line = self.read_line().await?;
-        Ok(String::from_utf8(line.to_vec()).map_err(|_| {
-            io::Error::new(io::ErrorKind::InvalidData, "Invalid UTF-8 data")
-        })?)
+    /// Reads the length after the type byte of an array or a bulk string.
+    async fn read_length(
+        &mut self,
+        too_big: &str,
+        invalid: &str,
+        max: usize,
+        size: &mut usize,
+        limits: &ProtocolLimits,
+    ) -> io::Result<i64> {
+        let line = self.read_line(b"\r\n", too_big, limits).await?;
+        add_request_size(size, line.len() + 2, limits)?;
+        std::str::from_utf8(&line)
+            .ok()
+            .filter(|line| !line.starts_with('+'))
+            .and_then(|line| line.parse::<i64>().ok())
+            .filter(|&length| length <= max as i64)
+            .ok_or_else(|| ProtocolError::io(invalid))
     }
 
-    async fn parse_bulk_string(&mut self) -> io::Result<BytesMut> {
-        let len: usize = self.parse_int().await?;
-        let line = self.read_bytes(len).await?;
-        if self.read_bytes(2).await?.as_ref() != b"\r\n" {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Expected CRLF at end of bulk string",
-            ));
-        }
+    /// Reads a frame after its type byte. In a request, arrays may only hold bulk strings, and
+    /// there are no null bulk strings. `size` adds up every byte read so far, type bytes
+    /// included, and the memory taken by array elements.
+    async fn read_frame(
+        &mut self,
+        kind: u8,
+        request: bool,
+        size: &mut usize,
+        limits: &ProtocolLimits,
+    ) -> io::Result<RedisValue> {
+        add_request_size(size, 1, limits)?;
+        match kind {
+            b'+' => {
+                let line = self.read_line(b"\r\n", "too big simple string", limits).await?;
+                add_request_size(size, line.len() + 2, limits)?;
+                Ok(RedisValue::SimpleString(line))
+            }
+            b'-' => {
+                let line = self.read_line(b"\r\n", "too big error", limits).await?;
+                add_request_size(size, line.len() + 2, limits)?;
+                Ok(RedisValue::Error(line))
+            }
+            b':' => {
+                let line = self.read_line(b"\r\n", "too big integer", limits).await?;
+                add_request_size(size, line.len() + 2, limits)?;
+                std::str::from_utf8(&line)
+                    .ok()
+                    .and_then(|line| line.parse().ok())
+                    .map(RedisValue::Integer)
+                    .ok_or_else(|| ProtocolError::io("invalid integer"))
+            }
+            b'$' => {
+                let length = self
+                    .read_length(
+                        "too big bulk count string",
+                        "invalid bulk length",
+                        limits.max_bulk_len,
+                        size,
+                        limits,
+                    )
+                    .await?;
+                if length == -1 && !request {
+                    return Ok(RedisValue::BulkString(None));
+                }
+                if length < 0 {
+                    return Err(ProtocolError::io("invalid bulk length"));
+                }
+
+                let length = length as usize;
+                add_request_size(size, length + 2, limits)?;
+                let mut bulk = self.read_bytes(length + 2).await?;
+                bulk.truncate(length);
+                Ok(RedisValue::BulkString(Some(bulk)))
+            }
+            b'*' => {
+                let length = self
+                    .read_length(
+                        "too big mbulk count string",
+                        "invalid multibulk length",
+                        limits.max_multibulk_len,
+                        size,
+                        limits,
+                    )
+                    .await?;
+                // Empty and null requests are skipped like empty lines.
+                if length < 0 && !request {
+                    return Ok(RedisValue::Array(None));
+                }
 
-        Ok(line)
+                // Refuse arrays whose elements alone would not fit, before reading any of them.
+                let length = length.max(0) as usize;
+                let remaining = limits.query_buffer_limit.saturating_sub(*size);
+                if length.saturating_mul(ELEMENT_OVERHEAD) > remaining {
+                    return Err(ProtocolError::io("client query buffer limit reached"));
+                }
+
+                let mut elements = Vec::with_capacity(length.min(1024));
+                for _ in 0..length {
+                    add_request_size(size, ELEMENT_OVERHEAD, limits)?;
+                    let kind = self.read_u8().await?;
+                    if request && kind != b'$' {
+                        return Err(ProtocolError::io(format!(
+                            "expected '$', got '{}'",
+                            kind as char
+                        )));
+                    }
+                    elements.push(Box::pin(self.read_frame(kind, request, size, limits)).await?);
+                }
+                Ok(RedisValue::Array(Some(elements)))
+            }
+            _ => Err(ProtocolError::io(format!(
+                "unknown frame type '{}'",
+                kind as char
+            ))),
+        }
     }
 
-    async fn parse_int<T>(&mut self) -> io::Result<T>
-    where
-        T: std::str::FromStr,
-    {
-        let line = self.parse_line().await?;
-        line.parse::<T>().map_err(|_| {
-            io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Failed to parse integer from line: {}", line),
-            )
-        })
+    async fn read_inline(&mut self, limits: &ProtocolLimits) -> io::Result<Vec<BytesMut>> {
+        let mut line = self
+            .read_line(b"\n", "too big inline request", limits)
+            .await?;
+        if line.last() == Some(&b'\r') {
+            line.truncate(line.len() - 1);
+        }
+        split_inline_args(&line).ok_or_else(|| ProtocolError::io("unbalanced quotes in request"))
     }
 }
 
 impl<R: BufReader> RedisRead for R {
-    async fn read_value(&mut self) -> io::Result<RedisValue> {
-        Ok(match self.read_u8().await? {
-            // Simple string
-            b'+' => RedisValue::String(self.read_line().await?),
-            // Bulk string
-            b'$' => RedisValue::String(self.parse_bulk_string().await?),
-            // Array
-            b'*' => RedisValue::Array(self.parse_int().await?),
-            c => {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("Unknown / invalid value type: {}", c),
-                ))
+    async fn read_value(
+        &mut self,
+        request: bool,
+        limits: &ProtocolLimits,
+    ) -> io::Result<RedisValue> {
+        let kind = self.read_u8().await?;
+        self.read_frame(kind, request, &mut 0, limits).await
+    }
+
+    async fn read_command(&mut self, limits: &ProtocolLimits) -> io::Result<Option<Vec<BytesMut>>> {
+        loop {
+            if self.buffer().is_empty() && self.try_fill_buf().await? == 0 {
+                return Ok(None);
+            }
+
+            let command = if self.buffer()[0] == b'*' {
+                match self.read_value(true, limits).await? {
+                    RedisValue::Array(args) => args
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|arg| match arg {
+                            RedisValue::BulkString(Some(arg)) => arg,
+                            _ => unreachable!("Requests only hold bulk strings"),
+                        })
+                        .collect(),
+                    _ => unreachable!("Requests are arrays"),
+                }
+            } else {
+                self.read_inline(limits).await?
+            };
+            if !command.is_empty() {
+                return Ok(Some(command));
+            }
+        }
+    }
+}
+
+/// Splits an inline command into arguments like redis-cli does. Arguments are separated by
+/// spaces and may be quoted: double quotes support escapes like `\n` and `\x41`, single
+/// quotes only `\'`. Returns None if a quote is not closed, or is followed by more than a
+/// space.
+fn split_inline_args(line: &[u8]) -> Option<Vec<BytesMut>> {
+    let mut args = Vec::new();
+    let mut i = 0;
+    loop {
+        while line.get(i).is_some_and(u8::is_ascii_whitespace) {
+            i += 1;
+        }
+        if i == line.len() {
+            return Some(args);
+        }
+
+        let mut arg = BytesMut::new();
+        match line[i] {
+            quote @ (b'"' | b'\'') => {
+                i += 1;
+                loop {
+                    match (*line.get(i)?, line.get(i + 1).copied()) {
+                        (c, _) if c == quote => break,
+                        (b'\\', Some(b'x')) if quote == b'"' => {
+                            let hex = line.get(i + 2..i + 4).and_then(|hex| {
+                                u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()
+                            });
+                            match hex {
+                                Some(byte) => {
+                                    arg.extend_from_slice(&[byte]);
+                                    i += 3;
+                                }
+                                None => {
+                                    arg.extend_from_slice(b"x");
+                                    i += 1;
+                                }
+                            }
+                        }
+                        (b'\\', Some(c)) if quote == b'"' => {
+                            let c = match c {
+                                b'n' => b'\n',
+                                b'r' => b'\r',
+                                b't' => b'\t',
+                                b'b' => 0x08,
+                                b'a' => 0x07,
+                                c => c,
+                            };
+                            arg.extend_from_slice(&[c]);
+                            i += 1;
+                        }
+                        (b'\\', Some(b'\'')) => {
+                            arg.extend_from_slice(b"'");
+                            i += 1;
+                        }
+                        (c, _) => arg.extend_from_slice(&[c]),
+                    }
+                    i += 1;
+                }
+                // The closing quote must end the argument.
+                i += 1;
+                if line.get(i).is_some_and(|c| !c.is_ascii_whitespace()) {
+                    return None;
+                }
             }
-        })
+            _ => {
+                while let Some(&c) = line.get(i).filter(|c| !c.is_ascii_whitespace()) {
+                    arg.extend_from_slice(&[c]);
+                    i += 1;
+                }
+            }
+        }
+        args.push(arg);
     }
 }
 
+/// Replies are sent once this much output is buffered, even if more commands are pending.
+const OUTPUT_FLUSH_THRESHOLD: usize = 64 * 1024;
+
+/// Writes replies. Types only in RESP3 fall back to their RESP2 equivalent for RESP2 clients,
+/// like a map to a flat array of keys and values.
 pub(crate) trait RedisWrite {
-    async fn write_simple_string<T: IoBuf + 'static>(&mut self, s: T) -> io::Result<()>;
-    async fn write_bulk_string<T: IoBuf + 'static>(&mut self, s: T) -> io::Result<()>;
+    async fn write_simple_string<T: AsRef<[u8]>>(&mut self, s: T) -> io::Result<()>;
+    async fn write_error<T: AsRef<[u8]>>(&mut self, s: T) -> io::Result<()>;
+    async fn write_bulk_string<T: AsRef<[u8]>>(&mut self, s: T) -> io::Result<()>;
     async fn write_null_bulk_string(&mut self) -> io::Result<()>;
-    async fn write_bulk_string_opt<T: IoBuf + 'static>(&mut self, s: Option<T>) -> io::Result<()>;
+    async fn write_bulk_string_opt<T: AsRef<[u8]>>(&mut self, s: Option<T>) -> io::Result<()>;
     async fn write_array(&mut self, size: i64) -> io::Result<()>;
+    async fn write_null_array(&mut self) -> io::Result<()>;
+    async fn write_integer(&mut self, value: i64) -> io::Result<()>;
+    async fn write_double(&mut self, value: f64) -> io::Result<()>;
+    /// Map header, to be followed by `size` keys and values.
+    async fn write_map(&mut self, size: i64) -> io::Result<()>;
+    async fn write_set(&mut self, size: i64) -> io::Result<()>;
+    async fn write_bool(&mut self, value: bool) -> io::Result<()>;
+    /// Integer of arbitrary size, given as its decimal digits.
+    async fn write_big_number<T: AsRef<[u8]>>(&mut self, digits: T) -> io::Result<()>;
+    async fn write_null(&mut self) -> io::Result<()>;
+    /// String with a three letter format, like `txt` or `mkd`, to show it as is.
+    async fn write_verbatim_string<T: AsRef<[u8]>>(
+        &mut self,
+        format: &'static str,
+        s: T,
+    ) -> io::Result<()>;
+    /// Attribute header, to be followed by `size` keys and values and then by the reply they
+    /// describe. RESP2 has no attributes, so RESP2 clients must only get the reply.
+    async fn write_attribute(&mut self, size: i64) -> io::Result<()>;
+    /// Out of band push header, to be followed by `size` elements.
+    async fn write_push(&mut self, size: i64) -> io::Result<()>;
 }
 
-impl<W: AsyncWriteRent> RedisWrite for TcpBufReader<W>
-{
-    async fn write_simple_string<T: IoBuf + 'static>(&mut self, s: T) -> io::Result<()> {
-
-        self.inner.write_all(b"+").await.0?;
-        self.inner.write_all(s).await.0?;
-        self.inner.write_all(b"\r\n").await.0?;
-
-        Ok(())
+impl<W: AsyncReadRent + AsyncWriteRent> RedisWrite for TcpBufReader<W> {
+    async fn write_simple_string<T: AsRef<[u8]>>(&mut self, s: T) -> io::Result<()> {
+        self.write_output(&[b"+", s.as_ref(), b"\r\n"]).await
     }
 
-    async fn write_bulk_string<T: IoBuf + 'static>(&mut self, s: T) -> io::Result<()> {
-        let size = s.bytes_init().to_string().into_bytes();
-
-        self.inner.write_all(b"$").await.0?;
-        self.inner.write_all(size).await.0?;
-        self.inner.write_all(b"\r\n").await.0?;
-        self.inner.write_all(s).await.0?;
-        self.inner.write_all(b"\r\n").await.0?;
+    async fn write_error<T: AsRef<[u8]>>(&mut self, s: T) -> io::Result<()> {
+        self.write_output(&[b"-", s.as_ref(), b"\r\n"]).await
+    }
 
-        Ok(())
+    async fn write_bulk_string<T: AsRef<[u8]>>(&mut self, s: T) -> io::Result<()> {
+        let s = s.as_ref();
+        let size = s.len().to_string();
+        self.write_output(&[b"$", size.as_bytes(), b"\r\n", s, b"\r\n"])
+            .await
     }
 
     async fn write_null_bulk_string(&mut self) -> io::Result<()> {
-        self.inner.write_all(b"$-1\r\n").await.0?;
-        Ok(())
+        self.write_null().await
     }
 
-    async fn write_bulk_string_opt<T: IoBuf + 'static>(&mut self, s: Option<T>) -> io::Result<()> {
+    async fn write_bulk_string_opt<T: AsRef<[u8]>>(&mut self, s: Option<T>) -> io::Result<()> {
         match s {
             Some(s) => self.write_bulk_string(s).await,
             None => self.write_null_bulk_string().await,
@@ -174,12 +469,290 @@ impl<W: AsyncWriteRent> RedisWrite for TcpBufReader<W>
     }
 
     async fn write_array(&mut self, size: i64) -> io::Result<()> {
-        let size = size.to_string().into_bytes();
+        self.write_header(b"*", size).await
+    }
+
+    async fn write_null_array(&mut self) -> io::Result<()> {
+        match self.protocol {
+            Protocol::Resp2 => self.write_output(&[b"*-1\r\n"]).await,
+            Protocol::Resp3 => self.write_output(&[b"_\r\n"]).await,
+        }
+    }
+
+    async fn write_integer(&mut self, value: i64) -> io::Result<()> {
+        self.write_header(b":", value).await
+    }
+
+    async fn write_double(&mut self, value: f64) -> io::Result<()> {
+        let value = format_double(value);
+        match self.protocol {
+            Protocol::Resp2 => self.write_bulk_string(value).await,
+            Protocol::Resp3 => self.write_output(&[b",", value.as_bytes(), b"\r\n"]).await,
+        }
+    }
 
-        self.inner.write_all(b"*").await.0?;
-        self.inner.write_all(size).await.0?;
-        self.inner.write_all(b"\r\n").await.0?;
+    async fn write_map(&mut self, size: i64) -> io::Result<()> {
+        match self.protocol {
+            Protocol::Resp2 => self.write_header(b"*", size * 2).await,
+            Protocol::Resp3 => self.write_header(b"%", size).await,
+        }
+    }
+
+    async fn write_set(&mut self, size: i64) -> io::Result<()> {
+        match self.protocol {
+            Protocol::Resp2 => self.write_header(b"*", size).await,
+            Protocol::Resp3 => self.write_header(b"~", size).await,
+        }
+    }
+
+    async fn write_bool(&mut self, value: bool) -> io::Result<()> {
+        match self.protocol {
+            Protocol::Resp2 => self.write_integer(value as i64).await,
+            Protocol::Resp3 => {
+                let value: &[u8] = if value { b"#t\r\n" } else { b"#f\r\n" };
+                self.write_output(&[value]).await
+            }
+        }
+    }
 
+    async fn write_big_number<T: AsRef<[u8]>>(&mut self, digits: T) -> io::Result<()> {
+        match self.protocol {
+            Protocol::Resp2 => self.write_bulk_string(digits).await,
+            Protocol::Resp3 => self.write_output(&[b"(", digits.as_ref(), b"\r\n"]).await,
+        }
+    }
+
+    async fn write_null(&mut self) -> io::Result<()> {
+        match self.protocol {
+            Protocol::Resp2 => self.write_output(&[b"$-1\r\n"]).await,
+            Protocol::Resp3 => self.write_output(&[b"_\r\n"]).await,
+        }
+    }
+
+    async fn write_verbatim_string<T: AsRef<[u8]>>(
+        &mut self,
+        format: &'static str,
+        s: T,
+    ) -> io::Result<()> {
+        if self.protocol == Protocol::Resp2 {
+            return self.write_bulk_string(s).await;
+        }
+
+        // The format and the colon after it are part of the length.
+        let s = s.as_ref();
+        let size = (s.len() + 4).to_string();
+        self.write_output(&[
+            b"=",
+            size.as_bytes(),
+            b"\r\n",
+            format.as_bytes(),
+            b":",
+            s,
+            b"\r\n",
+        ])
+        .await
+    }
+
+    async fn write_attribute(&mut self, size: i64) -> io::Result<()> {
+        self.write_header(b"|", size).await
+    }
+
+    async fn write_push(&mut self, size: i64) -> io::Result<()> {
+        match self.protocol {
+            Protocol::Resp2 => self.write_header(b"*", size).await,
+            Protocol::Resp3 => self.write_header(b">", size).await,
+        }
+    }
+}
+
+impl<W: AsyncReadRent + AsyncWriteRent> TcpBufReader<W> {
+    /// Adds the parts of a reply to the output buffer. The buffer is only sent once it grows
+    /// past a threshold, or on `flush`.
+    async fn write_output(&mut self, parts: &[&[u8]]) -> io::Result<()> {
+        for part in parts {
+            self.output_mut().extend_from_slice(part);
+        }
+        if !self.hold_output && self.output_mut().len() >= OUTPUT_FLUSH_THRESHOLD {
+            self.flush().await?;
+        }
         Ok(())
     }
+
+    /// Writes a type byte followed by a number, like an array length.
+    async fn write_header(&mut self, kind: &[u8], value: i64) -> io::Result<()> {
+        let value = value.to_string();
+        self.write_output(&[kind, value.as_bytes(), b"\r\n"]).await
+    }
+}
+
+/// Formats a double like Redis replies with scores: the shortest representation that parses
+/// back to the same value, in exponent notation where `%.17g` would use it.
+pub(crate) fn format_double(value: f64) -> String {
+    if value.is_infinite() {
+        return if value > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+
+    let scientific = format!("{:e}", value);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    if !(-4..17).contains(&exponent) {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        return format!("{}e{}{:02}", mantissa, sign, exponent.abs());
+    }
+    format!("{}", value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Most bytes the client sends at once.
+    const CHUNK_LEN: usize = 1000;
+
+    /// Reads requests from `input`, a chunk at a time like from a socket.
+    struct SliceReader<'a> {
+        input: &'a [u8],
+        buffer: BytesMut,
+    }
+
+    impl BufReader for SliceReader<'_> {
+        async fn try_fill_buf(&mut self) -> io::Result<usize> {
+            let (chunk, rest) = self.input.split_at(self.input.len().min(CHUNK_LEN));
+            self.buffer.extend_from_slice(chunk);
+            self.input = rest;
+            Ok(chunk.len())
+        }
+
+        fn buffer(&self) -> &BytesMut {
+            &self.buffer
+        }
+
+        fn buffer_mut(&mut self) -> &mut BytesMut {
+            &mut self.buffer
+        }
+    }
+
+    fn reader(input: &[u8]) -> SliceReader<'_> {
+        SliceReader {
+            input,
+            buffer: BytesMut::new(),
+        }
+    }
+
+    fn bulk(s: &str) -> RedisValue {
+        RedisValue::BulkString(Some(BytesMut::from(s)))
+    }
+
+    #[monoio::test(driver = "legacy")]
+    async fn read_all_frame_types() {
+        let limits = ProtocolLimits::default();
+        let mut reader = reader(
+            b"+OK\r\n-ERR bad\r\n:-42\r\n$5\r\nhello\r\n$-1\r\n*-1\r\n\
+              *3\r\n:1\r\n*1\r\n$0\r\n\r\n$-1\r\n",
+        );
+        let mut values = Vec::new();
+        for _ in 0..7 {
+            values.push(reader.read_value(false, &limits).await.unwrap());
+        }
+        assert_eq!(
+            values,
+            [
+                RedisValue::SimpleString(BytesMut::from("OK")),
+                RedisValue::Error(BytesMut::from("ERR bad")),
+                RedisValue::Integer(-42),
+                bulk("hello"),
+                RedisValue::BulkString(None),
+                RedisValue::Array(None),
+                RedisValue::Array(Some(vec![
+                    RedisValue::Integer(1),
+                    RedisValue::Array(Some(vec![bulk("")])),
+                    RedisValue::BulkString(None),
+                ])),
+            ]
+        );
+        assert!(reader.input.is_empty() && reader.buffer.is_empty());
+    }
+
+    #[monoio::test(driver = "legacy")]
+    async fn read_multibulk_and_inline_requests() {
+        let limits = ProtocolLimits::default();
+        let mut reader = reader(b"*2\r\n$4\r\nECHO\r\n$2\r\nhi\r\n*0\r\n\r\nSET k \"a b\"\r\n");
+        let command = reader.read_command(&limits).await.unwrap().unwrap();
+        assert_eq!(command, ["ECHO", "hi"]);
+        let command = reader.read_command(&limits).await.unwrap().unwrap();
+        assert_eq!(command, ["SET", "k", "a b"]);
+        assert!(reader.read_command(&limits).await.unwrap().is_none());
+    }
+
+    #[monoio::test(driver = "legacy")]
+    async fn request_element_not_bulk_string() {
+        let limits = ProtocolLimits::default();
+        let (error, _) = reject(b"*1\r\n:1\r\n", &limits).await;
+        assert_eq!(error, "Protocol error: expected '$', got ':'");
+        let (error, _) = reject(b"*1\r\n$-1\r\n", &limits).await;
+        assert_eq!(error, "Protocol error: invalid bulk length");
+    }
+
+    /// Reads the first request of `input`, which must be rejected. Returns the error message
+    /// and how much of the input was buffered.
+    async fn reject(input: &[u8], limits: &ProtocolLimits) -> (String, usize) {
+        let mut reader = reader(input);
+        let error = reader.read_command(limits).await.unwrap_err();
+        let message = ProtocolError::from_io(&error).unwrap().to_string();
+        (message, input.len() - reader.input.len())
+    }
+
+    #[monoio::test(driver = "legacy")]
+    async fn bulk_length_over_limit() {
+        let limits = ProtocolLimits::default();
+        let (error, _) = reject(b"*1\r\n$999999999999\r\n", &limits).await;
+        assert_eq!(error, "Protocol error: invalid bulk length");
+    }
+
+    #[monoio::test(driver = "legacy")]
+    async fn multibulk_length_over_limit() {
+        let limits = ProtocolLimits {
+            max_multibulk_len: 1024,
+            ..ProtocolLimits::default()
+        };
+        let (error, _) = reject(b"*1025\r\n", &limits).await;
+        assert_eq!(error, "Protocol error: invalid multibulk length");
+
+        let limits = ProtocolLimits::default();
+        let (error, _) = reject(b"*2147483648\r\n", &limits).await;
+        assert_eq!(error, "Protocol error: invalid multibulk length");
+    }
+
+    #[monoio::test(driver = "legacy")]
+    async fn unterminated_inline_over_query_buffer_limit() {
+        let limits = ProtocolLimits {
+            query_buffer_limit: 1024,
+            ..ProtocolLimits::default()
+        };
+        let input = [b'a'; 64 * 1024];
+        let (error, buffered) = reject(&input, &limits).await;
+        assert_eq!(error, "Protocol error: client query buffer limit reached");
+        assert!(buffered <= 1024 + CHUNK_LEN);
+    }
+
+    #[monoio::test(driver = "legacy")]
+    async fn huge_multibulk_of_empty_bulks() {
+        let limits = ProtocolLimits::default();
+        let mut input = b"*2147483647\r\n".to_vec();
+        input.extend(b"$0\r\n\r\n".repeat(10_000));
+        let (error, buffered) = reject(&input, &limits).await;
+        assert_eq!(error, "Protocol error: client query buffer limit reached");
+        assert!(buffered <= CHUNK_LEN);
+
+        // Framing counts toward the limit even when the element count fits it.
+        let limits = ProtocolLimits {
+            query_buffer_limit: 64 * 1024,
+            ..ProtocolLimits::default()
+        };
+        let count = limits.query_buffer_limit / ELEMENT_OVERHEAD - 1;
+        let mut input = format!("*{count}\r\n").into_bytes();
+        input.extend(b"$0\r\n\r\n".repeat(count));
+        let (error, _) = reject(&input, &limits).await;
+        assert_eq!(error, "Protocol error: client query buffer limit reached");
+    }
 }
@@ -1,6 +1,7 @@
 use std::{
+    collections::{BTreeMap, VecDeque},
     io,
-    time::{Duration, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use bytes::BytesMut;
@@ -9,14 +10,72 @@ use monoio::fs::File;
 use crate::{
     buf_reader::{BufReader, BufReaderExt, FileBufReader},
     database::{Dataset, Value},
+    dict::Dict,
+    set::Set,
+    sorted_set::SortedSet,
+    stream::{Consumer, ConsumerGroup, PendingEntry, Stream, StreamId},
 };
 
+mod crc64;
+mod intset;
+mod listpack;
+mod lzf;
+mod stream_node;
+mod writer;
+mod ziplist;
+mod zipmap;
+
+pub(crate) use writer::save_rdb;
+
+/// Version written by `save_rdb`.
+const RDB_VERSION: u32 = 11;
+/// Newest version `read_rdb` understands.
+const RDB_MAX_READ_VERSION: u32 = 12;
+/// First version with a CRC64 checksum after the EOF opcode.
+const RDB_CHECKSUM_VERSION: u32 = 5;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_HASH_ZIPMAP: u8 = 9;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const TYPE_SET_LISTPACK: u8 = 20;
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+/// Quicklist node holding a single large element instead of a listpack.
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+
+const OPCODE_SLOT_INFO: u8 = 0xF4;
+const OPCODE_MODULE_AUX: u8 = 0xF5;
+const OPCODE_FUNCTION_PRE_GA: u8 = 0xF6;
+const OPCODE_FUNCTION2: u8 = 0xF7;
+const OPCODE_IDLE: u8 = 0xF8;
+const OPCODE_FREQ: u8 = 0xF9;
+const OPCODE_AUX: u8 = 0xFA;
+const OPCODE_RESIZEDB: u8 = 0xFB;
+const OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const OPCODE_EXPIRETIME: u8 = 0xFD;
+const OPCODE_SELECTDB: u8 = 0xFE;
+const OPCODE_EOF: u8 = 0xFF;
+
 const FLAG_BITS: u8 = 0b1100_0000;
 const REMAINING_BITS: u8 = 0b0011_1111;
 
 #[derive(Debug, PartialEq)]
 enum Length {
-    Normal(u32),
+    Normal(u64),
     Special(u8),
 }
 
@@ -26,33 +85,67 @@ async fn read_length<R: BufReader>(reader: &mut R) -> io::Result<Length> {
 
     match flag {
         0b0000_0000 => {
-            return Ok(Length::Normal(first as u32));
+            Ok(Length::Normal(first as u64))
         }
         0b0100_0000 => {
-            let first = (first & REMAINING_BITS) as u32;
-            let second = reader.read_u8().await? as u32;
-            return Ok(Length::Normal(first << 8 | second));
-        }
-        0b1000_0000 => {
-            return Ok(Length::Normal(reader.read_u32().await?));
+            let first = (first & REMAINING_BITS) as u64;
+            let second = reader.read_u8().await? as u64;
+            Ok(Length::Normal(first << 8 | second))
         }
+        0b1000_0000 => match first {
+            0x80 => Ok(Length::Normal(reader.read_u32_be().await? as u64)),
+            0x81 => Ok(Length::Normal(reader.read_u64_be().await?)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid length encoding: {:#x}", first),
+            )),
+        },
         0b1100_0000 => {
-            return Ok(Length::Special(first & REMAINING_BITS));
+            Ok(Length::Special(first & REMAINING_BITS))
         }
         _ => unreachable!(),
     }
 }
 
+/// Decodes a little-endian two's complement integer of 1 to 8 bytes.
+fn le_int(bytes: &[u8]) -> i64 {
+    let mut value: i64 = 0;
+    for (i, byte) in bytes.iter().enumerate() {
+        value |= (*byte as i64) << (8 * i);
+    }
+    // Sign extend from the width of the integer.
+    let shift = 64 - 8 * bytes.len();
+    (value << shift) >> shift
+}
+
+/// Reads a length that is not allowed to use the special string encodings.
+async fn read_plain_length<R: BufReader>(reader: &mut R) -> io::Result<u64> {
+    match read_length(reader).await? {
+        Length::Normal(len) => Ok(len),
+        Length::Special(mode) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unexpected special length encoding: {}", mode),
+        )),
+    }
+}
+
 async fn read_string<R: BufReader>(reader: &mut R) -> io::Result<BytesMut> {
     let length = read_length(reader).await?;
     match length {
         Length::Normal(len) => Ok(reader.read_bytes(len as usize).await?),
         Length::Special(mode) => {
-            // TODO: Byte order?
-            let bytes = match mode {
-                0 => reader.read_bytes(1).await?,
-                1 => reader.read_bytes(2).await?,
-                2 => reader.read_bytes(4).await?,
+            // Integers are stored in little-endian binary form, but are strings for clients.
+            let value = match mode {
+                0 => reader.read_u8().await? as i8 as i64,
+                1 => reader.read_u16().await? as i16 as i64,
+                2 => reader.read_u32().await? as i32 as i64,
+                3 => {
+                    let compressed_len = read_plain_length(reader).await?;
+                    let len = read_plain_length(reader).await?;
+                    let compressed = reader.read_bytes(compressed_len as usize).await?;
+                    let decompressed = lzf::decompress(&compressed, len as usize)?;
+                    return Ok(BytesMut::from(decompressed.as_slice()));
+                }
                 _ => {
                     return Err(io::Error::new(
                         io::ErrorKind::InvalidData,
@@ -60,12 +153,287 @@ async fn read_string<R: BufReader>(reader: &mut R) -> io::Result<BytesMut> {
                     ))
                 }
             };
-            Ok(bytes)
+            Ok(BytesMut::from(value.to_string().as_bytes()))
+        }
+    }
+}
+
+/// Skips a value serialized by a module, which is a sequence of typed opcodes ending in EOF.
+async fn skip_module_value<R: BufReader>(reader: &mut R) -> anyhow::Result<()> {
+    loop {
+        match read_plain_length(reader).await? {
+            // EOF
+            0 => return Ok(()),
+            // Signed and unsigned integers
+            1 | 2 => {
+                read_plain_length(reader).await?;
+            }
+            // Float
+            3 => {
+                reader.read_bytes(4).await?;
+            }
+            // Double
+            4 => {
+                reader.read_bytes(8).await?;
+            }
+            // String
+            5 => {
+                read_string(reader).await?;
+            }
+            opcode => anyhow::bail!("Invalid RDB file, unknown module opcode: {}", opcode),
+        }
+    }
+}
+
+/// Builds a hash from alternating fields and values, as stored in ziplists and listpacks.
+fn hash_from_entries(entries: Vec<Vec<u8>>) -> anyhow::Result<Value> {
+    if !entries.len().is_multiple_of(2) {
+        anyhow::bail!("Invalid RDB file, hash with a field without value");
+    }
+    let mut hash = Dict::new();
+    let mut entries = entries.into_iter();
+    while let (Some(field), Some(value)) = (entries.next(), entries.next()) {
+        hash.insert(field, value);
+    }
+    Ok(Value::Hash(hash))
+}
+
+fn set_from_members(members: Vec<Vec<u8>>) -> Value {
+    let mut set = Set::new();
+    for member in members {
+        set.insert(&member);
+    }
+    Value::Set(set)
+}
+
+/// Builds a sorted set from alternating members and scores, as stored in ziplists and
+/// listpacks.
+fn sorted_set_from_entries(entries: Vec<Vec<u8>>) -> anyhow::Result<Value> {
+    if !entries.len().is_multiple_of(2) {
+        anyhow::bail!("Invalid RDB file, sorted set member without score");
+    }
+    let mut set = SortedSet::new();
+    let mut entries = entries.into_iter();
+    while let (Some(member), Some(score)) = (entries.next(), entries.next()) {
+        set.insert(&member, parse_score(&score)?);
+    }
+    Ok(Value::SortedSet(set))
+}
+
+fn parse_score(score: &[u8]) -> anyhow::Result<f64> {
+    std::str::from_utf8(score)
+        .ok()
+        .and_then(|score| score.parse::<f64>().ok())
+        .filter(|score| !score.is_nan())
+        .ok_or_else(|| anyhow::anyhow!("Invalid RDB file, invalid sorted set score"))
+}
+
+/// Reads a score of the first sorted set encoding, stored as a string with a one byte length.
+async fn read_string_score<R: BufReader>(reader: &mut R) -> anyhow::Result<f64> {
+    match reader.read_u8().await? {
+        253 => anyhow::bail!("Invalid RDB file, invalid sorted set score"),
+        254 => Ok(f64::INFINITY),
+        255 => Ok(f64::NEG_INFINITY),
+        len => parse_score(&reader.read_bytes(len as usize).await?),
+    }
+}
+
+async fn read_stream_id<R: BufReader>(reader: &mut R) -> io::Result<StreamId> {
+    let ms = read_plain_length(reader).await?;
+    let seq = read_plain_length(reader).await?;
+    Ok(StreamId::new(ms, seq))
+}
+
+/// Reads an ID stored in big endian binary form.
+async fn read_raw_stream_id<R: BufReader>(reader: &mut R) -> io::Result<StreamId> {
+    let bytes = reader.read_bytes(16).await?;
+    Ok(StreamId::from_be_bytes(bytes.as_ref().try_into().unwrap()))
+}
+
+async fn read_stream<R: BufReader>(reader: &mut R, value_type: u8) -> anyhow::Result<Value> {
+    let nodes = read_plain_length(reader).await?;
+    let mut entries = BTreeMap::new();
+    for _ in 0..nodes {
+        let key = read_string(reader).await?;
+        let master_id = key
+            .as_ref()
+            .try_into()
+            .map(StreamId::from_be_bytes)
+            .map_err(|_| anyhow::anyhow!("Invalid RDB file, invalid stream node key"))?;
+        stream_node::decode(master_id, &read_string(reader).await?, &mut entries)?;
+    }
+
+    let _len = read_plain_length(reader).await?;
+    let last_id = read_stream_id(reader).await?;
+    let (max_deleted_id, entries_added) = if value_type >= TYPE_STREAM_LISTPACKS_2 {
+        let _first_id = read_stream_id(reader).await?;
+        let max_deleted_id = read_stream_id(reader).await?;
+        (max_deleted_id, read_plain_length(reader).await?)
+    } else {
+        (StreamId::MIN, entries.len() as u64)
+    };
+
+    let mut groups = BTreeMap::new();
+    for _ in 0..read_plain_length(reader).await? {
+        let name = read_string(reader).await?.to_vec();
+        let last_id = read_stream_id(reader).await?;
+        let mut group = ConsumerGroup::new(last_id, None);
+        if value_type >= TYPE_STREAM_LISTPACKS_2 {
+            // Stored as -1 when unknown.
+            let entries_read = read_plain_length(reader).await? as i64;
+            group.entries_read = u64::try_from(entries_read).ok();
+        }
+
+        for _ in 0..read_plain_length(reader).await? {
+            let id = read_raw_stream_id(reader).await?;
+            let delivery_time = reader.read_u64().await?;
+            let delivery_count = read_plain_length(reader).await?;
+            group.pending.insert(
+                id,
+                PendingEntry {
+                    consumer: Vec::new(),
+                    delivery_time,
+                    delivery_count,
+                },
+            );
         }
+
+        for _ in 0..read_plain_length(reader).await? {
+            let name = read_string(reader).await?.to_vec();
+            let mut consumer = Consumer::new(reader.read_u64().await?);
+            if value_type >= TYPE_STREAM_LISTPACKS_3 {
+                consumer.active_time = u64::try_from(reader.read_u64().await? as i64).ok();
+            }
+            for _ in 0..read_plain_length(reader).await? {
+                let id = read_raw_stream_id(reader).await?;
+                let entry = group.pending.get_mut(&id).ok_or_else(|| {
+                    anyhow::anyhow!("Invalid RDB file, consumer entry missing from the group")
+                })?;
+                entry.consumer = name.clone();
+                consumer.pending.insert(id);
+            }
+            group.consumers.insert(name, consumer);
+        }
+        groups.insert(name, group);
     }
+
+    Ok(Value::Stream(Stream::from_parts(
+        entries,
+        last_id,
+        max_deleted_id,
+        entries_added,
+        groups,
+    )))
 }
 
-pub(crate) async fn read_rdb(file: File) -> anyhow::Result<Vec<Dataset>> {
+async fn read_object<R: BufReader>(reader: &mut R, value_type: u8) -> anyhow::Result<Value> {
+    match value_type {
+        TYPE_STRING => Ok(Value::String(read_string(reader).await?.to_vec())),
+        TYPE_LIST => {
+            let len = read_plain_length(reader).await?;
+            let mut list = VecDeque::new();
+            for _ in 0..len {
+                list.push_back(read_string(reader).await?.to_vec());
+            }
+            Ok(Value::List(list))
+        }
+        TYPE_LIST_ZIPLIST => {
+            let ziplist = read_string(reader).await?;
+            Ok(Value::List(ziplist::decode(&ziplist)?.into()))
+        }
+        TYPE_LIST_QUICKLIST | TYPE_LIST_QUICKLIST_2 => {
+            let nodes = read_plain_length(reader).await?;
+            let mut list = VecDeque::new();
+            for _ in 0..nodes {
+                if value_type == TYPE_LIST_QUICKLIST {
+                    list.extend(ziplist::decode(&read_string(reader).await?)?);
+                    continue;
+                }
+
+                let container = read_plain_length(reader).await?;
+                let node = read_string(reader).await?;
+                if container == QUICKLIST_NODE_PLAIN {
+                    list.push_back(node.to_vec());
+                } else {
+                    list.extend(listpack::decode(&node)?);
+                }
+            }
+            Ok(Value::List(list))
+        }
+        TYPE_SET => {
+            let len = read_plain_length(reader).await?;
+            let mut set = Set::new();
+            for _ in 0..len {
+                set.insert(&read_string(reader).await?);
+            }
+            Ok(Value::Set(set))
+        }
+        TYPE_SET_INTSET => Ok(set_from_members(intset::decode(&read_string(reader).await?)?)),
+        TYPE_SET_LISTPACK => Ok(set_from_members(listpack::decode(&read_string(reader).await?)?)),
+        TYPE_HASH => {
+            let len = read_plain_length(reader).await?;
+            let mut hash = Dict::new();
+            for _ in 0..len {
+                let field = read_string(reader).await?.to_vec();
+                let value = read_string(reader).await?.to_vec();
+                hash.insert(field, value);
+            }
+            Ok(Value::Hash(hash))
+        }
+        TYPE_HASH_ZIPMAP => hash_from_entries(zipmap::decode(&read_string(reader).await?)?),
+        TYPE_HASH_ZIPLIST => hash_from_entries(ziplist::decode(&read_string(reader).await?)?),
+        TYPE_HASH_LISTPACK => hash_from_entries(listpack::decode(&read_string(reader).await?)?),
+        TYPE_ZSET | TYPE_ZSET_2 => {
+            let len = read_plain_length(reader).await?;
+            let mut set = SortedSet::new();
+            for _ in 0..len {
+                let member = read_string(reader).await?;
+                let score = if value_type == TYPE_ZSET {
+                    read_string_score(reader).await?
+                } else {
+                    f64::from_bits(reader.read_u64().await?)
+                };
+                set.insert(&member, score);
+            }
+            Ok(Value::SortedSet(set))
+        }
+        TYPE_ZSET_ZIPLIST => {
+            sorted_set_from_entries(ziplist::decode(&read_string(reader).await?)?)
+        }
+        TYPE_ZSET_LISTPACK => {
+            sorted_set_from_entries(listpack::decode(&read_string(reader).await?)?)
+        }
+        TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+            read_stream(reader, value_type).await
+        }
+        _ => anyhow::bail!("Invalid RDB file, unsupported value type: {}", value_type),
+    }
+}
+
+/// Computes the checksum of the first `len` bytes of the file.
+async fn checksum_prefix(file: &File, len: u64) -> io::Result<u64> {
+    const CHUNK: usize = 1024 * 1024;
+
+    let mut crc = 0;
+    let mut pos = 0;
+    let mut buffer = Vec::with_capacity(CHUNK);
+    while pos < len {
+        buffer.clear();
+        let (n, buf) = file.read_at(buffer, pos).await;
+        buffer = buf;
+        let n = n?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Unexpected EOF"));
+        }
+
+        let n = n.min((len - pos) as usize);
+        crc = crc64::crc64(crc, &buffer[..n]);
+        pos += n as u64;
+    }
+    Ok(crc)
+}
+
+pub(crate) async fn read_rdb(file: File, databases: usize) -> anyhow::Result<Vec<Dataset<'static>>> {
     let mut reader = FileBufReader::new(file);
 
     let magic = reader.read_bytes(5).await?;
@@ -75,79 +443,163 @@ pub(crate) async fn read_rdb(file: File) -> anyhow::Result<Vec<Dataset>> {
         magic
     );
 
-    let version = reader.read_bytes(4).await?;
-    anyhow::ensure!(
-        version.as_ref() == b"0003",
-        "Invalid RDB file, invalid version: {:?}, expected 0003.",
-        version
-    );
+    let version_bytes = reader.read_bytes(4).await?;
+    let version = std::str::from_utf8(&version_bytes)
+        .ok()
+        .and_then(|v| v.parse::<u32>().ok())
+        .filter(|v| (1..=RDB_MAX_READ_VERSION).contains(v));
+    let Some(version) = version else {
+        anyhow::bail!(
+            "Invalid RDB file, unsupported version: {:?}, expected 1 to {}.",
+            version_bytes,
+            RDB_MAX_READ_VERSION
+        );
+    };
 
-    let mut datasets = vec![Dataset::new()];
-    let current_db = 0;
+    let mut datasets: Vec<Dataset> = (0..databases).map(|_| Dataset::new()).collect();
+    let mut current_db = 0;
+    let mut expiry: Option<SystemTime> = None;
+    let now = SystemTime::now();
 
     loop {
         let opcode = reader.read_u8().await?;
         match opcode {
-            0x00 => {
-                // String
-                let key = read_string(&mut reader).await?.to_vec().into_boxed_slice();
-                let value = read_string(&mut reader).await?.to_vec();
-
-                datasets[current_db].set(key, Value::String(value));
+            OPCODE_SLOT_INFO => {
+                let _slot_id = read_plain_length(&mut reader).await?;
+                let _slot_size = read_plain_length(&mut reader).await?;
+                let _expires_slot_size = read_plain_length(&mut reader).await?;
+            }
+            OPCODE_MODULE_AUX => {
+                let module_id = read_plain_length(&mut reader).await?;
+                let when_opcode = read_plain_length(&mut reader).await?;
+                let _when = read_plain_length(&mut reader).await?;
+                anyhow::ensure!(
+                    when_opcode == 2,
+                    "Invalid RDB file, bad module aux data for module {:#x}",
+                    module_id
+                );
+                println!("Skipping auxiliary data of module {:#x}", module_id);
+                skip_module_value(&mut reader).await?;
             }
-            0xFA => {
-                // Auxiallary data
+            OPCODE_FUNCTION_PRE_GA => {
+                anyhow::bail!("Pre-release function format is not supported");
+            }
+            OPCODE_FUNCTION2 => {
+                let _library = read_string(&mut reader).await?;
+                println!("Skipping function library, functions are not supported");
+            }
+            OPCODE_IDLE => {
+                let _idle_seconds = read_plain_length(&mut reader).await?;
+            }
+            OPCODE_FREQ => {
+                let _frequency = reader.read_u8().await?;
+            }
+            OPCODE_AUX => {
                 let _key = read_string(&mut reader).await?;
                 let _value = read_string(&mut reader).await?;
             }
-            0xFB => {
-                let _hash_table_size = read_length(&mut reader).await?;
-                let _expiry_hash_table_size = read_length(&mut reader).await?;
+            OPCODE_RESIZEDB => {
+                let _hash_table_size = read_plain_length(&mut reader).await?;
+                let _expiry_hash_table_size = read_plain_length(&mut reader).await?;
             }
-            0xFC => {
-                let expiry = reader.read_u64().await?;
-                anyhow::ensure!(
-                    reader.read_u8().await? == 0,
-                    "Only string values are supported"
-                );
-                let key = read_string(&mut reader).await?.to_vec().into_boxed_slice();
-                let value = read_string(&mut reader).await?.to_vec();
-
-                datasets[current_db]
-                    .set_expiry(key.clone(), UNIX_EPOCH + Duration::from_millis(expiry));
-                datasets[current_db].set(key, Value::String(value));
+            OPCODE_EXPIRETIME_MS => {
+                let ms = reader.read_u64().await?;
+                expiry = Some(UNIX_EPOCH + Duration::from_millis(ms));
             }
-            0xFD => {
-                let expiry = reader.read_u32().await?;
-                anyhow::ensure!(
-                    reader.read_u8().await? == 0,
-                    "Only string values are supported"
-                );
-                let key = read_string(&mut reader).await?.to_vec().into_boxed_slice();
-                let value = read_string(&mut reader).await?.to_vec();
-
-                datasets[current_db]
-                    .set_expiry(key.clone(), UNIX_EPOCH + Duration::from_secs(expiry as u64));
-                datasets[current_db].set(key, Value::String(value));
+            OPCODE_EXPIRETIME => {
+                let secs = reader.read_u32().await?;
+                expiry = Some(UNIX_EPOCH + Duration::from_secs(secs as u64));
             }
-            0xFE => {
-                // Select dataset
-                let db = read_length(&mut reader).await?;
+            OPCODE_SELECTDB => {
+                let db = read_plain_length(&mut reader).await? as usize;
                 anyhow::ensure!(
-                    db == Length::Normal(0),
-                    "Only DB 0 is supported right now, got: {:?}",
-                    db
+                    db < databases,
+                    "RDB file selects DB {}, but only {} databases are configured",
+                    db,
+                    databases
                 );
+                current_db = db;
             }
-            0xFF => {
-                // EOF
+            OPCODE_EOF => {
                 break;
             }
-            _ => {
-                anyhow::bail!("Invalid RDB file, unknown opcode: {}", opcode);
+            value_type => {
+                let key = read_string(&mut reader).await?.to_vec().into_boxed_slice();
+                let value = read_object(&mut reader, value_type).await?;
+
+                // Keys that expired while the server was down are not loaded at all.
+                match expiry.take() {
+                    Some(expiry) if expiry <= now => {}
+                    Some(expiry) => {
+                        datasets[current_db].set(key.clone(), value);
+                        datasets[current_db].set_expiry(key, expiry);
+                    }
+                    None => datasets[current_db].set(key, value),
+                }
             }
         }
     }
 
-    return Ok(datasets);
+    if version >= RDB_CHECKSUM_VERSION {
+        let end = reader.position();
+        let expected = reader.read_u64().await?;
+        // A zero checksum means the file was written with checksums disabled.
+        if expected != 0 {
+            let actual = checksum_prefix(reader.file(), end).await?;
+            anyhow::ensure!(
+                actual == expected,
+                "Invalid RDB file, checksum mismatch: expected {:#x}, got {:#x}",
+                expected,
+                actual
+            );
+        }
+    }
+
+    Ok(datasets)
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    /// Loads an RDB file with the given contents into one database.
+    pub(super) async fn load(name: &str, contents: &[u8]) -> anyhow::Result<Dataset<'static>> {
+        let path = std::env::temp_dir().join(format!("redder-{}-{}.rdb", std::process::id(), name));
+        std::fs::write(&path, contents)?;
+        let result = read_rdb(File::open(&path).await?, 1).await;
+        std::fs::remove_file(&path)?;
+        Ok(result?.pop().unwrap())
+    }
+
+    fn string<'a>(dataset: &'a Dataset, key: &[u8]) -> &'a [u8] {
+        match dataset.get(key) {
+            Some(Value::String(s)) => s,
+            _ => panic!("{:?} is not a string", String::from_utf8_lossy(key)),
+        }
+    }
+
+    #[monoio::test(driver = "legacy")]
+    async fn integer_and_compressed_strings() {
+        let mut contents = b"REDIS0009".to_vec();
+        for (key, value) in [
+            (&b"int8"[..], &[0xC0, 0x85][..]),
+            (b"int16", &[0xC1, 0x39, 0x30]),
+            (b"int32", &[0xC2, 0x00, 0x00, 0x00, 0x80]),
+            (b"lzf", &[0xC3, 0x06, 0x09, 0x02, b'a', b'b', b'c', 0x80, 0x02]),
+        ] {
+            contents.push(TYPE_STRING);
+            contents.push(key.len() as u8);
+            contents.extend_from_slice(key);
+            contents.extend_from_slice(value);
+        }
+        contents.push(OPCODE_EOF);
+        // A zero checksum is not verified.
+        contents.extend_from_slice(&[0; 8]);
+
+        let dataset = load("encoded-strings", &contents).await.unwrap();
+        assert_eq!(string(&dataset, b"int8"), b"-123");
+        assert_eq!(string(&dataset, b"int16"), b"12345");
+        assert_eq!(string(&dataset, b"int32"), b"-2147483648");
+        assert_eq!(string(&dataset, b"lzf"), b"abcabcabc");
+    }
 }
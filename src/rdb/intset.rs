@@ -0,0 +1,51 @@
+//! Decoder for the intset encoding of sets whose members are all integers.
+
+use std::io;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid intset: {}", message),
+    )
+}
+
+/// Returns the members of the intset, formatted as strings.
+pub(crate) fn decode(data: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+    let header = data.get(..8).ok_or_else(|| invalid("truncated header"))?;
+    // Width in bytes of each integer, followed by the number of integers.
+    let width = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let len = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+    if !matches!(width, 2 | 4 | 8) {
+        return Err(invalid("unknown encoding"));
+    }
+    let contents = &data[8..];
+    if contents.len() != width * len {
+        return Err(invalid("length does not match the contents"));
+    }
+
+    Ok(contents
+        .chunks_exact(width)
+        .map(|bytes| {
+            let value = match width {
+                2 => i16::from_le_bytes(bytes.try_into().unwrap()) as i64,
+                4 => i32::from_le_bytes(bytes.try_into().unwrap()) as i64,
+                _ => i64::from_le_bytes(bytes.try_into().unwrap()),
+            };
+            value.to_string().into_bytes()
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_known_intset() {
+        let intset = [2, 0, 0, 0, 3, 0, 0, 0, 0xFE, 0xFF, 0x05, 0x00, 0x2C, 0x01];
+        let members = decode(&intset).unwrap();
+        assert_eq!(members, [&b"-2"[..], b"5", b"300"]);
+
+        assert!(decode(&intset[..intset.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,209 @@
+//! Encoder and decoder for the listpack encoding used by RDB version 10 and newer.
+
+use std::io;
+
+use super::le_int;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid listpack: {}", message))
+}
+
+fn take<'a>(data: &'a [u8], pos: &mut usize, len: usize) -> io::Result<&'a [u8]> {
+    let bytes = data
+        .get(*pos..*pos + len)
+        .ok_or_else(|| invalid("entry runs past the end"))?;
+    *pos += len;
+    Ok(bytes)
+}
+
+/// Size of the trailing "backlen" field for an entry of `len` bytes.
+fn backlen_size(len: usize) -> usize {
+    match len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+/// Returns all entries of the listpack. Integer entries are converted to their decimal form.
+pub(crate) fn decode(data: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+    if data.len() < 7 {
+        return Err(invalid("too short"));
+    }
+    let count = u16::from_le_bytes([data[4], data[5]]) as usize;
+    let mut entries = Vec::with_capacity(count);
+    let mut pos = 6;
+
+    loop {
+        let start = pos;
+        let encoding = *data.get(pos).ok_or_else(|| invalid("missing end marker"))?;
+        pos += 1;
+
+        let entry = if encoding == 0xFF {
+            break;
+        } else if encoding & 0x80 == 0 {
+            // 7 bit unsigned integer
+            (encoding as i64).to_string().into_bytes()
+        } else if encoding & 0xC0 == 0x80 {
+            // String of up to 63 bytes
+            take(data, &mut pos, (encoding & 0x3F) as usize)?.to_vec()
+        } else if encoding & 0xE0 == 0xC0 {
+            // 13 bit signed integer
+            let low = take(data, &mut pos, 1)?[0] as i64;
+            let value = ((encoding & 0x1F) as i64) << 8 | low;
+            let value = if value >= 1 << 12 { value - (1 << 13) } else { value };
+            value.to_string().into_bytes()
+        } else if encoding & 0xF0 == 0xE0 {
+            // String of up to 4095 bytes
+            let low = take(data, &mut pos, 1)?[0] as usize;
+            let len = ((encoding & 0x0F) as usize) << 8 | low;
+            take(data, &mut pos, len)?.to_vec()
+        } else {
+            match encoding {
+                0xF0 => {
+                    let len = take(data, &mut pos, 4)?;
+                    let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+                    take(data, &mut pos, len)?.to_vec()
+                }
+                0xF1 => le_int(take(data, &mut pos, 2)?).to_string().into_bytes(),
+                0xF2 => le_int(take(data, &mut pos, 3)?).to_string().into_bytes(),
+                0xF3 => le_int(take(data, &mut pos, 4)?).to_string().into_bytes(),
+                0xF4 => le_int(take(data, &mut pos, 8)?).to_string().into_bytes(),
+                _ => return Err(invalid("unknown entry encoding")),
+            }
+        };
+
+        let backlen = backlen_size(pos - start);
+        take(data, &mut pos, backlen)?;
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+/// Encodes the entry in the smallest integer encoding if it is an integer in canonical
+/// form, like Redis does.
+fn encode_entry(out: &mut Vec<u8>, entry: &[u8]) {
+    let start = out.len();
+    let int = std::str::from_utf8(entry)
+        .ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .filter(|value| value.to_string().as_bytes() == entry);
+
+    match int {
+        Some(value @ 0..=127) => out.push(value as u8),
+        Some(value @ -4096..=4095) => {
+            let value = value as u16 & 0x1FFF;
+            out.extend_from_slice(&[0xC0 | (value >> 8) as u8, value as u8]);
+        }
+        Some(value) if i16::try_from(value).is_ok() => {
+            out.push(0xF1);
+            out.extend_from_slice(&(value as i16).to_le_bytes());
+        }
+        Some(value) if (-(1 << 23)..1 << 23).contains(&value) => {
+            out.push(0xF2);
+            out.extend_from_slice(&(value as i32).to_le_bytes()[..3]);
+        }
+        Some(value) if i32::try_from(value).is_ok() => {
+            out.push(0xF3);
+            out.extend_from_slice(&(value as i32).to_le_bytes());
+        }
+        Some(value) => {
+            out.push(0xF4);
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        None if entry.len() < 64 => out.push(0x80 | entry.len() as u8),
+        None if entry.len() < 4096 => {
+            out.extend_from_slice(&[0xE0 | (entry.len() >> 8) as u8, entry.len() as u8]);
+        }
+        None => {
+            out.push(0xF0);
+            out.extend_from_slice(&(entry.len() as u32).to_le_bytes());
+        }
+    }
+    if int.is_none() {
+        out.extend_from_slice(entry);
+    }
+
+    // The length of the entry, in 7 bit groups with the most significant first. All but the
+    // first byte have the high bit set, so the length can be read backwards.
+    let len = out.len() - start;
+    let size = backlen_size(len);
+    for i in (0..size).rev() {
+        let group = ((len >> (7 * i)) & 0x7F) as u8;
+        out.push(if i == size - 1 { group } else { group | 0x80 });
+    }
+}
+
+/// Encodes the entries as a listpack.
+pub(crate) fn encode<'a>(entries: impl IntoIterator<Item = &'a [u8]>) -> Vec<u8> {
+    // Total size and number of entries, filled in at the end.
+    let mut out = vec![0; 6];
+    let mut count = 0;
+    for entry in entries {
+        encode_entry(&mut out, entry);
+        count += 1;
+    }
+    out.push(0xFF);
+
+    let total = out.len() as u32;
+    out[..4].copy_from_slice(&total.to_le_bytes());
+    // Counts that do not fit are stored as u16::MAX, meaning unknown.
+    let count = u16::try_from(count).unwrap_or(u16::MAX);
+    out[4..6].copy_from_slice(&count.to_le_bytes());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// "a", 5, -1, 1000 and 100000, each followed by its length.
+    const LISTPACK: [u8; 23] = [
+        23, 0, 0, 0, 5, 0, // Header
+        0x81, b'a', 2, // 6 bit string
+        0x05, 1, // 7 bit unsigned integer
+        0xDF, 0xFF, 2, // 13 bit integer
+        0xC3, 0xE8, 2, // 13 bit integer
+        0xF2, 0xA0, 0x86, 0x01, 4, // 24 bit integer
+        0xFF,
+    ];
+
+    #[test]
+    fn decode_known_listpack() {
+        let entries = decode(&LISTPACK).unwrap();
+        assert_eq!(entries, [&b"a"[..], b"5", b"-1", b"1000", b"100000"]);
+    }
+
+    #[test]
+    fn encode_known_listpack() {
+        let entries: [&[u8]; 5] = [b"a", b"5", b"-1", b"1000", b"100000"];
+        assert_eq!(encode(entries), LISTPACK);
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let long = vec![b'x'; 5000];
+        let entries: Vec<&[u8]> = vec![
+            b"",
+            &long[..100],
+            &long,
+            b"-4097",
+            b"32767",
+            b"-8388608",
+            b"2147483647",
+            b"-9223372036854775808",
+            b"007",
+            b"1.5",
+        ];
+        assert_eq!(decode(&encode(entries.clone())).unwrap(), entries);
+    }
+
+    #[test]
+    fn truncated_listpack() {
+        assert!(decode(&LISTPACK[..LISTPACK.len() - 1]).is_err());
+        assert!(decode(&LISTPACK[..8]).is_err());
+    }
+}
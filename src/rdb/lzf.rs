@@ -0,0 +1,99 @@
+use std::io;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Most bytes a single byte of LZF data expands to: a back reference of 3 bytes copies up to
+/// 264 bytes.
+const MAX_EXPANSION: usize = 88;
+
+/// Decompresses LZF data (as produced by liblzf's `lzf_compress`) of a known decompressed size.
+pub(crate) fn decompress(input: &[u8], expected_len: usize) -> io::Result<Vec<u8>> {
+    // The length comes from the file, so it is only trusted as far as the input could
+    // possibly decompress to it.
+    if expected_len > input.len().saturating_mul(MAX_EXPANSION) {
+        return Err(invalid("LZF data is too short for the expected length"));
+    }
+    let mut output = Vec::with_capacity(expected_len.min(input.len().saturating_mul(8)));
+    let mut i = 0;
+
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+
+        if ctrl < 1 << 5 {
+            // Literal run of ctrl + 1 bytes.
+            let len = ctrl + 1;
+            let literal = input
+                .get(i..i + len)
+                .ok_or_else(|| invalid("LZF literal runs past the end of the input"))?;
+            output.extend_from_slice(literal);
+            i += len;
+        } else {
+            // Back reference. The top 3 bits hold the length, 7 means an extra length byte.
+            let mut len = ctrl >> 5;
+            if len == 7 {
+                len += *input
+                    .get(i)
+                    .ok_or_else(|| invalid("LZF back reference is truncated"))?
+                    as usize;
+                i += 1;
+            }
+            len += 2;
+
+            let low = *input
+                .get(i)
+                .ok_or_else(|| invalid("LZF back reference is truncated"))? as usize;
+            i += 1;
+            let offset = ((ctrl & 0x1f) << 8) + low + 1;
+
+            if offset > output.len() {
+                return Err(invalid("LZF back reference points before the start"));
+            }
+            // The referenced range may overlap the bytes being written, so copy one at a time.
+            let start = output.len() - offset;
+            for j in 0..len {
+                output.push(output[start + j]);
+            }
+        }
+
+        if output.len() > expected_len {
+            return Err(invalid("LZF data decompresses past the expected length"));
+        }
+    }
+
+    if output.len() != expected_len {
+        return Err(invalid("LZF data decompressed to an unexpected length"));
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literals_and_back_references() {
+        // "abc", then 6 bytes from 3 back.
+        let input = [0x02, b'a', b'b', b'c', 0x80, 0x02];
+        assert_eq!(decompress(&input, 9).unwrap(), b"abcabcabc");
+
+        // "a", then 19 bytes from 1 back, with the length in an extra byte.
+        let input = [0x00, b'a', 0xE0, 0x0A, 0x00];
+        assert_eq!(decompress(&input, 20).unwrap(), [b'a'; 20]);
+    }
+
+    #[test]
+    fn invalid_input() {
+        // Back reference before any output.
+        assert!(decompress(&[0x20, 0x00], 3).is_err());
+        // Truncated literal run.
+        assert!(decompress(&[0x02, b'a'], 3).is_err());
+        // Decompresses to fewer or more bytes than expected.
+        assert!(decompress(&[0x00, b'a'], 2).is_err());
+        assert!(decompress(&[0x00, b'a', 0x20, 0x00], 2).is_err());
+        // Longer than the input could ever expand to.
+        assert!(decompress(&[0x00, b'a'], 1 << 20).is_err());
+    }
+}
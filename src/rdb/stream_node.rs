@@ -0,0 +1,189 @@
+//! Encoding of stream entries in listpack nodes, as stored in RDB files.
+//!
+//! A node starts with a master entry: the number of valid and deleted entries and the
+//! field names of the first entry. Each entry then stores its ID as a difference to the
+//! node key, and either all its fields and values or, if its fields are the same as the
+//! master fields, only its values.
+
+use std::{collections::BTreeMap, io};
+
+use crate::stream::{Fields, StreamId};
+
+use super::listpack;
+
+const FLAG_DELETED: i64 = 1;
+const FLAG_SAME_FIELDS: i64 = 2;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid stream node: {}", message),
+    )
+}
+
+/// Reads entries from the decoded listpack.
+struct Reader {
+    entries: std::vec::IntoIter<Vec<u8>>,
+}
+
+impl Reader {
+    fn bytes(&mut self) -> io::Result<Vec<u8>> {
+        self.entries
+            .next()
+            .ok_or_else(|| invalid("entry runs past the end"))
+    }
+
+    fn int(&mut self) -> io::Result<i64> {
+        let bytes = self.bytes()?;
+        std::str::from_utf8(&bytes)
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| invalid("expected an integer"))
+    }
+
+    fn len(&mut self) -> io::Result<usize> {
+        usize::try_from(self.int()?).map_err(|_| invalid("negative count"))
+    }
+}
+
+/// Decodes the node stored under `master_id` into `entries`, skipping deleted entries.
+pub(crate) fn decode(
+    master_id: StreamId,
+    data: &[u8],
+    entries: &mut BTreeMap<StreamId, Fields>,
+) -> io::Result<()> {
+    let mut reader = Reader {
+        entries: listpack::decode(data)?.into_iter(),
+    };
+
+    let count = reader.len()? + reader.len()?;
+    let master_fields = (0..reader.len()?)
+        .map(|_| reader.bytes())
+        .collect::<io::Result<Vec<_>>>()?;
+    // End of the master entry.
+    reader.int()?;
+
+    for _ in 0..count {
+        let flags = reader.int()?;
+        // The differences wrap around like the unsigned arithmetic of Redis.
+        let id = StreamId::new(
+            master_id.ms.wrapping_add(reader.int()? as u64),
+            master_id.seq.wrapping_add(reader.int()? as u64),
+        );
+
+        let mut fields = Fields::new();
+        if flags & FLAG_SAME_FIELDS != 0 {
+            for field in &master_fields {
+                fields.push((field.clone(), reader.bytes()?));
+            }
+        } else {
+            for _ in 0..reader.len()? {
+                fields.push((reader.bytes()?, reader.bytes()?));
+            }
+        }
+        // Number of listpack entries of the entry, used to iterate backwards.
+        reader.int()?;
+
+        if flags & FLAG_DELETED == 0 {
+            entries.insert(id, fields);
+        }
+    }
+    Ok(())
+}
+
+/// Encodes consecutive entries as a node. Returns the node key and the listpack.
+pub(crate) fn encode(entries: &[(&StreamId, &Fields)]) -> ([u8; 16], Vec<u8>) {
+    let (master_id, master_fields) = entries[0];
+    let int = |value: i64| value.to_string().into_bytes();
+
+    let mut items: Vec<Vec<u8>> = vec![
+        int(entries.len() as i64),
+        int(0),
+        int(master_fields.len() as i64),
+    ];
+    items.extend(master_fields.iter().map(|(field, _)| field.clone()));
+    items.push(int(0));
+
+    for (id, fields) in entries {
+        let same_fields = fields.len() == master_fields.len()
+            && fields
+                .iter()
+                .zip(master_fields.iter())
+                .all(|((a, _), (b, _))| a == b);
+
+        items.push(int(if same_fields { FLAG_SAME_FIELDS } else { 0 }));
+        items.push(int(id.ms.wrapping_sub(master_id.ms) as i64));
+        items.push(int(id.seq.wrapping_sub(master_id.seq) as i64));
+        if same_fields {
+            items.extend(fields.iter().map(|(_, value)| value.clone()));
+            items.push(int(fields.len() as i64 + 3));
+        } else {
+            items.push(int(fields.len() as i64));
+            for (field, value) in fields.iter() {
+                items.push(field.clone());
+                items.push(value.clone());
+            }
+            items.push(int(2 * fields.len() as i64 + 4));
+        }
+    }
+
+    (
+        master_id.to_be_bytes(),
+        listpack::encode(items.iter().map(|item| item.as_slice())),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(fields: &[(&str, &str)]) -> Fields {
+        fields
+            .iter()
+            .map(|(field, value)| (field.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn encode_and_decode_node() {
+        let ids = [StreamId::new(1, 1), StreamId::new(1, 2), StreamId::new(2, 0)];
+        let entries = [fields(&[("a", "1")]), fields(&[("a", "2")]), fields(&[("b", "3")])];
+        let node: Vec<_> = ids.iter().zip(entries.iter()).collect();
+
+        let (key, listpack) = encode(&node);
+        assert_eq!(key, ids[0].to_be_bytes());
+        let items = listpack::decode(&listpack).unwrap();
+        let expected: [&[u8]; 22] = [
+            // Master entry: 3 valid and 0 deleted entries, with the field "a".
+            b"3", b"0", b"1", b"a", b"0",
+            // Same fields as the master entry.
+            b"2", b"0", b"0", b"1", b"4",
+            b"2", b"0", b"1", b"2", b"4",
+            // Own fields. The sequence difference wraps around.
+            b"0", b"1", b"-1", b"1", b"b", b"3", b"6",
+        ];
+        assert_eq!(items, expected);
+
+        let mut decoded = BTreeMap::new();
+        decode(ids[0], &listpack, &mut decoded).unwrap();
+        let decoded: Vec<_> = decoded.keys().copied().zip(decoded.values()).collect();
+        let node: Vec<_> = node.into_iter().map(|(id, fields)| (*id, fields)).collect();
+        assert_eq!(decoded, node);
+    }
+
+    #[test]
+    fn deleted_entries_are_skipped() {
+        let listpack = listpack::encode(
+            [
+                "1", "1", "1", "a", "0", // Master entry
+                "3", "0", "0", "1", "4", // Deleted
+                "2", "0", "1", "2", "4",
+            ]
+            .map(str::as_bytes),
+        );
+        let mut entries = BTreeMap::new();
+        decode(StreamId::new(5, 0), &listpack, &mut entries).unwrap();
+        let ids: Vec<_> = entries.keys().copied().collect();
+        assert_eq!(ids, [StreamId::new(5, 1)]);
+    }
+}
@@ -0,0 +1,408 @@
+use std::{
+    fs,
+    io::{self, BufWriter, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    database::{Dataset, Value},
+    stream::{StreamId, NODE_MAX_ENTRIES},
+};
+
+use super::{
+    crc64::crc64, stream_node, OPCODE_AUX, OPCODE_EOF, OPCODE_EXPIRETIME_MS, OPCODE_RESIZEDB, OPCODE_SELECTDB,
+    RDB_VERSION, TYPE_HASH, TYPE_LIST, TYPE_SET, TYPE_STREAM_LISTPACKS_3, TYPE_STRING,
+    TYPE_ZSET_2,
+};
+
+/// Serializes values in the RDB format while keeping a running CRC64 of everything written.
+struct RdbWriter<W: Write> {
+    inner: W,
+    crc: u64,
+}
+
+impl<W: Write> RdbWriter<W> {
+    fn new(inner: W) -> Self {
+        Self { inner, crc: 0 }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.crc = crc64(self.crc, bytes);
+        self.inner.write_all(bytes)
+    }
+
+    fn write_u8(&mut self, value: u8) -> io::Result<()> {
+        self.write_bytes(&[value])
+    }
+
+    fn write_length(&mut self, length: u64) -> io::Result<()> {
+        if length < 1 << 6 {
+            self.write_u8(length as u8)
+        } else if length < 1 << 14 {
+            self.write_bytes(&[0b0100_0000 | (length >> 8) as u8, length as u8])
+        } else if length <= u32::MAX as u64 {
+            self.write_u8(0x80)?;
+            self.write_bytes(&(length as u32).to_be_bytes())
+        } else {
+            self.write_u8(0x81)?;
+            self.write_bytes(&length.to_be_bytes())
+        }
+    }
+
+    fn write_string(&mut self, s: &[u8]) -> io::Result<()> {
+        self.write_length(s.len() as u64)?;
+        self.write_bytes(s)
+    }
+
+    fn write_stream_id(&mut self, id: StreamId) -> io::Result<()> {
+        self.write_length(id.ms)?;
+        self.write_length(id.seq)
+    }
+
+    fn write_aux(&mut self, key: &str, value: &str) -> io::Result<()> {
+        self.write_u8(OPCODE_AUX)?;
+        self.write_string(key.as_bytes())?;
+        self.write_string(value.as_bytes())
+    }
+
+    fn write_entry(&mut self, key: &[u8], value: &Value) -> io::Result<()> {
+        match value {
+            Value::String(s) => {
+                self.write_u8(TYPE_STRING)?;
+                self.write_string(key)?;
+                self.write_string(s)
+            }
+            Value::List(list) => {
+                self.write_u8(TYPE_LIST)?;
+                self.write_string(key)?;
+                self.write_length(list.len() as u64)?;
+                for element in list {
+                    self.write_string(element)?;
+                }
+                Ok(())
+            }
+            Value::Set(set) => {
+                self.write_u8(TYPE_SET)?;
+                self.write_string(key)?;
+                self.write_length(set.len() as u64)?;
+                for member in set.iter() {
+                    self.write_string(&member)?;
+                }
+                Ok(())
+            }
+            Value::Hash(hash) => {
+                self.write_u8(TYPE_HASH)?;
+                self.write_string(key)?;
+                self.write_length(hash.len() as u64)?;
+                for (field, value) in hash.iter() {
+                    self.write_string(field)?;
+                    self.write_string(value)?;
+                }
+                Ok(())
+            }
+            Value::SortedSet(set) => {
+                self.write_u8(TYPE_ZSET_2)?;
+                self.write_string(key)?;
+                self.write_length(set.len() as u64)?;
+                for (member, score) in set.iter() {
+                    self.write_string(member)?;
+                    self.write_bytes(&score.to_le_bytes())?;
+                }
+                Ok(())
+            }
+            Value::Stream(stream) => {
+                self.write_u8(TYPE_STREAM_LISTPACKS_3)?;
+                self.write_string(key)?;
+                let entries: Vec<_> = stream.iter().collect();
+                self.write_length(entries.len().div_ceil(NODE_MAX_ENTRIES) as u64)?;
+                for node in entries.chunks(NODE_MAX_ENTRIES) {
+                    let (master_id, listpack) = stream_node::encode(node);
+                    self.write_string(&master_id)?;
+                    self.write_string(&listpack)?;
+                }
+
+                self.write_length(stream.len() as u64)?;
+                self.write_stream_id(stream.last_id())?;
+                self.write_stream_id(stream.first_id().unwrap_or(StreamId::MIN))?;
+                self.write_stream_id(stream.max_deleted_id())?;
+                self.write_length(stream.entries_added())?;
+
+                self.write_length(stream.groups().len() as u64)?;
+                for (name, group) in stream.groups() {
+                    self.write_string(name)?;
+                    self.write_stream_id(group.last_id)?;
+                    // -1 when unknown.
+                    self.write_length(group.entries_read.unwrap_or(u64::MAX))?;
+
+                    self.write_length(group.pending.len() as u64)?;
+                    for (id, entry) in &group.pending {
+                        self.write_bytes(&id.to_be_bytes())?;
+                        self.write_bytes(&entry.delivery_time.to_le_bytes())?;
+                        self.write_length(entry.delivery_count)?;
+                    }
+
+                    self.write_length(group.consumers.len() as u64)?;
+                    for (name, consumer) in &group.consumers {
+                        self.write_string(name)?;
+                        self.write_bytes(&consumer.seen_time.to_le_bytes())?;
+                        let active_time = consumer.active_time.unwrap_or(u64::MAX);
+                        self.write_bytes(&active_time.to_le_bytes())?;
+                        self.write_length(consumer.pending.len() as u64)?;
+                        for id in &consumer.pending {
+                            self.write_bytes(&id.to_be_bytes())?;
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Writes the EOF opcode and the checksum, returning the underlying writer.
+    fn finish(mut self) -> io::Result<W> {
+        self.write_u8(OPCODE_EOF)?;
+        let crc = self.crc;
+        self.inner.write_all(&crc.to_le_bytes())?;
+        Ok(self.inner)
+    }
+}
+
+/// Serializes the datasets as an RDB file into `out`.
+pub(crate) fn write_rdb<W: Write>(out: W, datasets: &[&Dataset]) -> io::Result<W> {
+    let mut writer = RdbWriter::new(out);
+    let now = SystemTime::now();
+    let ctime = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+    writer.write_bytes(b"REDIS")?;
+    writer.write_bytes(format!("{:04}", RDB_VERSION).as_bytes())?;
+    writer.write_aux("redis-ver", "7.2.0")?;
+    writer.write_aux("redis-bits", "64")?;
+    writer.write_aux("ctime", &ctime.to_string())?;
+
+    for (index, dataset) in datasets.iter().enumerate() {
+        if dataset.len() == 0 {
+            continue;
+        }
+
+        writer.write_u8(OPCODE_SELECTDB)?;
+        writer.write_length(index as u64)?;
+        writer.write_u8(OPCODE_RESIZEDB)?;
+        writer.write_length(dataset.len() as u64)?;
+        writer.write_length(dataset.expires_len() as u64)?;
+
+        for (key, value, expiry) in dataset.iter() {
+            if let Some(expiry) = expiry {
+                if expiry <= now {
+                    continue;
+                }
+                let ms = expiry.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+                writer.write_u8(OPCODE_EXPIRETIME_MS)?;
+                writer.write_bytes(&(ms as u64).to_le_bytes())?;
+            }
+            writer.write_entry(key, value)?;
+        }
+    }
+
+    writer.finish()
+}
+
+/// Writes the datasets to `path`. The dump is first written to a temporary file in the same
+/// directory and then renamed over `path`, so a crash never leaves a partially written dump.
+/// Only one save may run at a time, claimed with `SaveState::start_save`.
+pub(crate) fn save_rdb(path: &Path, datasets: &[&Dataset]) -> io::Result<()> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let temp_path = dir.join(format!("temp-{}.rdb", std::process::id()));
+
+    let result = (|| {
+        let file = fs::File::create(&temp_path)?;
+        let file = write_rdb(BufWriter::new(file), datasets)?
+            .into_inner()
+            .map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, path)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, time::Duration};
+
+    use super::*;
+
+    use crate::{
+        dict::Dict,
+        rdb::tests::load,
+        set::Set,
+        sorted_set::SortedSet,
+        stream::{Fields, Stream},
+    };
+
+    /// Describes the value in a form that does not depend on the order of hash tables.
+    fn describe(value: &Value) -> String {
+        match value {
+            Value::String(s) => format!("string {:?}", s),
+            Value::List(list) => format!("list {:?}", list),
+            Value::Set(set) => {
+                let mut members: Vec<_> = set.iter().map(|member| member.into_owned()).collect();
+                members.sort();
+                format!("set {:?}", members)
+            }
+            Value::Hash(hash) => {
+                let mut entries: Vec<_> = hash.iter().collect();
+                entries.sort();
+                format!("hash {:?}", entries)
+            }
+            Value::SortedSet(set) => format!("zset {:?}", set.iter().collect::<Vec<_>>()),
+            Value::Stream(stream) => {
+                let mut description = format!(
+                    "stream {:?} last {:?} max deleted {:?} added {}",
+                    stream.iter().collect::<Vec<_>>(),
+                    stream.last_id(),
+                    stream.max_deleted_id(),
+                    stream.entries_added(),
+                );
+                for (name, group) in stream.groups() {
+                    description += &format!(
+                        "\ngroup {:?} last {:?} read {:?}",
+                        name, group.last_id, group.entries_read
+                    );
+                    for (id, entry) in &group.pending {
+                        description += &format!(
+                            "\n  pending {:?} {:?} {} {}",
+                            id, entry.consumer, entry.delivery_time, entry.delivery_count
+                        );
+                    }
+                    for (name, consumer) in &group.consumers {
+                        description += &format!(
+                            "\n  consumer {:?} {} {:?} {:?}",
+                            name, consumer.seen_time, consumer.active_time, consumer.pending
+                        );
+                    }
+                }
+                description
+            }
+        }
+    }
+
+    /// Writes the value, reads it back and checks that it did not change.
+    async fn round_trip(name: &str, value: Value) {
+        let mut dataset = Dataset::new();
+        dataset.set(Box::from(&b"key"[..]), value.clone());
+        let contents = write_rdb(Vec::new(), &[&dataset]).unwrap();
+
+        let loaded = load(name, &contents).await.unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(describe(loaded.get(b"key").unwrap()), describe(&value));
+    }
+
+    #[monoio::test(driver = "legacy")]
+    async fn string_round_trip() {
+        round_trip("string", Value::String(b"value".to_vec())).await;
+        round_trip("empty-string", Value::String(Vec::new())).await;
+        round_trip("long-string", Value::String(vec![0xFF; 100_000])).await;
+    }
+
+    #[monoio::test(driver = "legacy")]
+    async fn list_round_trip() {
+        let list: VecDeque<Vec<u8>> = ["a", "", "123", "b"]
+            .iter()
+            .map(|element| element.as_bytes().to_vec())
+            .collect();
+        round_trip("list", Value::List(list)).await;
+    }
+
+    #[monoio::test(driver = "legacy")]
+    async fn set_round_trip() {
+        let mut ints = Set::new();
+        let mut strings = Set::new();
+        for i in -100..100 {
+            ints.insert(i.to_string().as_bytes());
+            strings.insert(format!("member-{}", i).as_bytes());
+        }
+        round_trip("intset", Value::Set(ints)).await;
+        round_trip("set", Value::Set(strings)).await;
+    }
+
+    #[monoio::test(driver = "legacy")]
+    async fn hash_round_trip() {
+        let mut hash = Dict::new();
+        for i in 0..100 {
+            hash.insert(format!("field-{}", i).into_bytes(), i.to_string().into_bytes());
+        }
+        round_trip("hash", Value::Hash(hash)).await;
+    }
+
+    #[monoio::test(driver = "legacy")]
+    async fn sorted_set_round_trip() {
+        let mut set = SortedSet::new();
+        set.insert(b"a", 1.5);
+        set.insert(b"b", -0.1);
+        set.insert(b"c", f64::INFINITY);
+        set.insert(b"d", f64::NEG_INFINITY);
+        set.insert(b"e", 1e300);
+        round_trip("zset", Value::SortedSet(set)).await;
+    }
+
+    #[monoio::test(driver = "legacy")]
+    async fn stream_round_trip() {
+        let mut stream = Stream::new();
+        // Enough entries for several nodes, with fields that change between entries.
+        for i in 0..250u64 {
+            let field = if i % 7 == 0 { "other" } else { "field" };
+            let fields: Fields = vec![(field.into(), i.to_string().into_bytes())];
+            stream.insert(StreamId::new(1000 + i / 3, i % 3), fields);
+        }
+        stream.remove(StreamId::new(1010, 1));
+
+        stream.create_group(b"readers", StreamId::MIN, Some(0));
+        stream.read_group_new(b"readers", b"alice", Some(5), false, 2000);
+        stream.read_group_new(b"readers", b"bob", Some(3), false, 3000);
+        stream.read_group_new(b"readers", b"carol", Some(2), true, 4000);
+        stream
+            .group_mut(b"readers")
+            .unwrap()
+            .acknowledge(StreamId::new(1000, 1));
+        stream.create_group(b"idle", StreamId::new(1020, 0), None);
+
+        round_trip("stream", Value::Stream(stream)).await;
+        round_trip("empty-stream", Value::Stream(Stream::new())).await;
+    }
+
+    #[monoio::test(driver = "legacy")]
+    async fn expiry_round_trip() {
+        // Expiry times are stored in milliseconds.
+        let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        let expiry = UNIX_EPOCH + Duration::from_millis(now_ms + 60_000);
+        let mut dataset = Dataset::new();
+        dataset.set(Box::from(&b"later"[..]), Value::String(b"1".to_vec()));
+        dataset.set_expiry(Box::from(&b"later"[..]), expiry);
+        dataset.set(Box::from(&b"kept"[..]), Value::String(b"2".to_vec()));
+        // Keys that already expired are not written.
+        dataset.set(Box::from(&b"gone"[..]), Value::String(b"3".to_vec()));
+        dataset.set_expiry(Box::from(&b"gone"[..]), UNIX_EPOCH);
+        let contents = write_rdb(Vec::new(), &[&dataset]).unwrap();
+
+        let loaded = load("expiry", &contents).await.unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.get_expiry(b"later"), Some(expiry));
+        assert_eq!(loaded.get_expiry(b"kept"), None);
+    }
+
+    #[monoio::test(driver = "legacy")]
+    async fn checksum_mismatch() {
+        let mut dataset = Dataset::new();
+        dataset.set(Box::from(&b"key"[..]), Value::String(b"value".to_vec()));
+        let mut contents = write_rdb(Vec::new(), &[&dataset]).unwrap();
+        // Change the last byte of the value, before the EOF opcode and the checksum.
+        let value_pos = contents.len() - 10;
+        contents[value_pos] ^= 1;
+
+        assert!(load("checksum", &contents).await.is_err());
+    }
+}
@@ -0,0 +1,90 @@
+//! Decoder for the ziplist encoding used by RDB versions before 10.
+
+use std::io;
+
+use super::le_int;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid ziplist: {}", message))
+}
+
+fn take<'a>(data: &'a [u8], pos: &mut usize, len: usize) -> io::Result<&'a [u8]> {
+    let bytes = data
+        .get(*pos..*pos + len)
+        .ok_or_else(|| invalid("entry runs past the end"))?;
+    *pos += len;
+    Ok(bytes)
+}
+
+/// Returns all entries of the ziplist. Integer entries are converted to their decimal form.
+pub(crate) fn decode(data: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+    if data.len() < 11 {
+        return Err(invalid("too short"));
+    }
+    let count = u16::from_le_bytes([data[8], data[9]]) as usize;
+    let mut entries = Vec::with_capacity(count);
+    let mut pos = 10;
+
+    loop {
+        let first = *data.get(pos).ok_or_else(|| invalid("missing end marker"))?;
+        if first == 0xFF {
+            break;
+        }
+
+        // Length of the previous entry, only needed for backwards traversal.
+        let prev_len_size = if first == 0xFE { 5 } else { 1 };
+        take(data, &mut pos, prev_len_size)?;
+
+        let encoding = take(data, &mut pos, 1)?[0];
+        let entry = match encoding >> 6 {
+            0b00 => take(data, &mut pos, (encoding & 0x3F) as usize)?.to_vec(),
+            0b01 => {
+                let low = take(data, &mut pos, 1)?[0] as usize;
+                let len = ((encoding & 0x3F) as usize) << 8 | low;
+                take(data, &mut pos, len)?.to_vec()
+            }
+            0b10 => {
+                let len = take(data, &mut pos, 4)?;
+                let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+                take(data, &mut pos, len)?.to_vec()
+            }
+            _ => {
+                let value = match encoding {
+                    0xC0 => le_int(take(data, &mut pos, 2)?),
+                    0xD0 => le_int(take(data, &mut pos, 4)?),
+                    0xE0 => le_int(take(data, &mut pos, 8)?),
+                    0xF0 => le_int(take(data, &mut pos, 3)?),
+                    0xFE => le_int(take(data, &mut pos, 1)?),
+                    0xF1..=0xFD => (encoding & 0x0F) as i64 - 1,
+                    _ => return Err(invalid("unknown entry encoding")),
+                };
+                value.to_string().into_bytes()
+            }
+        };
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_known_ziplist() {
+        let ziplist = [
+            23, 0, 0, 0, 19, 0, 0, 0, 4, 0, // Header
+            0x00, 0x01, b'a', // 6 bit string
+            0x03, 0xFD, // 4 bit integer
+            0x02, 0xC0, 0xE8, 0x03, // 16 bit integer
+            0x04, 0xFE, 0xFB, // 8 bit integer
+            0xFF,
+        ];
+        let entries = decode(&ziplist).unwrap();
+        assert_eq!(entries, [&b"a"[..], b"12", b"1000", b"-5"]);
+
+        assert!(decode(&ziplist[..ziplist.len() - 1]).is_err());
+        assert!(decode(&ziplist[..12]).is_err());
+    }
+}
@@ -0,0 +1,63 @@
+//! Decoder for the zipmap encoding of small hashes, used by RDB files before version 4.
+
+use std::io;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid zipmap: {}", message),
+    )
+}
+
+fn take<'a>(data: &'a [u8], pos: &mut usize, len: usize) -> io::Result<&'a [u8]> {
+    let bytes = data
+        .get(*pos..*pos + len)
+        .ok_or_else(|| invalid("entry runs past the end"))?;
+    *pos += len;
+    Ok(bytes)
+}
+
+/// Reads a length, returning None at the end marker.
+fn read_len(data: &[u8], pos: &mut usize) -> io::Result<Option<usize>> {
+    match take(data, pos, 1)?[0] {
+        0xFF => Ok(None),
+        0xFE => {
+            let len = take(data, pos, 4)?;
+            Ok(Some(u32::from_le_bytes(len.try_into().unwrap()) as usize))
+        }
+        len => Ok(Some(len as usize)),
+    }
+}
+
+/// Returns the fields and values of the zipmap, alternating.
+pub(crate) fn decode(data: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+    let mut entries = Vec::new();
+    // The first byte is the number of entries, only valid below 254.
+    let mut pos = 1;
+
+    while let Some(len) = read_len(data, &mut pos)? {
+        entries.push(take(data, &mut pos, len)?.to_vec());
+
+        let len = read_len(data, &mut pos)?.ok_or_else(|| invalid("field without value"))?;
+        // Unused bytes left after the value when it was updated in place.
+        let free = take(data, &mut pos, 1)?[0] as usize;
+        entries.push(take(data, &mut pos, len)?.to_vec());
+        take(data, &mut pos, free)?;
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_known_zipmap() {
+        // The value is followed by 2 free bytes.
+        let zipmap = [1, 1, b'f', 3, 2, b'v', b'a', b'l', 0, 0, 0xFF];
+        assert_eq!(decode(&zipmap).unwrap(), [&b"f"[..], b"val"]);
+
+        assert!(decode(&zipmap[..zipmap.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,163 @@
+use std::{borrow::Cow, collections::HashSet};
+
+use rand::{seq::index, Rng};
+
+use crate::dict::Dict;
+
+/// Largest set kept as an intset, like Redis' default `set-max-intset-entries`.
+const MAX_INTSET_ENTRIES: usize = 512;
+
+/// Unordered set of unique members.
+#[derive(Clone)]
+pub(crate) enum Set {
+    /// Sorted integers, used while all members are integers in canonical decimal form and
+    /// the set is small. Uses much less memory than a hash table.
+    IntSet(Vec<i64>),
+    Dict(Dict<Vec<u8>, ()>),
+}
+
+/// Parses a member that can be stored in an intset, i.e. one that formats back to the
+/// same bytes.
+fn as_int(member: &[u8]) -> Option<i64> {
+    let value: i64 = std::str::from_utf8(member).ok()?.parse().ok()?;
+    (value.to_string().as_bytes() == member).then_some(value)
+}
+
+impl Set {
+    pub(crate) fn new() -> Self {
+        Set::IntSet(Vec::new())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Set::IntSet(ints) => ints.len(),
+            Set::Dict(dict) => dict.len(),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub(crate) fn contains(&self, member: &[u8]) -> bool {
+        match self {
+            Set::IntSet(ints) => as_int(member).is_some_and(|int| ints.binary_search(&int).is_ok()),
+            Set::Dict(dict) => dict.contains_key(member),
+        }
+    }
+
+    /// Converts an intset to a hash table.
+    fn convert_to_dict(&mut self) -> &mut Dict<Vec<u8>, ()> {
+        if let Set::IntSet(ints) = self {
+            let mut dict = Dict::new();
+            for int in ints.iter() {
+                dict.insert(int.to_string().into_bytes(), ());
+            }
+            *self = Set::Dict(dict);
+        }
+        match self {
+            Set::Dict(dict) => dict,
+            Set::IntSet(_) => unreachable!(),
+        }
+    }
+
+    /// Adds the member. Returns false if it was already in the set.
+    pub(crate) fn insert(&mut self, member: &[u8]) -> bool {
+        if let Set::IntSet(ints) = self {
+            if let Some(int) = as_int(member) {
+                match ints.binary_search(&int) {
+                    Ok(_) => return false,
+                    Err(position) if ints.len() < MAX_INTSET_ENTRIES => {
+                        ints.insert(position, int);
+                        return true;
+                    }
+                    Err(_) => {}
+                }
+            }
+        }
+        self.convert_to_dict().insert(member.to_vec(), ()).is_none()
+    }
+
+    pub(crate) fn remove(&mut self, member: &[u8]) -> bool {
+        match self {
+            Set::IntSet(ints) => {
+                let Some(int) = as_int(member) else {
+                    return false;
+                };
+                match ints.binary_search(&int) {
+                    Ok(position) => {
+                        ints.remove(position);
+                        true
+                    }
+                    Err(_) => false,
+                }
+            }
+            Set::Dict(dict) => dict.remove(member).is_some(),
+        }
+    }
+
+    pub(crate) fn iter(&self) -> Box<dyn Iterator<Item = Cow<'_, [u8]>> + '_> {
+        match self {
+            Set::IntSet(ints) => Box::new(
+                ints.iter()
+                    .map(|int| Cow::Owned(int.to_string().into_bytes())),
+            ),
+            Set::Dict(dict) => Box::new(dict.iter().map(|(member, _)| Cow::Borrowed(&member[..]))),
+        }
+    }
+
+    /// Returns a random member, None if the set is empty.
+    pub(crate) fn random_member(&self, rng: &mut impl Rng) -> Option<Vec<u8>> {
+        match self {
+            Set::IntSet(ints) if ints.is_empty() => None,
+            Set::IntSet(ints) => Some(ints[rng.gen_range(0..ints.len())].to_string().into_bytes()),
+            Set::Dict(dict) => dict.random_entry(rng).map(|(member, _)| member.clone()),
+        }
+    }
+
+    /// Returns up to `count` distinct random members, or all members if the set is smaller.
+    pub(crate) fn random_distinct(&self, rng: &mut impl Rng, count: usize) -> Vec<Vec<u8>> {
+        if count >= self.len() {
+            return self.iter().map(Cow::into_owned).collect();
+        }
+        match self {
+            Set::IntSet(ints) => index::sample(rng, ints.len(), count)
+                .into_iter()
+                .map(|i| ints[i].to_string().into_bytes())
+                .collect(),
+            // Like Redis, when most of the members are returned, they are sampled from all of
+            // them.
+            Set::Dict(dict) if count * 3 > dict.len() => {
+                let members: Vec<&Vec<u8>> = dict.iter().map(|(member, _)| member).collect();
+                index::sample(rng, members.len(), count)
+                    .into_iter()
+                    .map(|i| members[i].clone())
+                    .collect()
+            }
+            // Otherwise random members are picked until there are enough different ones, which
+            // takes few attempts as most members are not picked yet.
+            Set::Dict(dict) => {
+                let mut picked = HashSet::new();
+                while picked.len() < count {
+                    let (member, _) = dict.random_entry(rng).unwrap();
+                    picked.insert(member);
+                }
+                picked.into_iter().cloned().collect()
+            }
+        }
+    }
+
+    /// Visits some members and returns the cursor to continue from, 0 when done. See
+    /// `Dict::scan`. Intsets are small, so they are visited in a single call.
+    pub(crate) fn scan(&self, cursor: u64, mut visit: impl FnMut(&[u8])) -> u64 {
+        match self {
+            Set::IntSet(ints) => {
+                for int in ints {
+                    visit(int.to_string().as_bytes());
+                }
+                0
+            }
+            Set::Dict(dict) => dict.scan(cursor, |member, _| visit(member)),
+        }
+    }
+}
@@ -0,0 +1,379 @@
+use rand::Rng;
+
+use crate::dict::Dict;
+
+const MAX_LEVEL: usize = 32;
+/// Probability of a node having each additional level.
+const LEVEL_PROBABILITY: f64 = 0.25;
+/// Index of the header node, which holds no element.
+const HEADER: usize = 0;
+
+#[derive(Clone)]
+struct Level {
+    forward: Option<usize>,
+    /// Number of elements skipped when following `forward`, or to the end of the list.
+    span: usize,
+}
+
+#[derive(Clone)]
+struct Node {
+    member: Vec<u8>,
+    score: f64,
+    backward: Option<usize>,
+    levels: Vec<Level>,
+}
+
+impl Node {
+    /// Returns true if the node sorts before the element.
+    fn precedes(&self, score: f64, member: &[u8]) -> bool {
+        self.score < score || (self.score == score && self.member.as_slice() < member)
+    }
+}
+
+/// Skiplist ordered by score, then member, with spans to support rank operations in
+/// O(log n) as in Redis' `zskiplist`. Nodes are stored in an arena and refer to each other
+/// by index.
+#[derive(Clone)]
+struct SkipList {
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    tail: Option<usize>,
+    len: usize,
+    level: usize,
+}
+
+impl SkipList {
+    fn new() -> Self {
+        let header = Node {
+            member: Vec::new(),
+            score: 0.0,
+            backward: None,
+            levels: vec![
+                Level {
+                    forward: None,
+                    span: 0
+                };
+                MAX_LEVEL
+            ],
+        };
+        Self {
+            nodes: vec![header],
+            free: Vec::new(),
+            tail: None,
+            len: 0,
+            level: 1,
+        }
+    }
+
+    fn random_level() -> usize {
+        let mut rng = rand::thread_rng();
+        let mut level = 1;
+        while level < MAX_LEVEL && rng.gen_bool(LEVEL_PROBABILITY) {
+            level += 1;
+        }
+        level
+    }
+
+    fn forward(&self, node: usize, level: usize) -> Option<usize> {
+        self.nodes[node].levels[level].forward
+    }
+
+    fn span(&self, node: usize, level: usize) -> usize {
+        self.nodes[node].levels[level].span
+    }
+
+    /// Finds the last node before the element on each level.
+    fn find_predecessors(&self, score: f64, member: &[u8]) -> [usize; MAX_LEVEL] {
+        let mut update = [HEADER; MAX_LEVEL];
+        let mut x = HEADER;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.forward(x, i) {
+                if !self.nodes[next].precedes(score, member) {
+                    break;
+                }
+                x = next;
+            }
+            update[i] = x;
+        }
+        update
+    }
+
+    /// Inserts an element, which must not be in the list yet.
+    fn insert(&mut self, score: f64, member: Vec<u8>) {
+        let mut update = [HEADER; MAX_LEVEL];
+        let mut rank = [0; MAX_LEVEL];
+        let mut x = HEADER;
+        for i in (0..self.level).rev() {
+            rank[i] = if i == self.level - 1 { 0 } else { rank[i + 1] };
+            while let Some(next) = self.forward(x, i) {
+                if !self.nodes[next].precedes(score, &member) {
+                    break;
+                }
+                rank[i] += self.span(x, i);
+                x = next;
+            }
+            update[i] = x;
+        }
+
+        let level = Self::random_level();
+        if level > self.level {
+            for i in self.level..level {
+                rank[i] = 0;
+                update[i] = HEADER;
+                self.nodes[HEADER].levels[i].span = self.len;
+            }
+            self.level = level;
+        }
+
+        let node = Node {
+            member,
+            score,
+            backward: (update[0] != HEADER).then_some(update[0]),
+            levels: vec![
+                Level {
+                    forward: None,
+                    span: 0
+                };
+                level
+            ],
+        };
+        let x = match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = node;
+                index
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+
+        for i in 0..level {
+            let prev = update[i];
+            let prev_span = self.span(prev, i);
+            self.nodes[x].levels[i] = Level {
+                forward: self.forward(prev, i),
+                span: prev_span - (rank[0] - rank[i]),
+            };
+            self.nodes[prev].levels[i] = Level {
+                forward: Some(x),
+                span: rank[0] - rank[i] + 1,
+            };
+        }
+        for (i, &prev) in update.iter().enumerate().take(self.level).skip(level) {
+            self.nodes[prev].levels[i].span += 1;
+        }
+
+        match self.forward(x, 0) {
+            Some(next) => self.nodes[next].backward = Some(x),
+            None => self.tail = Some(x),
+        }
+        self.len += 1;
+    }
+
+    /// Removes an element. Returns false if it was not found.
+    fn remove(&mut self, score: f64, member: &[u8]) -> bool {
+        let update = self.find_predecessors(score, member);
+        let Some(x) = self.forward(update[0], 0) else {
+            return false;
+        };
+        if self.nodes[x].score != score || self.nodes[x].member != member {
+            return false;
+        }
+
+        for (i, &prev) in update.iter().enumerate().take(self.level) {
+            if self.forward(prev, i) == Some(x) {
+                self.nodes[prev].levels[i] = Level {
+                    forward: self.forward(x, i),
+                    span: self.span(prev, i) + self.span(x, i) - 1,
+                };
+            } else {
+                self.nodes[prev].levels[i].span -= 1;
+            }
+        }
+        match self.forward(x, 0) {
+            Some(next) => self.nodes[next].backward = self.nodes[x].backward,
+            None => self.tail = self.nodes[x].backward,
+        }
+        while self.level > 1 && self.forward(HEADER, self.level - 1).is_none() {
+            self.level -= 1;
+        }
+
+        self.nodes[x].member = Vec::new();
+        self.nodes[x].levels = Vec::new();
+        self.free.push(x);
+        self.len -= 1;
+        true
+    }
+
+    /// Returns the 0-based rank of the element.
+    fn rank(&self, score: f64, member: &[u8]) -> Option<usize> {
+        let mut rank = 0;
+        let mut x = HEADER;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.forward(x, i) {
+                let node = &self.nodes[next];
+                if !(node.precedes(score, member) || node.member == member) {
+                    break;
+                }
+                rank += self.span(x, i);
+                x = next;
+            }
+            if x != HEADER && self.nodes[x].member == member {
+                return Some(rank - 1);
+            }
+        }
+        None
+    }
+
+    /// Returns the number of leading elements for which `before` is true. `before` must be
+    /// true for a prefix of the list and false for the rest.
+    fn count_while(&self, before: impl Fn(f64, &[u8]) -> bool) -> usize {
+        let mut count = 0;
+        let mut x = HEADER;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.forward(x, i) {
+                if !before(self.nodes[next].score, &self.nodes[next].member) {
+                    break;
+                }
+                count += self.span(x, i);
+                x = next;
+            }
+        }
+        count
+    }
+
+    /// Returns the node at the 0-based rank.
+    fn node_by_rank(&self, rank: usize) -> Option<usize> {
+        let target = rank + 1;
+        let mut traversed = 0;
+        let mut x = HEADER;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.forward(x, i) {
+                if traversed + self.span(x, i) > target {
+                    break;
+                }
+                traversed += self.span(x, i);
+                x = next;
+            }
+            if traversed == target {
+                return Some(x);
+            }
+        }
+        None
+    }
+}
+
+/// Elements ordered by score, then by member. Each member appears once.
+#[derive(Clone)]
+pub(crate) struct SortedSet {
+    scores: Dict<Vec<u8>, f64>,
+    list: SkipList,
+}
+
+impl SortedSet {
+    pub(crate) fn new() -> Self {
+        Self {
+            scores: Dict::new(),
+            list: SkipList::new(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    /// Visits the members of one bucket of the score table, like `Dict::scan`.
+    pub(crate) fn scan(&self, cursor: u64, mut visit: impl FnMut(&[u8], f64)) -> u64 {
+        self.scores
+            .scan(cursor, |member, score| visit(member, *score))
+    }
+
+    pub(crate) fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Adds the member or updates its score. Returns true if the member is new.
+    pub(crate) fn insert(&mut self, member: &[u8], score: f64) -> bool {
+        match self.scores.get(member).copied() {
+            Some(old) => {
+                if old != score {
+                    self.list.remove(old, member);
+                    self.list.insert(score, member.to_vec());
+                    self.scores.insert(member.to_vec(), score);
+                }
+                false
+            }
+            None => {
+                self.list.insert(score, member.to_vec());
+                self.scores.insert(member.to_vec(), score);
+                true
+            }
+        }
+    }
+
+    pub(crate) fn remove(&mut self, member: &[u8]) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => self.list.remove(score, member),
+            None => false,
+        }
+    }
+
+    /// Returns the 0-based rank of the member, in ascending order.
+    pub(crate) fn rank(&self, member: &[u8]) -> Option<usize> {
+        let score = self.score(member)?;
+        self.list.rank(score, member)
+    }
+
+    /// Returns the number of lowest ranked elements for which `before(score, member)` is
+    /// true, e.g. the rank of the first element within a score range. `before` must be true
+    /// for a prefix of the elements in order and false for the rest.
+    pub(crate) fn count_while(&self, before: impl Fn(f64, &[u8]) -> bool) -> usize {
+        self.list.count_while(before)
+    }
+
+    /// Iterates in ascending order, starting at the 0-based rank.
+    pub(crate) fn iter_from(&self, rank: usize) -> impl Iterator<Item = (&[u8], f64)> {
+        let list = &self.list;
+        let mut next = list.node_by_rank(rank);
+        std::iter::from_fn(move || {
+            let node = &list.nodes[next?];
+            next = node.levels[0].forward;
+            Some((node.member.as_slice(), node.score))
+        })
+    }
+
+    /// Iterates in descending order, starting at the 0-based ascending rank.
+    pub(crate) fn iter_rev_from(&self, rank: usize) -> impl Iterator<Item = (&[u8], f64)> {
+        let list = &self.list;
+        let mut next = list.node_by_rank(rank);
+        std::iter::from_fn(move || {
+            let node = &list.nodes[next?];
+            next = node.backward;
+            Some((node.member.as_slice(), node.score))
+        })
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&[u8], f64)> {
+        self.iter_from(0)
+    }
+
+    /// Removes and returns the element with the lowest score, or the highest with `last`.
+    pub(crate) fn pop(&mut self, last: bool) -> Option<(Vec<u8>, f64)> {
+        let node = if last {
+            self.list.tail?
+        } else {
+            self.list.forward(HEADER, 0)?
+        };
+        let (member, score) = {
+            let node = &self.list.nodes[node];
+            (node.member.clone(), node.score)
+        };
+        self.remove(&member);
+        Some((member, score))
+    }
+}
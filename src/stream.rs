@@ -0,0 +1,520 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    ops::RangeInclusive,
+};
+
+/// Maximum number of entries in a node of a Redis stream, `stream-node-max-entries`.
+/// Entries are not stored in nodes here, but approximate trimming and RDB files still
+/// work in units of nodes.
+pub(crate) const NODE_MAX_ENTRIES: usize = 100;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) struct StreamId {
+    pub(crate) ms: u64,
+    pub(crate) seq: u64,
+}
+
+impl StreamId {
+    pub(crate) const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub(crate) const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    pub(crate) fn new(ms: u64, seq: u64) -> Self {
+        Self { ms, seq }
+    }
+
+    /// The smallest ID greater than this one.
+    pub(crate) fn next(self) -> Option<StreamId> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(StreamId::new(self.ms, seq)),
+            None => Some(StreamId::new(self.ms.checked_add(1)?, 0)),
+        }
+    }
+
+    /// The greatest ID smaller than this one.
+    pub(crate) fn prev(self) -> Option<StreamId> {
+        match self.seq.checked_sub(1) {
+            Some(seq) => Some(StreamId::new(self.ms, seq)),
+            None => Some(StreamId::new(self.ms.checked_sub(1)?, u64::MAX)),
+        }
+    }
+
+    /// Big endian encoding, which sorts like the IDs. Used for node keys in RDB files.
+    pub(crate) fn to_be_bytes(self) -> [u8; 16] {
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&self.ms.to_be_bytes());
+        bytes[8..].copy_from_slice(&self.seq.to_be_bytes());
+        bytes
+    }
+
+    pub(crate) fn from_be_bytes(bytes: [u8; 16]) -> Self {
+        Self {
+            ms: u64::from_be_bytes(bytes[..8].try_into().unwrap()),
+            seq: u64::from_be_bytes(bytes[8..].try_into().unwrap()),
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// Field-value pairs of an entry, in the order they were added.
+pub(crate) type Fields = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// ID requested for a new entry.
+pub(crate) enum NewId {
+    /// `*`: generated from the current time.
+    Auto,
+    /// `ms-*`: the sequence number is generated.
+    AutoSeq(u64),
+    Explicit(StreamId),
+}
+
+/// Entry delivered to a consumer of a group but not acknowledged yet.
+#[derive(Clone)]
+pub(crate) struct PendingEntry {
+    pub(crate) consumer: Vec<u8>,
+    /// Unix time in milliseconds of the last delivery.
+    pub(crate) delivery_time: u64,
+    pub(crate) delivery_count: u64,
+}
+
+#[derive(Clone)]
+pub(crate) struct Consumer {
+    /// Unix time in milliseconds of the last attempted interaction.
+    pub(crate) seen_time: u64,
+    /// Unix time in milliseconds of the last successful read or claim, if any.
+    pub(crate) active_time: Option<u64>,
+    /// IDs of the entries pending for this consumer, also in the group's list.
+    pub(crate) pending: BTreeSet<StreamId>,
+}
+
+impl Consumer {
+    pub(crate) fn new(now: u64) -> Self {
+        Self {
+            seen_time: now,
+            active_time: None,
+            pending: BTreeSet::new(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct ConsumerGroup {
+    /// ID of the last entry delivered to the group.
+    pub(crate) last_id: StreamId,
+    /// Number of entries read by the group, if it can be known.
+    pub(crate) entries_read: Option<u64>,
+    /// Pending entries list of the group.
+    pub(crate) pending: BTreeMap<StreamId, PendingEntry>,
+    pub(crate) consumers: BTreeMap<Vec<u8>, Consumer>,
+}
+
+impl ConsumerGroup {
+    pub(crate) fn new(last_id: StreamId, entries_read: Option<u64>) -> Self {
+        Self {
+            last_id,
+            entries_read,
+            pending: BTreeMap::new(),
+            consumers: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the consumer, creating it if needed, and marks it as seen.
+    pub(crate) fn consumer(&mut self, name: &[u8], now: u64) -> &mut Consumer {
+        let consumer = self
+            .consumers
+            .entry(name.to_vec())
+            .or_insert_with(|| Consumer::new(now));
+        consumer.seen_time = now;
+        consumer
+    }
+
+    /// Makes the consumer the owner of the pending entry, creating it if needed.
+    fn assign(&mut self, id: StreamId, consumer: &[u8], now: u64) -> &mut PendingEntry {
+        let entry = self.pending.entry(id).or_insert_with(|| PendingEntry {
+            consumer: Vec::new(),
+            delivery_time: now,
+            delivery_count: 0,
+        });
+        if entry.consumer != consumer {
+            if let Some(previous) = self.consumers.get_mut(&entry.consumer) {
+                previous.pending.remove(&id);
+            }
+            entry.consumer = consumer.to_vec();
+            self.consumers
+                .entry(consumer.to_vec())
+                .or_insert_with(|| Consumer::new(now))
+                .pending
+                .insert(id);
+        }
+        entry
+    }
+
+    /// Removes the entry from the pending entries lists. Returns false if it was not pending.
+    pub(crate) fn acknowledge(&mut self, id: StreamId) -> bool {
+        let Some(entry) = self.pending.remove(&id) else {
+            return false;
+        };
+        if let Some(consumer) = self.consumers.get_mut(&entry.consumer) {
+            consumer.pending.remove(&id);
+        }
+        true
+    }
+
+    /// Deletes the consumer and its pending entries. Returns the number of entries it had
+    /// pending, or None if it did not exist.
+    pub(crate) fn remove_consumer(&mut self, name: &[u8]) -> Option<usize> {
+        let consumer = self.consumers.remove(name)?;
+        for id in &consumer.pending {
+            self.pending.remove(id);
+        }
+        Some(consumer.pending.len())
+    }
+}
+
+/// Options of XCLAIM and XAUTOCLAIM.
+pub(crate) struct Claim<'a> {
+    pub(crate) consumer: &'a [u8],
+    /// Only entries idle for at least this many milliseconds are claimed.
+    pub(crate) min_idle: u64,
+    /// Unix time in milliseconds to set as the last delivery time.
+    pub(crate) delivery_time: u64,
+    /// Delivery count to set instead of incrementing it.
+    pub(crate) retry_count: Option<u64>,
+    /// Do not increment the delivery count.
+    pub(crate) just_id: bool,
+}
+
+/// Result of claiming a pending entry.
+pub(crate) enum Claimed {
+    /// The entry was claimed. Fields are included unless the claim was `just_id`.
+    Entry(StreamId, Option<Fields>),
+    /// The entry was deleted from the stream and has been removed from the pending lists.
+    Deleted(StreamId),
+    /// The entry is not pending or not idle long enough.
+    Skipped,
+}
+
+/// Append-only log of entries ordered by ID.
+#[derive(Clone)]
+pub(crate) struct Stream {
+    entries: BTreeMap<StreamId, Fields>,
+    /// ID of the last entry ever added, even if it was deleted since.
+    last_id: StreamId,
+    /// Greatest ID deleted with XDEL.
+    max_deleted_id: StreamId,
+    /// Number of entries ever added.
+    entries_added: u64,
+    groups: BTreeMap<Vec<u8>, ConsumerGroup>,
+}
+
+impl Stream {
+    pub(crate) fn new() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            last_id: StreamId::MIN,
+            max_deleted_id: StreamId::MIN,
+            entries_added: 0,
+            groups: BTreeMap::new(),
+        }
+    }
+
+    /// Creates a stream with the metadata stored in an RDB file.
+    pub(crate) fn from_parts(
+        entries: BTreeMap<StreamId, Fields>,
+        last_id: StreamId,
+        max_deleted_id: StreamId,
+        entries_added: u64,
+        groups: BTreeMap<Vec<u8>, ConsumerGroup>,
+    ) -> Self {
+        Self {
+            entries,
+            last_id,
+            max_deleted_id,
+            entries_added,
+            groups,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub(crate) fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    pub(crate) fn first_id(&self) -> Option<StreamId> {
+        self.entries.keys().next().copied()
+    }
+
+    pub(crate) fn max_deleted_id(&self) -> StreamId {
+        self.max_deleted_id
+    }
+
+    pub(crate) fn entries_added(&self) -> u64 {
+        self.entries_added
+    }
+
+    /// Returns the ID for a new entry, or None if it would not be greater than the last ID.
+    pub(crate) fn next_id(&self, requested: NewId, now_ms: u64) -> Option<StreamId> {
+        let id = match requested {
+            NewId::Auto if now_ms > self.last_id.ms => StreamId::new(now_ms, 0),
+            NewId::Auto => self.last_id.next()?,
+            NewId::AutoSeq(ms) if ms == self.last_id.ms => {
+                StreamId::new(ms, self.last_id.seq.checked_add(1)?)
+            }
+            // 0-0 is never a valid ID.
+            NewId::AutoSeq(0) => StreamId::new(0, 1),
+            NewId::AutoSeq(ms) => StreamId::new(ms, 0),
+            NewId::Explicit(id) => id,
+        };
+        (id > self.last_id).then_some(id)
+    }
+
+    /// Appends an entry. The ID must be greater than the last ID.
+    pub(crate) fn insert(&mut self, id: StreamId, fields: Fields) {
+        debug_assert!(id > self.last_id);
+        self.entries.insert(id, fields);
+        self.last_id = id;
+        self.entries_added += 1;
+    }
+
+    pub(crate) fn remove(&mut self, id: StreamId) -> bool {
+        if self.entries.remove(&id).is_none() {
+            return false;
+        }
+        self.max_deleted_id = self.max_deleted_id.max(id);
+        true
+    }
+
+    pub(crate) fn range(
+        &self,
+        range: RangeInclusive<StreamId>,
+    ) -> impl DoubleEndedIterator<Item = (&StreamId, &Fields)> {
+        self.entries.range(range)
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&StreamId, &Fields)> {
+        self.entries.iter()
+    }
+
+    /// Removes the first `count` entries, limited to whole nodes if `approx`, and at most
+    /// `limit` entries if set. Returns the number of entries removed.
+    fn trim_first(&mut self, count: usize, approx: bool, limit: Option<usize>) -> usize {
+        let mut count = count.min(limit.unwrap_or(usize::MAX));
+        if approx {
+            count -= count % NODE_MAX_ENTRIES;
+        }
+        for _ in 0..count {
+            self.entries.pop_first();
+        }
+        count
+    }
+
+    /// Removes the oldest entries until at most `max_len` remain.
+    pub(crate) fn trim_max_len(
+        &mut self,
+        max_len: usize,
+        approx: bool,
+        limit: Option<usize>,
+    ) -> usize {
+        let excess = self.len().saturating_sub(max_len);
+        self.trim_first(excess, approx, limit)
+    }
+
+    /// Removes the entries with an ID lower than `min_id`.
+    pub(crate) fn trim_min_id(
+        &mut self,
+        min_id: StreamId,
+        approx: bool,
+        limit: Option<usize>,
+    ) -> usize {
+        let older = self.entries.range(..min_id).count();
+        self.trim_first(older, approx, limit)
+    }
+
+    pub(crate) fn groups(&self) -> &BTreeMap<Vec<u8>, ConsumerGroup> {
+        &self.groups
+    }
+
+    pub(crate) fn group(&self, name: &[u8]) -> Option<&ConsumerGroup> {
+        self.groups.get(name)
+    }
+
+    pub(crate) fn group_mut(&mut self, name: &[u8]) -> Option<&mut ConsumerGroup> {
+        self.groups.get_mut(name)
+    }
+
+    /// Creates a group that delivers entries after `last_id`. Returns false if it exists.
+    pub(crate) fn create_group(
+        &mut self,
+        name: &[u8],
+        last_id: StreamId,
+        entries_read: Option<u64>,
+    ) -> bool {
+        if self.groups.contains_key(name) {
+            return false;
+        }
+        self.groups
+            .insert(name.to_vec(), ConsumerGroup::new(last_id, entries_read));
+        true
+    }
+
+    pub(crate) fn remove_group(&mut self, name: &[u8]) -> bool {
+        self.groups.remove(name).is_some()
+    }
+
+    /// Returns the number of entries added up to and including `id`, if it can be known,
+    /// i.e. if no entry was deleted from the middle of the stream.
+    fn entries_read_until(&self, id: StreamId) -> Option<u64> {
+        if self.entries_added == 0 || id >= self.last_id {
+            return Some(self.entries_added);
+        }
+        let first_id = self.first_id()?;
+        if self.max_deleted_id != StreamId::MIN && self.max_deleted_id >= first_id {
+            return None;
+        }
+        let before_first = self.entries_added - self.len() as u64;
+        match id.cmp(&first_id) {
+            std::cmp::Ordering::Less => Some(before_first),
+            std::cmp::Ordering::Equal => Some(before_first + 1),
+            std::cmp::Ordering::Greater => None,
+        }
+    }
+
+    /// Returns true if entries at or after `id` may have been deleted.
+    fn has_tombstones_from(&self, id: StreamId) -> bool {
+        !self.entries.is_empty()
+            && self.max_deleted_id != StreamId::MIN
+            && id <= self.max_deleted_id
+    }
+
+    /// Delivers up to `count` entries added after the group's last delivered ID to the
+    /// consumer, adding them to the pending entries lists unless `no_ack`. The group must
+    /// exist.
+    pub(crate) fn read_group_new(
+        &mut self,
+        group: &[u8],
+        consumer: &[u8],
+        count: Option<usize>,
+        no_ack: bool,
+        now: u64,
+    ) -> Vec<(StreamId, Fields)> {
+        let (last_id, mut entries_read) = {
+            let group = &self.groups[group];
+            (group.last_id, group.entries_read)
+        };
+        let delivered: Vec<(StreamId, Fields)> = match last_id.next() {
+            Some(start) => self
+                .entries
+                .range(start..=StreamId::MAX)
+                .take(count.unwrap_or(usize::MAX))
+                .map(|(id, fields)| (*id, fields.clone()))
+                .collect(),
+            None => Vec::new(),
+        };
+        // Keep counting the entries read while it is known that none were deleted.
+        for (id, _) in &delivered {
+            entries_read = match entries_read {
+                Some(read) if !self.has_tombstones_from(*id) => Some(read + 1),
+                _ => self.entries_read_until(*id),
+            };
+        }
+
+        let group = self.groups.get_mut(group).unwrap();
+        group.consumer(consumer, now);
+        let Some((last_delivered, _)) = delivered.last() else {
+            return delivered;
+        };
+        group.last_id = *last_delivered;
+        group.entries_read = entries_read;
+        group.consumer(consumer, now).active_time = Some(now);
+        if !no_ack {
+            for (id, _) in &delivered {
+                let entry = group.assign(*id, consumer, now);
+                entry.delivery_time = now;
+                entry.delivery_count = 1;
+            }
+        }
+        delivered
+    }
+
+    /// Delivers again up to `count` entries pending for the consumer with an ID greater than
+    /// `after`. Entries deleted from the stream have no fields. The group must exist.
+    pub(crate) fn read_group_pending(
+        &mut self,
+        group: &[u8],
+        consumer: &[u8],
+        after: StreamId,
+        count: Option<usize>,
+        now: u64,
+    ) -> Vec<(StreamId, Option<Fields>)> {
+        let group = self.groups.get_mut(group).unwrap();
+        let Some(start) = after.next() else {
+            return Vec::new();
+        };
+        let ids: Vec<StreamId> = group
+            .consumer(consumer, now)
+            .pending
+            .range(start..)
+            .take(count.unwrap_or(usize::MAX))
+            .copied()
+            .collect();
+
+        ids.into_iter()
+            .map(|id| {
+                let entry = group.pending.get_mut(&id).unwrap();
+                entry.delivery_time = now;
+                entry.delivery_count += 1;
+                (id, self.entries.get(&id).cloned())
+            })
+            .collect()
+    }
+
+    /// Claims a pending entry of the group for XCLAIM and XAUTOCLAIM. With `force`, an
+    /// entry of the stream that is not pending is added to the pending entries list. The
+    /// group must exist.
+    pub(crate) fn claim(
+        &mut self,
+        group: &[u8],
+        id: StreamId,
+        claim: &Claim,
+        force: bool,
+        now: u64,
+    ) -> Claimed {
+        let group = self.groups.get_mut(group).unwrap();
+        match group.pending.get(&id) {
+            Some(entry) => {
+                if now.saturating_sub(entry.delivery_time) < claim.min_idle {
+                    return Claimed::Skipped;
+                }
+            }
+            // A new pending entry is not idle yet.
+            None if force && claim.min_idle == 0 && self.entries.contains_key(&id) => {}
+            None => return Claimed::Skipped,
+        }
+
+        let Some(fields) = self.entries.get(&id) else {
+            group.acknowledge(id);
+            return Claimed::Deleted(id);
+        };
+
+        let entry = group.assign(id, claim.consumer, now);
+        entry.delivery_time = claim.delivery_time;
+        match claim.retry_count {
+            Some(count) => entry.delivery_count = count,
+            None if !claim.just_id => entry.delivery_count += 1,
+            None => {}
+        }
+        group.consumer(claim.consumer, now).active_time = Some(now);
+        Claimed::Entry(id, (!claim.just_id).then(|| fields.clone()))
+    }
+}